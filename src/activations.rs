@@ -1,50 +1,105 @@
 #![allow(dead_code, unused_variables)]
 
-use crate::matrixutil::{exp_weight, scalar_add, scalar_div, scalar_mult, scalar_sub};
-use ndarray::{Array, Dimension};
-use std::f32::consts::PI;
+use crate::{
+    autograd::{Tape, Var},
+    matrixutil::{exp_weight, numerical_gradient, scalar_add, scalar_mult, scalar_sub},
+};
+use ndarray::{Array, Array2, ArrayBase, Axis, Data, Dimension, Ix2};
+use std::{f32::consts::PI, fmt};
+
+// SELU constants from Klambauer et al., truncated to what an f32 can hold
+const SELU_ALPHA: f32 = 1.673_263_2;
+const SELU_LAMBDA: f32 = 1.050_701;
+
+// anything that can be used as a layer's activation, implement it and wrap it in Activations::Custom
+// to plug in your own; register a constructor under its id to load saved models that use it
+pub trait Activation {
+    fn activate(&self, z: &Array2<f32>) -> Array2<f32>;
+    // elementwise ∂a/∂z
+    fn derivate(&self, z: &Array2<f32>) -> Array2<f32>;
+    // ∂C/∂z given ∂C/∂a, override it when outputs depend on more than their own input
+    fn backward(&self, z: &Array2<f32>, c_wrt_a: &Array2<f32>) -> Array2<f32> {
+        self.derivate(z) * c_wrt_a
+    }
+    // what summary prints
+    fn name(&self) -> String;
+    // key the registry rebuilds it from when a saved model is loaded
+    fn id(&self) -> String {
+        self.name()
+    }
+    // whatever the registered constructor needs to rebuild it, saved alongside the id
+    fn params(&self) -> Vec<f32> {
+        Vec::new()
+    }
+}
+
+impl fmt::Debug for dyn Activation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
 
 // enum storing each activation function
 #[derive(Debug)]
 pub enum Activations {
+    // identity, used by layers without an activation of their own
+    Linear,
     Sigmoid,
     ReLU,
     LeakyReLU { a: f32 },
     Tanh,
-    Softmax,
+    // row-wise softmax(z/T), higher temperatures flatten the distribution and lower ones sharpen it
+    Softmax { temperature: f32 },
+    // log(softmax(z/T)) computed directly, which stays finite where the log of a softmax would underflow
+    LogSoftmax { temperature: f32 },
     SoftPlus,
     SoftSign,
     ELU { a: f32 },
     SELU,
+    // tanh approximation of x Φ(x)
     GELU,
+    // x Φ(x) computed with erf, slower than GELU but exact
+    ExactGELU,
+    // x σ(βx)
+    Swish { beta: f32 },
+    // swish with β = 1
+    SiLU,
+    // x tanh(softplus(x))
+    Mish,
+    // piecewise linear σ: clamp(x/6 + 1/2, 0, 1)
+    HardSigmoid,
+    // x HardSigmoid(x)
+    HardSwish,
+    // clamp(x, -1, 1)
+    HardTanh,
+    // user defined
+    Custom(Box<dyn Activation>),
 }
 
 impl Activations {
-    pub fn activate<D>(&self, weight: &Array<f32, D>) -> Array<f32, D>
+    pub fn activate<S, D>(&self, weight: &ArrayBase<S, D>) -> Array<f32, D>
     where
+        S: Data<Elem = f32>,
         D: Dimension,
     {
         match self {
+            Activations::Linear => weight.to_owned(),
             Activations::Sigmoid => weight.mapv(|x: f32| 1. / (1. + (-x).exp())),
             Activations::ReLU => weight.mapv(|x: f32| if x > 0. { x } else { 0. }),
             Activations::LeakyReLU { a } => {
                 weight.mapv(|x: f32| if x >= 0. { x } else { x * (*a) })
             }
             Activations::Tanh => weight.mapv(|x: f32| x.tanh()),
-            Activations::Softmax => {
-                let mut w = weight.clone();
-                let ex: &mut Array<f32, D> = exp_weight(&mut w);
-                let sum: f32 = (&ex).sum();
-                scalar_div(ex, sum).to_owned()
-            }
-            Activations::SoftPlus => weight.mapv(|x| (x.exp() + 1.).ln()),
+            Activations::Softmax { temperature } => softmax(weight, *temperature, false),
+            Activations::LogSoftmax { temperature } => softmax(weight, *temperature, true),
+            Activations::SoftPlus => weight.mapv(softplus),
             Activations::SoftSign => weight.mapv(|x: f32| x / (x.abs() + 1.)),
             Activations::ELU { a } => {
                 weight.mapv(|x: f32| if x > 0. { x } else { (x.exp() - 1.) * (*a) })
             }
             Activations::SELU => {
-                let a: f32 = 1.6732632423543772848170429916717;
-                let l: f32 = 1.0507009873554804934193349852946;
+                let a: f32 = SELU_ALPHA;
+                let l: f32 = SELU_LAMBDA;
                 weight.mapv(|x: f32| if x > 0. { x * l } else { l * (a * x.exp() - a) })
             }
             Activations::GELU => {
@@ -53,19 +108,31 @@ impl Activations {
                 let f1 = |x: f32| x + 0.044715 * x.powi(3);
                 weight.mapv(|x: f32| 0.5 * x * (1. + (c1 * f1(x)).tanh()))
             }
+            Activations::ExactGELU => weight.mapv(|x: f32| x * normal_cdf(x)),
+            Activations::Swish { beta } => weight.mapv(|x: f32| x * sigmoid(beta * x)),
+            Activations::SiLU => weight.mapv(|x: f32| x * sigmoid(x)),
+            Activations::Mish => weight.mapv(|x: f32| x * softplus(x).tanh()),
+            Activations::HardSigmoid => weight.mapv(hard_sigmoid),
+            Activations::HardSwish => weight.mapv(|x: f32| x * hard_sigmoid(x)),
+            Activations::HardTanh => weight.mapv(|x: f32| x.clamp(-1., 1.)),
+            Activations::Custom(f) => from_2d(f.activate(&to_2d(weight))),
         }
     }
 
     // I know this isn't technically grammatically correct but I like the name for homogeneity
-    pub fn derivate<D>(&self, weight: &Array<f32, D>) -> Array<f32, D>
+    // elementwise ∂a/∂z; for (Log)Softmax, whose outputs depend on the whole row, this is only the diagonal
+    // of the Jacobian, so backprop goes through backward instead
+    pub fn derivate<S, D>(&self, weight: &ArrayBase<S, D>) -> Array<f32, D>
     where
+        S: Data<Elem = f32>,
         D: Dimension,
     {
         match self {
+            Activations::Linear => weight.mapv(|_| 1.),
             Activations::Sigmoid => {
                 // e^-x
                 let ex: Array<f32, D> =
-                    exp_weight(scalar_mult(&mut weight.clone(), -1f32)).to_owned();
+                    exp_weight(scalar_mult(&mut weight.to_owned(), -1f32)).to_owned();
                 let ex2: &mut Array<f32, D> = &mut ex.clone();
                 // (e^-x)+1
                 let denom: &Array<f32, D> = scalar_add(ex2, 1.);
@@ -73,35 +140,36 @@ impl Activations {
                 ex.to_owned() / (denom * denom)
             }
             Activations::ReLU => {
-                // technically it's undefined at x[[i,j]] == 0
-                weight.mapv(|x: f32| if x > 0. { x } else { 0. })
-            }
-            Activations::LeakyReLU { a } => {
-                weight.mapv(|x: f32| if x >= 0. { x } else { x * (*a) })
+                // technically it's undefined at x[[i,j]] == 0, we take the left derivative
+                weight.mapv(|x: f32| if x > 0. { 1. } else { 0. })
             }
+            Activations::LeakyReLU { a } => weight.mapv(|x: f32| if x >= 0. { 1. } else { *a }),
             Activations::Tanh => {
                 let sech = |x: f32| 1. / x.cosh();
                 weight.mapv(|x: f32| sech(x).powi(2))
             }
-            Activations::Softmax => {
-                //TODO: add temperature
+            Activations::Softmax { temperature } => {
+                // ∂sᵢ/∂zᵢ = sᵢ(1-sᵢ)/T
                 let sf: Array<f32, D> = self.activate(weight);
-                let sf2: Array<f32, D> = scalar_sub(&mut sf.to_owned(), 1.).to_owned();
-                sf * sf2
+                sf.mapv(|s: f32| s * (1. - s) / temperature)
+            }
+            Activations::LogSoftmax { temperature } => {
+                // ∂log(sᵢ)/∂zᵢ = (1-sᵢ)/T
+                let sf: Array<f32, D> = softmax(weight, *temperature, false);
+                sf.mapv(|s: f32| (1. - s) / temperature)
             }
             Activations::SoftPlus => {
                 //derivative of softplus is sigmoid
                 weight.mapv(|x: f32| 1. / (1. + (-x).exp()))
             }
-            Activations::SoftSign => weight.mapv(|x: f32| x / ((x.abs() + 1.).powi(2))),
-            Activations::ELU { a } => {
-                let act = |x: f32| if x > 0. { x } else { (x.exp() - 1.) * (*a) };
-                weight.mapv(|x: f32| if x > 0. { 1. } else { act(x) + (*a) })
-            }
+            Activations::SoftSign => weight.mapv(|x: f32| 1. / (x.abs() + 1.).powi(2)),
+            // ELU(x) = a(e^x-1) for x <= 0 so its slope there is ae^x
+            Activations::ELU { a } => weight.mapv(|x: f32| if x > 0. { 1. } else { a * x.exp() }),
+            // SELU is λ ELU with a = α
             Activations::SELU => {
-                let a: f32 = 1.6732632423543772848170429916717;
-                let l: f32 = 1.0507009873554804934193349852946;
-                weight.mapv(|x: f32| if x > 0. { l } else { l * (a * x.exp()) })
+                let a: f32 = SELU_ALPHA;
+                let l: f32 = SELU_LAMBDA;
+                weight.mapv(|x: f32| if x > 0. { l } else { l * a * x.exp() })
             }
             Activations::GELU => {
                 //0.5tanh(0.0356774x^3+0.797885x)+(0.0535161x^3+0.398942x)sech^2(0.0356774x^3+0.797885x)+0.5
@@ -110,6 +178,511 @@ impl Activations {
                 let sech = |x: f32| 1. / x.cosh();
                 weight.mapv(|x: f32| 0.5 * f1(x).tanh() + f2(x) * sech(f1(x)).powi(2) + 0.5)
             }
+            // Φ(x) + x φ(x)
+            Activations::ExactGELU => {
+                weight.mapv(|x: f32| normal_cdf(x) + x * (-0.5 * x * x).exp() / (2. * PI).sqrt())
+            }
+            // σ(βx) + βx σ(βx)(1-σ(βx))
+            Activations::Swish { beta } => weight.mapv(|x: f32| {
+                let s: f32 = sigmoid(beta * x);
+                s + beta * x * s * (1. - s)
+            }),
+            Activations::SiLU => weight.mapv(|x: f32| {
+                let s: f32 = sigmoid(x);
+                s + x * s * (1. - s)
+            }),
+            // tanh(sp(x)) + x sech²(sp(x)) σ(x), since softplus' derivative is sigmoid
+            Activations::Mish => weight.mapv(|x: f32| {
+                let t: f32 = softplus(x).tanh();
+                t + x * (1. - t * t) * sigmoid(x)
+            }),
+            Activations::HardSigmoid => {
+                weight.mapv(|x: f32| if x > -3. && x < 3. { 1. / 6. } else { 0. })
+            }
+            Activations::HardSwish => weight.mapv(|x: f32| {
+                if x <= -3. {
+                    0.
+                } else if x >= 3. {
+                    1.
+                } else {
+                    (2. * x + 3.) / 6.
+                }
+            }),
+            Activations::HardTanh => weight.mapv(|x: f32| if x.abs() < 1. { 1. } else { 0. }),
+            Activations::Custom(f) => from_2d(f.derivate(&to_2d(weight))),
+        }
+    }
+
+    // ∂C/∂z given ∂C/∂a, i.e. the Jacobian of the activation transposed times c_wrt_a
+    // for elementwise activations the Jacobian is diagonal so this is derivate(z) * c_wrt_a
+    pub fn backward<S, T, D>(
+        &self,
+        weight: &ArrayBase<S, D>,
+        c_wrt_a: &ArrayBase<T, D>,
+    ) -> Array<f32, D>
+    where
+        S: Data<Elem = f32>,
+        T: Data<Elem = f32>,
+        D: Dimension,
+    {
+        match self {
+            Activations::Softmax { temperature } => {
+                // ∂sᵢ/∂zⱼ = sᵢ(δᵢⱼ - sⱼ)/T so ∂C/∂zᵢ = sᵢ(∂C/∂sᵢ - Σⱼ sⱼ ∂C/∂sⱼ)/T, row by row
+                let mut out: Array<f32, D> = self.activate(weight);
+                let axis: Axis = Axis(out.ndim() - 1);
+                for (mut s, g) in out.lanes_mut(axis).into_iter().zip(c_wrt_a.lanes(axis)) {
+                    let dot: f32 = s.iter().zip(g.iter()).map(|(s, g)| s * g).sum();
+                    s.zip_mut_with(&g, |s, g| *s *= (g - dot) / temperature);
+                }
+                out
+            }
+            Activations::LogSoftmax { temperature } => {
+                // ∂log(sᵢ)/∂zⱼ = (δᵢⱼ - sⱼ)/T so ∂C/∂zⱼ = (∂C/∂yⱼ - sⱼ Σᵢ ∂C/∂yᵢ)/T
+                let mut out: Array<f32, D> = softmax(weight, *temperature, false);
+                let axis: Axis = Axis(out.ndim() - 1);
+                for (mut s, g) in out.lanes_mut(axis).into_iter().zip(c_wrt_a.lanes(axis)) {
+                    let total: f32 = g.sum();
+                    s.zip_mut_with(&g, |s, g| *s = (g - *s * total) / temperature);
+                }
+                out
+            }
+            Activations::Custom(f) => from_2d(f.backward(&to_2d(weight), &to_2d(c_wrt_a))),
+            _ => self.derivate(weight) * c_wrt_a,
+        }
+    }
+
+    // activate on a (batch × features) tensor recorded on tape, out of primitive ops so the tape works the
+    // gradient out itself instead of going through backward
+    pub fn record<'a>(&'a self, tape: &mut Tape<'a>, z: Var) -> Var {
+        match self {
+            Activations::Linear => z,
+            Activations::Sigmoid => tape.sigmoid(z),
+            Activations::ReLU => {
+                let zero: Var = tape.scalar(0.);
+                tape.maximum(z, zero)
+            }
+            // max(x, 0) + a min(x, 0)
+            Activations::LeakyReLU { a } => {
+                let (positive, negative) = split_sign(tape, z);
+                let negative: Var = tape.scale(negative, *a);
+                tape.add(positive, negative)
+            }
+            Activations::Tanh => tape.tanh(z),
+            Activations::Softmax { temperature } => record_softmax(tape, z, *temperature, false),
+            Activations::LogSoftmax { temperature } => record_softmax(tape, z, *temperature, true),
+            Activations::SoftPlus => tape.softplus(z),
+            Activations::SoftSign => {
+                let denom: Var = tape.abs(z);
+                let denom: Var = tape.offset(denom, 1.);
+                tape.div(z, denom)
+            }
+            Activations::ELU { a } => record_elu(tape, z, *a),
+            Activations::SELU => {
+                let elu: Var = record_elu(tape, z, SELU_ALPHA);
+                tape.scale(elu, SELU_LAMBDA)
+            }
+            //0.5x(1+tanh(√2/π(x+0.044715x^3)))
+            Activations::GELU => {
+                let cube: Var = tape.powf(z, 3.);
+                let cube: Var = tape.scale(cube, 0.044715);
+                let inner: Var = tape.add(z, cube);
+                let inner: Var = tape.scale(inner, (2. / PI).sqrt());
+                let t: Var = tape.tanh(inner);
+                let t: Var = tape.offset(t, 1.);
+                let half: Var = tape.scale(z, 0.5);
+                tape.mul(half, t)
+            }
+            // x (1 + erf(x/√2))/2
+            Activations::ExactGELU => {
+                let scaled: Var = tape.scale(z, 1. / std::f32::consts::SQRT_2);
+                let cdf: Var = tape.erf(scaled);
+                let cdf: Var = tape.offset(cdf, 1.);
+                let cdf: Var = tape.scale(cdf, 0.5);
+                tape.mul(z, cdf)
+            }
+            Activations::Swish { beta } => {
+                let scaled: Var = tape.scale(z, *beta);
+                let gate: Var = tape.sigmoid(scaled);
+                tape.mul(z, gate)
+            }
+            Activations::SiLU => {
+                let gate: Var = tape.sigmoid(z);
+                tape.mul(z, gate)
+            }
+            Activations::Mish => {
+                let sp: Var = tape.softplus(z);
+                let gate: Var = tape.tanh(sp);
+                tape.mul(z, gate)
+            }
+            Activations::HardSigmoid => record_hard_sigmoid(tape, z),
+            Activations::HardSwish => {
+                let gate: Var = record_hard_sigmoid(tape, z);
+                tape.mul(z, gate)
+            }
+            Activations::HardTanh => tape.clamp(z, -1., 1.),
+            Activations::Custom(f) => tape.custom_activation(z, f.as_ref()),
+        }
+    }
+
+    // largest difference between backward and central finite differences of Σ a(z)⊙r at z, where r is
+    // a fixed uneven weighting so the off-diagonal terms of (Log)Softmax are exercised too
+    // anything much above h² (besides points sitting on a kink like ReLU's 0) means a wrong derivative
+    pub fn gradient_check(&self, z: &Array2<f32>, h: f32) -> f32 {
+        let r: Array2<f32> =
+            Array2::from_shape_fn(z.dim(), |(i, j)| ((i * 7 + j * 3 + 1) as f32).sin());
+        let analytic: Array2<f32> = self.backward(z, &r);
+        let numeric: Array2<f32> =
+            numerical_gradient(|x: &Array2<f32>| (self.activate(x) * &r).sum(), z, h);
+        (analytic - numeric)
+            .iter()
+            .fold(0f32, |m: f32, e: &f32| m.max(e.abs()))
+    }
+}
+
+// softmax(z/T) (or its log) along the last axis so every row of a batch is its own distribution
+// the row max is subtracted first, which leaves the result unchanged but keeps exp from overflowing
+fn softmax<S, D>(weight: &ArrayBase<S, D>, temperature: f32, log: bool) -> Array<f32, D>
+where
+    S: Data<Elem = f32>,
+    D: Dimension,
+{
+    let mut w: Array<f32, D> = weight.mapv(|x: f32| x / temperature);
+    let axis: Axis = Axis(w.ndim() - 1);
+    for mut lane in w.lanes_mut(axis) {
+        let max: f32 = lane.fold(f32::NEG_INFINITY, |m: f32, x: &f32| m.max(*x));
+        scalar_sub(&mut lane, max);
+        let log_sum: f32 = lane.mapv(f32::exp).sum().ln();
+        if log {
+            scalar_sub(&mut lane, log_sum);
+        } else {
+            lane.mapv_inplace(|x: f32| (x - log_sum).exp());
+        }
+    }
+    w
+}
+
+// softmax along the rows of z/T, with the row max taken off first just like softmax does
+pub fn record_softmax<'a>(tape: &mut Tape<'a>, z: Var, temperature: f32, log: bool) -> Var {
+    let scaled: Var = tape.scale(z, 1. / temperature);
+    let max: Var = tape.max_axis(scaled, 1);
+    let shifted: Var = tape.sub(scaled, max);
+    let e: Var = tape.exp(shifted);
+    let total: Var = tape.sum_axis(e, 1);
+    if log {
+        let log_sum: Var = tape.ln(total);
+        tape.sub(shifted, log_sum)
+    } else {
+        tape.div(e, total)
+    }
+}
+
+// (max(x, 0), min(x, 0))
+fn split_sign<'a>(tape: &mut Tape<'a>, z: Var) -> (Var, Var) {
+    let zero: Var = tape.scalar(0.);
+    (tape.maximum(z, zero), tape.minimum(z, zero))
+}
+
+// max(x, 0) + a(e^min(x, 0) - 1)
+fn record_elu<'a>(tape: &mut Tape<'a>, z: Var, a: f32) -> Var {
+    let (positive, negative) = split_sign(tape, z);
+    let e: Var = tape.exp(negative);
+    let e: Var = tape.offset(e, -1.);
+    let e: Var = tape.scale(e, a);
+    tape.add(positive, e)
+}
+
+fn record_hard_sigmoid<'a>(tape: &mut Tape<'a>, z: Var) -> Var {
+    let x: Var = tape.scale(z, 1. / 6.);
+    let x: Var = tape.offset(x, 0.5);
+    tape.clamp(x, 0., 1.)
+}
+
+impl Activation for Activations {
+    fn activate(&self, z: &Array2<f32>) -> Array2<f32> {
+        Activations::activate(self, z)
+    }
+
+    fn derivate(&self, z: &Array2<f32>) -> Array2<f32> {
+        Activations::derivate(self, z)
+    }
+
+    fn backward(&self, z: &Array2<f32>, c_wrt_a: &Array2<f32>) -> Array2<f32> {
+        Activations::backward(self, z, c_wrt_a)
+    }
+
+    fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+// custom activations work on (batch × features) matrices, these move the generic arrays in and out
+fn to_2d<S, D>(weight: &ArrayBase<S, D>) -> Array2<f32>
+where
+    S: Data<Elem = f32>,
+    D: Dimension,
+{
+    weight
+        .to_owned()
+        .into_dimensionality::<Ix2>()
+        .expect("custom activations take 2-D input")
+}
+
+fn from_2d<D>(weight: Array2<f32>) -> Array<f32, D>
+where
+    D: Dimension,
+{
+    weight.into_dimensionality::<D>().unwrap()
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+// ln(1+e^x) rearranged so large inputs don't overflow
+fn softplus(x: f32) -> f32 {
+    x.max(0.) + (-x.abs()).exp().ln_1p()
+}
+
+fn hard_sigmoid(x: f32) -> f32 {
+    (x / 6. + 0.5).clamp(0., 1.)
+}
+
+// Φ(x) = (1 + erf(x/√2))/2
+fn normal_cdf(x: f32) -> f32 {
+    0.5 * (1. + erf(x / std::f32::consts::SQRT_2))
+}
+
+// Abramowitz and Stegun 7.1.26, absolute error below 1.5e-7 which is about all an f32 holds anyway
+pub fn erf(x: f32) -> f32 {
+    let t: f32 = 1. / (1. + 0.327_591_1 * x.abs());
+    let poly: f32 = t
+        * (0.254_829_6
+            + t * (-0.284_496_74 + t * (1.421_413_7 + t * (-1.453_152_1 + t * 1.061_405_4))));
+    let y: f32 = 1. - poly * (-x * x).exp();
+    if x < 0. {
+        -y
+    } else {
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        autograd::{check_gradients, Adjoints},
+        layers::Layers,
+        matrixutil::to_rows,
+    };
+    use ndarray::{array, ArrayD};
+
+    // every entry sits well away from the kinks at 0, ±1 and ±3 so central differences are smooth
+    fn z() -> Array2<f32> {
+        array![[-2.5, -0.7, 0.4, 1.3], [2.2, -1.6, 0.9, -0.2]]
+    }
+
+    fn assert_gradient(activation: &Activations, z: &Array2<f32>) {
+        let error: f32 = activation.gradient_check(z, 1e-2);
+        assert!(error < 1e-3, "{:?} is off by {}", activation, error);
+    }
+
+    #[test]
+    fn elementwise_backward_matches_finite_differences() {
+        let activations: Vec<Activations> = vec![
+            Activations::Linear,
+            Activations::Sigmoid,
+            Activations::ReLU,
+            Activations::LeakyReLU { a: 0.1 },
+            Activations::Tanh,
+            Activations::SoftPlus,
+            Activations::SoftSign,
+            Activations::ELU { a: 0.8 },
+            Activations::SELU,
+            Activations::GELU,
+        ];
+        for activation in activations.iter() {
+            assert_gradient(activation, &z());
+        }
+    }
+
+    // gradient_check weighs the outputs unevenly, so a backward that only used the Jacobian's diagonal
+    // would fail here
+    #[test]
+    fn softmax_backward_uses_the_full_jacobian() {
+        for temperature in [1., 0.5, 3.].iter() {
+            let temperature: f32 = *temperature;
+            assert_gradient(&Activations::Softmax { temperature }, &z());
+            assert_gradient(&Activations::LogSoftmax { temperature }, &z());
+        }
+        // shifting every output by the same amount doesn't change a distribution that sums to 1
+        let softmax: Activations = Activations::Softmax { temperature: 1. };
+        let uniform: Array2<f32> = Array2::ones((2, 4));
+        assert!(softmax
+            .backward(&z(), &uniform)
+            .iter()
+            .all(|g: &f32| g.abs() < 1e-6));
+    }
+
+    // each row is its own distribution, and the temperature flattens or sharpens it
+    #[test]
+    fn softmax_works_row_by_row_with_a_temperature() {
+        let softmax: Array2<f32> = Activations::Softmax { temperature: 1. }.activate(&z());
+        for row in softmax.genrows() {
+            assert!((row.sum() - 1.).abs() < 1e-6);
+        }
+        let hot: Array2<f32> = Activations::Softmax { temperature: 0.5 }.activate(&z());
+        let cold: Array2<f32> = Activations::Softmax { temperature: 3. }.activate(&z());
+        // the largest logit of the first row is its last entry
+        assert!(hot[[0, 3]] > softmax[[0, 3]] && softmax[[0, 3]] > cold[[0, 3]]);
+        let log: Array2<f32> = Activations::LogSoftmax { temperature: 0.5 }.activate(&z());
+        assert!((log.mapv(f32::exp) - hot)
+            .iter()
+            .all(|d: &f32| d.abs() < 1e-6));
+    }
+
+    #[test]
+    fn newer_activations_match_finite_differences() {
+        let activations: Vec<Activations> = vec![
+            Activations::Swish { beta: 1.5 },
+            Activations::SiLU,
+            Activations::Mish,
+            Activations::HardSigmoid,
+            Activations::HardSwish,
+            Activations::HardTanh,
+            Activations::ExactGELU,
+        ];
+        for activation in activations.iter() {
+            assert_gradient(activation, &z());
+        }
+    }
+
+    #[test]
+    fn softplus_doesnt_overflow() {
+        let z: Array2<f32> = array![[100., -100., 0.]];
+        let a: Array2<f32> = Activations::SoftPlus.activate(&z);
+        assert!((a[[0, 0]] - 100.).abs() < 1e-4);
+        assert!(a[[0, 1]] >= 0. && a[[0, 1]] < 1e-6);
+        assert!((a[[0, 2]] - 2f32.ln()).abs() < 1e-6);
+        assert!(Activations::Mish
+            .activate(&z)
+            .iter()
+            .all(|x: &f32| x.is_finite()));
+    }
+
+    #[test]
+    fn prelu_slope_gradient_matches_finite_differences() {
+        let layer: Layers = Layers::PReLU { alpha: 0.25 };
+        let input: ArrayD<f32> = z().into_dyn();
+        let alpha: Array2<f32> = array![[0.1, 0.2, 0.3, 0.4]];
+        let empty: Array2<f32> = Array2::zeros((0, 0));
+        let r: Array2<f32> =
+            Array2::from_shape_fn((2, 4), |(i, j)| ((i * 7 + j * 3 + 1) as f32).sin());
+        let (_, cache) = layer.forward_propagate(&input, &alpha, &empty, &empty, &[4], &[4], true);
+        let (_, c_wrt_alpha, _) = layer.backward(
+            &input,
+            &alpha,
+            &empty,
+            &cache,
+            &r.clone().into_dyn(),
+            &[4],
+            &[4],
+        );
+        let numeric: Array2<f32> = numerical_gradient(
+            |a: &Array2<f32>| {
+                let (z, _) = layer.forward_propagate(&input, a, &empty, &empty, &[4], &[4], true);
+                (&to_rows(&z) * &r).sum()
+            },
+            &alpha,
+            1e-2,
+        );
+        let error: f32 = (c_wrt_alpha - numeric)
+            .iter()
+            .fold(0f32, |m: f32, e: &f32| m.max(e.abs()));
+        assert!(error < 1e-3, "PReLU slopes are off by {}", error);
+    }
+
+    #[test]
+    fn softmax_stays_finite_for_large_logits() {
+        let z: Array2<f32> = array![[1000., 0., -1000.]];
+        let s: Array2<f32> = Activations::Softmax { temperature: 1. }.activate(&z);
+        let log_s: Array2<f32> = Activations::LogSoftmax { temperature: 1. }.activate(&z);
+        assert!((s[[0, 0]] - 1.).abs() < 1e-6);
+        assert!(log_s.iter().all(|x: &f32| x.is_finite()));
+        assert_gradient(&Activations::Softmax { temperature: 1. }, &z);
+    }
+
+    // record has to give what activate gives, and its tape gradient what backward gives and what finite
+    // differences of it give
+    #[test]
+    fn recorded_activations_match_activate_and_backward() {
+        let activations: Vec<Activations> = vec![
+            Activations::Linear,
+            Activations::Sigmoid,
+            Activations::ReLU,
+            Activations::LeakyReLU { a: 0.1 },
+            Activations::Tanh,
+            Activations::Softmax { temperature: 0.5 },
+            Activations::LogSoftmax { temperature: 2. },
+            Activations::SoftPlus,
+            Activations::SoftSign,
+            Activations::ELU { a: 0.8 },
+            Activations::SELU,
+            Activations::GELU,
+            Activations::ExactGELU,
+            Activations::Swish { beta: 1.5 },
+            Activations::SiLU,
+            Activations::Mish,
+            Activations::HardSigmoid,
+            Activations::HardSwish,
+            Activations::HardTanh,
+        ];
+        // uneven weights on every output so softmax's gradient isn't trivially zero
+        let r: Array2<f32> =
+            Array2::from_shape_fn((2, 4), |(i, j)| ((i * 5 + j * 3 + 2) as f32).cos());
+        for activation in activations.iter() {
+            let mut tape: Tape = Tape::new();
+            let z_var: Var = tape.leaf(z().into_dyn());
+            let a: Var = activation.record(&mut tape, z_var);
+            let recorded: Array2<f32> = to_rows(tape.value(a)).into_owned();
+            let value_error: f32 = (recorded - activation.activate(&z()))
+                .iter()
+                .fold(0f32, |m: f32, e: &f32| m.max(e.abs()));
+            assert!(
+                value_error < 1e-5,
+                "{:?} records the wrong value",
+                activation
+            );
+
+            let weights: Var = tape.leaf(r.clone().into_dyn());
+            let weighed: Var = tape.mul(a, weights);
+            let total: Var = tape.sum(weighed);
+            let adjoints: Adjoints = tape.backward(total);
+            let gradient_error: f32 = (to_rows(adjoints.wrt(z_var)).into_owned()
+                - activation.backward(&z(), &r))
+            .iter()
+            .fold(0f32, |m: f32, e: &f32| m.max(e.abs()));
+            assert!(
+                gradient_error < 1e-4,
+                "{:?} is off by {}",
+                activation,
+                gradient_error
+            );
+
+            let numeric_error: f32 = check_gradients(
+                |tape: &mut Tape, vars: &[Var]| {
+                    let a: Var = activation.record(tape, vars[0]);
+                    let weights: Var = tape.leaf(r.clone().into_dyn());
+                    tape.mul(a, weights)
+                },
+                &[z().into_dyn()],
+                1e-2,
+            )[0];
+            assert!(
+                numeric_error < 1e-2,
+                "{:?} is off by {}",
+                activation,
+                numeric_error
+            );
         }
     }
 }
@@ -0,0 +1,715 @@
+#![allow(dead_code)]
+use crate::{
+    activations::{record_softmax, Activations},
+    autograd::{Tape, Var},
+    matrixutil::{init_xavier, normalize_backward, normalize_rows},
+};
+use ndarray::{s, Array1, Array2, ArrayView2, Axis, Ix2};
+
+// sequences come in as (batch × time*dim) rows like they do for the recurrent layers, every sample is
+// handled as a (time × dim) matrix where each row is one position
+
+// added to the scores of masked pairs, large enough that softmax gives them exactly 0 while staying finite
+// when a whole row ends up masked
+const MASKED: f32 = -1e9;
+
+// which key positions a query is allowed to look at
+pub struct Masking {
+    // position i only attends to positions j <= i
+    pub causal: bool,
+    // positions whose vector is all zeros (what Embedding emits for padding_idx) are never attended to
+    // and come out as zeros themselves, so the mask survives through stacked blocks
+    pub padding: bool,
+}
+
+// the fixed table from Attention Is All You Need
+// PE(pos, 2i) = sin(pos / 10000^(2i/dim)), PE(pos, 2i+1) = cos(pos / 10000^(2i/dim))
+pub fn sinusoidal(steps: usize, dim: usize) -> Array2<f32> {
+    let mut table: Array2<f32> = Array2::zeros((steps, dim));
+    for ((pos, i), v) in table.indexed_iter_mut() {
+        let angle: f32 = pos as f32 / 10000f32.powf((i - i % 2) as f32 / dim as f32);
+        *v = if i % 2 == 0 { angle.sin() } else { angle.cos() };
+    }
+    table
+}
+
+// 1 for every real position of a (time × dim) sample and 0 for padding ones
+fn kept_positions(x: &ArrayView2<f32>, padding: bool) -> Array1<f32> {
+    x.outer_iter()
+        .map(|v| {
+            if padding && v.iter().all(|e| *e == 0.) {
+                0.
+            } else {
+                1.
+            }
+        })
+        .collect()
+}
+
+// (batch × time*dim) mask that zeroes every padding position of the input, all ones without padding
+pub fn keep_mask(input: &ArrayView2<f32>, dim: usize, padding: bool) -> Array2<f32> {
+    let mut mask: Array2<f32> = Array2::ones(input.dim());
+    if padding {
+        for (x, mut m) in input.outer_iter().zip(mask.outer_iter_mut()) {
+            let x: ArrayView2<f32> = x.into_shape((x.len() / dim, dim)).unwrap();
+            for (t, keep) in kept_positions(&x, true).iter().enumerate() {
+                m.slice_mut(s![t * dim..(t + 1) * dim]).fill(*keep);
+            }
+        }
+    }
+    mask
+}
+
+// one sample as a (time × dim) view of its row
+fn sample<'a>(input: &'a ArrayView2<f32>, i: usize, dim: usize) -> ArrayView2<'a, f32> {
+    let row = input.row(i);
+    row.into_shape((input.ncols() / dim, dim)).unwrap()
+}
+
+// everything attend works out for a single sample that its backward pass needs again
+struct Attended {
+    q: Array2<f32>,
+    k: Array2<f32>,
+    v: Array2<f32>,
+    // every head's (time × time) attention weights
+    probs: Vec<Array2<f32>>,
+    // the heads' outputs side by side, before the output projection
+    heads: Array2<f32>,
+    keep: Array1<f32>,
+}
+
+// multi-head self attention over a single (time × dim) sample
+// weights are [W_q W_k W_v W_o] side by side as (dim × 4dim) and bias is (1 × 4dim) in the same order,
+// every head gets dim/heads consecutive columns of the projections
+fn attend(
+    x: &ArrayView2<f32>,
+    weights: &Array2<f32>,
+    bias: &Array2<f32>,
+    heads: usize,
+    masking: &Masking,
+) -> (Array2<f32>, Attended) {
+    let (steps, dim) = x.dim();
+    let head_dim: usize = dim / heads;
+    let scale: f32 = 1. / (head_dim as f32).sqrt();
+    let project = |k: usize| -> Array2<f32> {
+        x.dot(&weights.slice(s![.., k * dim..(k + 1) * dim]))
+            + bias.slice(s![.., k * dim..(k + 1) * dim])
+    };
+    let (q, k, v) = (project(0), project(1), project(2));
+    let keep: Array1<f32> = kept_positions(x, masking.padding);
+
+    let mut out: Array2<f32> = Array2::zeros((steps, dim));
+    let mut probs: Vec<Array2<f32>> = Vec::with_capacity(heads);
+    for h in 0..heads {
+        let (from, to) = (h * head_dim, (h + 1) * head_dim);
+        // softmax(Q_h K_hᵀ / √d_h) V_h
+        let mut scores: Array2<f32> = q
+            .slice(s![.., from..to])
+            .dot(&k.slice(s![.., from..to]).t())
+            * scale;
+        for ((i, j), score) in scores.indexed_iter_mut() {
+            if (masking.causal && j > i) || keep[j] == 0. {
+                *score = MASKED;
+            }
+        }
+        let p: Array2<f32> = Activations::Softmax { temperature: 1. }.activate(&scores);
+        out.slice_mut(s![.., from..to])
+            .assign(&p.dot(&v.slice(s![.., from..to])));
+        probs.push(p);
+    }
+
+    let y: Array2<f32> = (out.dot(&weights.slice(s![.., 3 * dim..]))
+        + bias.slice(s![.., 3 * dim..]))
+        * keep.view().insert_axis(Axis(1));
+    let attended: Attended = Attended {
+        q,
+        k,
+        v,
+        probs,
+        heads: out,
+        keep,
+    };
+    (y, attended)
+}
+
+// (∂C/∂x, ∂C/∂weights, ∂C/∂bias) for a single sample given ∂C/∂y
+fn attend_backward(
+    x: &ArrayView2<f32>,
+    weights: &Array2<f32>,
+    attended: &Attended,
+    c_wrt_y: &ArrayView2<f32>,
+) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+    let (steps, dim) = x.dim();
+    let heads: usize = attended.probs.len();
+    let head_dim: usize = dim / heads;
+    let scale: f32 = 1. / (head_dim as f32).sqrt();
+    // padding positions were zeroed on the way out so nothing reaches them
+    let dy: Array2<f32> = c_wrt_y * &attended.keep.view().insert_axis(Axis(1));
+
+    let mut c_wrt_w: Array2<f32> = Array2::zeros(weights.dim());
+    let mut c_wrt_b: Array2<f32> = Array2::zeros((1, 4 * dim));
+    c_wrt_w
+        .slice_mut(s![.., 3 * dim..])
+        .assign(&attended.heads.t().dot(&dy));
+    c_wrt_b
+        .slice_mut(s![.., 3 * dim..])
+        .assign(&dy.sum_axis(Axis(0)).insert_axis(Axis(0)));
+    let d_heads: Array2<f32> = dy.dot(&weights.slice(s![.., 3 * dim..]).t());
+
+    let mut dq: Array2<f32> = Array2::zeros((steps, dim));
+    let mut dk: Array2<f32> = Array2::zeros((steps, dim));
+    let mut dv: Array2<f32> = Array2::zeros((steps, dim));
+    for (h, p) in attended.probs.iter().enumerate() {
+        let (from, to) = (h * head_dim, (h + 1) * head_dim);
+        let d_out: ArrayView2<f32> = d_heads.slice(s![.., from..to]);
+        let dp: Array2<f32> = d_out.dot(&attended.v.slice(s![.., from..to]).t());
+        dv.slice_mut(s![.., from..to]).assign(&p.t().dot(&d_out));
+        // row-wise softmax jacobian, masked pairs have p = 0 so they drop out here
+        let row_dot: Array2<f32> = (&dp * p).sum_axis(Axis(1)).insert_axis(Axis(1));
+        let d_scores: Array2<f32> = p * &(dp - &row_dot) * scale;
+        dq.slice_mut(s![.., from..to])
+            .assign(&d_scores.dot(&attended.k.slice(s![.., from..to])));
+        dk.slice_mut(s![.., from..to])
+            .assign(&d_scores.t().dot(&attended.q.slice(s![.., from..to])));
+    }
+
+    let mut c_wrt_x: Array2<f32> = Array2::zeros((steps, dim));
+    for (k, d) in [dq, dk, dv].iter().enumerate() {
+        let cols = (k * dim, (k + 1) * dim);
+        c_wrt_w
+            .slice_mut(s![.., cols.0..cols.1])
+            .assign(&x.t().dot(d));
+        c_wrt_b
+            .slice_mut(s![.., cols.0..cols.1])
+            .assign(&d.sum_axis(Axis(0)).insert_axis(Axis(0)));
+        c_wrt_x += &d.dot(&weights.slice(s![.., cols.0..cols.1]).t());
+    }
+    (c_wrt_x, c_wrt_w, c_wrt_b)
+}
+
+// multi-head self attention over a whole (batch × time*dim) batch
+pub fn attention_forward(
+    input: &ArrayView2<f32>,
+    weights: &Array2<f32>,
+    bias: &Array2<f32>,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+) -> Array2<f32> {
+    let mut out: Array2<f32> = Array2::zeros(input.dim());
+    for i in 0..input.nrows() {
+        let (y, _) = attend(&sample(input, i, dim), weights, bias, heads, masking);
+        out.row_mut(i).assign(&y.into_shape(input.ncols()).unwrap());
+    }
+    out
+}
+
+// (∂C/∂input, ∂C/∂weights, ∂C/∂bias) summed over the batch
+// the attention weights are recomputed from the input instead of being cached, they're (time × time)
+// per head per sample and cheap to get back compared to keeping them around between passes
+pub fn attention_backward(
+    input: &ArrayView2<f32>,
+    weights: &Array2<f32>,
+    bias: &Array2<f32>,
+    c_wrt_z: &ArrayView2<f32>,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+    let mut c_wrt_input: Array2<f32> = Array2::zeros(input.dim());
+    let mut c_wrt_w: Array2<f32> = Array2::zeros(weights.dim());
+    let mut c_wrt_b: Array2<f32> = Array2::zeros(bias.dim());
+    for i in 0..input.nrows() {
+        let x: ArrayView2<f32> = sample(input, i, dim);
+        let (_, attended) = attend(&x, weights, bias, heads, masking);
+        let (dx, dw, db) = attend_backward(&x, weights, &attended, &sample(c_wrt_z, i, dim));
+        c_wrt_input
+            .row_mut(i)
+            .assign(&dx.into_shape(input.ncols()).unwrap());
+        c_wrt_w += &dw;
+        c_wrt_b += &db;
+    }
+    (c_wrt_input, c_wrt_w, c_wrt_b)
+}
+
+// attention_forward recorded on tape, one sample and one head at a time like attend
+// the masks only depend on which positions are padding, so they go on as constants
+pub fn record_attention(
+    tape: &mut Tape,
+    input: Var,
+    weights: Var,
+    bias: Var,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+) -> Var {
+    let shape: Vec<usize> = tape.shape(input);
+    let (batch, steps): (usize, usize) = (shape[0], shape[1] / dim);
+    let head_dim: usize = dim / heads;
+    let scale: f32 = 1. / (head_dim as f32).sqrt();
+    let values: Array2<f32> = tape
+        .value(input)
+        .clone()
+        .into_dimensionality::<Ix2>()
+        .unwrap();
+
+    // every position's [q k v] at once, then W_o and b_o for the output projection
+    let rows: Var = tape.reshape(input, &[batch * steps, dim]);
+    let w_qkv: Var = tape.slice(weights, 1, 0, 3 * dim);
+    let b_qkv: Var = tape.slice(bias, 1, 0, 3 * dim);
+    let qkv: Var = tape.matmul(rows, w_qkv);
+    let qkv: Var = tape.add(qkv, b_qkv);
+    let w_o: Var = tape.slice(weights, 1, 3 * dim, 4 * dim);
+    let b_o: Var = tape.slice(bias, 1, 3 * dim, 4 * dim);
+
+    let mut outputs: Vec<Var> = Vec::with_capacity(batch);
+    for i in 0..batch {
+        let keep: Array1<f32> = kept_positions(&sample(&values.view(), i, dim), masking.padding);
+        // scores of masked pairs are replaced by MASKED, so they get no gradient
+        let allowed: Array2<f32> = Array2::from_shape_fn((steps, steps), |(i, j)| {
+            if (masking.causal && j > i) || keep[j] == 0. {
+                0.
+            } else {
+                1.
+            }
+        });
+        let filler: Var = tape.leaf(allowed.mapv(|a| (1. - a) * MASKED).into_dyn());
+        let allowed: Var = tape.leaf(allowed.into_dyn());
+        let x: Var = tape.slice(qkv, 0, i * steps, (i + 1) * steps);
+        let mut head_outputs: Vec<Var> = Vec::with_capacity(heads);
+        for h in 0..heads {
+            let from: usize = h * head_dim;
+            let q: Var = tape.slice(x, 1, from, from + head_dim);
+            let k: Var = tape.slice(x, 1, dim + from, dim + from + head_dim);
+            let v: Var = tape.slice(x, 1, 2 * dim + from, 2 * dim + from + head_dim);
+            let k_t: Var = tape.transpose(k);
+            let scores: Var = tape.matmul(q, k_t);
+            let scores: Var = tape.scale(scores, scale);
+            let scores: Var = tape.mul(scores, allowed);
+            let scores: Var = tape.add(scores, filler);
+            let p: Var = record_softmax(tape, scores, 1., false);
+            head_outputs.push(tape.matmul(p, v));
+        }
+        let joined: Var = tape.concat(&head_outputs, 1);
+        let y: Var = tape.matmul(joined, w_o);
+        let y: Var = tape.add(y, b_o);
+        let keep: Var = tape.leaf(keep.insert_axis(Axis(1)).into_dyn());
+        outputs.push(tape.mul(y, keep));
+    }
+    let out: Var = tape.concat(&outputs, 0);
+    tape.reshape(out, &[batch, steps * dim])
+}
+
+// every parameter of a TransformerEncoderBlock, stored in the layer's weights and biases as single
+// (1 × n) rows since the matrices don't share a shape
+// weights: [W_qkvo (dim × 4dim) | W_1 (dim × ff) | W_2 (ff × dim) | gamma_1 | gamma_2]
+// biases:  [b_qkvo (4dim) | b_1 (ff) | b_2 (dim) | beta_1 | beta_2]
+pub struct EncoderParams {
+    pub attention_w: Array2<f32>,
+    pub attention_b: Array2<f32>,
+    pub w1: Array2<f32>,
+    pub b1: Array2<f32>,
+    pub w2: Array2<f32>,
+    pub b2: Array2<f32>,
+    pub gamma1: Array2<f32>,
+    pub beta1: Array2<f32>,
+    pub gamma2: Array2<f32>,
+    pub beta2: Array2<f32>,
+}
+
+impl EncoderParams {
+    // lengths of the packed (weights, biases) rows
+    pub fn sizes(dim: usize, ff_units: usize) -> (usize, usize) {
+        (
+            4 * dim * dim + 2 * dim * ff_units + 2 * dim,
+            4 * dim + ff_units + 3 * dim,
+        )
+    }
+
+    // xavier projections, gammas at 1 and every bias at 0
+    pub fn init(dim: usize, ff_units: usize) -> Self {
+        EncoderParams {
+            attention_w: init_xavier::<Ix2>(&vec![dim, 4 * dim]),
+            attention_b: Array2::zeros((1, 4 * dim)),
+            w1: init_xavier::<Ix2>(&vec![dim, ff_units]),
+            b1: Array2::zeros((1, ff_units)),
+            w2: init_xavier::<Ix2>(&vec![ff_units, dim]),
+            b2: Array2::zeros((1, dim)),
+            gamma1: Array2::ones((1, dim)),
+            beta1: Array2::zeros((1, dim)),
+            gamma2: Array2::ones((1, dim)),
+            beta2: Array2::zeros((1, dim)),
+        }
+    }
+
+    pub fn unpack(weights: &Array2<f32>, bias: &Array2<f32>, dim: usize, ff_units: usize) -> Self {
+        let mut w = weights.iter().cloned();
+        let mut b = bias.iter().cloned();
+        let take = |it: &mut dyn Iterator<Item = f32>, rows: usize, cols: usize| {
+            Array2::from_shape_vec((rows, cols), it.take(rows * cols).collect()).unwrap()
+        };
+        EncoderParams {
+            attention_w: take(&mut w, dim, 4 * dim),
+            w1: take(&mut w, dim, ff_units),
+            w2: take(&mut w, ff_units, dim),
+            gamma1: take(&mut w, 1, dim),
+            gamma2: take(&mut w, 1, dim),
+            attention_b: take(&mut b, 1, 4 * dim),
+            b1: take(&mut b, 1, ff_units),
+            b2: take(&mut b, 1, dim),
+            beta1: take(&mut b, 1, dim),
+            beta2: take(&mut b, 1, dim),
+        }
+    }
+
+    // (weights, biases) rows in the layout unpack reads
+    pub fn pack(&self) -> (Array2<f32>, Array2<f32>) {
+        let row = |parts: &[&Array2<f32>]| -> Array2<f32> {
+            let flat: Vec<f32> = parts.iter().flat_map(|m| m.iter().cloned()).collect();
+            Array2::from_shape_vec((1, flat.len()), flat).unwrap()
+        };
+        (
+            row(&[
+                &self.attention_w,
+                &self.w1,
+                &self.w2,
+                &self.gamma1,
+                &self.gamma2,
+            ]),
+            row(&[
+                &self.attention_b,
+                &self.b1,
+                &self.b2,
+                &self.beta1,
+                &self.beta2,
+            ]),
+        )
+    }
+}
+
+// everything between the block's input and output that the backward pass needs, with every position of
+// every sample as its own (batch*time × dim) row
+struct EncoderPass {
+    x_hat1: Array2<f32>,
+    inv_std1: Array2<f32>,
+    n1: Array2<f32>,
+    hidden: Array2<f32>,
+    x_hat2: Array2<f32>,
+    inv_std2: Array2<f32>,
+    keep: Array2<f32>,
+}
+
+// (batch × time*dim) rows as (batch*time × dim) positions
+fn positions(x: Array2<f32>, dim: usize) -> Array2<f32> {
+    let n: usize = x.len() / dim;
+    x.as_standard_layout()
+        .into_owned()
+        .into_shape((n, dim))
+        .unwrap()
+}
+
+// and back
+fn sequences(x: Array2<f32>, batch: usize) -> Array2<f32> {
+    let n: usize = x.len() / batch;
+    x.as_standard_layout()
+        .into_owned()
+        .into_shape((batch, n))
+        .unwrap()
+}
+
+// post-norm encoder block
+// n1 = LayerNorm(x + MultiHeadAttention(x)), out = LayerNorm(n1 + ReLU(n1.W_1 + b_1).W_2 + b_2)
+fn encode(
+    input: &ArrayView2<f32>,
+    params: &EncoderParams,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+    epsilon: f32,
+) -> (Array2<f32>, EncoderPass) {
+    let attended: Array2<f32> = attention_forward(
+        input,
+        &params.attention_w,
+        &params.attention_b,
+        dim,
+        heads,
+        masking,
+    );
+    let keep: Array2<f32> = positions(keep_mask(input, dim, masking.padding), dim)
+        .slice(s![.., 0..1])
+        .to_owned();
+    let (x_hat1, inv_std1) = normalize_rows(&positions(input + &attended, dim), epsilon);
+    let n1: Array2<f32> = &x_hat1 * &params.gamma1 + &params.beta1;
+    let hidden: Array2<f32> = (n1.dot(&params.w1) + &params.b1).mapv(|v: f32| v.max(0.));
+    let (x_hat2, inv_std2) =
+        normalize_rows(&(&n1 + &(hidden.dot(&params.w2) + &params.b2)), epsilon);
+    // LayerNorm turns a zero row into beta, padding positions are zeroed again to keep them recognizable
+    let out: Array2<f32> = (&x_hat2 * &params.gamma2 + &params.beta2) * &keep;
+    let pass: EncoderPass = EncoderPass {
+        x_hat1,
+        inv_std1,
+        n1,
+        hidden,
+        x_hat2,
+        inv_std2,
+        keep,
+    };
+    (sequences(out, input.nrows()), pass)
+}
+
+pub fn encoder_forward(
+    input: &ArrayView2<f32>,
+    params: &EncoderParams,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+    epsilon: f32,
+) -> Array2<f32> {
+    encode(input, params, dim, heads, masking, epsilon).0
+}
+
+// (∂C/∂input, gradients of every parameter in the same layout as params) summed over the batch
+pub fn encoder_backward(
+    input: &ArrayView2<f32>,
+    params: &EncoderParams,
+    c_wrt_z: &ArrayView2<f32>,
+    dim: usize,
+    heads: usize,
+    masking: &Masking,
+    epsilon: f32,
+) -> (Array2<f32>, EncoderParams) {
+    let (_, pass) = encode(input, params, dim, heads, masking, epsilon);
+    let sum_rows = |m: &Array2<f32>| m.sum_axis(Axis(0)).insert_axis(Axis(0));
+
+    let d_out: Array2<f32> = positions(c_wrt_z.to_owned(), dim) * &pass.keep;
+    let d_r2: Array2<f32> = normalize_backward(
+        &(&d_out * &params.gamma2),
+        &pass.x_hat2,
+        &pass.inv_std2,
+        Axis(1),
+    );
+    // the feed-forward branch and the residual both get d_r2
+    let d_hidden: Array2<f32> =
+        d_r2.dot(&params.w2.t()) * &pass.hidden.mapv(|v: f32| if v > 0. { 1. } else { 0. });
+    let d_n1: Array2<f32> = &d_r2 + &d_hidden.dot(&params.w1.t());
+    let d_r1: Array2<f32> = normalize_backward(
+        &(&d_n1 * &params.gamma1),
+        &pass.x_hat1,
+        &pass.inv_std1,
+        Axis(1),
+    );
+    let d_r1: Array2<f32> = sequences(d_r1, input.nrows());
+    let (d_attention, attention_w, attention_b) = attention_backward(
+        input,
+        &params.attention_w,
+        &params.attention_b,
+        &d_r1.view(),
+        dim,
+        heads,
+        masking,
+    );
+
+    let grads: EncoderParams = EncoderParams {
+        attention_w,
+        attention_b,
+        w1: pass.n1.t().dot(&d_hidden),
+        b1: sum_rows(&d_hidden),
+        w2: pass.hidden.t().dot(&d_r2),
+        b2: sum_rows(&d_r2),
+        gamma1: sum_rows(&(&d_n1 * &pass.x_hat1)),
+        beta1: sum_rows(&d_n1),
+        gamma2: sum_rows(&(&d_out * &pass.x_hat2)),
+        beta2: sum_rows(&d_out),
+    };
+    (d_r1 + d_attention, grads)
+}
+
+// encoder_forward recorded on tape, unpacking the parameters out of the packed rows as it goes
+#[allow(clippy::too_many_arguments)]
+pub fn record_encoder(
+    tape: &mut Tape,
+    input: Var,
+    weights: Var,
+    bias: Var,
+    dim: usize,
+    ff_units: usize,
+    heads: usize,
+    masking: &Masking,
+    epsilon: f32,
+) -> Var {
+    let w: Vec<Var> = tape.unpack(
+        weights,
+        &[
+            (dim, 4 * dim),
+            (dim, ff_units),
+            (ff_units, dim),
+            (1, dim),
+            (1, dim),
+        ],
+    );
+    let (attention_w, w1, w2, gamma1, gamma2) = (w[0], w[1], w[2], w[3], w[4]);
+    let b: Vec<Var> = tape.unpack(
+        bias,
+        &[(1, 4 * dim), (1, ff_units), (1, dim), (1, dim), (1, dim)],
+    );
+    let (attention_b, b1, b2, beta1, beta2) = (b[0], b[1], b[2], b[3], b[4]);
+    let batch: usize = tape.shape(input)[0];
+    let values: Array2<f32> = tape
+        .value(input)
+        .clone()
+        .into_dimensionality::<Ix2>()
+        .unwrap();
+    let keep: Array2<f32> = positions(keep_mask(&values.view(), dim, masking.padding), dim)
+        .slice(s![.., 0..1])
+        .to_owned();
+
+    let attended: Var =
+        record_attention(tape, input, attention_w, attention_b, dim, heads, masking);
+    let r1: Var = tape.add(input, attended);
+    let r1: Var = tape.reshape(r1, &[values.len() / dim, dim]);
+    let x_hat1: Var = tape.normalize(r1, 1, epsilon);
+    let n1: Var = tape.mul(x_hat1, gamma1);
+    let n1: Var = tape.add(n1, beta1);
+    let hidden: Var = tape.matmul(n1, w1);
+    let hidden: Var = tape.add(hidden, b1);
+    let zero: Var = tape.scalar(0.);
+    let hidden: Var = tape.maximum(hidden, zero);
+    let ff: Var = tape.matmul(hidden, w2);
+    let ff: Var = tape.add(ff, b2);
+    let r2: Var = tape.add(n1, ff);
+    let x_hat2: Var = tape.normalize(r2, 1, epsilon);
+    let out: Var = tape.mul(x_hat2, gamma2);
+    let out: Var = tape.add(out, beta2);
+    let keep: Var = tape.leaf(keep.into_dyn());
+    let out: Var = tape.mul(out, keep);
+    tape.reshape(out, &[batch, values.ncols()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrixutil::numerical_gradient;
+    use ndarray::array;
+
+    const DIM: usize = 4;
+    const HEADS: usize = 2;
+
+    fn parameters() -> (Array2<f32>, Array2<f32>) {
+        let weights: Array2<f32> =
+            Array2::from_shape_fn((DIM, 4 * DIM), |(i, j)| ((i * 16 + j) as f32 * 0.37).sin());
+        let bias: Array2<f32> = Array2::from_shape_fn((1, 4 * DIM), |(_, j)| 0.05 * j as f32);
+        (weights, bias)
+    }
+
+    fn masking(causal: bool, padding: bool) -> Masking {
+        Masking { causal, padding }
+    }
+
+    // a single sample of 3 positions
+    fn sequence() -> Array2<f32> {
+        array![[0.5, -0.2, 0.8, 0.1, -0.6, 0.3, 0.9, -0.4, 0.2, 0.7, -0.5, 0.6]]
+    }
+
+    #[test]
+    fn causal_positions_ignore_later_ones() {
+        let (weights, bias) = parameters();
+        let mut changed: Array2<f32> = sequence();
+        changed.slice_mut(s![.., 2 * DIM..]).fill(3.);
+        let causal: Masking = masking(true, false);
+        let before: Array2<f32> =
+            attention_forward(&sequence().view(), &weights, &bias, DIM, HEADS, &causal);
+        let after: Array2<f32> =
+            attention_forward(&changed.view(), &weights, &bias, DIM, HEADS, &causal);
+        assert_eq!(
+            before.slice(s![.., ..2 * DIM]),
+            after.slice(s![.., ..2 * DIM])
+        );
+        // without the mask the first positions see the change too
+        let open: Masking = masking(false, false);
+        let before: Array2<f32> =
+            attention_forward(&sequence().view(), &weights, &bias, DIM, HEADS, &open);
+        let after: Array2<f32> =
+            attention_forward(&changed.view(), &weights, &bias, DIM, HEADS, &open);
+        assert!(before.slice(s![.., ..DIM]) != after.slice(s![.., ..DIM]));
+    }
+
+    // a padded sequence attends exactly like the same sequence without the padding
+    #[test]
+    fn padding_is_never_attended_and_comes_out_as_zeros() {
+        let (weights, bias) = parameters();
+        let mut padded: Array2<f32> = sequence();
+        padded.slice_mut(s![.., 2 * DIM..]).fill(0.);
+        let short: Array2<f32> = sequence().slice(s![.., ..2 * DIM]).to_owned();
+        let y: Array2<f32> = attention_forward(
+            &padded.view(),
+            &weights,
+            &bias,
+            DIM,
+            HEADS,
+            &masking(false, true),
+        );
+        let expected: Array2<f32> = attention_forward(
+            &short.view(),
+            &weights,
+            &bias,
+            DIM,
+            HEADS,
+            &masking(false, false),
+        );
+        assert!((&y.slice(s![.., ..2 * DIM]) - &expected)
+            .iter()
+            .all(|d: &f32| d.abs() < 1e-6));
+        assert!(y.slice(s![.., 2 * DIM..]).iter().all(|v: &f32| *v == 0.));
+        assert_eq!(
+            keep_mask(&padded.view(), DIM, true)
+                .slice(s![0, ..])
+                .to_vec(),
+            [[1.; 8].to_vec(), [0.; 4].to_vec()].concat()
+        );
+    }
+
+    #[test]
+    fn backward_matches_finite_differences_under_both_masks() {
+        let (weights, bias) = parameters();
+        let mut input: Array2<f32> =
+            Array2::from_shape_fn((2, 3 * DIM), |(i, j)| ((i * 12 + j) as f32 * 0.53).cos());
+        input.slice_mut(s![1, 2 * DIM..]).fill(0.);
+        let both: Masking = masking(true, true);
+        let r: Array2<f32> =
+            Array2::from_shape_fn(input.dim(), |(i, j)| ((i + 2 * j) as f32).sin());
+        let (c_wrt_input, c_wrt_w, c_wrt_b) =
+            attention_backward(&input.view(), &weights, &bias, &r.view(), DIM, HEADS, &both);
+        let score = |x: &Array2<f32>, w: &Array2<f32>, b: &Array2<f32>| -> f32 {
+            (attention_forward(&x.view(), w, b, DIM, HEADS, &both) * &r).sum()
+        };
+        let checks: [(Array2<f32>, Array2<f32>); 3] = [
+            (
+                c_wrt_w,
+                numerical_gradient(|w: &Array2<f32>| score(&input, w, &bias), &weights, 1e-2),
+            ),
+            (
+                c_wrt_b,
+                numerical_gradient(|b: &Array2<f32>| score(&input, &weights, b), &bias, 1e-2),
+            ),
+            (
+                // only the first sample, nudging the second one's padding away from 0 would unmask it
+                c_wrt_input.slice(s![0..1, ..]).to_owned(),
+                numerical_gradient(
+                    |x: &Array2<f32>| {
+                        let mut full: Array2<f32> = input.clone();
+                        full.slice_mut(s![0..1, ..]).assign(x);
+                        score(&full, &weights, &bias)
+                    },
+                    &input.slice(s![0..1, ..]).to_owned(),
+                    1e-2,
+                ),
+            ),
+        ];
+        for (analytic, numeric) in checks.iter() {
+            let error: f32 = (analytic - numeric)
+                .iter()
+                .fold(0f32, |m: f32, e: &f32| m.max(e.abs()));
+            assert!(error < 1e-2, "off by {}", error);
+        }
+    }
+}
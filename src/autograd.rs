@@ -0,0 +1,829 @@
+#![allow(dead_code)]
+use crate::{
+    activations::{erf, Activation},
+    cost::Cost,
+};
+use ndarray::{stack, Array2, ArrayD, ArrayViewD, Axis, Ix2, IxDyn, Slice};
+use std::f32::consts::PI;
+
+// reverse-mode automatic differentiation
+// every operation on a Tape computes its value straight away and records what it was applied to, then
+// Tape::backward walks the records from the last one to the first, which is already a topological order
+// of the graph since an operation can only use values recorded before it
+// activations, costs, regularizers and layers all have a record method that builds them out of the ops
+// below, so a gradient worked out on a tape never goes through the hand written backward passes training
+// uses and can be checked against them
+
+pub type Tensor = ArrayD<f32>;
+
+// handle to a value recorded on a tape
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Var(usize);
+
+enum Op<'a> {
+    // an input, nothing further back to send its gradient to
+    Leaf,
+    // elementwise ops broadcast their operands numpy style
+    Add(Var, Var),
+    Sub(Var, Var),
+    Mul(Var, Var),
+    Div(Var, Var),
+    Maximum(Var, Var),
+    Minimum(Var, Var),
+    Neg(Var),
+    Scale(Var, f32),
+    Offset(Var, f32),
+    Exp(Var),
+    Ln(Var),
+    Powf(Var, f32),
+    Tanh(Var),
+    Erf(Var),
+    Abs(Var),
+    MatMul(Var, Var),
+    Permute(Var, Vec<usize>),
+    Sum(Var),
+    Mean(Var),
+    SumAxis(Var, usize),
+    MaxAxis(Var, usize),
+    Broadcast(Var),
+    Reshape(Var),
+    // select(axis, indices), an index can show up more than once
+    Index(Var, usize, Vec<usize>),
+    Concat(Vec<Var>, usize),
+    // user defined activations and costs only come with their own derivatives, so these are the only ops
+    // that aren't broken down any further
+    CustomActivation(Var, &'a dyn Activation),
+    // (batch × 1) per-sample losses of a Cost::Custom against fixed targets
+    CustomLoss(Var, Array2<f32>, &'a Cost),
+}
+
+struct Node<'a> {
+    value: Tensor,
+    op: Op<'a>,
+}
+
+pub struct Tape<'a> {
+    nodes: Vec<Node<'a>>,
+}
+
+// ∂output/∂v for every value v recorded on the tape, zeros for the ones output doesn't depend on
+pub struct Adjoints(Vec<Tensor>);
+
+impl Adjoints {
+    pub fn wrt(&self, var: Var) -> &Tensor {
+        &self.0[var.0]
+    }
+}
+
+impl<'a> Tape<'a> {
+    pub fn new() -> Self {
+        Tape { nodes: Vec::new() }
+    }
+
+    fn push(&mut self, value: Tensor, op: Op<'a>) -> Var {
+        self.nodes.push(Node { value, op });
+        Var(self.nodes.len() - 1)
+    }
+
+    pub fn value(&self, var: Var) -> &Tensor {
+        &self.nodes[var.0].value
+    }
+
+    pub fn shape(&self, var: Var) -> Vec<usize> {
+        self.value(var).shape().to_vec()
+    }
+
+    // inputs, parameters and constants alike, constants simply never have their adjoint read
+    pub fn leaf(&mut self, value: Tensor) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    // a 0d constant that broadcasts against anything
+    pub fn scalar(&mut self, x: f32) -> Var {
+        self.leaf(Tensor::from_elem(IxDyn(&[]), x))
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), |x, y| x + y);
+        self.push(value, Op::Add(a, b))
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), |x, y| x - y);
+        self.push(value, Op::Sub(a, b))
+    }
+
+    pub fn mul(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), |x, y| x * y);
+        self.push(value, Op::Mul(a, b))
+    }
+
+    pub fn div(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), |x, y| x / y);
+        self.push(value, Op::Div(a, b))
+    }
+
+    // ties go to a
+    pub fn maximum(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), f32::max);
+        self.push(value, Op::Maximum(a, b))
+    }
+
+    pub fn minimum(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = zip_broadcast(self.value(a), self.value(b), f32::min);
+        self.push(value, Op::Minimum(a, b))
+    }
+
+    pub fn neg(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(|x| -x);
+        self.push(value, Op::Neg(a))
+    }
+
+    pub fn scale(&mut self, a: Var, factor: f32) -> Var {
+        let value: Tensor = self.value(a) * factor;
+        self.push(value, Op::Scale(a, factor))
+    }
+
+    // a + c for a constant c
+    pub fn offset(&mut self, a: Var, c: f32) -> Var {
+        let value: Tensor = self.value(a) + c;
+        self.push(value, Op::Offset(a, c))
+    }
+
+    pub fn exp(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(f32::exp);
+        self.push(value, Op::Exp(a))
+    }
+
+    pub fn ln(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(f32::ln);
+        self.push(value, Op::Ln(a))
+    }
+
+    pub fn powf(&mut self, a: Var, exponent: f32) -> Var {
+        let value: Tensor = self.value(a).mapv(|x| x.powf(exponent));
+        self.push(value, Op::Powf(a, exponent))
+    }
+
+    pub fn tanh(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(f32::tanh);
+        self.push(value, Op::Tanh(a))
+    }
+
+    pub fn erf(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(erf);
+        self.push(value, Op::Erf(a))
+    }
+
+    pub fn abs(&mut self, a: Var) -> Var {
+        let value: Tensor = self.value(a).mapv(f32::abs);
+        self.push(value, Op::Abs(a))
+    }
+
+    // matrix product of two 2d tensors
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let value: Tensor = to_2d(self.value(a)).dot(&to_2d(self.value(b))).into_dyn();
+        self.push(value, Op::MatMul(a, b))
+    }
+
+    // axis i of the result is axis axes[i] of a
+    pub fn permute(&mut self, a: Var, axes: &[usize]) -> Var {
+        let value: Tensor = self
+            .value(a)
+            .view()
+            .permuted_axes(IxDyn(axes))
+            .as_standard_layout()
+            .into_owned();
+        self.push(value, Op::Permute(a, axes.to_vec()))
+    }
+
+    // reverses the order of the axes, the usual transpose for 2d tensors
+    pub fn transpose(&mut self, a: Var) -> Var {
+        let axes: Vec<usize> = (0..self.value(a).ndim()).rev().collect();
+        self.permute(a, &axes)
+    }
+
+    // sum of every element as a 0d tensor
+    pub fn sum(&mut self, a: Var) -> Var {
+        let value: Tensor = Tensor::from_elem(IxDyn(&[]), self.value(a).sum());
+        self.push(value, Op::Sum(a))
+    }
+
+    pub fn mean(&mut self, a: Var) -> Var {
+        let value: Tensor = Tensor::from_elem(IxDyn(&[]), self.value(a).mean().unwrap_or(0.));
+        self.push(value, Op::Mean(a))
+    }
+
+    // sums axis down to length 1, keeping it so the result still broadcasts against a
+    pub fn sum_axis(&mut self, a: Var, axis: usize) -> Var {
+        let value: Tensor = self.value(a).sum_axis(Axis(axis)).insert_axis(Axis(axis));
+        self.push(value, Op::SumAxis(a, axis))
+    }
+
+    // the largest entry along axis, kept like sum_axis keeps it, the gradient goes to the first largest
+    pub fn max_axis(&mut self, a: Var, axis: usize) -> Var {
+        let value: Tensor = self
+            .value(a)
+            .fold_axis(Axis(axis), f32::NEG_INFINITY, |m, x| m.max(*x))
+            .insert_axis(Axis(axis));
+        self.push(value, Op::MaxAxis(a, axis))
+    }
+
+    pub fn mean_axis(&mut self, a: Var, axis: usize) -> Var {
+        let n: usize = self.value(a).len_of(Axis(axis));
+        let sum: Var = self.sum_axis(a, axis);
+        self.scale(sum, 1. / n.max(1) as f32)
+    }
+
+    pub fn broadcast(&mut self, a: Var, shape: &[usize]) -> Var {
+        let value: Tensor = match self.value(a).broadcast(IxDyn(shape)) {
+            Some(value) => value.to_owned(),
+            None => panic!("can't broadcast {:?} to {:?}", self.value(a).shape(), shape),
+        };
+        self.push(value, Op::Broadcast(a))
+    }
+
+    // same elements in row-major order under a new shape
+    pub fn reshape(&mut self, a: Var, shape: &[usize]) -> Var {
+        let value: Tensor = reshaped(self.value(a), shape);
+        self.push(value, Op::Reshape(a))
+    }
+
+    // the entries at indices along axis, in that order
+    pub fn index(&mut self, a: Var, axis: usize, indices: &[usize]) -> Var {
+        let value: Tensor = self.value(a).select(Axis(axis), indices);
+        self.push(value, Op::Index(a, axis, indices.to_vec()))
+    }
+
+    // entries from..to along axis
+    pub fn slice(&mut self, a: Var, axis: usize, from: usize, to: usize) -> Var {
+        let indices: Vec<usize> = (from..to).collect();
+        self.index(a, axis, &indices)
+    }
+
+    // joins every tensor along axis, every other axis has to match
+    pub fn concat(&mut self, vars: &[Var], axis: usize) -> Var {
+        let views: Vec<ArrayViewD<f32>> = vars.iter().map(|v| self.value(*v).view()).collect();
+        let value: Tensor = match stack(Axis(axis), &views) {
+            Ok(value) => value,
+            Err(_) => panic!(
+                "can't join {:?} along axis {}",
+                views.iter().map(|v| v.shape().to_vec()).collect::<Vec<_>>(),
+                axis
+            ),
+        };
+        self.push(value, Op::Concat(vars.to_vec(), axis))
+    }
+
+    // composites, recorded as the primitive ops above
+
+    // σ(x) = (1 + tanh(x/2))/2, which unlike 1/(1+e^-x) never has to push an infinity backwards
+    pub fn sigmoid(&mut self, a: Var) -> Var {
+        let half: Var = self.scale(a, 0.5);
+        let t: Var = self.tanh(half);
+        let t: Var = self.offset(t, 1.);
+        self.scale(t, 0.5)
+    }
+
+    // ln(1+e^x) = max(x,0) + ln(1 + e^-|x|) so large inputs don't overflow
+    pub fn softplus(&mut self, a: Var) -> Var {
+        let zero: Var = self.scalar(0.);
+        let positive: Var = self.maximum(a, zero);
+        let magnitude: Var = self.abs(a);
+        let e: Var = self.scale(magnitude, -1.);
+        let e: Var = self.exp(e);
+        let e: Var = self.offset(e, 1.);
+        let tail: Var = self.ln(e);
+        self.add(positive, tail)
+    }
+
+    pub fn clamp(&mut self, a: Var, min: f32, max: f32) -> Var {
+        let (lo, hi): (Var, Var) = (self.scalar(min), self.scalar(max));
+        let a: Var = self.maximum(a, lo);
+        self.minimum(a, hi)
+    }
+
+    // (x-μ)/√(σ²+ε) with μ and σ² taken along axis
+    pub fn normalize(&mut self, x: Var, axis: usize, epsilon: f32) -> Var {
+        let mean: Var = self.mean_axis(x, axis);
+        let centered: Var = self.sub(x, mean);
+        let square: Var = self.mul(centered, centered);
+        let var: Var = self.mean_axis(square, axis);
+        let var: Var = self.offset(var, epsilon);
+        let inv_std: Var = self.powf(var, -0.5);
+        self.mul(centered, inv_std)
+    }
+
+    // splits a packed (1 × n) row into matrices of the given dimensions, taken one after another
+    pub fn unpack(&mut self, row: Var, shapes: &[(usize, usize)]) -> Vec<Var> {
+        let mut start: usize = 0;
+        shapes
+            .iter()
+            .map(|(rows, cols)| {
+                let part: Var = self.slice(row, 1, start, start + rows * cols);
+                start += rows * cols;
+                self.reshape(part, &[*rows, *cols])
+            })
+            .collect()
+    }
+
+    // user defined activations on a (batch × features) tensor
+    pub fn custom_activation(&mut self, a: Var, activation: &'a dyn Activation) -> Var {
+        let value: Tensor = activation.activate(&to_2d(self.value(a))).into_dyn();
+        self.push(value, Op::CustomActivation(a, activation))
+    }
+
+    // (batch × 1) losses of a Cost::Custom, one per row of predicted
+    pub fn custom_loss(&mut self, predicted: Var, expected: &Array2<f32>, cost: &'a Cost) -> Var {
+        let value: Tensor = cost
+            .per_sample(&to_2d(self.value(predicted)), expected)
+            .insert_axis(Axis(1))
+            .into_dyn();
+        self.push(value, Op::CustomLoss(predicted, expected.clone(), cost))
+    }
+
+    // ∂output/∂v for every recorded v, output is seeded with ones so a non scalar output is treated as
+    // the sum of its elements
+    pub fn backward(&self, output: Var) -> Adjoints {
+        let mut grads: Vec<Option<Tensor>> = vec![None; self.nodes.len()];
+        grads[output.0] = Some(Tensor::ones(self.value(output).raw_dim()));
+        for i in (0..=output.0).rev() {
+            let grad: Tensor = match grads[i].take() {
+                Some(grad) => grad,
+                None => continue,
+            };
+            for (var, contribution) in self.chain(i, &grad) {
+                grads[var.0] = Some(match grads[var.0].take() {
+                    Some(total) => total + contribution,
+                    None => contribution,
+                });
+            }
+            grads[i] = Some(grad);
+        }
+        Adjoints(
+            grads
+                .into_iter()
+                .zip(self.nodes.iter())
+                .map(|(grad, node)| grad.unwrap_or_else(|| Tensor::zeros(node.value.raw_dim())))
+                .collect(),
+        )
+    }
+
+    // the gradient node i sends to each of its operands given ∂output/∂node
+    fn chain(&self, i: usize, grad: &Tensor) -> Vec<(Var, Tensor)> {
+        let value = |v: Var| self.value(v);
+        let shape = |v: Var| self.value(v).shape().to_vec();
+        let out: &Tensor = &self.nodes[i].value;
+        match &self.nodes[i].op {
+            Op::Leaf => vec![],
+            Op::Add(a, b) => vec![
+                (*a, unbroadcast(grad.clone(), &shape(*a))),
+                (*b, unbroadcast(grad.clone(), &shape(*b))),
+            ],
+            Op::Sub(a, b) => vec![
+                (*a, unbroadcast(grad.clone(), &shape(*a))),
+                (*b, unbroadcast(grad.mapv(|g| -g), &shape(*b))),
+            ],
+            // ∂(ab)/∂a = b, ∂(ab)/∂b = a
+            Op::Mul(a, b) => vec![
+                (
+                    *a,
+                    unbroadcast(zip_broadcast(grad, value(*b), |g, y| g * y), &shape(*a)),
+                ),
+                (
+                    *b,
+                    unbroadcast(zip_broadcast(grad, value(*a), |g, x| g * x), &shape(*b)),
+                ),
+            ],
+            // ∂(a/b)/∂a = 1/b, ∂(a/b)/∂b = -a/b² = -(a/b)/b
+            Op::Div(a, b) => vec![
+                (
+                    *a,
+                    unbroadcast(zip_broadcast(grad, value(*b), |g, y| g / y), &shape(*a)),
+                ),
+                (
+                    *b,
+                    unbroadcast(
+                        zip_broadcast(
+                            &zip_broadcast(grad, out, |g, q| -g * q),
+                            value(*b),
+                            |g, y| g / y,
+                        ),
+                        &shape(*b),
+                    ),
+                ),
+            ],
+            // the whole gradient goes to whichever operand was picked
+            Op::Maximum(a, b) | Op::Minimum(a, b) => {
+                let picked_a: Tensor = zip_broadcast(value(*a), out, |x, o| (x == o) as u8 as f32);
+                let to_a: Tensor = zip_broadcast(grad, &picked_a, |g, p| g * p);
+                let to_b: Tensor = zip_broadcast(grad, &picked_a, |g, p| g * (1. - p));
+                vec![
+                    (*a, unbroadcast(to_a, &shape(*a))),
+                    (*b, unbroadcast(to_b, &shape(*b))),
+                ]
+            }
+            Op::Neg(a) => vec![(*a, grad.mapv(|g| -g))],
+            Op::Scale(a, factor) => vec![(*a, grad * *factor)],
+            Op::Offset(a, _) => vec![(*a, grad.clone())],
+            Op::Exp(a) => vec![(*a, grad * out)],
+            Op::Ln(a) => vec![(*a, grad / value(*a))],
+            Op::Powf(a, exponent) => {
+                vec![(
+                    *a,
+                    grad * &value(*a).mapv(|x| exponent * x.powf(exponent - 1.)),
+                )]
+            }
+            // tanh' = 1 - tanh²
+            Op::Tanh(a) => vec![(*a, grad * &out.mapv(|t| 1. - t * t))],
+            // erf' = 2/√π e^-x²
+            Op::Erf(a) => vec![(
+                *a,
+                grad * &value(*a).mapv(|x| 2. / PI.sqrt() * (-x * x).exp()),
+            )],
+            // sign(x), 0 at the kink
+            Op::Abs(a) => vec![(
+                *a,
+                grad * &value(*a).mapv(|x| if x == 0. { 0. } else { x.signum() }),
+            )],
+            // ∂C/∂A = ∂C/∂(AB) Bᵀ, ∂C/∂B = Aᵀ ∂C/∂(AB)
+            Op::MatMul(a, b) => {
+                let g: Array2<f32> = to_2d(grad);
+                vec![
+                    (*a, g.dot(&to_2d(value(*b)).t()).into_dyn()),
+                    (*b, to_2d(value(*a)).t().dot(&g).into_dyn()),
+                ]
+            }
+            Op::Permute(a, axes) => {
+                let mut inverse: Vec<usize> = vec![0; axes.len()];
+                for (k, axis) in axes.iter().enumerate() {
+                    inverse[*axis] = k;
+                }
+                let g: Tensor = grad
+                    .view()
+                    .permuted_axes(IxDyn(&inverse))
+                    .as_standard_layout()
+                    .into_owned();
+                vec![(*a, g)]
+            }
+            // the (kept) axes that were summed get the gradient copied back out over them
+            Op::Sum(a) | Op::SumAxis(a, _) => vec![(*a, spread(grad, &shape(*a)))],
+            Op::Mean(a) => {
+                let n: f32 = value(*a).len().max(1) as f32;
+                vec![(*a, spread(grad, &shape(*a)) / n)]
+            }
+            Op::MaxAxis(a, axis) => {
+                let mut g: Tensor = Tensor::zeros(value(*a).raw_dim());
+                for ((mut lane, x), g_max) in g
+                    .lanes_mut(Axis(*axis))
+                    .into_iter()
+                    .zip(value(*a).lanes(Axis(*axis)))
+                    .zip(grad.iter())
+                {
+                    let mut best: usize = 0;
+                    for (k, v) in x.iter().enumerate() {
+                        if *v > x[best] {
+                            best = k;
+                        }
+                    }
+                    lane[best] = *g_max;
+                }
+                vec![(*a, g)]
+            }
+            Op::Broadcast(a) => vec![(*a, unbroadcast(grad.clone(), &shape(*a)))],
+            Op::Reshape(a) => vec![(*a, reshaped(grad, &shape(*a)))],
+            Op::Index(a, axis, indices) => {
+                let mut g: Tensor = Tensor::zeros(value(*a).raw_dim());
+                for (k, index) in indices.iter().enumerate() {
+                    let mut lane = g.index_axis_mut(Axis(*axis), *index);
+                    lane += &grad.index_axis(Axis(*axis), k);
+                }
+                vec![(*a, g)]
+            }
+            Op::Concat(vars, axis) => {
+                let mut start: usize = 0;
+                vars.iter()
+                    .map(|v| {
+                        let n: usize = value(*v).len_of(Axis(*axis));
+                        let part: Tensor = grad
+                            .slice_axis(Axis(*axis), Slice::from(start..start + n))
+                            .to_owned();
+                        start += n;
+                        (*v, part)
+                    })
+                    .collect()
+            }
+            Op::CustomActivation(a, activation) => vec![(
+                *a,
+                activation
+                    .backward(&to_2d(value(*a)), &to_2d(grad))
+                    .into_dyn(),
+            )],
+            Op::CustomLoss(predicted, expected, cost) => {
+                let g: Array2<f32> =
+                    cost.per_sample_derivate(&to_2d(value(*predicted)), expected) * &to_2d(grad);
+                vec![(*predicted, g.into_dyn())]
+            }
+        }
+    }
+}
+
+// largest |autograd - central difference| for each input
+// f records some computation of the inputs on a fresh tape and returns its output, which is summed when
+// it isn't a scalar
+pub fn check_gradients<'a, F>(f: F, inputs: &[Tensor], h: f32) -> Vec<f32>
+where
+    F: Fn(&mut Tape<'a>, &[Var]) -> Var,
+{
+    let run = |inputs: &[Tensor]| -> (Tape<'a>, Vec<Var>, Var) {
+        let mut tape: Tape = Tape::new();
+        let vars: Vec<Var> = inputs.iter().map(|x| tape.leaf(x.clone())).collect();
+        let output: Var = f(&mut tape, &vars);
+        (tape, vars, output)
+    };
+    let (tape, vars, output) = run(inputs);
+    let adjoints: Adjoints = tape.backward(output);
+    let mut errors: Vec<f32> = Vec::with_capacity(inputs.len());
+    for (k, var) in vars.iter().enumerate() {
+        let mut error: f32 = 0.;
+        for (j, analytic) in adjoints.wrt(*var).iter().enumerate() {
+            let total = |delta: f32| -> f32 {
+                let mut nudged: Vec<Tensor> = inputs.to_vec();
+                *nudged[k].iter_mut().nth(j).unwrap() += delta;
+                let (tape, _, output) = run(&nudged);
+                tape.value(output).sum()
+            };
+            let numerical: f32 = (total(h) - total(-h)) / (2. * h);
+            error = error.max((analytic - numerical).abs());
+        }
+        errors.push(error);
+    }
+    errors
+}
+
+fn to_2d(x: &Tensor) -> Array2<f32> {
+    match x.clone().into_dimensionality::<Ix2>() {
+        Ok(x) => x,
+        Err(_) => panic!("expected a 2d tensor but got shape {:?}", x.shape()),
+    }
+}
+
+fn reshaped(x: &Tensor, shape: &[usize]) -> Tensor {
+    match x.as_standard_layout().into_owned().into_shape(IxDyn(shape)) {
+        Ok(x) => x,
+        Err(_) => panic!("can't reshape {:?} into {:?}", x.shape(), shape),
+    }
+}
+
+// shape of a numpy style broadcast of a with b, shapes are lined up from their last axis
+fn broadcast_shape(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let n: usize = a.len().max(b.len());
+    let axis = |shape: &[usize], i: usize| -> usize {
+        if i + shape.len() >= n {
+            shape[i + shape.len() - n]
+        } else {
+            1
+        }
+    };
+    (0..n)
+        .map(|i| match (axis(a, i), axis(b, i)) {
+            (x, y) if x == y => x,
+            (1, y) => y,
+            (x, 1) => x,
+            _ => panic!("can't broadcast {:?} with {:?}", a, b),
+        })
+        .collect()
+}
+
+fn zip_broadcast<F>(a: &Tensor, b: &Tensor, f: F) -> Tensor
+where
+    F: Fn(f32, f32) -> f32,
+{
+    let shape: IxDyn = IxDyn(&broadcast_shape(a.shape(), b.shape()));
+    let mut out: Tensor = a.broadcast(shape.clone()).unwrap().to_owned();
+    out.zip_mut_with(&b.broadcast(shape).unwrap(), |x, y| *x = f(*x, *y));
+    out
+}
+
+// sums grad over every axis that broadcasting stretched, undoing it
+fn unbroadcast(mut grad: Tensor, shape: &[usize]) -> Tensor {
+    while grad.ndim() > shape.len() {
+        grad = grad.sum_axis(Axis(0));
+    }
+    for (i, n) in shape.iter().enumerate() {
+        if *n == 1 && grad.shape()[i] != 1 {
+            grad = grad.sum_axis(Axis(i)).insert_axis(Axis(i));
+        }
+    }
+    grad
+}
+
+// grad copied out over shape, the adjoint of a reduction
+fn spread(grad: &Tensor, shape: &[usize]) -> Tensor {
+    grad.broadcast(IxDyn(shape)).unwrap().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{array, Array};
+
+    // a fixed uneven pattern, outputs are weighed by it before they're summed so a gradient that's only
+    // right up to a per-element shuffle or a constant shift still fails
+    fn pattern(shape: &[usize]) -> Tensor {
+        let n: usize = shape.iter().product();
+        Array::from_iter((0..n).map(|i| ((i * 7 + 3) as f32).sin() + 0.2))
+            .into_shape(IxDyn(shape))
+            .unwrap()
+    }
+
+    fn check<'a, F>(name: &str, f: F, inputs: &[Tensor])
+    where
+        F: Fn(&mut Tape<'a>, &[Var]) -> Var,
+    {
+        let weighed = |tape: &mut Tape<'a>, vars: &[Var]| -> Var {
+            let out: Var = f(tape, vars);
+            let w: Var = tape.leaf(pattern(&tape.shape(out)));
+            let out: Var = tape.mul(out, w);
+            tape.sum(out)
+        };
+        for (k, error) in check_gradients(weighed, inputs, 1e-2).iter().enumerate() {
+            assert!(*error < 1e-2, "{} is off by {} on input {}", name, error, k);
+        }
+    }
+
+    fn a() -> Tensor {
+        array![[0.3, -1.2, 0.8], [1.7, -0.4, 0.6]].into_dyn()
+    }
+
+    fn b() -> Tensor {
+        array![[-0.9, 0.5, 1.4], [0.2, 1.1, -1.6]].into_dyn()
+    }
+
+    fn block() -> Tensor {
+        pattern(&[2, 3, 4])
+    }
+
+    fn positive() -> Tensor {
+        a().mapv(|x: f32| x.abs() + 0.5)
+    }
+
+    #[test]
+    fn elementwise_ops_match_finite_differences() {
+        check("add", |t, v| t.add(v[0], v[1]), &[a(), b()]);
+        check("sub", |t, v| t.sub(v[0], v[1]), &[a(), b()]);
+        check("mul", |t, v| t.mul(v[0], v[1]), &[a(), b()]);
+        check("div", |t, v| t.div(v[0], v[1]), &[a(), positive()]);
+        check("maximum", |t, v| t.maximum(v[0], v[1]), &[a(), b()]);
+        check("minimum", |t, v| t.minimum(v[0], v[1]), &[a(), b()]);
+        check("neg", |t, v| t.neg(v[0]), &[a()]);
+        check("scale", |t, v| t.scale(v[0], -2.5), &[a()]);
+        check("offset", |t, v| t.offset(v[0], 0.7), &[a()]);
+        check("exp", |t, v| t.exp(v[0]), &[a()]);
+        check("ln", |t, v| t.ln(v[0]), &[positive()]);
+        check("powf", |t, v| t.powf(v[0], -1.5), &[positive()]);
+        check("tanh", |t, v| t.tanh(v[0]), &[a()]);
+        check("erf", |t, v| t.erf(v[0]), &[a()]);
+        check("abs", |t, v| t.abs(v[0]), &[a()]);
+    }
+
+    #[test]
+    fn broadcasting_sums_gradients_back() {
+        let row: Tensor = array![[0.4, -0.8, 1.3]].into_dyn();
+        let column: Tensor = array![[0.6], [-1.1]].into_dyn();
+        let flat: Tensor = array![0.9, 0.3, -0.5].into_dyn();
+        check("add row", |t, v| t.add(v[0], v[1]), &[a(), row.clone()]);
+        check(
+            "mul column",
+            |t, v| t.mul(v[0], v[1]),
+            &[a(), column.clone()],
+        );
+        check(
+            "div flat",
+            |t, v| t.div(v[0], v[1]),
+            &[a(), flat.mapv(|x: f32| x + 1.)],
+        );
+        check("sub outer", |t, v| t.sub(v[0], v[1]), &[column, row]);
+        check(
+            "mul scalar",
+            |t, v| t.mul(v[0], v[1]),
+            &[a(), Tensor::from_elem(IxDyn(&[]), 1.3)],
+        );
+    }
+
+    #[test]
+    fn matrix_and_shape_ops_match_finite_differences() {
+        check(
+            "matmul",
+            |t, v| {
+                let b_t: Var = t.transpose(v[1]);
+                t.matmul(v[0], b_t)
+            },
+            &[a(), b()],
+        );
+        check("permute", |t, v| t.permute(v[0], &[2, 0, 1]), &[block()]);
+        check("reshape", |t, v| t.reshape(v[0], &[4, 6]), &[block()]);
+        check("broadcast", |t, v| t.broadcast(v[0], &[4, 2, 3]), &[a()]);
+        check("index", |t, v| t.index(v[0], 1, &[2, 0, 2]), &[a()]);
+        check("slice", |t, v| t.slice(v[0], 2, 1, 3), &[block()]);
+        check(
+            "concat",
+            |t, v| t.concat(&[v[0], v[1], v[0]], 0),
+            &[a(), b()],
+        );
+        check(
+            "concat columns",
+            |t, v| t.concat(&[v[0], v[1]], 1),
+            &[a(), b()],
+        );
+    }
+
+    #[test]
+    fn reductions_match_finite_differences() {
+        check("sum", |t, v| t.sum(v[0]), &[a()]);
+        check("mean", |t, v| t.mean(v[0]), &[a()]);
+        for axis in 0..3 {
+            check("sum_axis", |t, v| t.sum_axis(v[0], axis), &[block()]);
+            check("max_axis", |t, v| t.max_axis(v[0], axis), &[block()]);
+            check("mean_axis", |t, v| t.mean_axis(v[0], axis), &[block()]);
+        }
+        // reduced axes are kept so the result broadcasts straight back against the input
+        let mut tape: Tape = Tape::new();
+        let x: Var = tape.leaf(block());
+        let max: Var = tape.max_axis(x, 1);
+        assert_eq!(tape.shape(max), vec![2, 1, 4]);
+    }
+
+    #[test]
+    fn composites_match_finite_differences() {
+        check("sigmoid", |t, v| t.sigmoid(v[0]), &[a()]);
+        check("softplus", |t, v| t.softplus(v[0]), &[a()]);
+        check("clamp", |t, v| t.clamp(v[0], -1., 1.), &[a()]);
+        check("normalize rows", |t, v| t.normalize(v[0], 1, 1e-5), &[a()]);
+        check(
+            "normalize columns",
+            |t, v| t.normalize(v[0], 0, 1e-5),
+            &[pattern(&[4, 3])],
+        );
+        check(
+            "unpack",
+            |t, v| {
+                let parts: Vec<Var> = t.unpack(v[0], &[(2, 2), (1, 3)]);
+                let m: Var = t.matmul(parts[0], parts[0]);
+                let m: Var = t.sum(m);
+                t.mul(parts[1], m)
+            },
+            &[pattern(&[1, 7])],
+        );
+
+        let mut tape: Tape = Tape::new();
+        let x: Var = tape.leaf(a());
+        let s: Var = tape.sigmoid(x);
+        let p: Var = tape.softplus(x);
+        for ((s, p), x) in tape
+            .value(s)
+            .iter()
+            .zip(tape.value(p).iter())
+            .zip(a().iter())
+        {
+            assert!((s - 1. / (1. + (-x).exp())).abs() < 1e-6);
+            assert!((p - (1. + x.exp()).ln()).abs() < 1e-6);
+        }
+    }
+
+    struct Cube;
+
+    impl Activation for Cube {
+        fn activate(&self, z: &Array2<f32>) -> Array2<f32> {
+            z.mapv(|x: f32| x * x * x)
+        }
+
+        fn derivate(&self, z: &Array2<f32>) -> Array2<f32> {
+            z.mapv(|x: f32| 3. * x * x)
+        }
+
+        fn name(&self) -> String {
+            String::from("cube")
+        }
+    }
+
+    #[test]
+    fn custom_activations_use_their_own_derivative() {
+        let cube: Cube = Cube;
+        check(
+            "custom activation",
+            |t, v| t.custom_activation(v[0], &cube),
+            &[a()],
+        );
+    }
+
+    #[test]
+    fn gradients_flowing_into_a_value_twice_add_up() {
+        let mut tape: Tape = Tape::new();
+        let x: Var = tape.leaf(array![2.].into_dyn());
+        let square: Var = tape.mul(x, x);
+        let y: Var = tape.add(square, x);
+        let adjoints: Adjoints = tape.backward(y);
+        assert_eq!(adjoints.wrt(x)[[0]], 5.);
+    }
+}
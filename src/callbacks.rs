@@ -0,0 +1,417 @@
+#![allow(dead_code, unused_variables)]
+use crate::{metrics::Evaluation, optimizers::Optimizer, training::Trainable};
+use ndarray::Array2;
+use std::path::PathBuf;
+
+// metrics collected for a single epoch of training
+pub struct EpochLogs {
+    pub epoch: usize,
+    // mean cost and accuracy over the training batches
+    pub loss: f32,
+    pub accuracy: f32,
+    pub validation: Option<Evaluation>,
+}
+
+impl EpochLogs {
+    pub fn display(&self) -> String {
+        let mut report: String = format!(
+            "epoch {} - loss: {:.4} - accuracy: {:.4}",
+            self.epoch + 1,
+            self.loss,
+            self.accuracy
+        );
+        if let Some(validation) = &self.validation {
+            report.push_str(&format!(" - {}", validation.display("val_")));
+        }
+        report
+    }
+}
+
+// everything train recorded, one entry per epoch that actually ran
+pub struct History {
+    pub epochs: Vec<EpochLogs>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History { epochs: Vec::new() }
+    }
+
+    pub fn loss(&self) -> Vec<f32> {
+        self.epochs.iter().map(|e: &EpochLogs| e.loss).collect()
+    }
+
+    pub fn accuracy(&self) -> Vec<f32> {
+        self.epochs.iter().map(|e: &EpochLogs| e.accuracy).collect()
+    }
+
+    // empty when training ran without validation data
+    pub fn val_loss(&self) -> Vec<f32> {
+        self.epochs
+            .iter()
+            .filter_map(|e: &EpochLogs| e.validation.as_ref().map(|v: &Evaluation| v.loss))
+            .collect()
+    }
+
+    pub fn val_accuracy(&self) -> Vec<f32> {
+        self.epochs
+            .iter()
+            .filter_map(|e: &EpochLogs| e.validation.as_ref().map(|v: &Evaluation| v.accuracy))
+            .collect()
+    }
+}
+
+// what a callback can inspect and change while train is running
+// model.set_stop_training(true) ends training after the current epoch
+pub struct TrainingContext<'a> {
+    pub model: &'a mut dyn Trainable,
+    pub optimizer: &'a mut dyn Optimizer,
+}
+
+// hooks train calls as it runs, every hook defaults to doing nothing
+pub trait Callback {
+    fn on_epoch_begin(&mut self, epoch: usize, ctx: &mut TrainingContext) {}
+    fn on_batch_end(&mut self, batch: usize, loss: f32, ctx: &mut TrainingContext) {}
+    fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {}
+    fn on_train_end(&mut self, history: &History, ctx: &mut TrainingContext) {}
+    // true for callbacks that write the model to a file, which models that can't be saved refuse to train with
+    fn saves_model(&self) -> bool {
+        false
+    }
+}
+
+// prints one line per epoch, and every `batch_frequency` batches when that isn't 0
+pub struct ProgressLogger {
+    pub batch_frequency: usize,
+}
+
+impl Callback for ProgressLogger {
+    fn on_batch_end(&mut self, batch: usize, loss: f32, ctx: &mut TrainingContext) {
+        if self.batch_frequency > 0 && (batch + 1).is_multiple_of(self.batch_frequency) {
+            println!("batch {} - cost: {:.4}", batch + 1, loss);
+        }
+    }
+
+    fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+        println!("{}", logs.display());
+    }
+}
+
+// saves the model at the end of every epoch, "{epoch}" in the path is replaced by the epoch number
+pub struct ModelCheckpoint {
+    pub path: String,
+}
+
+impl Callback for ModelCheckpoint {
+    fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+        let path: PathBuf =
+            PathBuf::from(self.path.replace("{epoch}", &(logs.epoch + 1).to_string()));
+        if let Err(e) = ctx.model.save(&path) {
+            println!("failed to save checkpoint to {}: {}", path.display(), e);
+        }
+    }
+
+    fn saves_model(&self) -> bool {
+        true
+    }
+}
+
+// a quantity from EpochLogs that a callback can watch
+pub enum Monitor {
+    Loss,
+    Accuracy,
+    ValLoss,
+    ValAccuracy,
+}
+
+impl Monitor {
+    // None when a validation metric is watched but train ran without validation data
+    pub fn value(&self, logs: &EpochLogs) -> Option<f32> {
+        match self {
+            Monitor::Loss => Some(logs.loss),
+            Monitor::Accuracy => Some(logs.accuracy),
+            Monitor::ValLoss => logs.validation.as_ref().map(|v: &Evaluation| v.loss),
+            Monitor::ValAccuracy => logs.validation.as_ref().map(|v: &Evaluation| v.accuracy),
+        }
+    }
+}
+
+// whether the monitored quantity should go down (losses) or up (accuracies)
+pub enum Mode {
+    Min,
+    Max,
+}
+
+impl Mode {
+    // true when value beats best by more than min_delta, or there is no best yet
+    pub fn improved(&self, value: f32, best: Option<f32>, min_delta: f32) -> bool {
+        match (best, self) {
+            (None, _) => true,
+            (Some(best), Mode::Min) => value < best - min_delta,
+            (Some(best), Mode::Max) => value > best + min_delta,
+        }
+    }
+}
+
+// stops training once the monitored quantity hasn't improved by at least min_delta for patience epochs
+// with restore_best_weights a model that gets stopped ends up with the parameters from its best epoch
+pub struct EarlyStopping {
+    pub monitor: Monitor,
+    pub mode: Mode,
+    pub patience: usize,
+    pub min_delta: f32,
+    pub restore_best_weights: bool,
+    // epoch training was halted on, None if it ran to completion
+    pub stopped_epoch: Option<usize>,
+    pub best: Option<f32>,
+    pub best_epoch: usize,
+    wait: usize,
+    best_weights: Vec<Array2<f32>>,
+    best_biases: Vec<Array2<f32>>,
+    best_state: Vec<Array2<f32>>,
+}
+
+impl EarlyStopping {
+    pub fn new(
+        monitor: Monitor,
+        mode: Mode,
+        patience: usize,
+        min_delta: f32,
+        restore_best_weights: bool,
+    ) -> Self {
+        EarlyStopping {
+            monitor,
+            mode,
+            patience,
+            min_delta,
+            restore_best_weights,
+            stopped_epoch: None,
+            best: None,
+            best_epoch: 0,
+            wait: 0,
+            best_weights: Vec::new(),
+            best_biases: Vec::new(),
+            best_state: Vec::new(),
+        }
+    }
+}
+
+impl Callback for EarlyStopping {
+    fn on_epoch_begin(&mut self, epoch: usize, ctx: &mut TrainingContext) {
+        // reset so the same callback can be reused across several train calls
+        if epoch == 0 {
+            self.stopped_epoch = None;
+            self.best = None;
+            self.best_epoch = 0;
+            self.wait = 0;
+        }
+    }
+
+    fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+        let value: f32 = match self.monitor.value(logs) {
+            Some(value) => value,
+            None => return,
+        };
+        if self.mode.improved(value, self.best, self.min_delta) {
+            self.best = Some(value);
+            self.best_epoch = logs.epoch;
+            self.wait = 0;
+            if self.restore_best_weights {
+                self.best_weights = ctx.model.weights().clone();
+                self.best_biases = ctx.model.biases().clone();
+                self.best_state = ctx.model.state().clone();
+            }
+            return;
+        }
+        self.wait += 1;
+        if self.wait >= self.patience {
+            self.stopped_epoch = Some(logs.epoch);
+            ctx.model.set_stop_training(true);
+        }
+    }
+
+    fn on_train_end(&mut self, history: &History, ctx: &mut TrainingContext) {
+        if let Some(epoch) = self.stopped_epoch {
+            println!("early stopping at epoch {}", epoch + 1);
+        }
+        // like keras, a run that finishes every epoch keeps the weights it ended on
+        if self.restore_best_weights && self.stopped_epoch.is_some() && self.best.is_some() {
+            println!("restoring weights from epoch {}", self.best_epoch + 1);
+            *ctx.model.weights() = self.best_weights.clone();
+            *ctx.model.biases() = self.best_biases.clone();
+            *ctx.model.state() = self.best_state.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activations::Activations,
+        cost::Cost,
+        layers::Layers,
+        netutil::{Net, Sequential},
+        optimizers::Optimizers,
+        regularizers::Regularizer,
+        typings::{Sample, Validation},
+    };
+    use ndarray::{Array, Array2};
+
+    // counts every hook and stops training once stop_at epochs have ended
+    struct Recorder {
+        stop_at: usize,
+        epochs_begun: usize,
+        batches: usize,
+        epochs_ended: usize,
+        finished: bool,
+    }
+
+    impl Callback for Recorder {
+        fn on_epoch_begin(&mut self, epoch: usize, ctx: &mut TrainingContext) {
+            self.epochs_begun += 1;
+        }
+
+        fn on_batch_end(&mut self, batch: usize, loss: f32, ctx: &mut TrainingContext) {
+            self.batches += 1;
+        }
+
+        fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+            self.epochs_ended += 1;
+            if self.epochs_ended == self.stop_at {
+                ctx.model.set_stop_training(true);
+            }
+        }
+
+        fn on_train_end(&mut self, history: &History, ctx: &mut TrainingContext) {
+            self.finished = true;
+        }
+    }
+
+    // (1 × 3) inputs with the sum of each row as the label
+    fn dataset(samples: usize) -> Vec<Sample> {
+        (0..samples)
+            .map(|i| {
+                let x: Array2<f32> =
+                    Array::from_shape_fn((1, 3), |(_, j)| ((i * 3 + j) as f32 * 0.37).sin());
+                let y: Array2<f32> = Array2::from_elem((1, 1), x.sum());
+                Sample::new(x.into_dyn(), y.into_dyn())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn callbacks_see_every_hook_and_can_stop_training() {
+        let mut model: Sequential = Sequential::new(3, Cost::MSE);
+        model.add(Layers::Dense {
+            units: 1,
+            activation: Activations::Tanh,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        });
+        let mut optimizer = Optimizers::SGD.build(0.05, &model);
+        let mut stopper: Recorder = Recorder {
+            stop_at: 2,
+            epochs_begun: 0,
+            batches: 0,
+            epochs_ended: 0,
+            finished: false,
+        };
+        let history: History = model.train(
+            dataset(16),
+            &mut optimizer,
+            4,
+            10,
+            Validation::Split(0.25),
+            &mut [&mut stopper],
+        );
+        // 12 training samples in batches of 4, stopped after the second epoch
+        assert_eq!(history.epochs.len(), 2);
+        assert_eq!(history.loss().len(), 2);
+        assert_eq!(history.val_accuracy().len(), 2);
+        assert_eq!(stopper.epochs_begun, 2);
+        assert_eq!(stopper.batches, 6);
+        assert!(stopper.finished);
+
+        // a second run clears the flag the first one left set
+        let history: History =
+            model.train(dataset(8), &mut optimizer, 4, 3, Validation::None, &mut []);
+        assert_eq!(history.epochs.len(), 3);
+        assert!(history.val_loss().is_empty());
+    }
+
+    fn early_stopping_model() -> Sequential {
+        let mut model: Sequential = Sequential::new(2, Cost::MSE);
+        model.add(Layers::Dense {
+            units: 1,
+            activation: Activations::Tanh,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        });
+        model
+    }
+
+    // one epoch per loss, the weights are set to the epoch number so it's easy to tell which were kept
+    fn run(early_stopping: &mut EarlyStopping, model: &mut Sequential, losses: &[f32]) {
+        let mut optimizer = Optimizers::SGD.build(0.1, model);
+        let mut ctx: TrainingContext = TrainingContext {
+            model,
+            optimizer: &mut optimizer,
+        };
+        // cleared at the start of every run like train does
+        ctx.model.set_stop_training(false);
+        for (epoch, loss) in losses.iter().enumerate() {
+            early_stopping.on_epoch_begin(epoch, &mut ctx);
+            ctx.model.weights()[0].fill(epoch as f32);
+            let logs: EpochLogs = EpochLogs {
+                epoch,
+                loss: *loss,
+                accuracy: 0.,
+                validation: None,
+            };
+            early_stopping.on_epoch_end(&logs, &mut ctx);
+            if ctx.model.stop_training() {
+                break;
+            }
+        }
+        early_stopping.on_train_end(&History::new(), &mut ctx);
+    }
+
+    #[test]
+    fn best_weights_are_restored_after_an_early_stop() {
+        let mut model: Sequential = early_stopping_model();
+        let mut early_stopping: EarlyStopping =
+            EarlyStopping::new(Monitor::Loss, Mode::Min, 2, 0., true);
+        run(&mut early_stopping, &mut model, &[3., 1., 2., 2., 0.5]);
+        assert_eq!(early_stopping.stopped_epoch, Some(3));
+        assert_eq!(early_stopping.best_epoch, 1);
+        assert!(model.weights[0].iter().all(|w: &f32| *w == 1.));
+    }
+
+    #[test]
+    fn runs_that_finish_keep_their_last_weights() {
+        let mut model: Sequential = early_stopping_model();
+        let mut early_stopping: EarlyStopping =
+            EarlyStopping::new(Monitor::Loss, Mode::Min, 5, 0., true);
+        run(&mut early_stopping, &mut model, &[3., 1., 2., 2.]);
+        assert_eq!(early_stopping.stopped_epoch, None);
+        assert!(model.weights[0].iter().all(|w: &f32| *w == 3.));
+    }
+
+    #[test]
+    fn a_second_run_starts_over() {
+        let mut model: Sequential = early_stopping_model();
+        let mut early_stopping: EarlyStopping =
+            EarlyStopping::new(Monitor::Loss, Mode::Min, 1, 0., true);
+        run(&mut early_stopping, &mut model, &[3., 2., 1., 4.]);
+        assert_eq!(early_stopping.best_epoch, 2);
+        // the first epoch of the new run is its best even though it's worse than the last run's
+        run(&mut early_stopping, &mut model, &[5., 6.]);
+        assert_eq!(early_stopping.best, Some(5.));
+        assert_eq!(early_stopping.best_epoch, 0);
+        assert_eq!(early_stopping.stopped_epoch, Some(1));
+        assert!(model.weights[0].iter().all(|w: &f32| *w == 0.));
+    }
+}
@@ -1,53 +1,794 @@
 #![allow(dead_code)]
 use crate::{
-    matrixutil::{create_weight, power_of, scalar_mult},
-    typings::ForwardBatch,
+    activations::Activations,
+    autograd::{Tape, Var},
 };
-use ndarray::Array2;
-use std::ops::{Add, Sub};
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+use std::{f32::consts::LN_2, fmt};
+
+// probabilities are clamped to [EPSILON, 1 - EPSILON] before taking a log so ln(0) never shows up
+pub const EPSILON: f32 = 1e-7;
+
+// anything the model can minimize, implement it and wrap it in Cost::Custom to plug in your own
+// register a constructor under its id to load saved models that use it
+pub trait Loss {
+    // mean over the rows of a (batch × outputs) prediction
+    fn calculate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> f32;
+    // ∂calculate/∂predicted, same shape as predicted
+    fn derivate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> Array2<f32>;
+    fn name(&self) -> String;
+    // key the registry rebuilds it from when a saved model is loaded
+    fn id(&self) -> String {
+        self.name()
+    }
+    // whatever the registered constructor needs to rebuild it, saved alongside the id
+    fn params(&self) -> Vec<f32> {
+        Vec::new()
+    }
+}
+
+impl fmt::Debug for dyn Loss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
 // enum storing each cost function
+// every function takes (batch × outputs) predictions and labels, one sample per row; elementwise costs
+// are summed over a row's outputs and averaged over the rows
+#[derive(Debug)]
 pub enum Cost {
     MSE,
+    // categorical cross-entropy, expects one-hot labels and a probability distribution per row
     CrossEntropy,
+    // binary cross-entropy, expects every output to be an independent probability in (0, 1)
+    BinaryCrossEntropy,
+    MAE,
+    // quadratic for errors up to delta and linear past it, so outliers pull less than with MSE
+    Huber { delta: f32 },
+    // ln(cosh(ŷ-y)), smooth like MSE near 0 and like MAE far from it
+    LogCosh,
+    // labels are ±1 (0 is read as -1) and predictions are raw scores
+    Hinge,
+    SquaredHinge,
+    // binary cross-entropy on raw logits, folding the sigmoid in so it never saturates
+    BinaryCrossEntropyWithLogits,
+    // categorical cross-entropy with a (batch × 1) column of integer class indices as labels
+    SparseCategoricalCrossEntropy,
+    // Σ y ln(y/ŷ) between the label and predicted distributions
+    KLDivergence,
+    // negative cosine similarity of every row with its label, -1 when they point the same way
+    CosineSimilarity,
+    // user defined
+    Custom(Box<dyn Loss>),
+}
+
+// how calculate_with combines per-sample losses and derivate_with scales their gradients
+pub enum Reduction {
+    // Σ wᵢlᵢ / n, what calculate uses and what models train with unless told otherwise
+    Mean,
+    Sum,
+    // every sample's weighted loss on its own, gradients are the same as for Sum
+    None,
 }
 
 impl Cost {
+    // mean loss over the rows
     // MSE(xᵢ,yᵢ) = 1/n Σ(i=0;n) (yᵢ-ŷᵢ)^2
-    pub fn calculate(&self, predicted: &ForwardBatch, expected: &Vec<Array2<f32>>) -> f32 {
-        match self {
+    // CE(xᵢ,yᵢ) = -1/n Σ(i=0;n) Σ(k) yᵢₖ ln(ŷᵢₖ)
+    // BCE(xᵢ,yᵢ) = -1/n Σ(i=0;n) Σ(k) yᵢₖ ln(ŷᵢₖ) + (1-yᵢₖ) ln(1-ŷᵢₖ)
+    pub fn calculate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> f32 {
+        if let Cost::Custom(f) = self {
+            return f.calculate(predicted, expected);
+        }
+        self.calculate_with(predicted, expected, None, &Reduction::Mean)[0]
+    }
+
+    // derivative of the batch cost with respect to every prediction, same shape as predicted
+    // ∂MSE/∂ŷᵢ = 2/n (ŷᵢ-yᵢ)
+    // ∂CE/∂ŷᵢ = -1/n yᵢ/ŷᵢ
+    // ∂BCE/∂ŷᵢ = 1/n (ŷᵢ-yᵢ)/(ŷᵢ(1-ŷᵢ))
+    pub fn derivate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> Array2<f32> {
+        if let Cost::Custom(f) = self {
+            return f.derivate(predicted, expected);
+        }
+        self.derivate_with(predicted, expected, None, &Reduction::Mean)
+    }
+
+    // per-sample losses scaled by weights (one per row) and then reduced
+    // Mean and Sum give a single element, None gives one per row
+    pub fn calculate_with(
+        &self,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+        weights: Option<&Array1<f32>>,
+        reduction: &Reduction,
+    ) -> Array1<f32> {
+        let mut losses: Array1<f32> = self.per_sample(predicted, expected);
+        if let Some(w) = weights {
+            losses *= w;
+        }
+        match reduction {
+            Reduction::Mean => Array1::from_elem(1, losses.sum() / predicted.nrows() as f32),
+            Reduction::Sum => Array1::from_elem(1, losses.sum()),
+            Reduction::None => losses,
+        }
+    }
+
+    // gradient of calculate_with with respect to every prediction
+    pub fn derivate_with(
+        &self,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+        weights: Option<&Array1<f32>>,
+        reduction: &Reduction,
+    ) -> Array2<f32> {
+        reduce_gradient(
+            self.per_sample_derivate(predicted, expected),
+            weights,
+            reduction,
+        )
+    }
+
+    // calculate_with recorded on tape for a (batch × outputs) prediction, out of primitive ops so the tape
+    // works out the gradient itself instead of going through derivate_with
+    // Mean and Sum give a 0d tensor and so does None, summed since that's what its gradients add up to
+    pub fn record<'a>(
+        &'a self,
+        tape: &mut Tape<'a>,
+        predicted: Var,
+        expected: &Array2<f32>,
+        weights: Option<&Array1<f32>>,
+        reduction: &Reduction,
+    ) -> Var {
+        let mut losses: Var = self.record_per_sample(tape, predicted, expected);
+        if let Some(w) = weights {
+            let w: Var = tape.leaf(w.view().insert_axis(Axis(1)).to_owned().into_dyn());
+            losses = tape.mul(losses, w);
+        }
+        let total: Var = tape.sum(losses);
+        match reduction {
+            Reduction::Mean => tape.scale(total, 1. / expected.nrows() as f32),
+            Reduction::Sum | Reduction::None => total,
+        }
+    }
+
+    // per_sample as a (batch × 1) tensor on tape
+    fn record_per_sample<'a>(
+        &'a self,
+        tape: &mut Tape<'a>,
+        predicted: Var,
+        expected: &Array2<f32>,
+    ) -> Var {
+        let (rows, cols) = expected.dim();
+        let y: Var = tape.leaf(expected.clone().into_dyn());
+        let elements: Var = match self {
+            Cost::SparseCategoricalCrossEntropy => {
+                let classes: usize = tape.shape(predicted)[1];
+                // every row's labelled probability picked out of the flattened predictions
+                let picks: Vec<usize> = expected
+                    .iter()
+                    .enumerate()
+                    .map(|(i, label)| i * classes + class_index(*label, classes))
+                    .collect();
+                let flat: Var = tape.reshape(predicted, &[rows * classes]);
+                let picked: Var = tape.index(flat, 0, &picks);
+                let p: Var = tape.clamp(picked, EPSILON, 1. - EPSILON);
+                let log: Var = tape.ln(p);
+                let loss: Var = tape.neg(log);
+                return tape.reshape(loss, &[rows, 1]);
+            }
+            Cost::CosineSimilarity => {
+                let dot: Var = tape.mul(predicted, y);
+                let dot: Var = tape.sum_axis(dot, 1);
+                let square: Var = tape.mul(predicted, predicted);
+                let p_norm: Var = tape.sum_axis(square, 1);
+                let p_norm: Var = tape.powf(p_norm, 0.5);
+                let floor: Var = tape.scalar(EPSILON);
+                let p_norm: Var = tape.maximum(p_norm, floor);
+                // the labels are constants so their norms are too
+                let y_norm: Array2<f32> = expected
+                    .map_axis(Axis(1), |y| y.dot(&y).sqrt().max(EPSILON))
+                    .insert_axis(Axis(1));
+                let y_norm: Var = tape.leaf(y_norm.into_dyn());
+                let norms: Var = tape.mul(p_norm, y_norm);
+                let cos: Var = tape.div(dot, norms);
+                return tape.neg(cos);
+            }
+            Cost::Custom(_) => return tape.custom_loss(predicted, expected, self),
             Cost::MSE => {
-                let mut outp: f32 = 0f32;
-                for i in 0..predicted.len() {
-                    let mut error: Array2<f32> =
-                        (&expected[i]).sub(predicted[i][1].last().unwrap());
-                    let squared_error: &Array2<f32> = power_of(&mut error, 2);
-                    outp += squared_error.sum();
+                let e: Var = tape.sub(predicted, y);
+                tape.mul(e, e)
+            }
+            // -y ln(p)
+            Cost::CrossEntropy => {
+                let p: Var = tape.clamp(predicted, EPSILON, 1. - EPSILON);
+                let log: Var = tape.ln(p);
+                let loss: Var = tape.mul(y, log);
+                tape.neg(loss)
+            }
+            // -(y ln(p) + (1-y) ln(1-p))
+            Cost::BinaryCrossEntropy => {
+                let p: Var = tape.clamp(predicted, EPSILON, 1. - EPSILON);
+                let log_p: Var = tape.ln(p);
+                let hit: Var = tape.mul(y, log_p);
+                let q: Var = tape.neg(p);
+                let q: Var = tape.offset(q, 1.);
+                let log_q: Var = tape.ln(q);
+                let not_y: Var = tape.leaf(expected.mapv(|y| 1. - y).into_dyn());
+                let miss: Var = tape.mul(not_y, log_q);
+                let loss: Var = tape.add(hit, miss);
+                tape.neg(loss)
+            }
+            Cost::MAE => {
+                let e: Var = tape.sub(predicted, y);
+                tape.abs(e)
+            }
+            // with m = min(|e|, δ), 0.5m² + δ(|e| - m) is 0.5e² up to δ and δ(|e| - 0.5δ) past it
+            Cost::Huber { delta } => {
+                let e: Var = tape.sub(predicted, y);
+                let r: Var = tape.abs(e);
+                let cap: Var = tape.scalar(*delta);
+                let m: Var = tape.minimum(r, cap);
+                let quadratic: Var = tape.mul(m, m);
+                let quadratic: Var = tape.scale(quadratic, 0.5);
+                let linear: Var = tape.sub(r, m);
+                let linear: Var = tape.scale(linear, *delta);
+                tape.add(quadratic, linear)
+            }
+            // |e| + ln(1 + e^-2|e|) - ln(2)
+            Cost::LogCosh => {
+                let e: Var = tape.sub(predicted, y);
+                let r: Var = tape.abs(e);
+                let tail: Var = tape.scale(r, -2.);
+                let tail: Var = tape.exp(tail);
+                let tail: Var = tape.offset(tail, 1.);
+                let tail: Var = tape.ln(tail);
+                let loss: Var = tape.add(r, tail);
+                tape.offset(loss, -LN_2)
+            }
+            // max(1 - t p, 0)
+            Cost::Hinge | Cost::SquaredHinge => {
+                let t: Var = tape.leaf(expected.mapv(hinge_label).into_dyn());
+                let margin: Var = tape.mul(t, predicted);
+                let margin: Var = tape.neg(margin);
+                let margin: Var = tape.offset(margin, 1.);
+                let zero: Var = tape.scalar(0.);
+                let loss: Var = tape.maximum(margin, zero);
+                match self {
+                    Cost::SquaredHinge => tape.mul(loss, loss),
+                    _ => loss,
                 }
-                outp / predicted.len() as f32
             }
-            Cost::CrossEntropy => 1f32,
+            // max(z,0) - zy + ln(1 + e^-|z|), which is softplus(z) - zy
+            Cost::BinaryCrossEntropyWithLogits => {
+                let sp: Var = tape.softplus(predicted);
+                let zy: Var = tape.mul(predicted, y);
+                tape.sub(sp, zy)
+            }
+            // y ln(y) - y ln(p), the first half is a constant and 0 wherever y is
+            Cost::KLDivergence => {
+                let p: Var = tape.clamp(predicted, EPSILON, 1. - EPSILON);
+                let log: Var = tape.ln(p);
+                let cross: Var = tape.mul(y, log);
+                let entropy: Var = tape.leaf(
+                    expected
+                        .mapv(|y| if y > 0. { y * y.ln() } else { 0. })
+                        .into_dyn(),
+                );
+                tape.sub(entropy, cross)
+            }
+        };
+        debug_assert_eq!(tape.shape(elements), vec![rows, cols]);
+        tape.sum_axis(elements, 1)
+    }
+
+    // Err with the reason when the cost's parameters make no sense
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Cost::Huber { delta } if *delta <= 0. || delta.is_nan() => {
+                Err(format!("huber delta must be above 0 but got {}", delta))
+            }
+            _ => Ok(()),
         }
     }
 
-    // I'm confused because it seems like it's supposed to be a scalar but it's not idk bro leave me alone
-    // ∂MSE/∂ŷ = -2/n Σ(i=0;n) (yᵢ-ŷᵢ)
-    pub fn derivate(&self, predicted: &ForwardBatch, expected: &Vec<Array2<f32>>) -> Array2<f32> {
+    // where a single output column flips from class 0 to class 1, 0 for costs that read predictions as
+    // raw scores or logits and 0.5 for probabilities
+    pub fn threshold(&self) -> f32 {
         match self {
-            Cost::MSE => {
-                let m: &[usize] = expected[0].shape();
-                let mut outp: Array2<f32> = create_weight(&vec![m[0], m[1]]);
-                for i in 0..predicted.len() {
-                    // using predicted - expected as a substitute for -(expected - predicted)
-                    let mut error: Array2<f32> =
-                        (&predicted[i][1].last().unwrap()).sub(&expected[i]);
-                    //temporarily leaving the - here because for some reason my gradient is ascending without it
-                    let derivative_squared_error: &Array2<f32> =
-                        scalar_mult(&mut error, -2f32 / predicted.len() as f32);
-                    outp = outp.clone().add(derivative_squared_error);
+            Cost::BinaryCrossEntropyWithLogits | Cost::Hinge | Cost::SquaredHinge => 0.,
+            _ => 0.5,
+        }
+    }
+
+    // the loss of every row on its own
+    pub fn per_sample(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> Array1<f32> {
+        match self {
+            Cost::SparseCategoricalCrossEntropy => predicted
+                .outer_iter()
+                .zip(expected.iter())
+                .map(|(row, label)| -clamp_probability(row[class_index(*label, row.len())]).ln())
+                .collect(),
+            Cost::CosineSimilarity => predicted
+                .outer_iter()
+                .zip(expected.outer_iter())
+                .map(|(p, y)| -cosine(&p, &y).0)
+                .collect(),
+            // a custom loss averaged over a single row is that row's loss
+            Cost::Custom(f) => (0..predicted.nrows())
+                .map(|i| f.calculate(&row(predicted, i), &row(expected, i)))
+                .collect(),
+            _ => {
+                let mut losses: Array2<f32> = predicted.clone();
+                losses.zip_mut_with(expected, |p, y| *p = self.element_loss(*p, *y));
+                losses.sum_axis(Axis(1))
+            }
+        }
+    }
+
+    // row i holds the derivative of row i's loss with respect to its predictions
+    pub fn per_sample_derivate(
+        &self,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+    ) -> Array2<f32> {
+        let mut grad: Array2<f32> = Array2::zeros(predicted.dim());
+        match self {
+            // only the labelled class shows up in the loss
+            Cost::SparseCategoricalCrossEntropy => {
+                for (i, label) in expected.iter().enumerate() {
+                    let label: usize = class_index(*label, predicted.ncols());
+                    grad[[i, label]] = -1. / clamp_probability(predicted[[i, label]]);
+                }
+            }
+            // ∂(-cos)/∂ŷ = -(y/(|ŷ||y|) - cos ŷ/|ŷ|²)
+            Cost::CosineSimilarity => {
+                for (i, (p, y)) in predicted
+                    .outer_iter()
+                    .zip(expected.outer_iter())
+                    .enumerate()
+                {
+                    let (cos, p_norm, y_norm) = cosine(&p, &y);
+                    let g: Array1<f32> =
+                        (&y / (p_norm * y_norm) - &p * (cos / (p_norm * p_norm))) * -1.;
+                    grad.row_mut(i).assign(&g);
+                }
+            }
+            Cost::Custom(f) => {
+                for i in 0..predicted.nrows() {
+                    let g: Array2<f32> = f.derivate(&row(predicted, i), &row(expected, i));
+                    grad.row_mut(i).assign(&g.row(0));
+                }
+            }
+            _ => {
+                grad.assign(predicted);
+                grad.zip_mut_with(expected, |p, y| *p = self.element_derivate(*p, *y));
+            }
+        }
+        grad
+    }
+
+    // contribution of a single output for the costs that treat every output independently
+    fn element_loss(&self, p: f32, y: f32) -> f32 {
+        let e: f32 = p - y;
+        match self {
+            Cost::MSE => e * e,
+            Cost::CrossEntropy => -y * clamp_probability(p).ln(),
+            Cost::BinaryCrossEntropy => {
+                let p: f32 = clamp_probability(p);
+                -(y * p.ln() + (1. - y) * (1. - p).ln())
+            }
+            Cost::MAE => e.abs(),
+            Cost::Huber { delta } => {
+                if e.abs() <= *delta {
+                    0.5 * e * e
+                } else {
+                    delta * (e.abs() - 0.5 * delta)
+                }
+            }
+            // ln(cosh(e)) = |e| + ln(1 + e^-2|e|) - ln(2) without overflowing cosh
+            Cost::LogCosh => e.abs() + (-2. * e.abs()).exp().ln_1p() - LN_2,
+            Cost::Hinge => (1. - hinge_label(y) * p).max(0.),
+            Cost::SquaredHinge => (1. - hinge_label(y) * p).max(0.).powi(2),
+            // max(z,0) - zy + ln(1 + e^-|z|) is -(y ln σ(z) + (1-y) ln(1-σ(z))) rearranged to stay finite
+            Cost::BinaryCrossEntropyWithLogits => p.max(0.) - p * y + (-p.abs()).exp().ln_1p(),
+            Cost::KLDivergence => {
+                if y > 0. {
+                    y * (y / clamp_probability(p)).ln()
+                } else {
+                    0.
+                }
+            }
+            _ => unreachable!("{:?} isn't an elementwise cost", self),
+        }
+    }
+
+    fn element_derivate(&self, p: f32, y: f32) -> f32 {
+        let e: f32 = p - y;
+        match self {
+            Cost::MSE => 2. * e,
+            Cost::CrossEntropy | Cost::KLDivergence => -y / clamp_probability(p),
+            Cost::BinaryCrossEntropy => {
+                let p: f32 = clamp_probability(p);
+                (p - y) / (p * (1. - p))
+            }
+            Cost::MAE => sign(e),
+            Cost::Huber { delta } => e.clamp(-delta, *delta),
+            Cost::LogCosh => e.tanh(),
+            Cost::Hinge => {
+                let t: f32 = hinge_label(y);
+                if 1. - t * p > 0. {
+                    -t
+                } else {
+                    0.
                 }
-                outp
             }
-            Cost::CrossEntropy => expected[0].clone(),
+            Cost::SquaredHinge => {
+                let t: f32 = hinge_label(y);
+                -2. * t * (1. - t * p).max(0.)
+            }
+            Cost::BinaryCrossEntropyWithLogits => 1. / (1. + (-p).exp()) - y,
+            _ => unreachable!("{:?} isn't an elementwise cost", self),
+        }
+    }
+
+    // softmax followed by cross-entropy (and sigmoid followed by binary cross-entropy) collapse into ŷ - y
+    // when differentiated with respect to the logits, which avoids dividing by tiny probabilities
+    pub fn fuses_with(&self, activation: &Activations) -> bool {
+        matches!(
+            (self, activation),
+            (Cost::CrossEntropy, Activations::Softmax { .. })
+                | (
+                    Cost::SparseCategoricalCrossEntropy,
+                    Activations::Softmax { .. }
+                )
+                | (Cost::BinaryCrossEntropy, Activations::Sigmoid)
+        )
+    }
+
+    // ∂C/∂zᵢ = (ŷᵢ-yᵢ)/n for every row (divided by T for a softmax with temperature), only valid when
+    // fuses_with returned true
+    pub fn derivate_fused(
+        &self,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+        activation: &Activations,
+    ) -> Array2<f32> {
+        self.derivate_fused_with(predicted, expected, activation, None, &Reduction::Mean)
+    }
+
+    // derivate_fused with the sample weights and reduction of derivate_with
+    pub fn derivate_fused_with(
+        &self,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+        activation: &Activations,
+        weights: Option<&Array1<f32>>,
+        reduction: &Reduction,
+    ) -> Array2<f32> {
+        let temperature: f32 = match activation {
+            Activations::Softmax { temperature } => *temperature,
+            _ => 1.,
+        };
+        let mut grad: Array2<f32> = predicted.clone();
+        if let Cost::SparseCategoricalCrossEntropy = self {
+            // subtracting the one-hot label the indices stand for
+            for (i, label) in expected.iter().enumerate() {
+                grad[[i, class_index(*label, predicted.ncols())]] -= 1.;
+            }
+        } else {
+            grad -= expected;
+        }
+        reduce_gradient(grad / temperature, weights, reduction)
+    }
+}
+
+impl Loss for Cost {
+    fn calculate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> f32 {
+        Cost::calculate(self, predicted, expected)
+    }
+
+    fn derivate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> Array2<f32> {
+        Cost::derivate(self, predicted, expected)
+    }
+
+    fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+// scales every row's gradient by its sample weight, and by 1/n for a mean
+fn reduce_gradient(
+    mut grad: Array2<f32>,
+    weights: Option<&Array1<f32>>,
+    reduction: &Reduction,
+) -> Array2<f32> {
+    if let Some(w) = weights {
+        grad *= &w.view().insert_axis(Axis(1));
+    }
+    if let Reduction::Mean = reduction {
+        grad /= grad.nrows() as f32;
+    }
+    grad
+}
+
+// a sparse label as an index into a row of classes, anything else is a bug in the data so it fails loudly
+pub fn class_index(label: f32, classes: usize) -> usize {
+    if label < 0. || label.fract() != 0. || label as usize >= classes {
+        panic!(
+            "label {} isn't a class index, expected an integer in [0, {})",
+            label, classes
+        );
+    }
+    label as usize
+}
+
+fn clamp_probability(p: f32) -> f32 {
+    p.clamp(EPSILON, 1. - EPSILON)
+}
+
+fn sign(x: f32) -> f32 {
+    if x == 0. {
+        0.
+    } else {
+        x.signum()
+    }
+}
+
+// hinge losses want ±1 labels, 0/1 labels are mapped onto them
+fn hinge_label(y: f32) -> f32 {
+    if y > 0. {
+        1.
+    } else {
+        -1.
+    }
+}
+
+// (cos, |p|, |y|) with both norms kept away from 0
+fn cosine(p: &ArrayView1<f32>, y: &ArrayView1<f32>) -> (f32, f32, f32) {
+    let p_norm: f32 = p.dot(p).sqrt().max(EPSILON);
+    let y_norm: f32 = y.dot(y).sqrt().max(EPSILON);
+    (p.dot(y) / (p_norm * y_norm), p_norm, y_norm)
+}
+
+// row i as a (1 × outputs) batch of its own
+fn row(m: &Array2<f32>, i: usize) -> Array2<f32> {
+    m.row(i).insert_axis(Axis(0)).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        autograd::{check_gradients, Adjoints},
+        matrixutil::numerical_gradient,
+    };
+    use ndarray::{array, Ix2};
+
+    fn max_diff(a: &Array2<f32>, b: &Array2<f32>) -> f32 {
+        (a - b).iter().fold(0f32, |m: f32, x: &f32| m.max(x.abs()))
+    }
+
+    // ∂C/∂ŷ from derivate against central differences of calculate
+    fn check(cost: &Cost, predicted: &Array2<f32>, expected: &Array2<f32>) -> f32 {
+        let analytic: Array2<f32> = cost.derivate(predicted, expected);
+        let numeric: Array2<f32> = numerical_gradient(
+            |p: &Array2<f32>| cost.calculate(p, expected),
+            predicted,
+            1e-3,
+        );
+        max_diff(&analytic, &numeric)
+    }
+
+    #[test]
+    fn cross_entropy_matches_finite_differences() {
+        let predicted: Array2<f32> = array![[0.2, 0.5, 0.3], [0.6, 0.1, 0.3]];
+        let expected: Array2<f32> = array![[0., 1., 0.], [0., 0., 1.]];
+        assert!(check(&Cost::CrossEntropy, &predicted, &expected) < 1e-2);
+    }
+
+    #[test]
+    fn binary_cross_entropy_matches_finite_differences() {
+        let predicted: Array2<f32> = array![[0.2, 0.7], [0.9, 0.4]];
+        let expected: Array2<f32> = array![[0., 1.], [1., 1.]];
+        assert!(check(&Cost::BinaryCrossEntropy, &predicted, &expected) < 1e-2);
+    }
+
+    #[test]
+    fn cross_entropy_stays_finite_at_zero_and_one() {
+        let predicted: Array2<f32> = array![[0., 1.]];
+        let expected: Array2<f32> = array![[1., 0.]];
+        for cost in [Cost::CrossEntropy, Cost::BinaryCrossEntropy].iter() {
+            assert!(cost.calculate(&predicted, &expected).is_finite());
+            assert!(cost
+                .derivate(&predicted, &expected)
+                .iter()
+                .all(|g: &f32| g.is_finite()));
+        }
+    }
+
+    // the fused ŷ - y shortcut has to agree with going through the activation's Jacobian
+    fn check_fused(cost: &Cost, activation: &Activations, z: &Array2<f32>, expected: &Array2<f32>) {
+        assert!(cost.fuses_with(activation));
+        let predicted: Array2<f32> = activation.activate(z);
+        let fused: Array2<f32> = cost.derivate_fused(&predicted, expected, activation);
+        let chained: Array2<f32> = activation.backward(z, &cost.derivate(&predicted, expected));
+        assert!(max_diff(&fused, &chained) < 1e-5);
+    }
+
+    #[test]
+    fn fused_softmax_cross_entropy_matches_chained_jacobian() {
+        let z: Array2<f32> = array![[1., -0.5, 2.], [0.3, 0.2, -1.]];
+        let expected: Array2<f32> = array![[0., 0., 1.], [1., 0., 0.]];
+        for temperature in [1., 2.5].iter() {
+            let softmax: Activations = Activations::Softmax {
+                temperature: *temperature,
+            };
+            check_fused(&Cost::CrossEntropy, &softmax, &z, &expected);
+        }
+    }
+
+    #[test]
+    fn fused_sigmoid_binary_cross_entropy_matches_chained_jacobian() {
+        let z: Array2<f32> = array![[1.5, -0.5], [0., 2.]];
+        let expected: Array2<f32> = array![[1., 0.], [0., 1.]];
+        check_fused(
+            &Cost::BinaryCrossEntropy,
+            &Activations::Sigmoid,
+            &z,
+            &expected,
+        );
+    }
+
+    #[test]
+    fn every_cost_matches_finite_differences() {
+        // away from the kinks of MAE, Huber and the hinges
+        let predicted: Array2<f32> = array![[0.3, 0.6, 0.15], [0.7, 0.2, 0.45]];
+        let expected: Array2<f32> = array![[0., 1., 0.], [1., 0., 0.]];
+        let costs: Vec<Cost> = vec![
+            Cost::MSE,
+            Cost::MAE,
+            Cost::Huber { delta: 0.5 },
+            Cost::LogCosh,
+            Cost::Hinge,
+            Cost::SquaredHinge,
+            Cost::BinaryCrossEntropyWithLogits,
+            Cost::KLDivergence,
+            Cost::CosineSimilarity,
+        ];
+        for cost in costs.iter() {
+            let error: f32 = check(cost, &predicted, &expected);
+            assert!(error < 1e-2, "{:?} is off by {}", cost, error);
+        }
+        let sparse: Array2<f32> = array![[1.], [0.]];
+        assert!(check(&Cost::SparseCategoricalCrossEntropy, &predicted, &sparse) < 1e-2);
+    }
+
+    #[test]
+    fn weighted_reductions_match_finite_differences() {
+        let predicted: Array2<f32> = array![[0.2, 0.5], [0.6, 0.1], [0.3, 0.9]];
+        let expected: Array2<f32> = array![[0., 1.], [1., 0.], [1., 1.]];
+        let weights: Array1<f32> = array![1., 0., 2.5];
+        for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None].iter() {
+            let analytic: Array2<f32> =
+                Cost::MSE.derivate_with(&predicted, &expected, Some(&weights), reduction);
+            let numeric: Array2<f32> = numerical_gradient(
+                |p: &Array2<f32>| {
+                    Cost::MSE
+                        .calculate_with(p, &expected, Some(&weights), reduction)
+                        .sum()
+                },
+                &predicted,
+                1e-3,
+            );
+            assert!(max_diff(&analytic, &numeric) < 1e-2);
+        }
+        // a sample weighted 0 drops out of the loss and gets no gradient
+        let losses: Array1<f32> =
+            Cost::MSE.calculate_with(&predicted, &expected, Some(&weights), &Reduction::None);
+        assert_eq!(losses[1], 0.);
+    }
+
+    #[test]
+    fn weighted_fused_gradient_matches_chained_jacobian() {
+        let softmax: Activations = Activations::Softmax { temperature: 1. };
+        let z: Array2<f32> = array![[1., -0.5, 2.], [0.3, 0.2, -1.]];
+        let expected: Array2<f32> = array![[0., 0., 1.], [1., 0., 0.]];
+        let weights: Array1<f32> = array![0.5, 2.];
+        let predicted: Array2<f32> = softmax.activate(&z);
+        let fused: Array2<f32> = Cost::CrossEntropy.derivate_fused_with(
+            &predicted,
+            &expected,
+            &softmax,
+            Some(&weights),
+            &Reduction::Sum,
+        );
+        let chained: Array2<f32> = softmax.backward(
+            &z,
+            &Cost::CrossEntropy.derivate_with(
+                &predicted,
+                &expected,
+                Some(&weights),
+                &Reduction::Sum,
+            ),
+        );
+        assert!(max_diff(&fused, &chained) < 1e-5);
+    }
+
+    #[test]
+    fn huber_needs_a_positive_delta() {
+        assert!(Cost::Huber { delta: 0. }.validate().is_err());
+        assert!(Cost::Huber { delta: -1. }.validate().is_err());
+        assert!(Cost::Huber { delta: 1. }.validate().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a class index")]
+    fn sparse_labels_out_of_range_fail_loudly() {
+        let predicted: Array2<f32> = array![[0.2, 0.8]];
+        Cost::SparseCategoricalCrossEntropy.calculate(&predicted, &array![[2.]]);
+    }
+
+    // record has to give calculate_with's total, and its tape gradient what derivate_with gives and what
+    // finite differences of it give
+    #[test]
+    fn recorded_costs_match_calculate_and_derivate() {
+        let predicted: Array2<f32> = array![[0.3, 0.6, 0.15], [0.7, 0.2, 0.45]];
+        let one_hot: Array2<f32> = array![[0., 1., 0.], [1., 0., 0.]];
+        let normalized: Array2<f32> = array![[0.2, 0.5, 0.3], [0.5, 0.1, 0.4]];
+        let cases: Vec<(Cost, Array2<f32>)> = vec![
+            (Cost::MSE, one_hot.clone()),
+            (Cost::CrossEntropy, one_hot.clone()),
+            (Cost::BinaryCrossEntropy, one_hot.clone()),
+            (Cost::MAE, one_hot.clone()),
+            (Cost::Huber { delta: 0.5 }, one_hot.clone()),
+            (Cost::LogCosh, one_hot.clone()),
+            (Cost::Hinge, one_hot.clone()),
+            (Cost::SquaredHinge, one_hot.clone()),
+            (Cost::BinaryCrossEntropyWithLogits, one_hot.clone()),
+            (Cost::SparseCategoricalCrossEntropy, array![[1.], [0.]]),
+            (Cost::KLDivergence, normalized),
+            (Cost::CosineSimilarity, one_hot),
+        ];
+        let weights: Array1<f32> = array![0.5, 2.];
+        for (cost, expected) in cases.iter() {
+            for reduction in [Reduction::Mean, Reduction::Sum, Reduction::None].iter() {
+                let mut tape: Tape = Tape::new();
+                let p: Var = tape.leaf(predicted.clone().into_dyn());
+                let loss: Var = cost.record(&mut tape, p, expected, Some(&weights), reduction);
+                let total: f32 = cost
+                    .calculate_with(&predicted, expected, Some(&weights), reduction)
+                    .sum();
+                let recorded: f32 = tape.value(loss).sum();
+                assert!(
+                    (recorded - total).abs() < 1e-5 * total.abs().max(1.),
+                    "{:?} records {} instead of {}",
+                    cost,
+                    recorded,
+                    total
+                );
+
+                let adjoints: Adjoints = tape.backward(loss);
+                let analytic: Array2<f32> =
+                    cost.derivate_with(&predicted, expected, Some(&weights), reduction);
+                let recorded: Array2<f32> = adjoints
+                    .wrt(p)
+                    .clone()
+                    .into_dimensionality::<Ix2>()
+                    .unwrap();
+                let error: f32 = max_diff(&recorded, &analytic);
+                assert!(error < 1e-4, "{:?} is off by {}", cost, error);
+
+                let error: f32 = check_gradients(
+                    |tape: &mut Tape, vars: &[Var]| {
+                        cost.record(tape, vars[0], expected, Some(&weights), reduction)
+                    },
+                    &[predicted.clone().into_dyn()],
+                    1e-3,
+                )[0];
+                assert!(error < 1e-2, "{:?} is off by {}", cost, error);
+            }
         }
     }
 }
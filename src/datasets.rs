@@ -1,7 +1,7 @@
-use crate::matrixutil::{create_weight, flatten};
+use crate::matrixutil::create_weight;
 use crate::typings::{Dataset, Sample};
 use mnist::{Mnist, MnistBuilder};
-use ndarray::{s, Array2, Array3};
+use ndarray::{s, stack, Array2, Array3, ArrayView1, ArrayViewD, Axis};
 
 pub fn mnist_loader(mut dataset: Dataset, training_samples: usize) -> Dataset {
     let Mnist {
@@ -35,11 +35,37 @@ pub fn mnist_loader(mut dataset: Dataset, training_samples: usize) -> Dataset {
         let mut label_vec: Array2<f32> = create_weight(&vec![1, 10]);
         let label_val: usize = train_labels[[i, 0]] as usize;
         label_vec[[0, label_val]] = 1f32;
-        let sample: Sample = Sample {
-            0: flatten(&image.to_owned()),
-            1: label_vec,
-        };
+        // kept as a single channel (1, 28, 28) image, add a Flatten layer to feed it to Dense
+        let image = image.to_owned().insert_axis(Axis(0)).insert_axis(Axis(0));
+        let sample: Sample = Sample::new(image.into_dyn(), label_vec.into_dyn());
         dataset.push(sample);
     }
     dataset
 }
+
+// stacks samples into a single Sample along their batch axis
+pub fn stack_samples<'a, I>(samples: I) -> Sample
+where
+    I: Iterator<Item = &'a Sample>,
+{
+    let mut inputs: Vec<ArrayViewD<f32>> = Vec::new();
+    let mut labels: Vec<ArrayViewD<f32>> = Vec::new();
+    let mut weights: Vec<ArrayView1<f32>> = Vec::new();
+    for s in samples {
+        inputs.push(s.0.view());
+        labels.push(s.1.view());
+        weights.push(s.2.view());
+    }
+    Sample(
+        stack(Axis(0), &inputs).expect("samples must share a shape"),
+        stack(Axis(0), &labels).expect("labels must share a shape"),
+        stack(Axis(0), &weights).unwrap(),
+    )
+}
+
+// holds out the last `fraction` of the dataset for validation, like keras' validation_split
+pub fn split_dataset(mut dataset: Dataset, fraction: f32) -> (Dataset, Dataset) {
+    let held_out: usize = (dataset.len() as f32 * fraction.clamp(0., 1.)).round() as usize;
+    let validation: Dataset = dataset.split_off(dataset.len() - held_out);
+    (dataset, validation)
+}
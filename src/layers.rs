@@ -1,82 +1,2008 @@
 #![allow(dead_code, unused_variables)]
-use crate::activations::Activations;
-use ndarray::Array2;
+use crate::{
+    activations::Activations,
+    attention::{
+        attention_backward, attention_forward, encoder_backward, encoder_forward, keep_mask,
+        record_attention, record_encoder, sinusoidal, EncoderParams, Masking,
+    },
+    autograd::{Tape, Var},
+    matrixutil::{
+        channels_first, channels_last, col2im, conv_output_size, from_4d, from_rows, im2col,
+        im2col_indices, normalize_backward, normalize_rows, to_4d, to_rows,
+    },
+    model::Merge,
+    netutil::init_weights,
+    recurrent::{Bptt, Cell},
+    regularizers::Regularizer,
+    typings::Cache,
+};
+use ndarray::{s, stack, Array1, Array2, Array4, ArrayD, ArrayView2, ArrayView4, Axis};
+use rand::{thread_rng, Rng};
 
 // struct that can be used to accept layers as arguments generally
+// batches go in and out of a layer as (batch, ..sample shape) tensors, underneath every layer works on
+// them as (batch × features) rows, layers that work on images reading each row as a flattened
+// (channels, height, width) tensor using the sample shape they were given
 pub enum Layers {
     Dense {
         units: usize,
         activation: Activations,
         init_func: String,
+        kernel_regularizer: Regularizer,
+        bias_regularizer: Regularizer,
+    },
+    // weights are stored as (channels*kh*kw × filters) so the convolution is one matrix product
+    Conv2D {
+        filters: usize,
+        kernel_size: (usize, usize),
+        stride: usize,
+        padding: usize,
+        activation: Activations,
+        init_func: String,
+        kernel_regularizer: Regularizer,
+        bias_regularizer: Regularizer,
+    },
+    MaxPool2D {
+        pool_size: (usize, usize),
+        stride: usize,
+    },
+    AvgPool2D {
+        pool_size: (usize, usize),
+        stride: usize,
+    },
+    // collapses (channels, height, width) into a single axis so Dense layers can follow
+    Flatten,
+    // inverted dropout: zeroes each input with probability rate while training and scales the rest
+    // by 1/(1-rate) so nothing needs rescaling at inference, where the layer passes its input through
+    Dropout {
+        rate: f32,
+    },
+    // normalizes every feature over the batch (every channel over the batch and spatial axes for images)
+    // weights hold gamma and biases hold beta, the running mean and variance used at inference live in
+    // Sequential::state and are pulled toward each training batch's statistics by momentum
+    BatchNorm {
+        momentum: f32,
+        epsilon: f32,
+    },
+    // normalizes each sample over all of its features, which behaves the same in training and at inference
+    LayerNorm {
+        epsilon: f32,
+    },
+    // leaky relu whose negative slope is learned per feature, the slopes are the layer's weights and
+    // start out at alpha
+    PReLU {
+        alpha: f32,
+    },
+    // recurrent layers read each row as a flattened (time, features) sequence and emit the last hidden
+    // state, or every hidden state as (time, units) when return_sequences is set
+    // truncate cuts backpropagation through time every that many steps and clip_norm caps the L2 norm of
+    // the layer's parameter gradients, see recurrent::Bptt
+    SimpleRNN {
+        units: usize,
+        activation: Activations,
+        init_func: String,
+        return_sequences: bool,
+        truncate: Option<usize>,
+        clip_norm: Option<f32>,
+    },
+    LSTM {
+        units: usize,
+        init_func: String,
+        return_sequences: bool,
+        truncate: Option<usize>,
+        clip_norm: Option<f32>,
+    },
+    GRU {
+        units: usize,
+        init_func: String,
+        return_sequences: bool,
+        truncate: Option<usize>,
+        clip_norm: Option<f32>,
+    },
+    // looks every integer token of the input up in a learned (vocab_size × dim) table, so a (time) input
+    // comes out as (time, dim); tokens equal to padding_idx come out as zeros and never get a gradient
+    Embedding {
+        vocab_size: usize,
+        dim: usize,
+        padding_idx: Option<usize>,
+    },
+    // self attention over a (time, dim) sequence with dim split evenly across heads, weights are
+    // [W_q W_k W_v W_o] side by side as (dim × 4dim)
+    // causal stops positions from looking ahead and mask_padding skips all zero positions, see
+    // attention::Masking
+    MultiHeadAttention {
+        heads: usize,
+        causal: bool,
+        mask_padding: bool,
+        init_func: String,
+    },
+    // adds a position table to a (time, dim) sequence, either the fixed sinusoids or a learned
+    // (time × dim) table that starts out as them
+    PositionalEncoding {
+        learned: bool,
+        mask_padding: bool,
+    },
+    // post-norm transformer encoder: MultiHeadAttention then a ReLU feed-forward of ff_units, each one
+    // wrapped in a residual connection followed by LayerNorm
+    // every parameter is packed into single weight and bias rows, see attention::EncoderParams
+    TransformerEncoderBlock {
+        heads: usize,
+        ff_units: usize,
+        causal: bool,
+        mask_padding: bool,
+        epsilon: f32,
+    },
+    // runs block like a small Sequential and adds the block's input to what comes out, so the block has to
+    // keep the shape it was given
+    // every inner layer's weights, biases and state are packed one after another into the container's
+    // (1 × n) rows
+    Residual {
+        block: Vec<Layers>,
+    },
+    // runs every branch on the same input and concatenates their outputs along the first axis of the
+    // sample shape (features for flat samples, channels for images), parameters are packed like Residual's
+    Parallel {
+        branches: Vec<Vec<Layers>>,
     },
 }
 
 impl Layers {
     pub fn get_units(&self) -> usize {
         match self {
-            Layers::Dense {
-                units,
-                activation,
-                init_func,
-            } => units.clone(),
+            Layers::Dense { units, .. } => *units,
+            Layers::Conv2D { filters, .. } => *filters,
+            Layers::SimpleRNN { units, .. }
+            | Layers::LSTM { units, .. }
+            | Layers::GRU { units, .. } => *units,
+            _ => 0,
         }
     }
 
     pub fn get_init_func(&self) -> String {
         match self {
-            Layers::Dense {
+            Layers::Dense { init_func, .. }
+            | Layers::Conv2D { init_func, .. }
+            | Layers::SimpleRNN { init_func, .. }
+            | Layers::LSTM { init_func, .. }
+            | Layers::GRU { init_func, .. }
+            | Layers::MultiHeadAttention { init_func, .. } => init_func.clone(),
+            // keeps the initial vectors around ±1/√vocab_size
+            Layers::Embedding { .. } => String::from("xavier"),
+            // gamma starts at 1 so the layer begins as a plain normalization
+            Layers::BatchNorm { .. } | Layers::LayerNorm { .. } => String::from("ones"),
+            _ => String::new(),
+        }
+    }
+
+    pub fn get_activation(&self) -> &Activations {
+        match self {
+            Layers::Dense { activation, .. } | Layers::Conv2D { activation, .. } => activation,
+            _ => &Activations::Linear,
+        }
+    }
+
+    // (cell, units, return_sequences, bptt settings) for recurrent layers
+    // their activations are applied inside the cell, which is why get_activation gives Linear for them
+    pub fn recurrence(&self) -> Option<(Cell<'_>, usize, bool, Bptt)> {
+        let (cell, units, return_sequences, truncate, clip_norm) = match self {
+            Layers::SimpleRNN {
                 units,
                 activation,
-                init_func,
-            } => init_func.clone(),
+                return_sequences,
+                truncate,
+                clip_norm,
+                ..
+            } => (
+                Cell::Simple(activation),
+                units,
+                return_sequences,
+                truncate,
+                clip_norm,
+            ),
+            Layers::LSTM {
+                units,
+                return_sequences,
+                truncate,
+                clip_norm,
+                ..
+            } => (Cell::LSTM, units, return_sequences, truncate, clip_norm),
+            Layers::GRU {
+                units,
+                return_sequences,
+                truncate,
+                clip_norm,
+                ..
+            } => (Cell::GRU, units, return_sequences, truncate, clip_norm),
+            _ => return None,
+        };
+        let bptt: Bptt = Bptt {
+            truncate: *truncate,
+            clip_norm: *clip_norm,
+        };
+        Some((cell, *units, *return_sequences, bptt))
+    }
+
+    // (kernel, bias) regularizers, Regularizer::None for layers that don't take any
+    pub fn get_regularizers(&self) -> (&Regularizer, &Regularizer) {
+        match self {
+            Layers::Dense {
+                kernel_regularizer,
+                bias_regularizer,
+                ..
+            }
+            | Layers::Conv2D {
+                kernel_regularizer,
+                bias_regularizer,
+                ..
+            } => (kernel_regularizer, bias_regularizer),
+            _ => (&Regularizer::None, &Regularizer::None),
+        }
+    }
+
+    // (heads, masking) for the attention layers
+    pub fn attention(&self) -> Option<(usize, Masking)> {
+        match self {
+            Layers::MultiHeadAttention {
+                heads,
+                causal,
+                mask_padding,
+                ..
+            }
+            | Layers::TransformerEncoderBlock {
+                heads,
+                causal,
+                mask_padding,
+                ..
+            } => Some((
+                *heads,
+                Masking {
+                    causal: *causal,
+                    padding: *mask_padding,
+                },
+            )),
+            _ => None,
+        }
+    }
+
+    // starting weights for layers that don't fit any of the named init functions
+    pub fn initial_weights(&self, input_shape: &[usize]) -> Option<Array2<f32>> {
+        let ((rows, cols), _) = self.parameter_shapes(input_shape);
+        match self {
+            Layers::PReLU { alpha } => Some(Array2::from_elem((rows, cols), *alpha)),
+            Layers::PositionalEncoding { learned: true, .. } => {
+                Some(sinusoidal(input_shape[0], input_shape[1]))
+            }
+            Layers::TransformerEncoderBlock { ff_units, .. } => {
+                Some(EncoderParams::init(input_shape[1], *ff_units).pack().0)
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let weights: Vec<Array2<f32>> = self
+                    .inner_layers(input_shape)
+                    .iter()
+                    .map(|(layer, shape)| init_weights(layer, shape))
+                    .collect();
+                Some(pack_row(&weights))
+            }
+            _ => None,
+        }
+    }
+
+    // what the layer's state holds before any training, shaped like state_shape
+    pub fn initial_state(&self, input_shape: &[usize]) -> Array2<f32> {
+        let (rows, cols) = self.state_shape(input_shape);
+        match self {
+            // running variance starts at 1 so an untrained BatchNorm only applies gamma and beta
+            Layers::BatchNorm { .. } => {
+                let mut state: Array2<f32> = Array2::zeros((rows, cols));
+                state.row_mut(1).fill(1.);
+                state
+            }
+            // every inner layer's own starting state, packed
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let states: Vec<Array2<f32>> = self
+                    .inner_layers(input_shape)
+                    .iter()
+                    .map(|(layer, shape)| layer.initial_state(shape))
+                    .collect();
+                pack_row(&states)
+            }
+            _ => Array2::zeros((rows, cols)),
         }
     }
 
+    // shape of a single sample coming out of this layer, or why the input shape doesn't fit
+    pub fn output_shape(&self, input_shape: &[usize]) -> Result<Vec<usize>, String> {
+        match self {
+            Layers::Dense { units, .. } => {
+                if input_shape.len() != 1 {
+                    return Err(format!(
+                        "Dense expects a flat input but got {:?}, add a Flatten layer first",
+                        input_shape
+                    ));
+                }
+                Ok(vec![*units])
+            }
+            Layers::Conv2D {
+                filters,
+                kernel_size,
+                stride,
+                padding,
+                ..
+            } => {
+                let [c, h, w] = image_shape(self, input_shape)?;
+                check_window(*kernel_size, *stride)?;
+                if h + 2 * padding < kernel_size.0 || w + 2 * padding < kernel_size.1 {
+                    return Err(format!(
+                        "kernel {:?} is larger than the input {:?}",
+                        kernel_size, input_shape
+                    ));
+                }
+                Ok(vec![
+                    *filters,
+                    conv_output_size(h, kernel_size.0, *stride, *padding),
+                    conv_output_size(w, kernel_size.1, *stride, *padding),
+                ])
+            }
+            Layers::MaxPool2D { pool_size, stride } | Layers::AvgPool2D { pool_size, stride } => {
+                let [c, h, w] = image_shape(self, input_shape)?;
+                check_window(*pool_size, *stride)?;
+                if h < pool_size.0 || w < pool_size.1 {
+                    return Err(format!(
+                        "pool {:?} is larger than the input {:?}",
+                        pool_size, input_shape
+                    ));
+                }
+                Ok(vec![
+                    c,
+                    conv_output_size(h, pool_size.0, *stride, 0),
+                    conv_output_size(w, pool_size.1, *stride, 0),
+                ])
+            }
+            Layers::Flatten => Ok(vec![input_shape.iter().product()]),
+            Layers::Dropout { rate } => {
+                if !(0. ..1.).contains(rate) {
+                    return Err(format!("dropout rate must be in [0, 1) but got {}", rate));
+                }
+                Ok(input_shape.to_vec())
+            }
+            Layers::BatchNorm { momentum, .. } => {
+                if !(0. ..=1.).contains(momentum) {
+                    return Err(format!("momentum must be in [0, 1] but got {}", momentum));
+                }
+                Ok(input_shape.to_vec())
+            }
+            Layers::LayerNorm { .. } | Layers::PReLU { .. } => Ok(input_shape.to_vec()),
+            Layers::Embedding {
+                vocab_size,
+                dim,
+                padding_idx,
+            } => {
+                if let Some(idx) = padding_idx.filter(|idx| idx >= vocab_size) {
+                    return Err(format!(
+                        "padding_idx {} is outside the vocabulary of {} tokens",
+                        idx, vocab_size
+                    ));
+                }
+                let mut shape: Vec<usize> = input_shape.to_vec();
+                shape.push(*dim);
+                Ok(shape)
+            }
+            Layers::MultiHeadAttention { .. } | Layers::TransformerEncoderBlock { .. } => {
+                let (heads, _) = self.attention().unwrap();
+                match input_shape {
+                    [_, dim] if heads > 0 && dim % heads == 0 => Ok(input_shape.to_vec()),
+                    [_, dim] => Err(format!(
+                        "{} needs dim {} to split evenly across {} heads",
+                        self.display(),
+                        dim,
+                        heads
+                    )),
+                    _ => Err(format!(
+                        "{} expects a (time, dim) input but got {:?}",
+                        self.display(),
+                        input_shape
+                    )),
+                }
+            }
+            Layers::Residual { block } => {
+                let shapes: Vec<Vec<usize>> = stack_shapes(block, input_shape)?;
+                if shapes.last().unwrap()[..] != *input_shape {
+                    return Err(format!(
+                        "the block turns {:?} into {:?} so its input can't be added back",
+                        input_shape,
+                        shapes.last().unwrap()
+                    ));
+                }
+                Ok(input_shape.to_vec())
+            }
+            Layers::Parallel { branches } => {
+                let mut outputs: Vec<Vec<usize>> = Vec::with_capacity(branches.len());
+                for (i, branch) in branches.iter().enumerate() {
+                    let shapes: Vec<Vec<usize>> = stack_shapes(branch, input_shape)
+                        .map_err(|e| format!("branch {}: {}", i, e))?;
+                    outputs.push(shapes.last().unwrap().clone());
+                }
+                let outputs: Vec<&[usize]> = outputs.iter().map(|s| &s[..]).collect();
+                Merge::Concatenate { axis: 0 }.output_shape(&outputs)
+            }
+            Layers::PositionalEncoding { .. } => match input_shape {
+                [_, _] => Ok(input_shape.to_vec()),
+                _ => Err(format!(
+                    "PositionalEncoding expects a (time, dim) input but got {:?}",
+                    input_shape
+                )),
+            },
+            Layers::SimpleRNN { .. } | Layers::LSTM { .. } | Layers::GRU { .. } => {
+                let (_, units, return_sequences, _) = self.recurrence().unwrap();
+                match input_shape {
+                    [steps, _] if return_sequences => Ok(vec![*steps, units]),
+                    [_, _] => Ok(vec![units]),
+                    _ => Err(format!(
+                        "{} expects a (time, features) input but got {:?}",
+                        self.display(),
+                        input_shape
+                    )),
+                }
+            }
+        }
+    }
+
+    // (weights, biases) dimensions for this layer; parameterless layers get empty 0×0 matrices
+    pub fn parameter_shapes(&self, input_shape: &[usize]) -> ((usize, usize), (usize, usize)) {
+        match self {
+            Layers::Dense { units, .. } => ((input_shape.iter().product(), *units), (1, *units)),
+            Layers::Conv2D {
+                filters,
+                kernel_size,
+                ..
+            } => (
+                (input_shape[0] * kernel_size.0 * kernel_size.1, *filters),
+                (1, *filters),
+            ),
+            Layers::BatchNorm { .. } => {
+                let n: usize = norm_features(input_shape);
+                ((1, n), (1, n))
+            }
+            Layers::LayerNorm { .. } => {
+                let n: usize = input_shape.iter().product();
+                ((1, n), (1, n))
+            }
+            Layers::PReLU { .. } => ((1, input_shape.iter().product()), (0, 0)),
+            Layers::Embedding {
+                vocab_size, dim, ..
+            } => ((*vocab_size, *dim), (0, 0)),
+            Layers::MultiHeadAttention { .. } => {
+                let dim: usize = input_shape[1];
+                ((dim, 4 * dim), (1, 4 * dim))
+            }
+            Layers::PositionalEncoding { learned: true, .. } => {
+                ((input_shape[0], input_shape[1]), (0, 0))
+            }
+            Layers::TransformerEncoderBlock { ff_units, .. } => {
+                let (weights, biases) = EncoderParams::sizes(input_shape[1], *ff_units);
+                ((1, weights), (1, biases))
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let (mut weights, mut biases) = (0, 0);
+                for (layer, shape) in self.inner_layers(input_shape) {
+                    let ((wr, wc), (br, bc)) = layer.parameter_shapes(&shape);
+                    weights += wr * wc;
+                    biases += br * bc;
+                }
+                ((1, weights), (1, biases))
+            }
+            // [W_x; W_h] with every gate's columns side by side
+            Layers::SimpleRNN { .. } | Layers::LSTM { .. } | Layers::GRU { .. } => {
+                let (cell, units, _, _) = self.recurrence().unwrap();
+                (
+                    (input_shape[1] + units, cell.gates() * units),
+                    (1, cell.gates() * units),
+                )
+            }
+            _ => ((0, 0), (0, 0)),
+        }
+    }
+
+    // 1 for every weight entry that multiplies an input (kernels, embeddings, projections) and 0 for the rest
+    // (normalization gains, PReLU slopes, learned positions), shaped like the layer's weights
+    // decoupled weight decay only shrinks the entries marked 1, biases are never decayed
+    pub fn decay_mask(&self, input_shape: &[usize]) -> Array2<f32> {
+        let (shape, _) = self.parameter_shapes(input_shape);
+        match self {
+            Layers::Dense { .. }
+            | Layers::Conv2D { .. }
+            | Layers::Embedding { .. }
+            | Layers::MultiHeadAttention { .. }
+            | Layers::SimpleRNN { .. }
+            | Layers::LSTM { .. }
+            | Layers::GRU { .. } => Array2::ones(shape),
+            // the two layer norm gains sit at the end of the packed row, after every projection
+            Layers::TransformerEncoderBlock { .. } => {
+                let gains: usize = 2 * input_shape[1];
+                Array2::from_shape_fn(shape, |(_, j)| (j < shape.1 - gains) as u8 as f32)
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let masks: Vec<Array2<f32>> = self
+                    .inner_layers(input_shape)
+                    .iter()
+                    .map(|(layer, shape)| layer.decay_mask(shape))
+                    .collect();
+                pack_row(&masks)
+            }
+            _ => Array2::zeros(shape),
+        }
+    }
+
+    // dimensions of the non-trainable buffers kept in Sequential::state, 0×0 for layers without any
+    pub fn state_shape(&self, input_shape: &[usize]) -> (usize, usize) {
+        match self {
+            // [running mean; running variance]
+            Layers::BatchNorm { .. } => (2, norm_features(input_shape)),
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let size: usize = self
+                    .inner_layers(input_shape)
+                    .iter()
+                    .map(|(layer, shape)| {
+                        let (rows, cols) = layer.state_shape(shape);
+                        rows * cols
+                    })
+                    .sum();
+                (1, size)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    // pulls the running statistics toward the ones forward_propagate measured on the latest training batch
+    pub fn update_state(&self, state: &mut Array2<f32>, cache: &Cache, input_shape: &[usize]) {
+        match (self, cache) {
+            (Layers::BatchNorm { momentum, .. }, Cache::Matrix(stats)) if !stats.is_empty() => {
+                state.zip_mut_with(stats, |s, c| *s = momentum * *s + (1. - momentum) * c);
+            }
+            (Layers::Residual { .. } | Layers::Parallel { .. }, Cache::Inner(saved)) => {
+                let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+                let mut states: Vec<Array2<f32>> = unpack_row(state, &inner_state_shapes(&inner));
+                for (j, (layer, shape)) in inner.iter().enumerate() {
+                    layer.update_state(&mut states[j], &saved[j].2, shape);
+                }
+                *state = pack_row(&states);
+            }
+            _ => {}
+        }
+    }
+
+    // the layers a container runs, one slice per branch, None for every other layer
+    pub fn branches(&self) -> Option<Vec<&[Layers]>> {
+        match self {
+            Layers::Residual { block } => Some(vec![&block[..]]),
+            Layers::Parallel { branches } => Some(branches.iter().map(|b| &b[..]).collect()),
+            _ => None,
+        }
+    }
+
+    // every layer inside a container with the shape of a sample going into it, branch after branch, which
+    // is also the order their parameters are packed in
+    fn inner_layers(&self, input_shape: &[usize]) -> Vec<(&Layers, Vec<usize>)> {
+        let mut inner: Vec<(&Layers, Vec<usize>)> = Vec::new();
+        for branch in self.branches().unwrap_or_default() {
+            let shapes: Vec<Vec<usize>> = stack_shapes(branch, input_shape).unwrap();
+            inner.extend(branch.iter().zip(shapes));
+        }
+        inner
+    }
+
+    // shape of a sample coming out of every layer inside a container, in the same order as inner_layers
+    fn inner_output_shapes(&self, input_shape: &[usize]) -> Vec<Vec<usize>> {
+        let mut outputs: Vec<Vec<usize>> = Vec::new();
+        for branch in self.branches().unwrap_or_default() {
+            let shapes: Vec<Vec<usize>> = stack_shapes(branch, input_shape).unwrap();
+            outputs.extend(shapes.into_iter().skip(1));
+        }
+        outputs
+    }
+
+    // the regularization penalty on this layer's parameters, including every layer inside a container
+    pub fn penalty(&self, weights: &Array2<f32>, bias: &Array2<f32>, input_shape: &[usize]) -> f32 {
+        if self.branches().is_some() {
+            let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+            let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+            let ws: Vec<Array2<f32>> = unpack_row(weights, &weight_shapes);
+            let bs: Vec<Array2<f32>> = unpack_row(bias, &bias_shapes);
+            return inner
+                .iter()
+                .enumerate()
+                .map(|(j, (layer, shape))| layer.penalty(&ws[j], &bs[j], shape))
+                .sum();
+        }
+        let (kernel, bias_regularizer) = self.get_regularizers();
+        kernel.penalty(weights) + bias_regularizer.penalty(bias)
+    }
+
+    // (∂penalty/∂weights, ∂penalty/∂biases)
+    pub fn penalty_gradient(
+        &self,
+        weights: &Array2<f32>,
+        bias: &Array2<f32>,
+        input_shape: &[usize],
+    ) -> (Array2<f32>, Array2<f32>) {
+        if self.branches().is_some() {
+            let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+            let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+            let ws: Vec<Array2<f32>> = unpack_row(weights, &weight_shapes);
+            let bs: Vec<Array2<f32>> = unpack_row(bias, &bias_shapes);
+            let (dws, dbs): (Vec<Array2<f32>>, Vec<Array2<f32>>) = inner
+                .iter()
+                .enumerate()
+                .map(|(j, (layer, shape))| layer.penalty_gradient(&ws[j], &bs[j], shape))
+                .unzip();
+            return (pack_row(&dws), pack_row(&dbs));
+        }
+        let (kernel, bias_regularizer) = self.get_regularizers();
+        (kernel.gradient(weights), bias_regularizer.gradient(bias))
+    }
+
+    // (z, cache) for a (batch, ..input_shape) batch, z comes out as (batch, ..output_shape)
+    // cache holds whatever backward needs besides the layer's input (the dropout mask, the batch
+    // statistics, a container's inner layers), empty otherwise; state is the layer's entry in Sequential::state
+    // output_shape is what output_shape(input_shape) returned when the layer was added, so it isn't
+    // worked out again on every batch
+    #[allow(clippy::too_many_arguments)]
     pub fn forward_propagate(
         &self,
-        input: &Array2<f32>,
+        input: &ArrayD<f32>,
         weights: &Array2<f32>,
         bias: &Array2<f32>,
-    ) -> Array2<f32> {
+        state: &Array2<f32>,
+        input_shape: &[usize],
+        output_shape: &[usize],
+        training: bool,
+    ) -> (ArrayD<f32>, Cache) {
+        if input.ndim() == 0 || input.shape()[1..] != *input_shape {
+            panic!(
+                "{} expects samples of shape {:?} but got a batch of shape {:?}",
+                self.display(),
+                input_shape,
+                input.shape()
+            );
+        }
+        let (z, cache) = self.forward_rows(
+            &to_rows(input).view(),
+            weights,
+            bias,
+            state,
+            input_shape,
+            output_shape,
+            training,
+        );
+        (from_rows(z, output_shape), cache)
+    }
+
+    // forward_propagate on (batch × features) rows
+    #[allow(clippy::too_many_arguments)]
+    fn forward_rows(
+        &self,
+        input: &ArrayView2<f32>,
+        weights: &Array2<f32>,
+        bias: &Array2<f32>,
+        state: &Array2<f32>,
+        input_shape: &[usize],
+        output_shape: &[usize],
+        training: bool,
+    ) -> (Array2<f32>, Cache) {
+        let z: Array2<f32> = match self {
+            Layers::Dropout { rate } if training => {
+                let mask: Array2<f32> = dropout_mask(input.dim(), *rate);
+                return (input * &mask, Cache::Matrix(mask));
+            }
+            Layers::BatchNorm { epsilon, .. } => {
+                let x: Array2<f32> = channels_last(input, input_shape);
+                // training normalizes with this batch's statistics and hands them back to update the running ones
+                let stats: Array2<f32> = if training {
+                    stack(
+                        Axis(0),
+                        &[
+                            x.mean_axis(Axis(0)).unwrap().insert_axis(Axis(0)).view(),
+                            x.var_axis(Axis(0), 0.).insert_axis(Axis(0)).view(),
+                        ],
+                    )
+                    .unwrap()
+                } else {
+                    state.clone()
+                };
+                let inv_std: Array2<f32> = stats
+                    .slice(s![1..2, ..])
+                    .mapv(|v| 1. / (v + epsilon).sqrt());
+                let x_hat: Array2<f32> = (&x - &stats.slice(s![0..1, ..])) * &inv_std;
+                let z: Array2<f32> =
+                    channels_first(x_hat * weights + bias, input_shape, input.nrows());
+                if training {
+                    return (z, Cache::Matrix(stats));
+                }
+                z
+            }
+            Layers::LayerNorm { epsilon } => {
+                let (x_hat, _) = normalize_rows(input, *epsilon);
+                x_hat * weights + bias
+            }
+            Layers::SimpleRNN { .. } | Layers::LSTM { .. } | Layers::GRU { .. } => {
+                let (cell, units, return_sequences, _) = self.recurrence().unwrap();
+                let (z, cache) = cell.forward(
+                    input,
+                    weights,
+                    bias,
+                    input_shape[0],
+                    units,
+                    return_sequences,
+                );
+                // every timestep's activations are only worth keeping when backward will need them
+                if training {
+                    return (z, Cache::Matrix(cache));
+                }
+                z
+            }
+            Layers::PReLU { .. } => {
+                // the (1 × features) slopes broadcast over every row
+                let mut z: Array2<f32> = input.to_owned();
+                z.zip_mut_with(weights, |x, a| {
+                    if *x < 0. {
+                        *x *= a
+                    }
+                });
+                z
+            }
+            Layers::Embedding {
+                dim, padding_idx, ..
+            } => {
+                let mut z: Array2<f32> = Array2::zeros((input.nrows(), input.ncols() * dim));
+                for ((i, j), token) in input.indexed_iter() {
+                    let token: usize = token_index(*token, weights.nrows());
+                    if Some(token) != *padding_idx {
+                        z.slice_mut(s![i, j * dim..(j + 1) * dim])
+                            .assign(&weights.row(token));
+                    }
+                }
+                z
+            }
+            Layers::MultiHeadAttention { .. } => {
+                let (heads, masking) = self.attention().unwrap();
+                attention_forward(input, weights, bias, input_shape[1], heads, &masking)
+            }
+            Layers::PositionalEncoding {
+                learned,
+                mask_padding,
+            } => {
+                let (steps, dim) = (input_shape[0], input_shape[1]);
+                let table: Array2<f32> = if *learned {
+                    weights.clone()
+                } else {
+                    sinusoidal(steps, dim)
+                };
+                // the (1 × time*dim) table broadcasts over every sample
+                let table: Array2<f32> = table.into_shape((1, steps * dim)).unwrap();
+                (input + &table) * &keep_mask(input, dim, *mask_padding)
+            }
+            Layers::TransformerEncoderBlock {
+                ff_units, epsilon, ..
+            } => {
+                let (heads, masking) = self.attention().unwrap();
+                let dim: usize = input_shape[1];
+                let params: EncoderParams = EncoderParams::unpack(weights, bias, dim, *ff_units);
+                encoder_forward(input, &params, dim, heads, &masking, *epsilon)
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+                let output_shapes: Vec<Vec<usize>> = self.inner_output_shapes(input_shape);
+                let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+                let ws: Vec<Array2<f32>> = unpack_row(weights, &weight_shapes);
+                let bs: Vec<Array2<f32>> = unpack_row(bias, &bias_shapes);
+                let states: Vec<Array2<f32>> = unpack_row(state, &inner_state_shapes(&inner));
+                // every inner layer's (input, z, cache), which is what its backward needs
+                let mut saved: Vec<(Array2<f32>, Array2<f32>, Cache)> =
+                    Vec::with_capacity(inner.len());
+                let mut outputs: Vec<Array2<f32>> = Vec::new();
+                let mut j: usize = 0;
+                for branch in self.branches().unwrap() {
+                    let mut a: Array2<f32> = input.to_owned();
+                    for layer in branch {
+                        let (z, cache) = layer.forward_rows(
+                            &a.view(),
+                            &ws[j],
+                            &bs[j],
+                            &states[j],
+                            &inner[j].1,
+                            &output_shapes[j],
+                            training,
+                        );
+                        let next: Array2<f32> = layer.get_activation().activate(&z);
+                        saved.push((a, z, cache));
+                        a = next;
+                        j += 1;
+                    }
+                    outputs.push(a);
+                }
+                let z: Array2<f32> = match self {
+                    Layers::Residual { .. } => input + &outputs[0],
+                    _ => {
+                        let shapes: Vec<Vec<usize>> = self.branch_output_shapes(input_shape);
+                        let shapes: Vec<&[usize]> = shapes.iter().map(|s| &s[..]).collect();
+                        let values: Vec<ArrayView2<f32>> =
+                            outputs.iter().map(|o| o.view()).collect();
+                        Merge::Concatenate { axis: 0 }.forward(&values, &shapes)
+                    }
+                };
+                if training {
+                    return (z, Cache::Inner(saved));
+                }
+                z
+            }
+            Layers::Dense { .. } => input.dot(weights) + bias,
+            Layers::Conv2D {
+                kernel_size,
+                stride,
+                padding,
+                ..
+            } => {
+                let cols: Array2<f32> = im2col(
+                    &to_4d(input, input_shape).view(),
+                    *kernel_size,
+                    *stride,
+                    *padding,
+                );
+                let out: Array2<f32> = cols.dot(weights) + bias;
+                // rows of out are (batch, y, x) positions, move filters in front of the spatial axes
+                let out: Array4<f32> = out
+                    .into_shape((
+                        input.nrows(),
+                        output_shape[1],
+                        output_shape[2],
+                        output_shape[0],
+                    ))
+                    .unwrap()
+                    .permuted_axes([0, 3, 1, 2]);
+                from_4d(out)
+            }
+            Layers::MaxPool2D { pool_size, stride } => from_4d(pool(
+                &to_4d(input, input_shape).view(),
+                *pool_size,
+                *stride,
+                true,
+            )),
+            Layers::AvgPool2D { pool_size, stride } => from_4d(pool(
+                &to_4d(input, input_shape).view(),
+                *pool_size,
+                *stride,
+                false,
+            )),
+            Layers::Flatten | Layers::Dropout { .. } => input.to_owned(),
+        };
+        (z, Cache::empty())
+    }
+
+    // given ∂C/∂z for this layer returns (∂C/∂input, ∂C/∂weights, ∂C/∂biases), summed over the batch
+    // cache is whatever forward_propagate returned alongside z for the same input in training mode
+    // output_shape is the same cached shape forward_propagate was given
+    #[allow(clippy::too_many_arguments)]
+    pub fn backward(
+        &self,
+        input: &ArrayD<f32>,
+        weights: &Array2<f32>,
+        bias: &Array2<f32>,
+        cache: &Cache,
+        c_wrt_z: &ArrayD<f32>,
+        input_shape: &[usize],
+        output_shape: &[usize],
+    ) -> (ArrayD<f32>, Array2<f32>, Array2<f32>) {
+        let (c_wrt_input, c_wrt_w, c_wrt_b) = self.backward_rows(
+            &to_rows(input).view(),
+            weights,
+            bias,
+            cache,
+            &to_rows(c_wrt_z).view(),
+            input_shape,
+            output_shape,
+        );
+        (from_rows(c_wrt_input, input_shape), c_wrt_w, c_wrt_b)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn backward_rows(
+        &self,
+        input: &ArrayView2<f32>,
+        weights: &Array2<f32>,
+        bias: &Array2<f32>,
+        cache: &Cache,
+        c_wrt_z: &ArrayView2<f32>,
+        input_shape: &[usize],
+        output_shape: &[usize],
+    ) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
         match self {
-            Layers::Dense {
-                units,
-                activation,
-                init_func,
-            } => input.dot(weights) + bias,
+            Layers::Dense { .. } => (
+                // ∂C/∂aₙ₋₁ = ∂C/∂zₙ * wₙᵀ
+                c_wrt_z.dot(&weights.t()),
+                // Z(w,X,b) = X.w + b, ∂Z/∂w = Xᵀ and the matrix product sums over the batch for us
+                input.t().dot(c_wrt_z),
+                // ∂Z/∂b = 1, summed over every row in the batch
+                c_wrt_z.sum_axis(Axis(0)).insert_axis(Axis(0)),
+            ),
+            Layers::Conv2D {
+                kernel_size,
+                stride,
+                padding,
+                ..
+            } => {
+                let x = to_4d(input, input_shape);
+                let cols: Array2<f32> = im2col(&x.view(), *kernel_size, *stride, *padding);
+                // undo the permutation from forward_propagate so rows line up with cols again
+                let dz: Array2<f32> = channels_last(c_wrt_z, output_shape);
+                let dcols: Array2<f32> = dz.dot(&weights.t());
+                let dx: Array4<f32> = col2im(&dcols, x.dim(), *kernel_size, *stride, *padding);
+                (
+                    from_4d(dx),
+                    cols.t().dot(&dz),
+                    dz.sum_axis(Axis(0)).insert_axis(Axis(0)),
+                )
+            }
+            Layers::MaxPool2D { pool_size, stride } | Layers::AvgPool2D { pool_size, stride } => {
+                let dx: Array4<f32> = pool_backward(
+                    &to_4d(input, input_shape).view(),
+                    &to_4d(c_wrt_z, output_shape).view(),
+                    *pool_size,
+                    *stride,
+                    matches!(self, Layers::MaxPool2D { .. }),
+                );
+                (from_4d(dx), Array2::zeros((0, 0)), Array2::zeros((0, 0)))
+            }
+            Layers::Flatten => (
+                c_wrt_z.to_owned(),
+                Array2::zeros((0, 0)),
+                Array2::zeros((0, 0)),
+            ),
+            Layers::Dropout { .. } => {
+                // dropped inputs had no say in the output so they get no gradient either
+                let c_wrt_input: Array2<f32> = if cache.is_empty() {
+                    c_wrt_z.to_owned()
+                } else {
+                    c_wrt_z * cache.matrix()
+                };
+                (c_wrt_input, Array2::zeros((0, 0)), Array2::zeros((0, 0)))
+            }
+            Layers::BatchNorm { epsilon, .. } => {
+                let x: Array2<f32> = channels_last(input, input_shape);
+                let dy: Array2<f32> = channels_last(c_wrt_z, input_shape);
+                let stats: &Array2<f32> = cache.matrix();
+                let inv_std: Array2<f32> = stats
+                    .slice(s![1..2, ..])
+                    .mapv(|v| 1. / (v + epsilon).sqrt());
+                let x_hat: Array2<f32> = (&x - &stats.slice(s![0..1, ..])) * &inv_std;
+                let dx: Array2<f32> =
+                    normalize_backward(&(&dy * weights), &x_hat, &inv_std, Axis(0));
+                (
+                    channels_first(dx, input_shape, input.nrows()),
+                    (&dy * &x_hat).sum_axis(Axis(0)).insert_axis(Axis(0)),
+                    dy.sum_axis(Axis(0)).insert_axis(Axis(0)),
+                )
+            }
+            Layers::LayerNorm { epsilon } => {
+                let (x_hat, inv_std) = normalize_rows(input, *epsilon);
+                (
+                    normalize_backward(&(c_wrt_z * weights), &x_hat, &inv_std, Axis(1)),
+                    (c_wrt_z * &x_hat).sum_axis(Axis(0)).insert_axis(Axis(0)),
+                    c_wrt_z.sum_axis(Axis(0)).insert_axis(Axis(0)),
+                )
+            }
+            Layers::PReLU { .. } => {
+                let mut slope: Array2<f32> = input.to_owned();
+                slope.zip_mut_with(weights, |x, a| *x = if *x > 0. { 1. } else { *a });
+                (
+                    c_wrt_z * &slope,
+                    // ∂z/∂a = min(x, 0)
+                    (c_wrt_z * &input.mapv(|x: f32| x.min(0.)))
+                        .sum_axis(Axis(0))
+                        .insert_axis(Axis(0)),
+                    Array2::zeros((0, 0)),
+                )
+            }
+            Layers::Embedding {
+                dim, padding_idx, ..
+            } => {
+                // only the rows that were looked up pick up a gradient, added straight into the table
+                // instead of multiplying through a one-hot matrix
+                let mut c_wrt_w: Array2<f32> = Array2::zeros(weights.dim());
+                for ((i, j), token) in input.indexed_iter() {
+                    let token: usize = token_index(*token, weights.nrows());
+                    if Some(token) != *padding_idx {
+                        let mut row = c_wrt_w.row_mut(token);
+                        row += &c_wrt_z.slice(s![i, j * dim..(j + 1) * dim]);
+                    }
+                }
+                // token ids aren't differentiable so nothing flows back past the lookup
+                (Array2::zeros(input.dim()), c_wrt_w, Array2::zeros((0, 0)))
+            }
+            Layers::MultiHeadAttention { .. } => {
+                let (heads, masking) = self.attention().unwrap();
+                attention_backward(
+                    input,
+                    weights,
+                    bias,
+                    c_wrt_z,
+                    input_shape[1],
+                    heads,
+                    &masking,
+                )
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+                let output_shapes: Vec<Vec<usize>> = self.inner_output_shapes(input_shape);
+                let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+                let ws: Vec<Array2<f32>> = unpack_row(weights, &weight_shapes);
+                let bs: Vec<Array2<f32>> = unpack_row(bias, &bias_shapes);
+                let saved: &[(Array2<f32>, Array2<f32>, Cache)] = cache.inner();
+                // ∂C/∂output of every branch
+                let c_wrt_outputs: Vec<Array2<f32>> = match self {
+                    Layers::Residual { .. } => vec![c_wrt_z.to_owned()],
+                    _ => {
+                        let shapes: Vec<Vec<usize>> = self.branch_output_shapes(input_shape);
+                        let shapes: Vec<&[usize]> = shapes.iter().map(|s| &s[..]).collect();
+                        Merge::Concatenate { axis: 0 }.backward(&[], &shapes, c_wrt_z)
+                    }
+                };
+                // the skip connection hands ∂C/∂z straight back to the input
+                let mut c_wrt_input: Array2<f32> = match self {
+                    Layers::Residual { .. } => c_wrt_z.to_owned(),
+                    _ => Array2::zeros(input.dim()),
+                };
+                let mut dws: Vec<Array2<f32>> = Vec::with_capacity(inner.len());
+                let mut dbs: Vec<Array2<f32>> = Vec::with_capacity(inner.len());
+                let mut start: usize = 0;
+                for (branch, c_wrt_out) in self.branches().unwrap().iter().zip(c_wrt_outputs) {
+                    let mut c_wrt_a: Array2<f32> = c_wrt_out;
+                    let mut grads: Vec<(Array2<f32>, Array2<f32>)> =
+                        Vec::with_capacity(branch.len());
+                    for j in (start..start + branch.len()).rev() {
+                        let (layer, shape) = &inner[j];
+                        let (a, z, cache) = &saved[j];
+                        let dz: Array2<f32> = layer.get_activation().backward(z, &c_wrt_a);
+                        let (dx, dw, db) = layer.backward_rows(
+                            &a.view(),
+                            &ws[j],
+                            &bs[j],
+                            cache,
+                            &dz.view(),
+                            shape,
+                            &output_shapes[j],
+                        );
+                        c_wrt_a = dx;
+                        grads.push((dw, db));
+                    }
+                    c_wrt_input += &c_wrt_a;
+                    // collected from the end of the branch backwards
+                    for (dw, db) in grads.into_iter().rev() {
+                        dws.push(dw);
+                        dbs.push(db);
+                    }
+                    start += branch.len();
+                }
+                (c_wrt_input, pack_row(&dws), pack_row(&dbs))
+            }
+            Layers::PositionalEncoding {
+                learned,
+                mask_padding,
+            } => {
+                let dz: Array2<f32> = c_wrt_z * &keep_mask(input, input_shape[1], *mask_padding);
+                // every sample adds the same table so its gradient is the batch sum
+                let c_wrt_w: Array2<f32> = if *learned {
+                    dz.sum_axis(Axis(0))
+                        .into_shape((input_shape[0], input_shape[1]))
+                        .unwrap()
+                } else {
+                    Array2::zeros((0, 0))
+                };
+                (dz, c_wrt_w, Array2::zeros((0, 0)))
+            }
+            Layers::TransformerEncoderBlock {
+                ff_units, epsilon, ..
+            } => {
+                let (heads, masking) = self.attention().unwrap();
+                let dim: usize = input_shape[1];
+                let params: EncoderParams = EncoderParams::unpack(weights, bias, dim, *ff_units);
+                let (c_wrt_input, grads) =
+                    encoder_backward(input, &params, c_wrt_z, dim, heads, &masking, *epsilon);
+                let (c_wrt_w, c_wrt_b) = grads.pack();
+                (c_wrt_input, c_wrt_w, c_wrt_b)
+            }
+            Layers::SimpleRNN { .. } | Layers::LSTM { .. } | Layers::GRU { .. } => {
+                let (cell, units, return_sequences, bptt) = self.recurrence().unwrap();
+                cell.backward(
+                    input,
+                    weights,
+                    cache.matrix(),
+                    c_wrt_z,
+                    input_shape[0],
+                    units,
+                    return_sequences,
+                    &bptt,
+                )
+            }
         }
     }
 
     pub fn display(&self) -> String {
         match self {
             Layers::Dense {
-                units,
-                activation,
-                init_func,
+                units, activation, ..
             } => format!(
                 "Dense Layer - {:?} Units - {:?} activation",
                 units, activation
             ),
+            Layers::Conv2D {
+                filters,
+                kernel_size,
+                stride,
+                padding,
+                activation,
+                ..
+            } => format!(
+                "Conv2D Layer - {:?} Filters - {:?} kernel - stride {:?} - padding {:?} - {:?} activation",
+                filters, kernel_size, stride, padding, activation
+            ),
+            Layers::MaxPool2D { pool_size, stride } => {
+                format!("MaxPool2D Layer - {:?} pool - stride {:?}", pool_size, stride)
+            }
+            Layers::AvgPool2D { pool_size, stride } => {
+                format!("AvgPool2D Layer - {:?} pool - stride {:?}", pool_size, stride)
+            }
+            Layers::Flatten => String::from("Flatten Layer"),
+            Layers::Dropout { rate } => format!("Dropout Layer - {:?} rate", rate),
+            Layers::BatchNorm { momentum, epsilon } => format!(
+                "BatchNorm Layer - momentum {:?} - epsilon {:?}",
+                momentum, epsilon
+            ),
+            Layers::LayerNorm { epsilon } => format!("LayerNorm Layer - epsilon {:?}", epsilon),
+            Layers::PReLU { alpha } => format!("PReLU Layer - initial slope {:?}", alpha),
+            Layers::SimpleRNN {
+                units,
+                activation,
+                return_sequences,
+                ..
+            } => format!(
+                "SimpleRNN Layer - {:?} Units - {:?} activation - return_sequences {:?}",
+                units, activation, return_sequences
+            ),
+            Layers::LSTM {
+                units,
+                return_sequences,
+                ..
+            } => format!(
+                "LSTM Layer - {:?} Units - return_sequences {:?}",
+                units, return_sequences
+            ),
+            Layers::GRU {
+                units,
+                return_sequences,
+                ..
+            } => format!(
+                "GRU Layer - {:?} Units - return_sequences {:?}",
+                units, return_sequences
+            ),
+            Layers::Embedding {
+                vocab_size,
+                dim,
+                padding_idx,
+            } => format!(
+                "Embedding Layer - vocab {:?} - dim {:?} - padding {:?}",
+                vocab_size, dim, padding_idx
+            ),
+            Layers::MultiHeadAttention {
+                heads,
+                causal,
+                mask_padding,
+                ..
+            } => format!(
+                "MultiHeadAttention Layer - {:?} heads - causal {:?} - mask_padding {:?}",
+                heads, causal, mask_padding
+            ),
+            Layers::PositionalEncoding {
+                learned,
+                mask_padding,
+            } => format!(
+                "PositionalEncoding Layer - {} - mask_padding {:?}",
+                if *learned { "learned" } else { "sinusoidal" },
+                mask_padding
+            ),
+            Layers::TransformerEncoderBlock {
+                heads,
+                ff_units,
+                causal,
+                mask_padding,
+                epsilon,
+            } => format!(
+                "TransformerEncoderBlock Layer - {:?} heads - {:?} ff units - causal {:?} - mask_padding {:?} - epsilon {:?}",
+                heads, ff_units, causal, mask_padding, epsilon
+            ),
+            Layers::Residual { block } => format!("Residual Block - {:?} layers", block.len()),
+            Layers::Parallel { branches } => {
+                format!("Parallel Block - {:?} branches", branches.len())
+            }
         }
     }
 
-    pub fn activate(&self, input: &Array2<f32>) -> Array2<f32> {
-        match self {
-            Layers::Dense {
-                units,
-                activation,
-                init_func,
-            } => activation.activate(input),
+    // display lines for every layer inside a container, indented under it, empty for other layers
+    pub fn inner_summary(&self, input_shape: &[usize]) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let parallel: bool = matches!(self, Layers::Parallel { .. });
+        for (i, branch) in self.branches().unwrap_or_default().iter().enumerate() {
+            if parallel {
+                lines.push(format!("    branch {}", i));
+            }
+            let indent: &str = if parallel { "        " } else { "    " };
+            let shapes: Vec<Vec<usize>> = stack_shapes(branch, input_shape).unwrap();
+            for (layer, shape) in branch.iter().zip(shapes.iter()) {
+                lines.push(format!(
+                    "{}{} / Output: {:?}",
+                    indent,
+                    layer.display(),
+                    layer.output_shape(shape).unwrap()
+                ));
+                for line in layer.inner_summary(shape) {
+                    lines.push(format!("{}{}", indent, line));
+                }
+            }
         }
+        lines
     }
 
-    pub fn derivate_activation(&self, input: &Array2<f32>) -> Array2<f32> {
-        match self {
-            Layers::Dense {
-                units,
-                activation,
-                init_func,
-            } => activation.derivate(input),
+    // shape of a sample coming out of every branch of a container
+    fn branch_output_shapes(&self, input_shape: &[usize]) -> Vec<Vec<usize>> {
+        self.branches()
+            .unwrap_or_default()
+            .iter()
+            .map(|branch| stack_shapes(branch, input_shape).unwrap().pop().unwrap())
+            .collect()
+    }
+
+    // activations see the batch as (batch × features) rows so Softmax normalizes over a whole sample
+    pub fn activate(&self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        from_rows(
+            self.get_activation().activate(&to_rows(input)),
+            &input.shape()[1..],
+        )
+    }
+
+    pub fn derivate_activation(&self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        from_rows(
+            self.get_activation().derivate(&to_rows(input)),
+            &input.shape()[1..],
+        )
+    }
+
+    // ∂C/∂z from ∂C/∂a, going through the full Jacobian for activations like Softmax
+    pub fn activation_backward(&self, input: &ArrayD<f32>, c_wrt_a: &ArrayD<f32>) -> ArrayD<f32> {
+        from_rows(
+            self.get_activation()
+                .backward(&to_rows(input), &to_rows(c_wrt_a)),
+            &input.shape()[1..],
+        )
+    }
+
+    // forward_propagate recorded on tape out of primitive ops, input is a (batch, ..input_shape) tensor and
+    // the result comes out as (batch, ..output_shape), weights, bias and state like forward_propagate's
+    // dropout draws a fresh mask, which goes on as a constant
+    #[allow(clippy::too_many_arguments)]
+    pub fn record<'a>(
+        &'a self,
+        tape: &mut Tape<'a>,
+        input: Var,
+        weights: Var,
+        bias: Var,
+        state: &Array2<f32>,
+        input_shape: &[usize],
+        output_shape: &[usize],
+        training: bool,
+    ) -> Var {
+        let batch: usize = tape.shape(input)[0];
+        let features: usize = input_shape.iter().product();
+        let x: Var = tape.reshape(input, &[batch, features]);
+        let z: Var = match self {
+            Layers::Dense { .. } => {
+                let xw: Var = tape.matmul(x, weights);
+                tape.add(xw, bias)
+            }
+            Layers::Conv2D {
+                kernel_size,
+                stride,
+                padding,
+                ..
+            } => {
+                let (c, h, w) = (input_shape[0], input_shape[1], input_shape[2]);
+                // a trailing zero for every index im2col_indices sends into the padding
+                let flat: Var = tape.reshape(x, &[batch * features]);
+                let zero: Var = tape.leaf(ArrayD::zeros(vec![1]));
+                let flat: Var = tape.concat(&[flat, zero], 0);
+                let indices: Vec<usize> =
+                    im2col_indices((batch, c, h, w), *kernel_size, *stride, *padding);
+                let cols: Var = tape.index(flat, 0, &indices);
+                let (f, out_h, out_w) = (output_shape[0], output_shape[1], output_shape[2]);
+                let cols: Var = tape.reshape(
+                    cols,
+                    &[batch * out_h * out_w, c * kernel_size.0 * kernel_size.1],
+                );
+                let out: Var = tape.matmul(cols, weights);
+                let out: Var = tape.add(out, bias);
+                let out: Var = tape.reshape(out, &[batch, out_h, out_w, f]);
+                tape.permute(out, &[0, 3, 1, 2])
+            }
+            Layers::MaxPool2D { pool_size, stride } | Layers::AvgPool2D { pool_size, stride } => {
+                let (c, h, w) = (input_shape[0], input_shape[1], input_shape[2]);
+                let (out_h, out_w) = (output_shape[1], output_shape[2]);
+                let flat: Var = tape.reshape(x, &[batch * features]);
+                let indices: Vec<usize> = im2col_indices((batch, c, h, w), *pool_size, *stride, 0);
+                let windows: Var = tape.index(flat, 0, &indices);
+                let windows: Var = tape.reshape(
+                    windows,
+                    &[batch * out_h * out_w, c, pool_size.0 * pool_size.1],
+                );
+                let pooled: Var = match self {
+                    Layers::MaxPool2D { .. } => tape.max_axis(windows, 2),
+                    _ => {
+                        let total: Var = tape.sum_axis(windows, 2);
+                        tape.scale(total, 1. / (pool_size.0 * pool_size.1) as f32)
+                    }
+                };
+                let pooled: Var = tape.reshape(pooled, &[batch, out_h, out_w, c]);
+                tape.permute(pooled, &[0, 3, 1, 2])
+            }
+            Layers::Dropout { rate } if training => {
+                let mask: Var = tape.leaf(dropout_mask((batch, features), *rate).into_dyn());
+                tape.mul(x, mask)
+            }
+            Layers::Flatten | Layers::Dropout { .. } => x,
+            Layers::BatchNorm { epsilon, .. } => {
+                // channels last so every statistic is a column one, like forward_rows
+                let columns: Var = match input_shape {
+                    [c, h, w] => {
+                        let images: Var = tape.reshape(x, &[batch, *c, *h, *w]);
+                        let images: Var = tape.permute(images, &[0, 2, 3, 1]);
+                        tape.reshape(images, &[batch * h * w, *c])
+                    }
+                    _ => x,
+                };
+                let x_hat: Var = if training {
+                    tape.normalize(columns, 0, *epsilon)
+                } else {
+                    let mean: Var = tape.leaf(state.slice(s![0..1, ..]).to_owned().into_dyn());
+                    let inv_std: Var = tape.leaf(
+                        state
+                            .slice(s![1..2, ..])
+                            .mapv(|v| 1. / (v + epsilon).sqrt())
+                            .into_dyn(),
+                    );
+                    let centered: Var = tape.sub(columns, mean);
+                    tape.mul(centered, inv_std)
+                };
+                let scaled: Var = tape.mul(x_hat, weights);
+                let z: Var = tape.add(scaled, bias);
+                match input_shape {
+                    [c, h, w] => {
+                        let images: Var = tape.reshape(z, &[batch, *h, *w, *c]);
+                        tape.permute(images, &[0, 3, 1, 2])
+                    }
+                    _ => z,
+                }
+            }
+            Layers::LayerNorm { epsilon } => {
+                let x_hat: Var = tape.normalize(x, 1, *epsilon);
+                let scaled: Var = tape.mul(x_hat, weights);
+                tape.add(scaled, bias)
+            }
+            Layers::PReLU { .. } => {
+                let zero: Var = tape.scalar(0.);
+                let positive: Var = tape.maximum(x, zero);
+                let negative: Var = tape.minimum(x, zero);
+                let negative: Var = tape.mul(negative, weights);
+                tape.add(positive, negative)
+            }
+            Layers::Embedding {
+                dim, padding_idx, ..
+            } => {
+                let vocab_size: usize = tape.shape(weights)[0];
+                let tokens: Vec<usize> = tape
+                    .value(x)
+                    .iter()
+                    .map(|token| token_index(*token, vocab_size))
+                    .collect();
+                let keep: Array2<f32> = tokens
+                    .iter()
+                    .map(|token| if Some(*token) == *padding_idx { 0. } else { 1. })
+                    .collect::<Array1<f32>>()
+                    .insert_axis(Axis(1));
+                let rows: Var = tape.index(weights, 0, &tokens);
+                let keep: Var = tape.leaf(keep.into_dyn());
+                tape.mul(rows, keep)
+            }
+            Layers::MultiHeadAttention { .. } => {
+                let (heads, masking) = self.attention().unwrap();
+                record_attention(tape, x, weights, bias, input_shape[1], heads, &masking)
+            }
+            Layers::PositionalEncoding {
+                learned,
+                mask_padding,
+            } => {
+                let (steps, dim) = (input_shape[0], input_shape[1]);
+                let table: Var = if *learned {
+                    weights
+                } else {
+                    tape.leaf(sinusoidal(steps, dim).into_dyn())
+                };
+                let table: Var = tape.reshape(table, &[1, steps * dim]);
+                let mask: Array2<f32> = {
+                    let rows: Array2<f32> = to_rows(tape.value(x)).into_owned();
+                    keep_mask(&rows.view(), dim, *mask_padding)
+                };
+                let mask: Var = tape.leaf(mask.into_dyn());
+                let z: Var = tape.add(x, table);
+                tape.mul(z, mask)
+            }
+            Layers::TransformerEncoderBlock {
+                ff_units, epsilon, ..
+            } => {
+                let (heads, masking) = self.attention().unwrap();
+                record_encoder(
+                    tape,
+                    x,
+                    weights,
+                    bias,
+                    input_shape[1],
+                    *ff_units,
+                    heads,
+                    &masking,
+                    *epsilon,
+                )
+            }
+            Layers::SimpleRNN { .. } | Layers::LSTM { .. } | Layers::GRU { .. } => {
+                let (cell, units, return_sequences, _) = self.recurrence().unwrap();
+                cell.record(
+                    tape,
+                    x,
+                    weights,
+                    bias,
+                    input_shape[0],
+                    units,
+                    return_sequences,
+                )
+            }
+            Layers::Residual { .. } | Layers::Parallel { .. } => {
+                let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+                let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+                let ws: Vec<Var> = tape.unpack(weights, &weight_shapes);
+                let bs: Vec<Var> = tape.unpack(bias, &bias_shapes);
+                let states: Vec<Array2<f32>> = unpack_row(state, &inner_state_shapes(&inner));
+                let mut outputs: Vec<Var> = Vec::new();
+                let mut j: usize = 0;
+                for branch in self.branches().unwrap() {
+                    let shapes: Vec<Vec<usize>> = stack_shapes(branch, input_shape).unwrap();
+                    let mut a: Var = input;
+                    for (k, layer) in branch.iter().enumerate() {
+                        let z: Var = layer.record(
+                            tape,
+                            a,
+                            ws[j],
+                            bs[j],
+                            &states[j],
+                            &shapes[k],
+                            &shapes[k + 1],
+                            training,
+                        );
+                        a = layer.record_activation(tape, z);
+                        j += 1;
+                    }
+                    outputs.push(a);
+                }
+                match self {
+                    Layers::Residual { .. } => tape.add(input, outputs[0]),
+                    _ => tape.concat(&outputs, 1),
+                }
+            }
+        };
+        let mut shape: Vec<usize> = vec![batch];
+        shape.extend_from_slice(output_shape);
+        tape.reshape(z, &shape)
+    }
+
+    // activate recorded on tape, on (batch × features) rows like activate
+    pub fn record_activation<'a>(&'a self, tape: &mut Tape<'a>, z: Var) -> Var {
+        let shape: Vec<usize> = tape.shape(z);
+        let rows: Var = tape.reshape(z, &[shape[0], shape[1..].iter().product()]);
+        let a: Var = self.get_activation().record(tape, rows);
+        tape.reshape(a, &shape)
+    }
+
+    // penalty recorded on tape, None when neither the layer nor anything inside it is regularized
+    pub fn record_penalty(
+        &self,
+        tape: &mut Tape,
+        weights: Var,
+        bias: Var,
+        input_shape: &[usize],
+    ) -> Option<Var> {
+        let penalties: Vec<Var> = if self.branches().is_some() {
+            let inner: Vec<(&Layers, Vec<usize>)> = self.inner_layers(input_shape);
+            let (weight_shapes, bias_shapes) = inner_parameter_shapes(&inner);
+            let ws: Vec<Var> = tape.unpack(weights, &weight_shapes);
+            let bs: Vec<Var> = tape.unpack(bias, &bias_shapes);
+            inner
+                .iter()
+                .enumerate()
+                .filter_map(|(j, (layer, shape))| layer.record_penalty(tape, ws[j], bs[j], shape))
+                .collect()
+        } else {
+            let (kernel, bias_regularizer) = self.get_regularizers();
+            kernel
+                .record(tape, weights)
+                .into_iter()
+                .chain(bias_regularizer.record(tape, bias))
+                .collect()
+        };
+        penalties
+            .into_iter()
+            .reduce(|total, penalty| tape.add(total, penalty))
+    }
+}
+
+// inverted dropout mask, 1/(1-rate) for every kept entry and 0 for dropped ones
+fn dropout_mask(dim: (usize, usize), rate: f32) -> Array2<f32> {
+    let keep: f32 = 1. - rate;
+    let mut rng = thread_rng();
+    Array2::from_shape_fn(dim, |_| {
+        if rng.gen::<f32>() < keep {
+            1. / keep
+        } else {
+            0.
+        }
+    })
+}
+
+// a kernel or pool needs some extent and has to move forward, a 0 stride would divide by zero
+fn check_window(size: (usize, usize), stride: usize) -> Result<(), String> {
+    if size.0 == 0 || size.1 == 0 {
+        return Err(format!("window {:?} can't have a side of 0", size));
+    }
+    if stride == 0 {
+        return Err("stride must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// checks that a layer working on images gets a (channels, height, width) shape
+fn image_shape(layer: &Layers, input_shape: &[usize]) -> Result<[usize; 3], String> {
+    match input_shape {
+        [c, h, w] => Ok([*c, *h, *w]),
+        _ => Err(format!(
+            "{} expects a (channels, height, width) input but got {:?}",
+            layer.display(),
+            input_shape
+        )),
+    }
+}
+
+// tokens are passed in as floats alongside every other input, anything that isn't an integer inside the
+// vocabulary (fractions, NaN, infinities) is a bug in the data so it fails loudly like cost::class_index
+fn token_index(token: f32, vocab_size: usize) -> usize {
+    if !token.is_finite() || token < 0. || token.fract() != 0. || token as usize >= vocab_size {
+        panic!(
+            "token {} isn't an index into the embedding's vocabulary of {}",
+            token, vocab_size
+        );
+    }
+    token as usize
+}
+
+// BatchNorm keeps one statistic per channel for images and one per feature otherwise
+fn norm_features(input_shape: &[usize]) -> usize {
+    match input_shape {
+        [c, _, _] => *c,
+        _ => input_shape.iter().product(),
+    }
+}
+
+// max or average over every pool_size window of a (batch, c, h, w) tensor
+fn pool(
+    input: &ArrayView4<f32>,
+    pool_size: (usize, usize),
+    stride: usize,
+    max: bool,
+) -> Array4<f32> {
+    let (b, c, h, w) = input.dim();
+    let (ph, pw) = pool_size;
+    let out_h = conv_output_size(h, ph, stride, 0);
+    let out_w = conv_output_size(w, pw, stride, 0);
+    let mut out = Array4::zeros((b, c, out_h, out_w));
+    for ((n, ch, i, j), v) in out.indexed_iter_mut() {
+        let mut acc: f32 = if max { f32::NEG_INFINITY } else { 0. };
+        for ki in 0..ph {
+            for kj in 0..pw {
+                let x: f32 = input[[n, ch, i * stride + ki, j * stride + kj]];
+                acc = if max { acc.max(x) } else { acc + x };
+            }
+        }
+        *v = if max { acc } else { acc / (ph * pw) as f32 };
+    }
+    out
+}
+
+// max pooling routes each gradient to the element that won its window, average pooling spreads it evenly
+fn pool_backward(
+    input: &ArrayView4<f32>,
+    grad: &ArrayView4<f32>,
+    pool_size: (usize, usize),
+    stride: usize,
+    max: bool,
+) -> Array4<f32> {
+    let (ph, pw) = pool_size;
+    let mut dx = Array4::zeros(input.dim());
+    for ((n, ch, i, j), g) in grad.indexed_iter() {
+        if max {
+            let mut best: (usize, usize) = (i * stride, j * stride);
+            for ki in 0..ph {
+                for kj in 0..pw {
+                    let (y, x) = (i * stride + ki, j * stride + kj);
+                    if input[[n, ch, y, x]] > input[[n, ch, best.0, best.1]] {
+                        best = (y, x);
+                    }
+                }
+            }
+            dx[[n, ch, best.0, best.1]] += g;
+        } else {
+            for ki in 0..ph {
+                for kj in 0..pw {
+                    dx[[n, ch, i * stride + ki, j * stride + kj]] += g / (ph * pw) as f32;
+                }
+            }
+        }
+    }
+    dx
+}
+
+// the shape going into every layer of a stack followed by the shape coming out of the last one
+fn stack_shapes(layers: &[Layers], input_shape: &[usize]) -> Result<Vec<Vec<usize>>, String> {
+    let mut shapes: Vec<Vec<usize>> = vec![input_shape.to_vec()];
+    for (i, layer) in layers.iter().enumerate() {
+        let next: Vec<usize> = layer
+            .output_shape(shapes.last().unwrap())
+            .map_err(|e| format!("inner layer {}: {}", i, e))?;
+        shapes.push(next);
+    }
+    Ok(shapes)
+}
+
+// (weights, biases) dimensions of every inner layer of a container
+type ParameterShapes = (Vec<(usize, usize)>, Vec<(usize, usize)>);
+
+fn inner_parameter_shapes(inner: &[(&Layers, Vec<usize>)]) -> ParameterShapes {
+    inner
+        .iter()
+        .map(|(layer, shape)| layer.parameter_shapes(shape))
+        .unzip()
+}
+
+fn inner_state_shapes(inner: &[(&Layers, Vec<usize>)]) -> Vec<(usize, usize)> {
+    inner
+        .iter()
+        .map(|(layer, shape)| layer.state_shape(shape))
+        .collect()
+}
+
+// splits a packed (1 × n) row back into matrices of the given dimensions
+fn unpack_row(row: &Array2<f32>, shapes: &[(usize, usize)]) -> Vec<Array2<f32>> {
+    let mut values = row.iter().cloned();
+    shapes
+        .iter()
+        .map(|(rows, cols)| {
+            Array2::from_shape_vec((*rows, *cols), values.by_ref().take(rows * cols).collect())
+                .unwrap()
+        })
+        .collect()
+}
+
+// every matrix flattened one after another into a single (1 × n) row
+fn pack_row(matrices: &[Array2<f32>]) -> Array2<f32> {
+    let values: Vec<f32> = matrices.iter().flat_map(|m| m.iter().cloned()).collect();
+    Array2::from_shape_vec((1, values.len()), values).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrixutil::numerical_gradient;
+
+    #[test]
+    fn zero_stride_is_rejected() {
+        let conv: Layers = Layers::Conv2D {
+            filters: 2,
+            kernel_size: (2, 2),
+            stride: 0,
+            padding: 0,
+            activation: Activations::ReLU,
+            init_func: "he".to_string(),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        };
+        let pool: Layers = Layers::MaxPool2D {
+            pool_size: (2, 2),
+            stride: 0,
+        };
+        assert!(conv.output_shape(&[1, 4, 4]).is_err());
+        assert!(pool.output_shape(&[1, 4, 4]).is_err());
+    }
+
+    #[test]
+    fn dropout_scales_survivors_in_training_and_passes_through_otherwise() {
+        let layer: Layers = Layers::Dropout { rate: 0.25 };
+        let shape: [usize; 1] = [40];
+        let empty: Array2<f32> = Array2::zeros((0, 0));
+        let input: ArrayD<f32> =
+            Array2::from_shape_fn((50, 40), |(i, j)| 1. + ((i * 40 + j) as f32).sin()).into_dyn();
+        let (z, cache) =
+            layer.forward_propagate(&input, &empty, &empty, &empty, &shape, &shape, true);
+        let mut kept: usize = 0;
+        for (x, y) in input.iter().zip(z.iter()) {
+            if *y != 0. {
+                assert!((y - x / 0.75).abs() < 1e-6);
+                kept += 1;
+            }
+        }
+        // 2000 draws keep 3/4 of them give or take a couple of percent
+        let fraction: f32 = kept as f32 / input.len() as f32;
+        assert!((fraction - 0.75).abs() < 0.05, "kept {}", fraction);
+        // the gradient goes through the same mask
+        let ones: ArrayD<f32> = ArrayD::ones(input.raw_dim());
+        let (dx, _, _) = layer.backward(&input, &empty, &empty, &cache, &ones, &shape, &shape);
+        for (x, (y, g)) in input.iter().zip(z.iter().zip(dx.iter())) {
+            assert!((g * x - y).abs() < 1e-5);
+        }
+
+        let (z, cache) =
+            layer.forward_propagate(&input, &empty, &empty, &empty, &shape, &shape, false);
+        assert_eq!(z, input);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn whole_tokens_inside_the_vocabulary_are_indices() {
+        assert_eq!(token_index(0., 4), 0);
+        assert_eq!(token_index(3., 4), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't an index")]
+    fn fractional_tokens_fail_loudly() {
+        token_index(2.7, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't an index")]
+    fn nan_tokens_fail_loudly() {
+        token_index(f32::NAN, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't an index")]
+    fn tokens_past_the_vocabulary_fail_loudly() {
+        token_index(4., 4);
+    }
+
+    #[test]
+    fn batch_norm_starts_with_unit_variance_wherever_it_sits() {
+        let batch_norm = || Layers::BatchNorm {
+            momentum: 0.9,
+            epsilon: 1e-5,
+        };
+        let state: Array2<f32> = batch_norm().initial_state(&[3]);
+        assert_eq!(
+            state,
+            Array2::from_shape_vec((2, 3), vec![0., 0., 0., 1., 1., 1.]).unwrap()
+        );
+        let residual: Layers = Layers::Residual {
+            block: vec![Layers::LayerNorm { epsilon: 1e-5 }, batch_norm()],
+        };
+        assert_eq!(
+            residual.initial_state(&[3]),
+            Array2::from_shape_vec((1, 6), vec![0., 0., 0., 1., 1., 1.]).unwrap()
+        );
+        assert!(Layers::LayerNorm { epsilon: 1e-5 }
+            .initial_state(&[3])
+            .is_empty());
+    }
+
+    fn dense(units: usize, activation: Activations) -> Layers {
+        Layers::Dense {
+            units,
+            activation,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        }
+    }
+
+    // a Residual nested inside one branch of a Parallel, with a BatchNorm in each so both levels of
+    // the cache carry batch statistics
+    fn nested() -> Layers {
+        Layers::Parallel {
+            branches: vec![
+                vec![
+                    dense(3, Activations::Tanh),
+                    Layers::Residual {
+                        block: vec![
+                            Layers::BatchNorm {
+                                momentum: 0.5,
+                                epsilon: 1e-3,
+                            },
+                            dense(3, Activations::Sigmoid),
+                        ],
+                    },
+                ],
+                vec![
+                    dense(2, Activations::Tanh),
+                    Layers::BatchNorm {
+                        momentum: 0.5,
+                        epsilon: 1e-3,
+                    },
+                ],
+            ],
+        }
+    }
+
+    fn max_diff(a: &Array2<f32>, b: &Array2<f32>) -> f32 {
+        (a - b).iter().fold(0f32, |m, x| m.max(x.abs()))
+    }
+
+    #[test]
+    fn nested_container_backward_matches_finite_differences() {
+        let layer: Layers = nested();
+        let shape: [usize; 1] = [4];
+        // fixed parameters so the check doesn't depend on how the weights happened to be drawn
+        let ((rows, cols), (bias_rows, bias_cols)) = layer.parameter_shapes(&shape);
+        let weights: Array2<f32> =
+            Array2::from_shape_fn((rows, cols), |(_, j)| 0.5 * (j as f32 * 1.3).sin());
+        let bias: Array2<f32> =
+            Array2::from_shape_fn((bias_rows, bias_cols), |(_, j)| 0.1 * j as f32 - 0.3);
+        let state: Array2<f32> = layer.initial_state(&shape);
+        let input: Array2<f32> =
+            Array2::from_shape_fn((5, 4), |(i, j)| ((i * 4 + j) as f32 * 0.7).sin());
+        // a fixed upstream gradient makes Σ z·r a loss whose ∂/∂z is r
+        let r: Array2<f32> = Array2::from_shape_fn((5, 5), |(i, j)| ((i + 2 * j) as f32).cos());
+        let output_shape: Vec<usize> = layer.output_shape(&shape).unwrap();
+        let loss = |x: &Array2<f32>, w: &Array2<f32>, b: &Array2<f32>| -> f32 {
+            let (z, _) = layer.forward_rows(&x.view(), w, b, &state, &shape, &output_shape, true);
+            (z * &r).sum()
+        };
+        let (_, cache) = layer.forward_rows(
+            &input.view(),
+            &weights,
+            &bias,
+            &state,
+            &shape,
+            &output_shape,
+            true,
+        );
+        let (dx, dw, db) = layer.backward_rows(
+            &input.view(),
+            &weights,
+            &bias,
+            &cache,
+            &r.view(),
+            &shape,
+            &output_shape,
+        );
+        // BatchNorm over 5 samples curves fast enough that a larger step is off by more than 1e-2
+        let h: f32 = 3e-3;
+        let nx: Array2<f32> = numerical_gradient(|x| loss(x, &weights, &bias), &input, h);
+        let nw: Array2<f32> = numerical_gradient(|w| loss(&input, w, &bias), &weights, h);
+        let nb: Array2<f32> = numerical_gradient(|b| loss(&input, &weights, b), &bias, h);
+        assert!(max_diff(&dx, &nx) < 1e-2, "input: {}", max_diff(&dx, &nx));
+        assert!(max_diff(&dw, &nw) < 1e-2, "weights: {}", max_diff(&dw, &nw));
+        assert!(max_diff(&db, &nb) < 1e-2, "bias: {}", max_diff(&db, &nb));
+    }
+
+    #[test]
+    fn nested_container_updates_every_inner_batch_norm() {
+        let layer: Layers = nested();
+        let shape: [usize; 1] = [4];
+        let weights: Array2<f32> = init_weights(&layer, &shape);
+        let (_, (rows, cols)) = layer.parameter_shapes(&shape);
+        let bias: Array2<f32> = Array2::zeros((rows, cols));
+        let initial: Array2<f32> = layer.initial_state(&shape);
+        let input: Array2<f32> =
+            Array2::from_shape_fn((5, 4), |(i, j)| ((i * 4 + j) as f32 * 0.7).sin() + 2.);
+        let output_shape: Vec<usize> = layer.output_shape(&shape).unwrap();
+        let (_, cache) = layer.forward_rows(
+            &input.view(),
+            &weights,
+            &bias,
+            &initial,
+            &shape,
+            &output_shape,
+            true,
+        );
+        let mut state: Array2<f32> = initial.clone();
+        layer.update_state(&mut state, &cache, &shape);
+        // the Residual's BatchNorm (3 channels) comes before the second branch's (2 channels)
+        let inner: Vec<(&Layers, Vec<usize>)> = layer.inner_layers(&shape);
+        let states: Vec<Array2<f32>> = unpack_row(&state, &inner_state_shapes(&inner));
+        let residual_states: Vec<Array2<f32>> = unpack_row(
+            &states[1],
+            &inner_state_shapes(&inner[1].0.inner_layers(&inner[1].1)),
+        );
+        let halfway = |stats: &Array2<f32>| {
+            // momentum 0.5 from a zero mean and unit variance
+            let mut expected: Array2<f32> = stats * 0.5;
+            expected.row_mut(1).mapv_inplace(|v| v + 0.5);
+            expected
+        };
+        let first: Array2<f32> = cache.inner()[1].2.inner()[0].2.matrix().clone();
+        assert!(max_diff(&residual_states[0], &halfway(&first)) < 1e-6);
+        let second: Array2<f32> = cache.inner()[3].2.matrix().clone();
+        assert!(max_diff(&states[3], &halfway(&second)) < 1e-6);
+    }
+
+    // goes through the (batch, c, h, w) entry points, so the conversions to and from rows are covered too
+    #[test]
+    fn image_layers_match_finite_differences() {
+        let shape: [usize; 3] = [2, 4, 4];
+        let layers: Vec<Layers> = vec![
+            Layers::Conv2D {
+                filters: 3,
+                kernel_size: (2, 2),
+                stride: 1,
+                padding: 1,
+                activation: Activations::Linear,
+                init_func: String::from("he"),
+                kernel_regularizer: Regularizer::None,
+                bias_regularizer: Regularizer::None,
+            },
+            Layers::AvgPool2D {
+                pool_size: (2, 2),
+                stride: 2,
+            },
+            Layers::BatchNorm {
+                momentum: 0.9,
+                epsilon: 1e-3,
+            },
+        ];
+        let input: Array2<f32> =
+            Array2::from_shape_fn((2, 32), |(i, j)| ((i * 32 + j) as f32 * 0.37).sin());
+        for layer in layers.iter() {
+            let output_shape: Vec<usize> = layer.output_shape(&shape).unwrap();
+            let ((rows, cols), (bias_rows, bias_cols)) = layer.parameter_shapes(&shape);
+            let weights: Array2<f32> =
+                Array2::from_shape_fn((rows, cols), |(i, j)| 0.5 * ((i * cols + j) as f32).cos());
+            let bias: Array2<f32> =
+                Array2::from_shape_fn((bias_rows, bias_cols), |(_, j)| 0.1 * j as f32);
+            let state: Array2<f32> = layer.initial_state(&shape);
+            let features: usize = output_shape.iter().product();
+            let r: Array2<f32> =
+                Array2::from_shape_fn((2, features), |(i, j)| ((i + 3 * j) as f32).sin());
+            let r: ArrayD<f32> = from_rows(r, &output_shape);
+            let loss = |x: &Array2<f32>, w: &Array2<f32>, b: &Array2<f32>| -> f32 {
+                let x: ArrayD<f32> = from_rows(x.clone(), &shape);
+                let (z, _) = layer.forward_propagate(&x, w, b, &state, &shape, &output_shape, true);
+                (z * &r).sum()
+            };
+            let x: ArrayD<f32> = from_rows(input.clone(), &shape);
+            let (z, cache) =
+                layer.forward_propagate(&x, &weights, &bias, &state, &shape, &output_shape, true);
+            assert_eq!(z.shape()[1..], output_shape[..]);
+            let (dx, dw, db) =
+                layer.backward(&x, &weights, &bias, &cache, &r, &shape, &output_shape);
+            let h: f32 = 3e-3;
+            let nx: Array2<f32> = numerical_gradient(|x| loss(x, &weights, &bias), &input, h);
+            let dx: Array2<f32> = to_rows(&dx).into_owned();
+            assert!(max_diff(&dx, &nx) < 1e-2, "{} input", layer.display());
+            if !weights.is_empty() {
+                let nw: Array2<f32> = numerical_gradient(|w| loss(&input, w, &bias), &weights, h);
+                let nb: Array2<f32> = numerical_gradient(|b| loss(&input, &weights, b), &bias, h);
+                assert!(max_diff(&dw, &nw) < 1e-2, "{} weights", layer.display());
+                assert!(max_diff(&db, &nb) < 1e-2, "{} bias", layer.display());
+            }
         }
     }
 }
@@ -1,19 +1,33 @@
+// acronym-named variants (MSE, SGD, ELU...) read better than Mse/Sgd/Elu
+#![allow(clippy::upper_case_acronyms)]
 mod activations;
+mod attention;
+mod autograd;
+mod callbacks;
 mod cost;
 mod datasets;
 mod layers;
 mod matrixutil;
+mod metrics;
+mod model;
 mod netutil;
 mod optimizers;
+mod recurrent;
+mod regularizers;
+mod schedules;
+mod serialization;
+mod training;
 mod typings;
 use crate::{
     activations::Activations::{ReLU, Softmax},
+    callbacks::{EarlyStopping, Mode, Monitor, ProgressLogger},
     cost::Cost::MSE,
     datasets::mnist_loader,
-    layers::Layers::Dense,
+    layers::Layers::{Dense, Flatten},
     netutil::{Net, Sequential},
     optimizers::Optimizers,
-    typings::Sample,
+    regularizers::Regularizer,
+    typings::{Sample, Validation},
 };
 fn main() {
     //load the dataset
@@ -26,33 +40,49 @@ fn main() {
     let first_sample: &Sample = &dataset[0];
     let batch_size: usize = 128;
 
-    let input_dim = 784;
-    let mut model = Sequential::new(input_dim, MSE);
+    let mut model = Sequential::with_input_shape(&[1, 28, 28], MSE);
+    model.add(Flatten);
     model.add(Dense {
         units: 128,
         activation: ReLU,
         init_func: String::from("he"),
+        kernel_regularizer: Regularizer::None,
+        bias_regularizer: Regularizer::None,
     });
     model.add(Dense {
         units: 32,
         activation: ReLU,
         init_func: String::from("he"),
+        kernel_regularizer: Regularizer::None,
+        bias_regularizer: Regularizer::None,
     });
     model.add(Dense {
         units: 10,
-        activation: Softmax,
+        activation: Softmax { temperature: 1. },
         init_func: String::from("he"),
+        kernel_regularizer: Regularizer::None,
+        bias_regularizer: Regularizer::None,
     });
     model.summary();
 
+    let mut optimizer = Optimizers::SGD.build(learning_rate, &model);
+    // 1000 samples overfit long before 250 epochs, so stop once validation loss stalls
+    let mut early_stopping = EarlyStopping::new(Monitor::ValLoss, Mode::Min, 10, 1e-4, true);
     model.train(
         dataset.clone(),
-        Optimizers::SGD,
-        learning_rate,
+        &mut optimizer,
         batch_size,
         epochs,
+        Validation::Split(0.1),
+        &mut [
+            &mut ProgressLogger { batch_frequency: 0 },
+            &mut early_stopping,
+        ],
     );
+    model.evaluate(&dataset).summary();
     let pred_one = model.predict(&first_sample.0);
 
     println!("{}, {}", pred_one, first_sample.1);
+
+    model.save("mnist.fe0").expect("failed to save the model");
 }
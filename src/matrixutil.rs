@@ -1,6 +1,9 @@
 #![allow(dead_code, unused_variables)]
 
-use ndarray::{Array, Array2, ArrayBase, ArrayView, DataMut, Dimension, OwnedRepr};
+use ndarray::{
+    Array, Array2, Array4, ArrayBase, ArrayD, ArrayView, ArrayView4, Axis, CowArray, Data, DataMut,
+    Dimension, Ix2, Ix4, IxDyn, OwnedRepr,
+};
 use rand::{thread_rng, Rng};
 use rand_distr::{Distribution, Normal};
 
@@ -186,3 +189,282 @@ pub fn flatten(weight: &Array2<f32>) -> Array2<f32> {
     }
     out
 }
+
+// central finite-difference estimate of ∂f/∂x for a scalar function of a 2D array
+// used to sanity check analytic gradients: (f(x+h) - f(x-h)) / 2h for every element
+pub fn numerical_gradient<F>(mut f: F, x: &Array2<f32>, h: f32) -> Array2<f32>
+where
+    F: FnMut(&Array2<f32>) -> f32,
+{
+    let m = x.shape();
+    let mut out = create_weight(&vec![m[0], m[1]]);
+    let mut probe = x.clone();
+    for i in 0..m[0] {
+        for j in 0..m[1] {
+            let original = probe[[i, j]];
+            probe[[i, j]] = original + h;
+            let plus = f(&probe);
+            probe[[i, j]] = original - h;
+            let minus = f(&probe);
+            probe[[i, j]] = original;
+            out[[i, j]] = (plus - minus) / (2. * h);
+        }
+    }
+    out
+}
+
+// number of positions a kernel fits along one spatial axis, Layers::output_shape makes sure stride isn't 0
+pub fn conv_output_size(size: usize, kernel: usize, stride: usize, padding: usize) -> usize {
+    (size + 2 * padding - kernel) / stride + 1
+}
+
+// reads a (batch × c*h*w) matrix as a (batch, c, h, w) tensor, shape holds [c, h, w]
+pub fn to_4d<'a, S>(weight: &'a ArrayBase<S, Ix2>, shape: &[usize]) -> CowArray<'a, f32, Ix4>
+where
+    S: Data<Elem = f32>,
+{
+    let dim = (weight.nrows(), shape[0], shape[1], shape[2]);
+    weight
+        .as_standard_layout()
+        .into_shape(dim)
+        .expect("row length doesn't match the layer's input shape")
+}
+
+// flattens a (batch, c, h, w) tensor back into (batch × c*h*w) rows
+pub fn from_4d(weight: Array4<f32>) -> Array2<f32> {
+    let (b, c, h, w) = weight.dim();
+    let weight: Array4<f32> = if weight.is_standard_layout() {
+        weight
+    } else {
+        weight.as_standard_layout().into_owned()
+    };
+    weight.into_shape((b, c * h * w)).unwrap()
+}
+
+// a (batch, ..sample shape) tensor as the (batch × features) rows layers do their math on
+// batches are kept in standard layout so this is a view of the same memory, anything else is copied once
+pub fn to_rows(batch: &ArrayD<f32>) -> CowArray<'_, f32, Ix2> {
+    let rows: usize = batch.shape()[0];
+    let features: usize = batch.shape()[1..].iter().product();
+    batch
+        .as_standard_layout()
+        .into_shape((rows, features))
+        .unwrap()
+}
+
+// (batch × features) rows back into a (batch, ..sample_shape) tensor, reusing the rows' memory
+pub fn from_rows(rows: Array2<f32>, sample_shape: &[usize]) -> ArrayD<f32> {
+    let mut shape: Vec<usize> = vec![rows.nrows()];
+    shape.extend_from_slice(sample_shape);
+    let rows: Array2<f32> = if rows.is_standard_layout() {
+        rows
+    } else {
+        rows.as_standard_layout().into_owned()
+    };
+    rows.into_shape(IxDyn(&shape))
+        .expect("row length doesn't match the sample shape")
+}
+
+// one row per (batch, y, x) position with a column per channel, so per-channel statistics become column ones
+// flat (batch × features) inputs are returned as they are
+pub fn channels_last<S>(x: &ArrayBase<S, Ix2>, shape: &[usize]) -> Array2<f32>
+where
+    S: Data<Elem = f32>,
+{
+    if shape.len() != 3 {
+        return x.to_owned();
+    }
+    let t: Array4<f32> = to_4d(x, shape)
+        .permuted_axes([0, 2, 3, 1])
+        .as_standard_layout()
+        .into_owned();
+    t.into_shape((x.nrows() * shape[1] * shape[2], shape[0]))
+        .unwrap()
+}
+
+// inverse of channels_last for a batch of the given size
+pub fn channels_first(x: Array2<f32>, shape: &[usize], batch: usize) -> Array2<f32> {
+    if shape.len() != 3 {
+        return x;
+    }
+    let t: Array4<f32> = x
+        .as_standard_layout()
+        .into_owned()
+        .into_shape((batch, shape[1], shape[2], shape[0]))
+        .unwrap()
+        .permuted_axes([0, 3, 1, 2]);
+    from_4d(t)
+}
+
+// unrolls every kernel sized patch of a (batch, c, h, w) tensor into one row
+// the output is (batch*out_h*out_w × c*kh*kw) so a convolution turns into a single matrix product
+pub fn im2col(
+    input: &ArrayView4<f32>,
+    kernel: (usize, usize),
+    stride: usize,
+    padding: usize,
+) -> Array2<f32> {
+    let (b, c, h, w) = input.dim();
+    let (kh, kw) = kernel;
+    let out_h = conv_output_size(h, kh, stride, padding);
+    let out_w = conv_output_size(w, kw, stride, padding);
+    let mut cols = create_weight(&vec![b * out_h * out_w, c * kh * kw]);
+    for n in 0..b {
+        for i in 0..out_h {
+            for j in 0..out_w {
+                let row = (n * out_h + i) * out_w + j;
+                for ch in 0..c {
+                    for ki in 0..kh {
+                        for kj in 0..kw {
+                            // positions that land in the zero padding are left as 0
+                            let y = (i * stride + ki) as isize - padding as isize;
+                            let x = (j * stride + kj) as isize - padding as isize;
+                            if y >= 0 && x >= 0 && (y as usize) < h && (x as usize) < w {
+                                cols[[row, (ch * kh + ki) * kw + kj]] =
+                                    input[[n, ch, y as usize, x as usize]];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    cols
+}
+
+// where every entry of im2col's output comes from in the flattened (batch, c, h, w) input, row by row
+// entries that land in the zero padding point one past the end of the input
+pub fn im2col_indices(
+    shape: (usize, usize, usize, usize),
+    kernel: (usize, usize),
+    stride: usize,
+    padding: usize,
+) -> Vec<usize> {
+    let (b, c, h, w) = shape;
+    let (kh, kw) = kernel;
+    let out_h = conv_output_size(h, kh, stride, padding);
+    let out_w = conv_output_size(w, kw, stride, padding);
+    let mut indices: Vec<usize> = Vec::with_capacity(b * out_h * out_w * c * kh * kw);
+    for n in 0..b {
+        for i in 0..out_h {
+            for j in 0..out_w {
+                for ch in 0..c {
+                    for ki in 0..kh {
+                        for kj in 0..kw {
+                            let y = (i * stride + ki) as isize - padding as isize;
+                            let x = (j * stride + kj) as isize - padding as isize;
+                            indices.push(
+                                if y >= 0 && x >= 0 && (y as usize) < h && (x as usize) < w {
+                                    ((n * c + ch) * h + y as usize) * w + x as usize
+                                } else {
+                                    b * c * h * w
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+    indices
+}
+
+// inverse of im2col, overlapping patches are summed which is exactly what the gradient needs
+pub fn col2im(
+    cols: &Array2<f32>,
+    shape: (usize, usize, usize, usize),
+    kernel: (usize, usize),
+    stride: usize,
+    padding: usize,
+) -> Array4<f32> {
+    let (b, c, h, w) = shape;
+    let (kh, kw) = kernel;
+    let out_h = conv_output_size(h, kh, stride, padding);
+    let out_w = conv_output_size(w, kw, stride, padding);
+    let mut out = Array4::zeros(shape);
+    for n in 0..b {
+        for i in 0..out_h {
+            for j in 0..out_w {
+                let row = (n * out_h + i) * out_w + j;
+                for ch in 0..c {
+                    for ki in 0..kh {
+                        for kj in 0..kw {
+                            let y = (i * stride + ki) as isize - padding as isize;
+                            let x = (j * stride + kj) as isize - padding as isize;
+                            if y >= 0 && x >= 0 && (y as usize) < h && (x as usize) < w {
+                                out[[n, ch, y as usize, x as usize]] +=
+                                    cols[[row, (ch * kh + ki) * kw + kj]];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+// (x̂, 1/σ) with every row normalized to zero mean and unit variance, 1/σ is (batch × 1)
+pub fn normalize_rows<S>(x: &ArrayBase<S, Ix2>, epsilon: f32) -> (Array2<f32>, Array2<f32>)
+where
+    S: Data<Elem = f32>,
+{
+    let mean: Array2<f32> = x.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+    let inv_std: Array2<f32> = x
+        .var_axis(Axis(1), 0.)
+        .mapv(|v| 1. / (v + epsilon).sqrt())
+        .insert_axis(Axis(1));
+    ((x - &mean) * &inv_std, inv_std)
+}
+
+// ∂C/∂x for x̂ = (x-μ)/σ with μ and σ² taken along axis, given ∂C/∂x̂
+// ∂C/∂x = 1/(nσ) (n ∂C/∂x̂ - Σ∂C/∂x̂ - x̂ Σ(∂C/∂x̂ x̂))
+pub fn normalize_backward(
+    dx_hat: &Array2<f32>,
+    x_hat: &Array2<f32>,
+    inv_std: &Array2<f32>,
+    axis: Axis,
+) -> Array2<f32> {
+    let n: f32 = x_hat.len_of(axis) as f32;
+    let sum_dx_hat: Array2<f32> = dx_hat.sum_axis(axis).insert_axis(axis);
+    let sum_dx_hat_x_hat: Array2<f32> = (dx_hat * x_hat).sum_axis(axis).insert_axis(axis);
+    (dx_hat * n - &sum_dx_hat - x_hat * &sum_dx_hat_x_hat) * inv_std / n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    #[test]
+    fn rows_reuse_the_batch_memory() {
+        let batch: ArrayD<f32> =
+            Array3::from_shape_fn((2, 3, 4), |(i, j, k)| (i * 12 + j * 4 + k) as f32).into_dyn();
+        let rows = to_rows(&batch);
+        assert!(rows.is_view());
+        assert_eq!(rows.as_ptr(), batch.as_ptr());
+        assert_eq!(rows.dim(), (2, 12));
+        let owned: Array2<f32> = Array2::from_shape_fn((2, 12), |(i, j)| (i * 12 + j) as f32);
+        let ptr: *const f32 = owned.as_ptr();
+        let back: ArrayD<f32> = from_rows(owned, &[3, 4]);
+        assert_eq!(back.as_ptr(), ptr);
+        assert_eq!(back, batch);
+    }
+
+    #[test]
+    fn transposed_batches_keep_their_order() {
+        // column-major, so a plain into_shape would read it down the columns
+        let t: Array2<f32> =
+            Array2::from_shape_fn((4, 2), |(i, j)| (i * 2 + j) as f32).reversed_axes();
+        let expected: Array2<f32> = Array2::from_shape_fn((2, 4), |(i, j)| (j * 2 + i) as f32);
+        assert_eq!(to_rows(&t.clone().into_dyn()), expected);
+        assert_eq!(from_rows(t.clone(), &[4]), expected.clone().into_dyn());
+        assert_eq!(
+            to_4d(&t, &[1, 2, 2])
+                .into_owned()
+                .into_shape((2, 4))
+                .unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,272 @@
+#![allow(dead_code)]
+use crate::{cost::class_index, matrixutil::arg_max};
+use ndarray::{Array1, Array2, Axis};
+
+// everything Sequential::evaluate reports for a dataset
+pub struct Evaluation {
+    pub loss: f32,
+    pub accuracy: f32,
+    pub top_k: usize,
+    pub top_k_accuracy: f32,
+    // per-class scores, indexed by class
+    pub precision: Vec<f32>,
+    pub recall: Vec<f32>,
+    pub f1: Vec<f32>,
+    // confusion[[actual, predicted]] counts how often each pair shows up
+    pub confusion: Array2<usize>,
+}
+
+impl Evaluation {
+    // threshold is where a single output column flips to class 1, see Cost::threshold
+    // top_k is how many of the highest scoring classes count as a hit, capped at the number of outputs
+    pub fn new(
+        loss: f32,
+        predicted: &Array2<f32>,
+        expected: &Array2<f32>,
+        threshold: f32,
+        top_k: usize,
+    ) -> Self {
+        let confusion: Array2<usize> = confusion_matrix(predicted, expected, threshold);
+        let (precision, recall, f1) = precision_recall_f1(&confusion);
+        let top_k: usize = top_k.min(predicted.ncols());
+        Evaluation {
+            loss,
+            accuracy: accuracy(predicted, expected, threshold),
+            top_k,
+            top_k_accuracy: top_k_accuracy(predicted, expected, top_k, threshold),
+            precision,
+            recall,
+            f1,
+            confusion,
+        }
+    }
+
+    // prefix is prepended to every metric name, e.g. "val_" for validation reports
+    pub fn display(&self, prefix: &str) -> String {
+        format!(
+            "{p}loss: {:.4} - {p}accuracy: {:.4} - {p}top_{}_accuracy: {:.4}",
+            self.loss,
+            self.accuracy,
+            self.top_k,
+            self.top_k_accuracy,
+            p = prefix
+        )
+    }
+
+    pub fn summary(&self) {
+        println!("{}", self.display(""));
+        for c in 0..self.precision.len() {
+            println!(
+                "class {} / precision: {:.4} / recall: {:.4} / f1: {:.4}",
+                c, self.precision[c], self.recall[c], self.f1[c]
+            );
+        }
+        println!("confusion matrix (rows are actual, columns are predicted):");
+        println!("{}", self.confusion);
+    }
+}
+
+// index of the predicted class for every row
+// a single output column is treated as a binary classifier, class 1 from threshold up
+pub fn classes(weight: &Array2<f32>, threshold: f32) -> Vec<usize> {
+    if weight.ncols() == 1 {
+        return weight
+            .iter()
+            .map(|p: &f32| (*p >= threshold) as usize)
+            .collect();
+    }
+    weight
+        .outer_iter()
+        .map(|row| arg_max(&row.insert_axis(Axis(0)).to_owned()).1)
+        .collect()
+}
+
+// the true class of every row, labels can be one-hot rows or, for a multi-class prediction,
+// a single column of class indices like SparseCategoricalCrossEntropy takes
+// single column labels are 0/1, or ±1 for hinge losses, so they're always split at 0.5
+pub fn labels(predicted: &Array2<f32>, expected: &Array2<f32>) -> Vec<usize> {
+    if expected.ncols() == 1 && predicted.ncols() > 1 {
+        return expected
+            .iter()
+            .map(|y: &f32| class_index(*y, predicted.ncols()))
+            .collect();
+    }
+    classes(expected, 0.5)
+}
+
+pub fn num_classes(weight: &Array2<f32>) -> usize {
+    weight.ncols().max(2)
+}
+
+pub fn accuracy(predicted: &Array2<f32>, expected: &Array2<f32>, threshold: f32) -> f32 {
+    let hits: usize = classes(predicted, threshold)
+        .iter()
+        .zip(labels(predicted, expected).iter())
+        .filter(|(p, y)| p == y)
+        .count();
+    hits as f32 / predicted.nrows() as f32
+}
+
+// fraction of rows whose true class is among the k highest scoring outputs
+pub fn top_k_accuracy(
+    predicted: &Array2<f32>,
+    expected: &Array2<f32>,
+    k: usize,
+    threshold: f32,
+) -> f32 {
+    if predicted.ncols() == 1 {
+        return accuracy(predicted, expected, threshold);
+    }
+    let mut hits: usize = 0;
+    for (row, label) in predicted
+        .outer_iter()
+        .zip(labels(predicted, expected).iter())
+    {
+        // the label is in the top k when fewer than k classes strictly outscore it
+        let score: f32 = row[*label];
+        if row.iter().filter(|p: &&f32| **p > score).count() < k {
+            hits += 1;
+        }
+    }
+    hits as f32 / predicted.nrows() as f32
+}
+
+pub fn confusion_matrix(
+    predicted: &Array2<f32>,
+    expected: &Array2<f32>,
+    threshold: f32,
+) -> Array2<usize> {
+    let n: usize = num_classes(predicted);
+    let mut confusion: Array2<usize> = Array2::zeros((n, n));
+    for (p, y) in classes(predicted, threshold)
+        .iter()
+        .zip(labels(predicted, expected).iter())
+    {
+        confusion[[*y, *p]] += 1;
+    }
+    confusion
+}
+
+// classes that never show up (or are never predicted) score 0 instead of NaN
+pub fn precision_recall_f1(confusion: &Array2<usize>) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let predicted_totals: Array1<usize> = confusion.sum_axis(Axis(0));
+    let actual_totals: Array1<usize> = confusion.sum_axis(Axis(1));
+    let ratio = |a: usize, b: usize| if b == 0 { 0. } else { a as f32 / b as f32 };
+    let mut precision: Vec<f32> = Vec::with_capacity(confusion.nrows());
+    let mut recall: Vec<f32> = Vec::with_capacity(confusion.nrows());
+    let mut f1: Vec<f32> = Vec::with_capacity(confusion.nrows());
+    for c in 0..confusion.nrows() {
+        let p: f32 = ratio(confusion[[c, c]], predicted_totals[c]);
+        let r: f32 = ratio(confusion[[c, c]], actual_totals[c]);
+        precision.push(p);
+        recall.push(r);
+        f1.push(if p + r > 0. { 2. * p * r / (p + r) } else { 0. });
+    }
+    (precision, recall, f1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost::Cost;
+    use ndarray::array;
+
+    #[test]
+    fn logit_outputs_are_split_at_zero() {
+        // raw logits: -0.2 and 0.3 are classes 0 and 1, even though neither is past 0.5
+        let predicted: Array2<f32> = array![[-0.2], [0.3], [2.], [-3.]];
+        let expected: Array2<f32> = array![[0.], [1.], [1.], [0.]];
+        let logits: f32 = Cost::BinaryCrossEntropyWithLogits.threshold();
+        assert_eq!(accuracy(&predicted, &expected, logits), 1.);
+        assert_eq!(accuracy(&predicted, &expected, 0.5), 0.75);
+    }
+
+    // rows are predicted as classes 0, 1, 1, 2, 2, 2 for labels 0, 0, 1, 1, 2, 2
+    fn three_classes() -> (Array2<f32>, Array2<f32>) {
+        let predicted: Array2<f32> = array![
+            [0.7, 0.2, 0.1],
+            [0.1, 0.6, 0.3],
+            [0.2, 0.5, 0.3],
+            [0.2, 0.3, 0.5],
+            [0.1, 0.2, 0.7],
+            [0.1, 0.1, 0.8]
+        ];
+        let expected: Array2<f32> = array![
+            [1., 0., 0.],
+            [1., 0., 0.],
+            [0., 1., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [0., 0., 1.]
+        ];
+        (predicted, expected)
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert!(
+            a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() < 1e-6),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn confusion_matrix_counts_actual_against_predicted() {
+        let (predicted, expected) = three_classes();
+        assert_eq!(
+            confusion_matrix(&predicted, &expected, 0.5),
+            array![[1, 1, 0], [0, 1, 1], [0, 0, 2]]
+        );
+    }
+
+    #[test]
+    fn precision_recall_and_f1_come_from_the_confusion_matrix() {
+        let (predicted, expected) = three_classes();
+        let (precision, recall, f1) =
+            precision_recall_f1(&confusion_matrix(&predicted, &expected, 0.5));
+        // predicted 1, 2 and 3 times with one, one and two of them right
+        assert_close(&precision, &[1., 0.5, 2. / 3.]);
+        // every class shows up twice
+        assert_close(&recall, &[0.5, 0.5, 1.]);
+        // 2pr / (p + r)
+        assert_close(&f1, &[2. / 3., 0.5, 0.8]);
+        assert_eq!(accuracy(&predicted, &expected, 0.5), 4. / 6.);
+    }
+
+    #[test]
+    fn classes_that_are_never_predicted_score_zero() {
+        let (precision, recall, f1) = precision_recall_f1(&array![[2, 0], [1, 0]]);
+        assert_close(&precision, &[2. / 3., 0.]);
+        assert_close(&recall, &[1., 0.]);
+        assert_close(&f1, &[0.8, 0.]);
+    }
+
+    #[test]
+    fn top_k_counts_labels_among_the_k_best_scores() {
+        let (predicted, expected) = three_classes();
+        assert_eq!(top_k_accuracy(&predicted, &expected, 1, 0.5), 4. / 6.);
+        // the second row's label scores last, the fourth's second
+        assert_eq!(top_k_accuracy(&predicted, &expected, 2, 0.5), 5. / 6.);
+        assert_eq!(top_k_accuracy(&predicted, &expected, 3, 0.5), 1.);
+    }
+
+    #[test]
+    fn evaluation_takes_k_and_caps_it_at_the_outputs() {
+        let (predicted, expected) = three_classes();
+        let evaluation: Evaluation = Evaluation::new(0., &predicted, &expected, 0.5, 2);
+        assert_eq!(evaluation.top_k, 2);
+        assert_eq!(evaluation.top_k_accuracy, 5. / 6.);
+        let evaluation: Evaluation = Evaluation::new(0., &predicted, &expected, 0.5, 5);
+        assert_eq!(evaluation.top_k, 3);
+        assert_eq!(evaluation.top_k_accuracy, 1.);
+    }
+
+    #[test]
+    fn sparse_labels_are_read_as_class_indices() {
+        let predicted: Array2<f32> = array![[0.1, 0.7, 0.2], [0.5, 0.3, 0.2]];
+        let expected: Array2<f32> = array![[1.], [2.]];
+        assert_eq!(labels(&predicted, &expected), vec![1, 2]);
+        assert_eq!(accuracy(&predicted, &expected, 0.5), 0.5);
+    }
+}
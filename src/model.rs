@@ -0,0 +1,941 @@
+#![allow(dead_code)]
+use crate::{
+    callbacks::{Callback, History},
+    cost::{Cost, Reduction},
+    layers::Layers,
+    matrixutil::{from_rows, to_rows},
+    metrics::{accuracy, Evaluation},
+    netutil::{init_biases, init_weights, Net},
+    optimizers::Optimizer,
+    serialization::ModelError,
+    training::{fit, Fit, Trainable},
+    typings::{Cache, Gradients, MultiSample, Validation},
+};
+use ndarray::{
+    s, stack, Array1, Array2, Array3, ArrayD, ArrayView2, ArrayView3, Axis, CowArray, Ix2, Ix3,
+    IxDyn, Slice,
+};
+use rand::{seq::SliceRandom, thread_rng};
+use std::path::Path;
+
+// handle to a node of a Model's graph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node(usize);
+
+// how a merge node combines the outputs of several nodes
+pub enum Merge {
+    // elementwise sum, every input has the same shape
+    Add,
+    // elementwise product, every input has the same shape
+    Multiply,
+    // joins the inputs along axis of their per-sample shape, every other axis has to match
+    Concatenate { axis: usize },
+}
+
+enum Op {
+    // the k-th array passed to forward
+    Input(usize),
+    // a layer of Model::layers applied to the output of another node, several nodes pointing at the same
+    // layer share its parameters
+    Apply { layer: usize, input: Node },
+    Merge { merge: Merge, inputs: Vec<Node> },
+}
+
+struct GraphNode {
+    op: Op,
+    // shape of a single sample coming out of the node
+    shape: Vec<usize>,
+}
+
+// every node's z, activation and cache from one forward pass, indexed like Model::nodes
+// z is 0×0 and cache empty for nodes that aren't layers
+pub struct GraphPass {
+    pub z: Vec<ArrayD<f32>>,
+    pub a: Vec<ArrayD<f32>>,
+    pub cache: Vec<Cache>,
+}
+
+// a directed acyclic graph of layers with any number of inputs and outputs
+// nodes can only be built on top of nodes that already exist, so the order they were added in is a
+// topological order: forward runs through them front to back and backprop back to front
+pub struct Model {
+    nodes: Vec<GraphNode>,
+    inputs: Vec<Node>,
+    // every output is scored with its own cost and the model minimizes their sum
+    outputs: Vec<(Node, Cost)>,
+    pub layers: Vec<Layers>,
+    // shape of a sample going into each layer, every other application of a shared layer has to match it
+    layer_shapes: Vec<Vec<usize>>,
+    pub weights: Vec<Array2<f32>>,
+    pub biases: Vec<Array2<f32>>,
+    pub state: Vec<Array2<f32>>,
+    // how every output's weighted per-sample losses are combined, like Sequential::reduction
+    pub reduction: Reduction,
+    // how many of the highest scoring classes count as a hit for the first output's top-k accuracy
+    pub top_k: usize,
+    // set by callbacks to end train after the current epoch
+    pub stop_training: bool,
+}
+
+impl Model {
+    pub fn new() -> Self {
+        Model {
+            nodes: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            layers: Vec::new(),
+            layer_shapes: Vec::new(),
+            weights: Vec::new(),
+            biases: Vec::new(),
+            state: Vec::new(),
+            reduction: Reduction::Mean,
+            top_k: 5,
+            stop_training: false,
+        }
+    }
+
+    fn push(&mut self, op: Op, shape: Vec<usize>) -> Node {
+        self.nodes.push(GraphNode { op, shape });
+        Node(self.nodes.len() - 1)
+    }
+
+    // shape of a single sample coming out of node
+    pub fn shape(&self, node: Node) -> &[usize] {
+        &self.nodes[node.0].shape
+    }
+
+    // a new input taking samples of shape, inputs are passed to forward in the order they were declared
+    pub fn input(&mut self, shape: &[usize]) -> Node {
+        let node: Node = self.push(Op::Input(self.inputs.len()), shape.to_vec());
+        self.inputs.push(node);
+        node
+    }
+
+    // applies a new layer to the output of input
+    pub fn layer(&mut self, layer: Layers, input: Node) -> Node {
+        let input_shape: Vec<usize> = self.shape(input).to_vec();
+        let shape: Vec<usize> = layer
+            .output_shape(&input_shape)
+            .unwrap_or_else(|e| panic!("can't add layer {}: {}", self.layers.len(), e));
+        self.weights.push(init_weights(&layer, &input_shape));
+        self.biases.push(init_biases(&layer, &input_shape));
+        self.state.push(layer.initial_state(&input_shape));
+        self.layers.push(layer);
+        self.layer_shapes.push(input_shape);
+        self.push(
+            Op::Apply {
+                layer: self.layers.len() - 1,
+                input,
+            },
+            shape,
+        )
+    }
+
+    // applies the layer behind from to the output of input as well, both share the same parameters
+    pub fn shared(&mut self, from: Node, input: Node) -> Node {
+        let layer: usize = match self.nodes[from.0].op {
+            Op::Apply { layer, .. } => layer,
+            _ => panic!("node {} isn't a layer so it has nothing to share", from.0),
+        };
+        if self.shape(input) != &self.layer_shapes[layer][..] {
+            panic!(
+                "can't share layer {}: it takes {:?} but got {:?}",
+                layer,
+                self.layer_shapes[layer],
+                self.shape(input)
+            );
+        }
+        let shape: Vec<usize> = self.shape(from).to_vec();
+        self.push(Op::Apply { layer, input }, shape)
+    }
+
+    pub fn merge(&mut self, merge: Merge, inputs: &[Node]) -> Node {
+        let shapes: Vec<&[usize]> = inputs.iter().map(|n| self.shape(*n)).collect();
+        let shape: Vec<usize> = merge
+            .output_shape(&shapes)
+            .unwrap_or_else(|e| panic!("can't merge nodes {:?}: {}", inputs, e));
+        self.push(
+            Op::Merge {
+                merge,
+                inputs: inputs.to_vec(),
+            },
+            shape,
+        )
+    }
+
+    // marks node as an output, scored with cost, outputs are returned in the order they were marked
+    pub fn output(&mut self, node: Node, cost: Cost) {
+        if let Err(e) = cost.validate() {
+            panic!("invalid cost for node {}: {}", node.0, e);
+        }
+        self.outputs.push((node, cost));
+    }
+
+    // every node's output for one (batch, ..input shape) tensor per input
+    pub fn forward(&self, inputs: &[ArrayD<f32>], training: bool) -> GraphPass {
+        if inputs.len() != self.inputs.len() {
+            panic!(
+                "the model takes {} inputs but got {}",
+                self.inputs.len(),
+                inputs.len()
+            );
+        }
+        let empty = || ArrayD::zeros(IxDyn(&[0, 0]));
+        let mut pass: GraphPass = GraphPass {
+            z: Vec::with_capacity(self.nodes.len()),
+            a: Vec::with_capacity(self.nodes.len()),
+            cache: Vec::with_capacity(self.nodes.len()),
+        };
+        for node in self.nodes.iter() {
+            let (z, a, cache) = match &node.op {
+                Op::Input(k) => {
+                    if inputs[*k].ndim() == 0 || inputs[*k].shape()[1..] != node.shape[..] {
+                        panic!(
+                            "input {} takes samples of shape {:?} but got a batch of shape {:?}",
+                            k,
+                            node.shape,
+                            inputs[*k].shape()
+                        );
+                    }
+                    (empty(), inputs[*k].clone(), Cache::empty())
+                }
+                Op::Apply { layer, input } => {
+                    let (z, cache) = self.layers[*layer].forward_propagate(
+                        &pass.a[input.0],
+                        &self.weights[*layer],
+                        &self.biases[*layer],
+                        &self.state[*layer],
+                        &self.layer_shapes[*layer],
+                        &node.shape,
+                        training,
+                    );
+                    let a: ArrayD<f32> = self.layers[*layer].activate(&z);
+                    (z, a, cache)
+                }
+                Op::Merge { merge, inputs } => {
+                    let values: Vec<CowArray<f32, Ix2>> =
+                        inputs.iter().map(|n| to_rows(&pass.a[n.0])).collect();
+                    let values: Vec<ArrayView2<f32>> = values.iter().map(|v| v.view()).collect();
+                    let shapes: Vec<&[usize]> = inputs.iter().map(|n| self.shape(*n)).collect();
+                    let a: Array2<f32> = merge.forward(&values, &shapes);
+                    (empty(), from_rows(a, &node.shape), Cache::empty())
+                }
+            };
+            pass.z.push(z);
+            pass.a.push(a);
+            pass.cache.push(cache);
+        }
+        pass
+    }
+
+    // inference mode outputs, in the order they were marked
+    pub fn predict(&self, inputs: &[ArrayD<f32>]) -> Vec<ArrayD<f32>> {
+        let pass: GraphPass = self.forward(inputs, false);
+        self.outputs
+            .iter()
+            .map(|(node, _)| pass.a[node.0].clone())
+            .collect()
+    }
+
+    // Σ of every output's cost plus every layer's regularization penalty
+    // weights scale every sample's loss before self.reduction combines them, see Sequential::loss
+    pub fn loss(
+        &self,
+        predicted: &[ArrayD<f32>],
+        expected: &[ArrayD<f32>],
+        weights: Option<&Array1<f32>>,
+    ) -> f32 {
+        let mut total: f32 = self.penalty();
+        for ((_, cost), (p, e)) in self
+            .outputs
+            .iter()
+            .zip(predicted.iter().zip(expected.iter()))
+        {
+            total += cost
+                .calculate_with(
+                    &to_rows(p).into_owned(),
+                    &to_rows(e).into_owned(),
+                    weights,
+                    &self.reduction,
+                )
+                .sum();
+        }
+        total
+    }
+
+    pub fn penalty(&self) -> f32 {
+        let mut total: f32 = 0f32;
+        for i in 0..self.layers.len() {
+            total +=
+                self.layers[i].penalty(&self.weights[i], &self.biases[i], &self.layer_shapes[i]);
+        }
+        total
+    }
+
+    // ∂C/∂w and ∂C/∂b for every layer, in the same order as self.weights
+    // ∂C/∂a is accumulated per node as the graph is walked backwards, so a node feeding several others
+    // (a skip connection, a shared layer's input) gets the sum of what each of them sends back, and a shared
+    // layer's parameter gradients are the sum over everywhere it was applied
+    // weights are the per-sample weights loss was given
+    pub fn backprop(
+        &self,
+        pass: &GraphPass,
+        expected: &[ArrayD<f32>],
+        weights: Option<&Array1<f32>>,
+    ) -> Gradients {
+        let mut c_wrt_a: Vec<Option<ArrayD<f32>>> = vec![None; self.nodes.len()];
+        // ∂C/∂z for outputs whose cost fuses with their layer's activation
+        let mut c_wrt_z: Vec<Option<ArrayD<f32>>> = vec![None; self.nodes.len()];
+        for ((node, cost), expected) in self.outputs.iter().zip(expected.iter()) {
+            let output: Array2<f32> = to_rows(&pass.a[node.0]).into_owned();
+            let expected: Array2<f32> = to_rows(expected).into_owned();
+            let shape: &[usize] = self.shape(*node);
+            match self.nodes[node.0].op {
+                Op::Apply { layer, .. } if cost.fuses_with(self.layers[layer].get_activation()) => {
+                    let activation = self.layers[layer].get_activation();
+                    let grad: Array2<f32> = cost.derivate_fused_with(
+                        &output,
+                        &expected,
+                        activation,
+                        weights,
+                        &self.reduction,
+                    );
+                    accumulate(&mut c_wrt_z, *node, from_rows(grad, shape));
+                }
+                _ => {
+                    let grad: Array2<f32> =
+                        cost.derivate_with(&output, &expected, weights, &self.reduction);
+                    accumulate(&mut c_wrt_a, *node, from_rows(grad, shape));
+                }
+            }
+        }
+
+        let mut gradients: Gradients = Gradients::zeros_like(&self.weights, &self.biases);
+        for i in (0..self.nodes.len()).rev() {
+            match &self.nodes[i].op {
+                Op::Input(_) => {}
+                Op::Apply { layer, input } => {
+                    let l: &Layers = &self.layers[*layer];
+                    let from_a: Option<ArrayD<f32>> = c_wrt_a[i]
+                        .take()
+                        .map(|g: ArrayD<f32>| l.activation_backward(&pass.z[i], &g));
+                    let dz: ArrayD<f32> = match (from_a, c_wrt_z[i].take()) {
+                        (Some(a), Some(z)) => a + z,
+                        (Some(g), None) | (None, Some(g)) => g,
+                        // nothing downstream of this node reaches an output
+                        (None, None) => continue,
+                    };
+                    let (c_wrt_input, c_wrt_w, c_wrt_b) = l.backward(
+                        &pass.a[input.0],
+                        &self.weights[*layer],
+                        &self.biases[*layer],
+                        &pass.cache[i],
+                        &dz,
+                        &self.layer_shapes[*layer],
+                        &self.nodes[i].shape,
+                    );
+                    gradients.weights[*layer] += &c_wrt_w;
+                    gradients.biases[*layer] += &c_wrt_b;
+                    accumulate(&mut c_wrt_a, *input, c_wrt_input);
+                }
+                Op::Merge { merge, inputs } => {
+                    if let Some(g) = c_wrt_a[i].take() {
+                        let values: Vec<CowArray<f32, Ix2>> =
+                            inputs.iter().map(|n| to_rows(&pass.a[n.0])).collect();
+                        let values: Vec<ArrayView2<f32>> =
+                            values.iter().map(|v| v.view()).collect();
+                        let shapes: Vec<&[usize]> = inputs.iter().map(|n| self.shape(*n)).collect();
+                        let grads: Vec<Array2<f32>> =
+                            merge.backward(&values, &shapes, &to_rows(&g).view());
+                        for ((n, shape), grad) in inputs.iter().zip(shapes.iter()).zip(grads) {
+                            accumulate(&mut c_wrt_a, *n, from_rows(grad, shape));
+                        }
+                    }
+                }
+            }
+        }
+
+        // the penalty only depends on the parameters themselves so its gradient is simply added on
+        for i in 0..self.layers.len() {
+            let (penalty_w, penalty_b) = self.layers[i].penalty_gradient(
+                &self.weights[i],
+                &self.biases[i],
+                &self.layer_shapes[i],
+            );
+            gradients.weights[i] += &penalty_w;
+            gradients.biases[i] += &penalty_b;
+        }
+        gradients
+    }
+
+    // minibatch training over (samples, ..shape) tensors, one per input and one per output
+    // runs every hook in callbacks as training progresses and returns the per-epoch logs
+    // accuracy in the logs is measured on the first output
+    // fails before training starts when the data doesn't fit the model, or a callback would have to save
+    // it, which graph models can't be
+    pub fn train(
+        &mut self,
+        data: MultiSample,
+        optimizer: &mut dyn Optimizer,
+        batch_size: usize,
+        epochs: usize,
+        validation: Validation<MultiSample>,
+        callbacks: &mut [&mut dyn Callback],
+    ) -> Result<History, ModelError> {
+        self.check_data(&data)?;
+        if let Validation::Data(held_out) = &validation {
+            self.check_data(held_out)?;
+        }
+        if callbacks.iter().any(|c| c.saves_model()) {
+            return Err(ModelError::Unsupported(String::from(
+                "graph models can't be written to a file, so they can't train with a callback that saves them",
+            )));
+        }
+        Ok(fit(
+            self, data, optimizer, batch_size, epochs, validation, callbacks,
+        ))
+    }
+
+    // one tensor per input and output, each holding the same samples as the weights
+    fn check_data(&self, data: &MultiSample) -> Result<(), ModelError> {
+        if self.inputs.is_empty() || self.outputs.is_empty() {
+            return Err(ModelError::InvalidData(String::from(
+                "the model needs at least one input and one output",
+            )));
+        }
+        if data.0.len() != self.inputs.len() || data.1.len() != self.outputs.len() {
+            return Err(ModelError::InvalidData(format!(
+                "the model takes {} inputs and {} outputs but got {} and {}",
+                self.inputs.len(),
+                self.outputs.len(),
+                data.0.len(),
+                data.1.len()
+            )));
+        }
+        let samples: usize = data.2.len();
+        for x in data.0.iter().chain(data.1.iter()) {
+            if x.ndim() == 0 || x.len_of(Axis(0)) != samples {
+                return Err(ModelError::InvalidData(format!(
+                    "every tensor needs {} samples along its first axis but one has shape {:?}",
+                    samples,
+                    x.shape()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Trainable for Model {
+    fn weights(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.weights
+    }
+
+    fn biases(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.biases
+    }
+
+    fn state(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.state
+    }
+
+    fn parameters(&self) -> (&[Array2<f32>], &[Array2<f32>]) {
+        (&self.weights, &self.biases)
+    }
+
+    fn decay_masks(&self) -> Vec<Array2<f32>> {
+        self.layers
+            .iter()
+            .zip(self.layer_shapes.iter())
+            .map(|(layer, shape)| layer.decay_mask(shape))
+            .collect()
+    }
+
+    fn set_stop_training(&mut self, stop: bool) {
+        self.stop_training = stop;
+    }
+
+    fn stop_training(&self) -> bool {
+        self.stop_training
+    }
+
+    fn save(&self, path: &Path) -> Result<(), ModelError> {
+        Err(ModelError::Unsupported(format!(
+            "{} is a graph model and only Sequential models can be written to a file",
+            path.display()
+        )))
+    }
+}
+
+impl Fit for Model {
+    type Data = MultiSample;
+    type Batch = MultiSample;
+
+    // holds out the last fraction of samples of every input and output
+    fn split(data: MultiSample, fraction: f32) -> (MultiSample, MultiSample) {
+        let samples: usize = data.2.len();
+        let held_out: usize = (samples as f32 * fraction.clamp(0., 1.)).round() as usize;
+        let at: usize = samples - held_out;
+        let split = |x: &Vec<ArrayD<f32>>| -> (Vec<ArrayD<f32>>, Vec<ArrayD<f32>>) {
+            x.iter()
+                .map(|x: &ArrayD<f32>| {
+                    (
+                        x.slice_axis(Axis(0), Slice::from(..at)).to_owned(),
+                        x.slice_axis(Axis(0), Slice::from(at..)).to_owned(),
+                    )
+                })
+                .unzip()
+        };
+        let (train_x, held_x) = split(&data.0);
+        let (train_y, held_y) = split(&data.1);
+        let (train_w, held_w) = (
+            data.2.slice_axis(Axis(0), Slice::from(..at)).to_owned(),
+            data.2.slice_axis(Axis(0), Slice::from(at..)).to_owned(),
+        );
+        (
+            MultiSample(train_x, train_y, train_w),
+            MultiSample(held_x, held_y, held_w),
+        )
+    }
+
+    // shuffles the samples and gathers every batch_size of them from each tensor
+    fn batches(data: MultiSample, batch_size: usize) -> Vec<MultiSample> {
+        let mut indices: Vec<usize> = (0..data.2.len()).collect();
+        indices.shuffle(&mut thread_rng());
+        let select = |x: &Vec<ArrayD<f32>>, batch: &[usize]| -> Vec<ArrayD<f32>> {
+            x.iter()
+                .map(|x: &ArrayD<f32>| x.select(Axis(0), batch))
+                .collect()
+        };
+        indices
+            .chunks(batch_size)
+            .map(|batch: &[usize]| {
+                MultiSample(
+                    select(&data.0, batch),
+                    select(&data.1, batch),
+                    data.2.select(Axis(0), batch),
+                )
+            })
+            .collect()
+    }
+
+    fn train_batch(&mut self, batch: &MultiSample, optimizer: &mut dyn Optimizer) -> (f32, f32) {
+        let pass: GraphPass = self.forward(&batch.0, true);
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Op::Apply { layer, .. } = node.op {
+                self.layers[layer].update_state(
+                    &mut self.state[layer],
+                    &pass.cache[i],
+                    &self.layer_shapes[layer],
+                );
+            }
+        }
+        let outputs: Vec<ArrayD<f32>> = self
+            .outputs
+            .iter()
+            .map(|(node, _)| pass.a[node.0].clone())
+            .collect();
+        let cost: f32 = self.loss(&outputs, &batch.1, Some(&batch.2));
+        let accuracy: f32 = accuracy(
+            &to_rows(&outputs[0]).into_owned(),
+            &to_rows(&batch.1[0]).into_owned(),
+            self.outputs[0].1.threshold(),
+        );
+
+        let gradients: Gradients = self.backprop(&pass, &batch.1, Some(&batch.2));
+        optimizer.step(&mut self.weights, &mut self.biases, &gradients);
+        (cost, accuracy)
+    }
+
+    // the loss covers every output, the other metrics only the first
+    fn validate(&self, data: &MultiSample) -> Evaluation {
+        let predicted: Vec<ArrayD<f32>> = self.predict(&data.0);
+        let loss: f32 = self.loss(&predicted, &data.1, Some(&data.2));
+        Evaluation::new(
+            loss,
+            &to_rows(&predicted[0]).into_owned(),
+            &to_rows(&data.1[0]).into_owned(),
+            self.outputs[0].1.threshold(),
+            self.top_k,
+        )
+    }
+}
+
+impl Net for Model {
+    // applies layer to the most recently added node, which makes a plain stack read like Sequential
+    fn add(&mut self, layer: Layers) {
+        if self.nodes.is_empty() {
+            panic!("declare an input before adding layers");
+        }
+        let last: Node = Node(self.nodes.len() - 1);
+        self.layer(layer, last);
+    }
+
+    fn summary(&self) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            let description: String = match &node.op {
+                Op::Input(k) => format!("Input {}", k),
+                Op::Apply { layer, input } => format!(
+                    "{} / Dimensions: {:?} <- node {}",
+                    self.layers[*layer].display(),
+                    self.weights[*layer].shape(),
+                    input.0
+                ),
+                Op::Merge { merge, inputs } => format!(
+                    "{} <- nodes {:?}",
+                    merge.display(),
+                    inputs.iter().map(|n| n.0).collect::<Vec<usize>>()
+                ),
+            };
+            println!("{}: {} / Output: {:?}", i, description, node.shape);
+            if let Op::Apply { layer, .. } = node.op {
+                for line in self.layers[layer].inner_summary(&self.layer_shapes[layer]) {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+}
+
+impl Merge {
+    pub fn output_shape(&self, shapes: &[&[usize]]) -> Result<Vec<usize>, String> {
+        if shapes.len() < 2 {
+            return Err(format!(
+                "merging needs at least two inputs, got {}",
+                shapes.len()
+            ));
+        }
+        match self {
+            Merge::Add | Merge::Multiply => {
+                if shapes.iter().any(|s| *s != shapes[0]) {
+                    return Err(format!(
+                        "every input needs the same shape but got {:?}",
+                        shapes
+                    ));
+                }
+                Ok(shapes[0].to_vec())
+            }
+            Merge::Concatenate { axis } => {
+                let mut shape: Vec<usize> = shapes[0].to_vec();
+                if *axis >= shape.len() {
+                    return Err(format!("axis {} is out of range for {:?}", axis, shape));
+                }
+                for other in shapes[1..].iter() {
+                    let matches: bool = other.len() == shape.len()
+                        && (0..shape.len()).all(|i| i == *axis || other[i] == shape[i]);
+                    if !matches {
+                        return Err(format!(
+                            "shapes {:?} only get to differ along axis {}",
+                            shapes, axis
+                        ));
+                    }
+                    shape[*axis] += other[*axis];
+                }
+                Ok(shape)
+            }
+        }
+    }
+
+    pub fn forward(&self, values: &[ArrayView2<f32>], shapes: &[&[usize]]) -> Array2<f32> {
+        match self {
+            Merge::Add => values[1..]
+                .iter()
+                .fold(values[0].to_owned(), |acc, v| acc + v),
+            Merge::Multiply => values[1..]
+                .iter()
+                .fold(values[0].to_owned(), |acc, v| acc * v),
+            Merge::Concatenate { axis } => {
+                // every row becomes (outer × inner) with inner covering axis and everything after it,
+                // so joining along axis is joining along inner
+                let parts: Vec<CowArray<f32, Ix3>> = values
+                    .iter()
+                    .zip(shapes.iter())
+                    .map(|(v, shape)| split_at_axis(v, shape, *axis))
+                    .collect();
+                let views: Vec<ArrayView3<f32>> = parts.iter().map(|p| p.view()).collect();
+                let joined: Array3<f32> = stack(Axis(2), &views).unwrap();
+                let (batch, outer, inner) = joined.dim();
+                joined.into_shape((batch, outer * inner)).unwrap()
+            }
+        }
+    }
+
+    // ∂C/∂input for every input given ∂C/∂output
+    pub fn backward(
+        &self,
+        values: &[ArrayView2<f32>],
+        shapes: &[&[usize]],
+        c_wrt_out: &ArrayView2<f32>,
+    ) -> Vec<Array2<f32>> {
+        match self {
+            Merge::Add => values.iter().map(|_| c_wrt_out.to_owned()).collect(),
+            // ∂(Π vⱼ)/∂vᵢ = Π over every other j
+            Merge::Multiply => (0..values.len())
+                .map(|i| {
+                    values
+                        .iter()
+                        .enumerate()
+                        .filter(|(j, _)| *j != i)
+                        .fold(c_wrt_out.to_owned(), |acc, (_, v)| acc * v)
+                })
+                .collect(),
+            Merge::Concatenate { axis } => {
+                let mut joined: Vec<usize> = shapes[0].to_vec();
+                joined[*axis] = shapes.iter().map(|s| s[*axis]).sum();
+                let g: CowArray<f32, Ix3> = split_at_axis(c_wrt_out, &joined, *axis);
+                let mut offset: usize = 0;
+                shapes
+                    .iter()
+                    .map(|shape| {
+                        let inner: usize = shape[*axis..].iter().product();
+                        let part: Array3<f32> =
+                            g.slice(s![.., .., offset..offset + inner]).to_owned();
+                        offset += inner;
+                        let features: usize = part.len() / c_wrt_out.nrows();
+                        part.into_shape((c_wrt_out.nrows(), features)).unwrap()
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub fn display(&self) -> String {
+        match self {
+            Merge::Add => String::from("Add Merge"),
+            Merge::Multiply => String::from("Multiply Merge"),
+            Merge::Concatenate { axis } => format!("Concatenate Merge - axis {:?}", axis),
+        }
+    }
+}
+
+// (batch × features) rows as (batch × outer × inner) where inner spans axis and every axis after it
+fn split_at_axis<'a>(
+    x: &'a ArrayView2<f32>,
+    shape: &[usize],
+    axis: usize,
+) -> CowArray<'a, f32, Ix3> {
+    let inner: usize = shape[axis..].iter().product();
+    x.as_standard_layout()
+        .into_shape((x.nrows(), x.ncols() / inner, inner))
+        .unwrap()
+}
+
+fn accumulate(grads: &mut [Option<ArrayD<f32>>], node: Node, grad: ArrayD<f32>) {
+    grads[node.0] = Some(match grads[node.0].take() {
+        Some(total) => total + grad,
+        None => grad,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activations::Activations, callbacks::ModelCheckpoint, matrixutil::numerical_gradient,
+        optimizers::Optimizers, regularizers::Regularizer,
+    };
+    use ndarray::{array, Array};
+
+    fn dense(units: usize, activation: Activations) -> Layers {
+        Layers::Dense {
+            units,
+            activation,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        }
+    }
+
+    // deterministic (samples × features) inputs in [-1, 1]
+    fn inputs(samples: usize, features: usize, seed: usize) -> ArrayD<f32> {
+        Array::from_shape_fn((samples, features), |(i, j)| {
+            ((i * features + j + seed) as f32 * 0.71).sin()
+        })
+        .into_dyn()
+    }
+
+    // one hot labels cycling through the classes
+    fn classes(samples: usize, classes: usize) -> ArrayD<f32> {
+        Array::from_shape_fn((samples, classes), |(i, j)| (i % classes == j) as u8 as f32)
+            .into_dyn()
+    }
+
+    fn max_diff(a: &Array2<f32>, b: &Array2<f32>) -> f32 {
+        (a - b).iter().fold(0f32, |m: f32, d: &f32| m.max(d.abs()))
+    }
+
+    // backprop against central differences of loss for every weight and bias, with uneven sample weights so
+    // they have to reach the gradients too
+    fn assert_matches_finite_differences(model: &mut Model, x: &[ArrayD<f32>], y: &[ArrayD<f32>]) {
+        let weights: Array1<f32> = Array::linspace(0.5, 1.5, x[0].len_of(Axis(0)));
+        let gradients: Gradients = model.backprop(&model.forward(x, true), y, Some(&weights));
+        let h: f32 = 1e-2;
+        for i in 0..model.layers.len() {
+            let original: Array2<f32> = model.weights[i].clone();
+            let numeric: Array2<f32> = numerical_gradient(
+                |w: &Array2<f32>| {
+                    model.weights[i] = w.clone();
+                    model.loss(&model.predict(x), y, Some(&weights))
+                },
+                &original,
+                h,
+            );
+            model.weights[i] = original;
+            let diff: f32 = max_diff(&gradients.weights[i], &numeric);
+            assert!(diff < 1e-2, "weights of layer {}: {}", i, diff);
+
+            let original: Array2<f32> = model.biases[i].clone();
+            let numeric: Array2<f32> = numerical_gradient(
+                |b: &Array2<f32>| {
+                    model.biases[i] = b.clone();
+                    model.loss(&model.predict(x), y, Some(&weights))
+                },
+                &original,
+                h,
+            );
+            model.biases[i] = original;
+            let diff: f32 = max_diff(&gradients.biases[i], &numeric);
+            assert!(diff < 1e-2, "biases of layer {}: {}", i, diff);
+        }
+    }
+
+    // two branches over the same input joined by merge, then scored by a softmax cross entropy head, which
+    // takes the fused path, and a linear MSE head
+    fn branches(merge: Merge) -> Model {
+        let mut model: Model = Model::new();
+        let input: Node = model.input(&[3]);
+        let left: Node = model.layer(dense(4, Activations::Tanh), input);
+        let right: Node = model.layer(dense(4, Activations::Sigmoid), input);
+        let merged: Node = model.merge(merge, &[left, right]);
+        let probabilities: Node =
+            model.layer(dense(2, Activations::Softmax { temperature: 1. }), merged);
+        let value: Node = model.layer(dense(1, Activations::Linear), merged);
+        model.output(probabilities, Cost::CrossEntropy);
+        model.output(value, Cost::MSE);
+        model
+    }
+
+    fn check_merge(merge: Merge) {
+        let mut model: Model = branches(merge);
+        let x: Vec<ArrayD<f32>> = vec![inputs(5, 3, 0)];
+        let y: Vec<ArrayD<f32>> = vec![classes(5, 2), inputs(5, 1, 7)];
+        assert_matches_finite_differences(&mut model, &x, &y);
+    }
+
+    #[test]
+    fn add_merges_match_finite_differences() {
+        check_merge(Merge::Add);
+    }
+
+    #[test]
+    fn multiply_merges_match_finite_differences() {
+        check_merge(Merge::Multiply);
+    }
+
+    #[test]
+    fn concatenate_merges_match_finite_differences() {
+        check_merge(Merge::Concatenate { axis: 0 });
+    }
+
+    #[test]
+    fn shared_layers_match_finite_differences() {
+        let mut model: Model = Model::new();
+        let first: Node = model.input(&[3]);
+        let second: Node = model.input(&[3]);
+        let encoded: Node = model.layer(dense(4, Activations::Tanh), first);
+        let again: Node = model.shared(encoded, second);
+        let joined: Node = model.merge(Merge::Concatenate { axis: 0 }, &[encoded, again]);
+        let output: Node = model.layer(dense(1, Activations::Linear), joined);
+        model.output(output, Cost::MSE);
+        assert_eq!(model.layers.len(), 2);
+        let x: Vec<ArrayD<f32>> = vec![inputs(5, 3, 0), inputs(5, 3, 11)];
+        let y: Vec<ArrayD<f32>> = vec![inputs(5, 1, 23)];
+        assert_matches_finite_differences(&mut model, &x, &y);
+    }
+
+    #[test]
+    fn sample_weights_and_reduction_reach_loss_and_gradients() {
+        let mut model: Model = branches(Merge::Add);
+        model.reduction = Reduction::Sum;
+        let x: ArrayD<f32> = inputs(2, 3, 0);
+        let y: Vec<ArrayD<f32>> = vec![classes(2, 2), inputs(2, 1, 7)];
+        let first = |a: &ArrayD<f32>| a.index_axis(Axis(0), 0).insert_axis(Axis(0)).to_owned();
+        let x_first: Vec<ArrayD<f32>> = vec![first(&x)];
+        let y_first: Vec<ArrayD<f32>> = y.iter().map(first).collect();
+        let x: Vec<ArrayD<f32>> = vec![x];
+        // weighing the second sample 0 leaves only the first one
+        let weights: Array1<f32> = array![1., 0.];
+        let weighted: Gradients = model.backprop(&model.forward(&x, true), &y, Some(&weights));
+        let alone: Gradients = model.backprop(&model.forward(&x_first, true), &y_first, None);
+        for (a, b) in weighted.weights.iter().zip(alone.weights.iter()) {
+            assert!(max_diff(a, b) < 1e-6);
+        }
+        assert_eq!(
+            model.loss(&model.predict(&x), &y, Some(&weights)),
+            model.loss(&model.predict(&x_first), &y_first, None)
+        );
+        // Sum adds up what Mean averages
+        let sum: f32 = model.loss(&model.predict(&x), &y, None);
+        model.reduction = Reduction::Mean;
+        let mean: f32 = model.loss(&model.predict(&x), &y, None);
+        assert!((sum - 2. * mean).abs() < 1e-5);
+    }
+
+    #[test]
+    fn data_that_doesnt_fit_is_an_error() {
+        let mut empty: Model = Model::new();
+        let mut optimizer = Optimizers::SGD.build(0.1, &empty);
+        let result = empty.train(
+            MultiSample::new(Vec::new(), Vec::new()),
+            &mut optimizer,
+            4,
+            1,
+            Validation::None,
+            &mut [],
+        );
+        assert!(matches!(result, Err(ModelError::InvalidData(_))));
+
+        let mut model: Model = branches(Merge::Add);
+        let mut optimizer = Optimizers::SGD.build(0.1, &model);
+        // a label missing for the second output
+        let result = model.train(
+            MultiSample::new(vec![inputs(4, 3, 0)], vec![classes(4, 2)]),
+            &mut optimizer,
+            4,
+            1,
+            Validation::None,
+            &mut [],
+        );
+        assert!(matches!(result, Err(ModelError::InvalidData(_))));
+        // labels for fewer samples than there are inputs
+        let result = model.train(
+            MultiSample::new(vec![inputs(4, 3, 0)], vec![classes(3, 2), inputs(3, 1, 0)]),
+            &mut optimizer,
+            4,
+            1,
+            Validation::None,
+            &mut [],
+        );
+        assert!(matches!(result, Err(ModelError::InvalidData(_))));
+    }
+
+    #[test]
+    fn checkpoints_are_rejected_before_training() {
+        let mut model: Model = branches(Merge::Add);
+        let before: Vec<Array2<f32>> = model.weights.clone();
+        let mut optimizer = Optimizers::SGD.build(0.1, &model);
+        let mut checkpoint: ModelCheckpoint = ModelCheckpoint {
+            path: String::from("graph_{epoch}.fe0"),
+        };
+        let result = model.train(
+            MultiSample::new(vec![inputs(4, 3, 0)], vec![classes(4, 2), inputs(4, 1, 0)]),
+            &mut optimizer,
+            4,
+            1,
+            Validation::None,
+            &mut [&mut checkpoint],
+        );
+        assert!(matches!(result, Err(ModelError::Unsupported(_))));
+        assert_eq!(model.weights, before);
+    }
+}
@@ -1,15 +1,21 @@
 #![allow(dead_code, unused_variables, non_snake_case)]
 use crate::{
-    cost::Cost,
+    autograd::{Adjoints, Tape, Var},
+    callbacks::{Callback, History},
+    cost::{Cost, Reduction},
+    datasets::{split_dataset, stack_samples},
     layers::Layers,
-    matrixutil::{create_weight, init_he, init_rand, init_xavier},
-    optimizers::Optimizers,
-    typings::{BatchedDataset, Dataset, ForwardBatch},
+    matrixutil::{create_weight, from_rows, init_he, init_rand, init_xavier, to_rows},
+    metrics::{accuracy, Evaluation},
+    optimizers::Optimizer,
+    serialization::{decode, decode_with, encode, ModelError, Registry},
+    training::{fit, Fit, Trainable},
+    typings::{BatchedDataset, Dataset, ForwardBatch, Gradients, Sample, Validation},
 };
-use ndarray::{Array2, Ix2};
+use ndarray::{Array1, Array2, ArrayD, Ix2};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use std::ops::Sub;
+use std::{fs, path::Path};
 
 pub trait Net {
     fn add(&mut self, layer: Layers);
@@ -22,183 +28,811 @@ pub struct Sequential {
     pub layers: Vec<Layers>,
     pub weights: Vec<Array2<f32>>,
     pub biases: Vec<Array2<f32>>,
+    // non-trainable buffers per layer that the optimizer never sees, e.g. BatchNorm's running statistics
+    pub state: Vec<Array2<f32>>,
     pub input_dim: usize,
+    // shape of a single input sample, [input_dim] unless built with with_input_shape
+    pub input_shape: Vec<usize>,
+    // shape of a single sample coming out of each layer
+    pub shapes: Vec<Vec<usize>>,
     pub cost: Cost,
+    // how train and evaluate combine the weighted per-sample losses of a batch
+    pub reduction: Reduction,
+    // how many of the highest scoring classes count as a hit for the top-k accuracy evaluate reports
+    pub top_k: usize,
+    // set by callbacks to end train after the current epoch
+    pub stop_training: bool,
 }
 
 #[allow(dead_code)]
 impl Sequential {
     pub fn new(input_dim: usize, cost: Cost) -> Self {
+        Self::with_input_shape(&[input_dim], cost)
+    }
+
+    // for inputs with structure, e.g. [channels, height, width] for images fed to Conv2D
+    pub fn with_input_shape(input_shape: &[usize], cost: Cost) -> Self {
+        if let Err(e) = cost.validate() {
+            panic!("invalid cost: {}", e);
+        }
         Sequential {
             layers: Vec::new(),
             weights: Vec::new(),
             biases: Vec::new(),
-            input_dim,
+            state: Vec::new(),
+            input_dim: input_shape.iter().product(),
+            input_shape: input_shape.to_vec(),
+            shapes: Vec::new(),
             cost,
+            reduction: Reduction::Mean,
+            top_k: 5,
+            stop_training: false,
         }
     }
 
-    pub fn generate_weights(&mut self, layer: &Layers) {
-        //possibly wasteful initialization here for the compiler to be comfy
-        let dim: &mut Vec<usize> = &mut Vec::with_capacity(2);
-        if self.weights.len() <= 0 {
-            dim.push(self.input_dim);
-            dim.push(layer.get_units());
+    // shape of a single sample going into layer i
+    pub fn layer_input_shape(&self, i: usize) -> &[usize] {
+        if i == 0 {
+            &self.input_shape
         } else {
-            dim.push(*self.weights.last().unwrap().shape().last().unwrap());
-            dim.push(layer.get_units());
-        }
-        // converting the string back and forth like this is ugly as fuck
-        println!("{:?}", dim);
-        let new_weights: Array2<f32> = match &layer.get_init_func().to_string().to_lowercase()[..] {
-            "xavier" | "glorot" => init_xavier(dim),
-            "kaiming" | "he" => init_he(dim),
-            //"lecun" => init_lecun(dim)
-            _ => init_rand(dim),
-        };
-        self.weights.push(new_weights);
+            &self.shapes[i - 1]
+        }
+    }
+
+    // shape of a single sample coming out of the model so far
+    pub fn output_shape(&self) -> &[usize] {
+        self.layer_input_shape(self.layers.len())
+    }
+
+    pub fn generate_weights(&mut self, layer: &Layers) {
+        let weights: Array2<f32> = init_weights(layer, self.output_shape());
+        self.weights.push(weights);
     }
 
     pub fn generate_biases(&mut self, layer: &Layers) {
-        let new_bias = create_weight::<Ix2>(&vec![1, (*layer).get_units()]);
-        self.biases.push(new_bias);
-    }
-
-    pub fn predict(&self, input: &Array2<f32>) -> Array2<f32> {
-        let mut x: &Array2<f32> = input;
-        //doing this dumb shit because rust won't let me run code with values that "could be uninitialized" fuck you
-        let mut z: Array2<f32> =
-            self.layers[0].forward_propagate(x, &self.weights[0], &self.biases[0]);
-        let mut a: Array2<f32> = self.layers[0].activate(&mut z);
-        x = &a;
-        for i in 1..self.layers.len() {
-            z = self.layers[i].forward_propagate(x, &self.weights[i], &self.biases[i]);
-            a = self.layers[i].activate(&mut z);
-            x = &a;
+        let biases: Array2<f32> = init_biases(layer, self.output_shape());
+        self.biases.push(biases);
+    }
+
+    pub fn generate_state(&mut self, layer: &Layers) {
+        let state: Array2<f32> = layer.initial_state(self.output_shape());
+        self.state.push(state);
+    }
+
+    // writes the layer stack, cost and every parameter to a versioned, checksummed binary file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ModelError> {
+        fs::write(path, encode(self))?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ModelError> {
+        decode(&fs::read(path)?)
+    }
+
+    // for models using custom activations or costs, registry says how to rebuild them
+    pub fn load_with<P: AsRef<Path>>(path: P, registry: &Registry) -> Result<Self, ModelError> {
+        decode_with(&fs::read(path)?, registry)
+    }
+
+    // panics naming both shapes when a batch doesn't hold samples of the model's input shape
+    fn check_input(&self, input: &ArrayD<f32>) {
+        if input.ndim() == 0 || input.shape()[1..] != self.input_shape[..] {
+            panic!(
+                "the model takes samples of shape {:?} but got a batch of shape {:?}",
+                self.input_shape,
+                input.shape()
+            );
+        }
+    }
+
+    // inference mode, so layers like Dropout behave the way they should once training is done
+    // input is (batch, ..input_shape) and the prediction comes out as (batch, ..output_shape)
+    pub fn predict(&self, input: &ArrayD<f32>) -> ArrayD<f32> {
+        self.check_input(input);
+        let mut a: ArrayD<f32> = input.clone();
+        for i in 0..self.layers.len() {
+            let (z, _) = self.layers[i].forward_propagate(
+                &a,
+                &self.weights[i],
+                &self.biases[i],
+                &self.state[i],
+                self.layer_input_shape(i),
+                &self.shapes[i],
+                false,
+            );
+            a = self.layers[i].activate(&z);
         }
         a
     }
 
+    // runs every hook in callbacks as training progresses and returns the per-epoch logs
     pub fn train(
         &mut self,
         dataset: Dataset,
-        optimizer: Optimizers,
-        lr: f32,
+        optimizer: &mut dyn Optimizer,
         batch_size: usize,
         epochs: usize,
-    ) {
-        //TODO: come back and optimize/simplify all this unorganized mess
-        let mut x: Array2<f32>;
-        let mut y: Array2<f32>;
-        let dataset_len = dataset.len() as f32;
-
-        //output for collect forward; wasteful initialization here to keep the compiler comfy, optimize this
-        let mut fw_vec: Vec<Vec<Array2<f32>>>;
-
-        // stores a_vec and z_vec for backprop and stuff; wasteful initialization here to keep the compiler comfy, optimize this
-        let mut predictions: ForwardBatch = Vec::with_capacity(dataset_len as usize);
-        let mut batch_input: Vec<Array2<f32>> = Vec::with_capacity(batch_size);
-        let mut batch_labels: Vec<Array2<f32>> = Vec::with_capacity(batch_size);
-        //println!("dataset len: {}", dataset.len());
-        let batches: BatchedDataset = Self::create_batches(dataset, batch_size);
-        for _ in 0..epochs {
-            for batch in batches.iter() {
-                for sample in batch.iter() {
-                    x = sample.0.to_owned();
-                    y = sample.1.to_owned();
-                    fw_vec = self.collect_forward(&x);
-                    predictions.push(fw_vec.to_owned());
-                    //println!("prediction: {:?}\nexpected: {:?}\n\n", fw_vec[1].last().unwrap(), y.clone());
-                    batch_input.push(x.to_owned());
-                    batch_labels.push(y.to_owned());
-                }
-
-                println!(
-                    "cost: {:?}",
-                    self.cost.calculate(&predictions, &batch_labels)
-                );
-
-                //TODO: what the fuck is this I need to pass multiple x's and y's
-                let gradient =
-                    optimizer.backward(&self, &mut predictions, &batch_input, &batch_labels);
-                //println!("Final weight updates: {:?}\nFinal bias updates: {:?}", gradient[0].iter().last().unwrap(), gradient[1][0].iter().last().unwrap());
-                //println!("Final weights: {:?}\nFinal bias: {:?}", self.weights.last().unwrap(), self.biases.last().unwrap());
-                let last_weight: usize = self.weights.len() - 1;
-                let last_grad = gradient[0].len() - 1;
-                //println!("\n\nweights before update: {:?}\n\n", self.weights.last().unwrap());
-                for i in (0..last_weight).rev() {
-                    self.weights[i] = self.weights[i]
-                        .clone()
-                        .sub(gradient[0][last_grad - i].clone() * lr);
-                    self.biases[i] = self.biases[i]
-                        .clone()
-                        .sub(gradient[1][last_grad - i].clone() * lr);
-                }
-                predictions.clear();
-                batch_input.clear();
-                batch_labels.clear()
+        validation: Validation,
+        callbacks: &mut [&mut dyn Callback],
+    ) -> History {
+        fit(
+            self, dataset, optimizer, batch_size, epochs, validation, callbacks,
+        )
+    }
+
+    // the cost plus every layer's regularization penalty, which is what training actually minimizes
+    // costs compare (batch × features) rows whatever the shape of the output
+    // weights scale every sample's loss before self.reduction combines them, with Reduction::None the
+    // per-sample losses are summed since that's what their gradients add up to
+    pub fn loss(
+        &self,
+        predicted: &ArrayD<f32>,
+        expected: &ArrayD<f32>,
+        weights: Option<&Array1<f32>>,
+    ) -> f32 {
+        let losses: Array1<f32> = self.cost.calculate_with(
+            &to_rows(predicted).into_owned(),
+            &to_rows(expected).into_owned(),
+            weights,
+            &self.reduction,
+        );
+        losses.sum() + self.penalty()
+    }
+
+    // Σ over layers of the kernel and bias regularizer penalties
+    pub fn penalty(&self) -> f32 {
+        let mut total: f32 = 0f32;
+        for i in 0..self.layers.len() {
+            total += self.layers[i].penalty(
+                &self.weights[i],
+                &self.biases[i],
+                self.layer_input_shape(i),
+            );
+        }
+        total
+    }
+
+    // scores the model on a dataset without touching its parameters
+    pub fn evaluate(&self, dataset: &Dataset) -> Evaluation {
+        let stacked: Sample = stack_samples(dataset.iter());
+        let predicted: ArrayD<f32> = self.predict(&stacked.0);
+        let loss: f32 = self.loss(&predicted, &stacked.1, Some(&stacked.2));
+        Evaluation::new(
+            loss,
+            &to_rows(&predicted).into_owned(),
+            &to_rows(&stacked.1).into_owned(),
+            self.cost.threshold(),
+            self.top_k,
+        )
+    }
+
+    // computes ∂C/∂w and ∂C/∂b for every layer over the whole batch, in the same order as self.weights
+    // predictions is the output of collect_forward for input, weights are the
+    // per-sample weights loss was given
+    pub fn backprop(
+        &self,
+        predictions: &ForwardBatch,
+        input: &ArrayD<f32>,
+        expected: &ArrayD<f32>,
+        weights: Option<&Array1<f32>>,
+    ) -> Gradients {
+        let last_layer: usize = self.layers.len() - 1;
+        let output: Array2<f32> = to_rows(&predictions.a[last_layer]).into_owned();
+        let expected: Array2<f32> = to_rows(expected).into_owned();
+        let mut c_wrt_z: ArrayD<f32> = if self
+            .cost
+            .fuses_with(self.layers[last_layer].get_activation())
+        {
+            // ∂C/∂zₙ = aₙ - y when softmax/sigmoid feed straight into their cross-entropy
+            from_rows(
+                self.cost.derivate_fused_with(
+                    &output,
+                    &expected,
+                    self.layers[last_layer].get_activation(),
+                    weights,
+                    &self.reduction,
+                ),
+                self.output_shape(),
+            )
+        } else {
+            // ∂C/∂zₙ = ∂aₙ/∂zₙ * ∂C/∂aₙ
+            self.layers[last_layer].activation_backward(
+                &predictions.z[last_layer],
+                &from_rows(
+                    self.cost
+                        .derivate_with(&output, &expected, weights, &self.reduction),
+                    self.output_shape(),
+                ),
+            )
+        };
+        let mut gradients: Gradients = Gradients::new();
+
+        for i in (0..=last_layer).rev() {
+            let a_prev: &ArrayD<f32> = if i > 0 { &predictions.a[i - 1] } else { input };
+            // ∂C/∂w = ∂Z/∂w * ∂A/∂Z * ∂C/∂A
+            let (c_wrt_a, c_wrt_w, c_wrt_b) = self.layers[i].backward(
+                a_prev,
+                &self.weights[i],
+                &self.biases[i],
+                &predictions.cache[i],
+                &c_wrt_z,
+                self.layer_input_shape(i),
+                &self.shapes[i],
+            );
+            // the penalty only depends on the parameters themselves so its gradient is simply added on
+            let (penalty_w, penalty_b) = self.layers[i].penalty_gradient(
+                &self.weights[i],
+                &self.biases[i],
+                self.layer_input_shape(i),
+            );
+            gradients.weights.push(c_wrt_w + penalty_w);
+            gradients.biases.push(c_wrt_b + penalty_b);
+            if i > 0 {
+                // ∂C/∂zₙ₋₁ = ∂aₙ₋₁/∂zₙ₋₁ * ∂C/∂aₙ₋₁
+                c_wrt_z = self.layers[i - 1].activation_backward(&predictions.z[i - 1], &c_wrt_a);
             }
         }
+        // gradients were collected from the output layer backwards
+        gradients.weights.reverse();
+        gradients.biases.reverse();
+        gradients
     }
 
-    pub fn create_batches(dataset: Dataset, batch_size: usize) -> BatchedDataset {
-        let mut batches: BatchedDataset = Vec::new();
-        let mut temp_batches: Dataset = Vec::new();
+    // records a forward pass on tape with every weight and bias as a leaf, returns (output, weights, biases)
+    // every layer and activation is broken down into primitive tape ops, see Layers::record
+    pub fn record<'a>(
+        &'a self,
+        tape: &mut Tape<'a>,
+        input: Var,
+        training: bool,
+    ) -> (Var, Vec<Var>, Vec<Var>) {
+        let mut x: Var = input;
+        let mut weights: Vec<Var> = Vec::with_capacity(self.layers.len());
+        let mut biases: Vec<Var> = Vec::with_capacity(self.layers.len());
+        for (i, layer) in self.layers.iter().enumerate() {
+            let w: Var = tape.leaf(self.weights[i].clone().into_dyn());
+            let b: Var = tape.leaf(self.biases[i].clone().into_dyn());
+            let z: Var = layer.record(
+                tape,
+                x,
+                w,
+                b,
+                &self.state[i],
+                self.layer_input_shape(i),
+                &self.shapes[i],
+                training,
+            );
+            x = layer.record_activation(tape, z);
+            weights.push(w);
+            biases.push(b);
+        }
+        (x, weights, biases)
+    }
 
-        let mut batch_indices: Vec<u32> = (0..dataset.len() as u32).collect();
-        let mut rng = thread_rng();
-        batch_indices.shuffle(&mut rng);
-        for i in batch_indices {
-            temp_batches.push(dataset[i as usize].clone());
-            if temp_batches.len() % batch_size == 0 {
-                batches.push(temp_batches);
-                temp_batches = Vec::new();
+    // the gradients of loss worked out by differentiating a recorded training forward pass, which never goes
+    // through the hand written backward passes, so it's an independent check on backprop
+    // training still uses backprop since it's much faster and recurrent layers there also honour Bptt
+    pub fn tape_gradients(
+        &self,
+        input: &ArrayD<f32>,
+        expected: &ArrayD<f32>,
+        weights: Option<&Array1<f32>>,
+    ) -> Gradients {
+        self.check_input(input);
+        let mut tape: Tape = Tape::new();
+        let x: Var = tape.leaf(input.clone());
+        let (output, params, biases) = self.record(&mut tape, x, true);
+        // costs compare (batch × features) rows of the output, labels can be shaped differently
+        let shape: Vec<usize> = tape.shape(output);
+        let output: Var = tape.reshape(output, &[shape[0], shape[1..].iter().product()]);
+        let expected: Array2<f32> = to_rows(expected).into_owned();
+        let mut loss: Var =
+            self.cost
+                .record(&mut tape, output, &expected, weights, &self.reduction);
+        for i in 0..self.layers.len() {
+            if let Some(penalty) = self.layers[i].record_penalty(
+                &mut tape,
+                params[i],
+                biases[i],
+                self.layer_input_shape(i),
+            ) {
+                loss = tape.add(loss, penalty);
             }
         }
-        if temp_batches.len() != 0 {
-            batches.push(temp_batches);
+        let adjoints: Adjoints = tape.backward(loss);
+        let to_2d = |v: &Var| -> Array2<f32> {
+            adjoints
+                .wrt(*v)
+                .clone()
+                .into_dimensionality::<Ix2>()
+                .unwrap()
+        };
+        Gradients {
+            weights: params.iter().map(to_2d).collect(),
+            biases: biases.iter().map(to_2d).collect(),
         }
+    }
+
+    // shuffles the dataset and stacks every batch_size samples into one Sample
+    pub fn create_batches(dataset: Dataset, batch_size: usize) -> BatchedDataset {
+        let mut batch_indices: Vec<usize> = (0..dataset.len()).collect();
+        let mut rng = thread_rng();
+        batch_indices.shuffle(&mut rng);
+        let batches: BatchedDataset = batch_indices
+            .chunks(batch_size)
+            .map(|chunk: &[usize]| stack_samples(chunk.iter().map(|i| &dataset[*i])))
+            .collect();
         println!("Num Batches Loaded: {}", batches.len());
         batches
     }
 
-    pub fn collect_forward(&self, input: &Array2<f32>) -> Vec<Vec<Array2<f32>>> {
-        let mut z_vec = Vec::new();
-        let mut a_vec = Vec::new();
-        let mut a: Array2<f32>;
-        let mut z: Array2<f32>;
-        let mut x: &Array2<f32> = input;
-        // okay this is one of the worst places to have clone statements for array2<f32> types
-        // I'm planning to optimize this heavily but I just want a working implementation atm
+    // runs a (batch, ..input_shape) input through every layer and keeps every z, activation and cache
+    // for backprop
+    // training switches on the train-only behaviour of layers like Dropout
+    pub fn collect_forward(&self, input: &ArrayD<f32>, training: bool) -> ForwardBatch {
+        self.check_input(input);
+        let mut z_vec = Vec::with_capacity(self.layers.len());
+        let mut a_vec: Vec<ArrayD<f32>> = Vec::with_capacity(self.layers.len());
+        let mut cache_vec = Vec::with_capacity(self.layers.len());
         for i in 0..self.layers.len() {
-            z = self.layers[i].forward_propagate(x, &self.weights[i], &self.biases[i]);
-            a = self.layers[i].activate(&mut z);
-            z_vec.push(z.to_owned());
-            x = &a;
-            a_vec.push(a.to_owned());
+            let x: &ArrayD<f32> = if i > 0 { &a_vec[i - 1] } else { input };
+            let (z, cache) = self.layers[i].forward_propagate(
+                x,
+                &self.weights[i],
+                &self.biases[i],
+                &self.state[i],
+                self.layer_input_shape(i),
+                &self.shapes[i],
+                training,
+            );
+            a_vec.push(self.layers[i].activate(&z));
+            z_vec.push(z);
+            cache_vec.push(cache);
         }
-        //println!("z: {:?}\n\na: {:?}\n\n", z_vec.clone(), a_vec.clone());
-        vec![z_vec, a_vec]
+        ForwardBatch {
+            z: z_vec,
+            a: a_vec,
+            cache: cache_vec,
+        }
+    }
+}
+
+// starting weights of layer for samples of input_shape
+pub fn init_weights(layer: &Layers, input_shape: &[usize]) -> Array2<f32> {
+    let (rows, cols) = layer.parameter_shapes(input_shape).0;
+    let dim: &mut Vec<usize> = &mut vec![rows, cols];
+    if rows * cols == 0 {
+        return create_weight(dim);
+    }
+    if let Some(weights) = layer.initial_weights(input_shape) {
+        return weights;
+    }
+    // converting the string back and forth like this is ugly as fuck
+    println!("{:?}", dim);
+    match &layer.get_init_func().to_string().to_lowercase()[..] {
+        "xavier" | "glorot" => init_xavier(dim),
+        "kaiming" | "he" => init_he(dim),
+        "ones" => Array2::ones((rows, cols)),
+        //"lecun" => init_lecun(dim)
+        _ => init_rand(dim),
     }
 }
 
+pub fn init_biases(layer: &Layers, input_shape: &[usize]) -> Array2<f32> {
+    let (rows, cols) = layer.parameter_shapes(input_shape).1;
+    create_weight::<Ix2>(&vec![rows, cols])
+}
+
 impl Net for Sequential {
     fn add(&mut self, layer: Layers) {
-        // push the layer to the network's layer vector
+        // work out the shape coming out of the layer first so a mismatch fails here and not inside a dot
+        let shape: Vec<usize> = layer
+            .output_shape(self.output_shape())
+            .unwrap_or_else(|e| panic!("can't add layer {}: {}", self.layers.len(), e));
 
         self.generate_weights(&layer);
         self.generate_biases(&layer);
+        self.generate_state(&layer);
 
+        // push the layer to the network's layer vector
         self.layers.push(layer);
+        self.shapes.push(shape);
     }
 
     fn summary(&self) {
         for i in 0..self.layers.len() {
             println!(
-                "{} / Dimensions: {:?}",
+                "{} / Dimensions: {:?} / Output: {:?}",
                 self.layers[i].display(),
-                self.weights[i].shape()
+                self.weights[i].shape(),
+                self.shapes[i]
             );
+            for line in self.layers[i].inner_summary(self.layer_input_shape(i)) {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+impl Trainable for Sequential {
+    fn weights(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.weights
+    }
+
+    fn biases(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.biases
+    }
+
+    fn state(&mut self) -> &mut Vec<Array2<f32>> {
+        &mut self.state
+    }
+
+    fn parameters(&self) -> (&[Array2<f32>], &[Array2<f32>]) {
+        (&self.weights, &self.biases)
+    }
+
+    fn decay_masks(&self) -> Vec<Array2<f32>> {
+        (0..self.layers.len())
+            .map(|i| self.layers[i].decay_mask(self.layer_input_shape(i)))
+            .collect()
+    }
+
+    fn set_stop_training(&mut self, stop: bool) {
+        self.stop_training = stop;
+    }
+
+    fn stop_training(&self) -> bool {
+        self.stop_training
+    }
+
+    fn save(&self, path: &Path) -> Result<(), ModelError> {
+        Sequential::save(self, path)
+    }
+}
+
+impl Fit for Sequential {
+    type Data = Dataset;
+    // every batch is stacked into a single (batch × features) sample
+    type Batch = Sample;
+
+    fn split(data: Dataset, fraction: f32) -> (Dataset, Dataset) {
+        split_dataset(data, fraction)
+    }
+
+    fn batches(data: Dataset, batch_size: usize) -> BatchedDataset {
+        Self::create_batches(data, batch_size)
+    }
+
+    fn train_batch(&mut self, batch: &Sample, optimizer: &mut dyn Optimizer) -> (f32, f32) {
+        let predictions: ForwardBatch = self.collect_forward(&batch.0, true);
+        for (j, layer) in self.layers.iter().enumerate() {
+            let input_shape: Vec<usize> = self.layer_input_shape(j).to_vec();
+            layer.update_state(&mut self.state[j], &predictions.cache[j], &input_shape);
         }
+        let output: &ArrayD<f32> = predictions.a.last().unwrap();
+
+        let cost: f32 = self.loss(output, &batch.1, Some(&batch.2));
+        let accuracy: f32 = accuracy(
+            &to_rows(output).into_owned(),
+            &to_rows(&batch.1).into_owned(),
+            self.cost.threshold(),
+        );
+
+        let gradients: Gradients = self.backprop(&predictions, &batch.0, &batch.1, Some(&batch.2));
+        optimizer.step(&mut self.weights, &mut self.biases, &gradients);
+        (cost, accuracy)
+    }
+
+    fn validate(&self, data: &Dataset) -> Evaluation {
+        self.evaluate(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activations::Activations, optimizers::Optimizers, regularizers::Regularizer};
+    use ndarray::{array, Array, Axis, IxDyn};
+
+    fn dense(units: usize, activation: Activations) -> Layers {
+        Layers::Dense {
+            units,
+            activation,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        }
+    }
+
+    // the same model with and without regularizers differs by exactly the penalty and its gradient
+    #[test]
+    fn regularizers_add_their_penalty_to_the_loss_and_the_gradients() {
+        let mut plain: Sequential = Sequential::new(3, Cost::MSE);
+        plain.add(dense(2, Activations::Tanh));
+        plain.biases[0].fill(0.5);
+        let mut regularized: Sequential = Sequential::new(3, Cost::MSE);
+        regularized.add(Layers::Dense {
+            units: 2,
+            activation: Activations::Tanh,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::ElasticNet { l1: 0.01, l2: 0.02 },
+            bias_regularizer: Regularizer::L2(0.05),
+        });
+        regularized.weights = plain.weights.clone();
+        regularized.biases = plain.biases.clone();
+        let x: ArrayD<f32> = inputs(&[4, 3]);
+        let y: ArrayD<f32> = inputs(&[4, 2]);
+        let predicted: ArrayD<f32> = plain.predict(&x);
+        let w: &Array2<f32> = &plain.weights[0];
+        let penalty: f32 = w
+            .iter()
+            .map(|w: &f32| 0.01 * w.abs() + 0.02 * w * w)
+            .sum::<f32>()
+            + 0.05 * 0.25 * 2.;
+        let difference: f32 =
+            regularized.loss(&predicted, &y, None) - plain.loss(&predicted, &y, None);
+        assert!((difference - penalty).abs() < 1e-6);
+
+        let g: Gradients = plain.backprop(&plain.collect_forward(&x, true), &x, &y, None);
+        let r: Gradients =
+            regularized.backprop(&regularized.collect_forward(&x, true), &x, &y, None);
+        let kernel: Array2<f32> = w.mapv(|w: f32| 0.01 * w.signum() + 0.04 * w);
+        assert!((&r.weights[0] - &g.weights[0] - kernel)
+            .iter()
+            .all(|d: &f32| d.abs() < 1e-6));
+        // 2 * 0.05 * 0.5
+        assert!((&r.biases[0] - &g.biases[0])
+            .iter()
+            .all(|d: &f32| (d - 0.05).abs() < 1e-6));
+    }
+
+    #[test]
+    fn sample_weights_reach_the_gradients() {
+        let mut model: Sequential = Sequential::new(3, Cost::CrossEntropy);
+        model.add(dense(4, Activations::Tanh));
+        model.add(dense(2, Activations::Softmax { temperature: 1. }));
+        model.reduction = Reduction::Sum;
+        let x: ArrayD<f32> = array![[0.5, -1., 2.], [1., 0.3, -0.7]].into_dyn();
+        let y: ArrayD<f32> = array![[1., 0.], [0., 1.]].into_dyn();
+        // weighing the second sample 0 leaves only the first one
+        let weighted: Gradients = model.backprop(
+            &model.collect_forward(&x, true),
+            &x,
+            &y,
+            Some(&array![1., 0.]),
+        );
+        let first: ArrayD<f32> = x.index_axis(Axis(0), 0).insert_axis(Axis(0)).to_owned();
+        let label: ArrayD<f32> = y.index_axis(Axis(0), 0).insert_axis(Axis(0)).to_owned();
+        let alone: Gradients =
+            model.backprop(&model.collect_forward(&first, true), &first, &label, None);
+        for (a, b) in weighted.weights.iter().zip(alone.weights.iter()) {
+            assert!((a - b).iter().all(|d: &f32| d.abs() < 1e-6));
+        }
+        assert_eq!(
+            model.loss(&model.predict(&x), &y, Some(&array![1., 0.])),
+            model.loss(&model.predict(&first), &label, None)
+        );
+    }
+
+    // every layer gets a gradient laid out like its parameters, and one step moves all of them, the output
+    // layer included
+    #[test]
+    fn gradients_mirror_the_parameters_and_every_layer_is_updated() {
+        let mut model: Sequential = Sequential::new(3, Cost::CrossEntropy);
+        model.add(dense(5, Activations::Tanh));
+        model.add(dense(4, Activations::Sigmoid));
+        model.add(dense(2, Activations::Softmax { temperature: 1. }));
+        let x: ArrayD<f32> = inputs(&[4, 3]);
+        let y: ArrayD<f32> = array![[1., 0.], [0., 1.], [0., 1.], [1., 0.]].into_dyn();
+        let gradients: Gradients = model.backprop(&model.collect_forward(&x, true), &x, &y, None);
+        assert_eq!(gradients.weights.len(), model.weights.len());
+        for (g, w) in gradients.weights.iter().zip(model.weights.iter()) {
+            assert_eq!(g.dim(), w.dim());
+        }
+        for (g, b) in gradients.biases.iter().zip(model.biases.iter()) {
+            assert_eq!(g.dim(), b.dim());
+        }
+
+        let (weights, biases) = (model.weights.clone(), model.biases.clone());
+        let mut optimizer = Optimizers::SGD.build(0.1, &model);
+        model.train_batch(&Sample::new(x, y), &mut optimizer);
+        for i in 0..model.layers.len() {
+            assert!(model.weights[i] != weights[i], "weights of layer {}", i);
+            assert!(model.biases[i] != biases[i], "biases of layer {}", i);
+        }
+    }
+
+    // the whole batch in one pass gives each sample's prediction, and the mean of each sample's gradients
+    #[test]
+    fn batches_match_samples_run_one_at_a_time() {
+        let mut model: Sequential = Sequential::new(3, Cost::CrossEntropy);
+        model.add(dense(5, Activations::Tanh));
+        model.add(dense(2, Activations::Softmax { temperature: 1. }));
+        let x: ArrayD<f32> = inputs(&[4, 3]);
+        let y: ArrayD<f32> = array![[1., 0.], [0., 1.], [0., 1.], [1., 0.]].into_dyn();
+        let batched: Gradients = model.backprop(&model.collect_forward(&x, true), &x, &y, None);
+        let predicted: ArrayD<f32> = model.predict(&x);
+        let mut summed: Gradients = Gradients::zeros_like(&model.weights, &model.biases);
+        for i in 0..4 {
+            let sample = |a: &ArrayD<f32>| a.index_axis(Axis(0), i).insert_axis(Axis(0)).to_owned();
+            let (xi, yi) = (sample(&x), sample(&y));
+            let alone: ArrayD<f32> = model.predict(&xi);
+            assert!((&alone - &sample(&predicted))
+                .iter()
+                .all(|d: &f32| d.abs() < 1e-6));
+            let g: Gradients = model.backprop(&model.collect_forward(&xi, true), &xi, &yi, None);
+            for (s, w) in summed.weights.iter_mut().zip(g.weights.iter()) {
+                *s += w;
+            }
+            for (s, b) in summed.biases.iter_mut().zip(g.biases.iter()) {
+                *s += b;
+            }
+        }
+        for (b, s) in batched.weights.iter().zip(summed.weights.iter()) {
+            assert!((b - &(s / 4.)).iter().all(|d: &f32| d.abs() < 1e-6));
+        }
+        for (b, s) in batched.biases.iter().zip(summed.biases.iter()) {
+            assert!((b - &(s / 4.)).iter().all(|d: &f32| d.abs() < 1e-6));
+        }
+    }
+
+    // deterministic inputs in [-1, 1] for a batch of the given shape
+    fn inputs(shape: &[usize]) -> ArrayD<f32> {
+        let n: usize = shape.iter().product();
+        Array::from_iter((0..n).map(|i| ((i * 13 + 5) as f32 * 0.71).sin()))
+            .into_shape(IxDyn(shape))
+            .unwrap()
+    }
+
+    // the tape never goes through Layers::backward, Activations::backward or Cost::derivate, so agreeing
+    // with backprop checks every one of them
+    fn assert_tape_matches_backprop(model: &Sequential, x: &ArrayD<f32>, y: &ArrayD<f32>) {
+        let weights: Array1<f32> = Array::linspace(0.5, 1.5, x.shape()[0]);
+        let backprop: Gradients =
+            model.backprop(&model.collect_forward(x, true), x, y, Some(&weights));
+        let tape: Gradients = model.tape_gradients(x, y, Some(&weights));
+        let pairs = backprop
+            .weights
+            .iter()
+            .zip(tape.weights.iter())
+            .chain(backprop.biases.iter().zip(tape.biases.iter()));
+        for (i, (expected, recorded)) in pairs.enumerate() {
+            // relative to the largest gradient since some of them are tiny
+            let scale: f32 = expected.iter().fold(1e-6, |m: f32, g: &f32| m.max(g.abs()));
+            let error: f32 = (expected - recorded)
+                .iter()
+                .fold(0f32, |m: f32, e: &f32| m.max(e.abs()));
+            assert!(
+                error < 1e-3 * scale,
+                "parameter {} is off by {} (largest gradient {})",
+                i % model.layers.len(),
+                error,
+                scale
+            );
+        }
+    }
+
+    #[test]
+    fn tape_matches_backprop_on_images() {
+        let mut model: Sequential = Sequential::with_input_shape(&[2, 5, 5], Cost::CrossEntropy);
+        model.add(Layers::Conv2D {
+            filters: 3,
+            kernel_size: (2, 2),
+            stride: 1,
+            padding: 1,
+            activation: Activations::Tanh,
+            init_func: String::from("he"),
+            kernel_regularizer: Regularizer::ElasticNet { l1: 0.01, l2: 0.02 },
+            bias_regularizer: Regularizer::L2(0.05),
+        });
+        model.add(Layers::BatchNorm {
+            momentum: 0.9,
+            epsilon: 1e-3,
+        });
+        model.add(Layers::MaxPool2D {
+            pool_size: (2, 2),
+            stride: 1,
+        });
+        model.add(Layers::AvgPool2D {
+            pool_size: (2, 2),
+            stride: 2,
+        });
+        model.add(Layers::Flatten);
+        model.add(dense(3, Activations::Softmax { temperature: 1. }));
+        let y: ArrayD<f32> = array![[1., 0., 0.], [0., 0., 1.], [0., 1., 0.]].into_dyn();
+        assert_tape_matches_backprop(&model, &inputs(&[3, 2, 5, 5]), &y);
+    }
+
+    #[test]
+    fn tape_matches_backprop_in_containers() {
+        let mut model: Sequential = Sequential::new(4, Cost::Huber { delta: 0.5 });
+        model.add(Layers::Residual {
+            block: vec![
+                Layers::LayerNorm { epsilon: 1e-3 },
+                Layers::PReLU { alpha: 0.2 },
+                Layers::Dense {
+                    units: 4,
+                    activation: Activations::GELU,
+                    init_func: String::from("xavier"),
+                    kernel_regularizer: Regularizer::L1(0.01),
+                    bias_regularizer: Regularizer::None,
+                },
+            ],
+        });
+        // a rate of 0 keeps every input, so the mask backprop draws is the same as the tape's
+        model.add(Layers::Dropout { rate: 0. });
+        model.add(Layers::Parallel {
+            branches: vec![
+                vec![dense(3, Activations::Sigmoid)],
+                vec![
+                    Layers::BatchNorm {
+                        momentum: 0.9,
+                        epsilon: 1e-3,
+                    },
+                    dense(2, Activations::SoftPlus),
+                ],
+            ],
+        });
+        model.add(dense(2, Activations::Linear));
+        let y: ArrayD<f32> = inputs(&[5, 2]).mapv(|v: f32| v * 2.);
+        model.reduction = Reduction::Sum;
+        assert_tape_matches_backprop(&model, &inputs(&[5, 4]), &y);
+    }
+
+    // sparse labels are (batch × 1) while the output is (batch × classes), which the tape used to
+    // reshape the output to
+    #[test]
+    fn tape_matches_backprop_on_sequences() {
+        let mut model: Sequential =
+            Sequential::with_input_shape(&[4], Cost::SparseCategoricalCrossEntropy);
+        model.add(Layers::Embedding {
+            vocab_size: 6,
+            dim: 4,
+            padding_idx: Some(0),
+        });
+        model.add(Layers::PositionalEncoding {
+            learned: true,
+            mask_padding: true,
+        });
+        model.add(Layers::MultiHeadAttention {
+            heads: 2,
+            causal: true,
+            mask_padding: true,
+            init_func: String::from("xavier"),
+        });
+        model.add(Layers::TransformerEncoderBlock {
+            heads: 2,
+            ff_units: 5,
+            causal: false,
+            mask_padding: true,
+            epsilon: 1e-3,
+        });
+        model.add(Layers::LSTM {
+            units: 3,
+            init_func: String::from("xavier"),
+            return_sequences: true,
+            truncate: None,
+            clip_norm: None,
+        });
+        model.add(Layers::GRU {
+            units: 3,
+            init_func: String::from("xavier"),
+            return_sequences: true,
+            truncate: None,
+            clip_norm: None,
+        });
+        model.add(Layers::SimpleRNN {
+            units: 3,
+            activation: Activations::Tanh,
+            init_func: String::from("xavier"),
+            return_sequences: false,
+            truncate: None,
+            clip_norm: None,
+        });
+        model.add(dense(3, Activations::Softmax { temperature: 1. }));
+        let x: ArrayD<f32> =
+            array![[3., 1., 5., 0.], [2., 4., 0., 0.], [1., 1., 2., 3.]].into_dyn();
+        let y: ArrayD<f32> = array![[2.], [0.], [1.]].into_dyn();
+        assert_tape_matches_backprop(&model, &x, &y);
     }
 }
@@ -1,71 +1,337 @@
-use crate::{matrixutil::transpose, netutil::Sequential, typings::ForwardBatch};
+#![allow(dead_code)]
+use crate::{training::Trainable, typings::Gradients};
 use ndarray::Array2;
 
+// anything that can turn a set of gradients into a parameter update
+// params and grads are both laid out in forward order, one entry per layer
+pub trait Optimizer {
+    fn step(&mut self, weights: &mut [Array2<f32>], biases: &mut [Array2<f32>], grads: &Gradients);
+    fn learning_rate(&self) -> f32;
+    // lets schedules change the step size between updates
+    fn set_learning_rate(&mut self, lr: f32);
+}
+
+// enum storing each optimizer along with its hyperparameters
 pub enum Optimizers {
     SGD,
+    // v = μv - lr*g, w = w + v (or w + μv - lr*g with nesterov lookahead)
+    Momentum {
+        momentum: f32,
+        nesterov: bool,
+    },
+    // s = s + g², w = w - lr*g/(√s+ε)
+    AdaGrad {
+        epsilon: f32,
+    },
+    // s = ρs + (1-ρ)g², w = w - lr*g/(√s+ε)
+    RMSProp {
+        rho: f32,
+        epsilon: f32,
+    },
+    // m = β₁m + (1-β₁)g, v = β₂v + (1-β₂)g², w = w - lr*m̂/(√v̂+ε)
+    Adam {
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    },
+    // adam with decoupled weight decay: w = w - lr*(m̂/(√v̂+ε) + λw)
+    // λw only applies to kernels, see Layers::decay_mask
+    AdamW {
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+        weight_decay: f32,
+    },
+}
+
+// one of the built-in optimizers bound to a learning rate and the per-parameter buffers it accumulates
+pub struct OptimizerState {
+    pub optimizer: Optimizers,
+    pub lr: f32,
+    pub step: i32,
+    pub first_moment: Gradients,
+    pub second_moment: Gradients,
+    // Trainable::decay_masks of the model the optimizer was built for
+    pub decay: Vec<Array2<f32>>,
 }
 
 impl Optimizers {
-    pub fn backward(
+    // the moment buffers are sized from model's parameters here, once, so the optimizer can only step
+    // models with the same layers
+    pub fn build(self, lr: f32, model: &dyn Trainable) -> OptimizerState {
+        let (weights, biases) = model.parameters();
+        OptimizerState {
+            optimizer: self,
+            lr,
+            step: 0,
+            first_moment: Gradients::zeros_like(weights, biases),
+            second_moment: Gradients::zeros_like(weights, biases),
+            decay: model.decay_masks(),
+        }
+    }
+
+    // applies the update rule to a single parameter and its moment buffers
+    // decay marks the entries weight decay applies to, None for parameters that are never decayed
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
         &self,
-        network: &Sequential,
-        predictions: &ForwardBatch,
-        input: &Vec<Array2<f32>>,
-        expected: &Vec<Array2<f32>>,
-    ) -> Vec<Vec<Array2<f32>>> {
+        step: i32,
+        m: &mut Array2<f32>,
+        v: &mut Array2<f32>,
+        param: &mut Array2<f32>,
+        grad: &Array2<f32>,
+        lr: f32,
+        decay: Option<&Array2<f32>>,
+    ) {
         match self {
-            Optimizers::SGD => {
-                let batch_size: usize = predictions.len();
-                let last_pred: usize = predictions[0][0].len();
-                let mut c_wrt_z: Array2<f32> = Array2::<f32>::zeros((1, 1));
-                let mut c_wrt_a: Array2<f32>;
-                let mut weight_updates: Vec<Array2<f32>> = Vec::new();
-                let mut bias_updates: Vec<Array2<f32>> = Vec::new();
-
-                //TODO: store transposes of the weights every batch to avoid creating a new transpose array for every batch
-
-                for j in 0..batch_size {
-                    for i in (0..last_pred).rev() {
-                        let mut z = &predictions[j][0][i];
-                        let a_prev = if i > 0 {
-                            &predictions[j][1][i - 1]
-                        } else {
-                            &input[j]
-                        };
-                        // ∂C/∂w = ∂Z/∂w * ∂A/∂Z * ∂C/∂A
-                        if i == last_pred - 1 {
-                            // ∂C/∂zₙ = ∂aₙ/∂zₙ * ∂C/∂aₙ
-                            c_wrt_z = network.layers[i].derivate_activation(&mut z)
-                                * network.cost.derivate(&predictions, &expected);
-                        } else {
-                            // ∂C/∂aₙ₋₁ = ∂zₙ/∂aₙ₋₁ * ∂C/∂zₙ
-                            // ∂zₙ/∂aₙ₋₁ = wₙ.T
-                            c_wrt_a = c_wrt_z.dot(&transpose(&network.weights[i + 1]));
-                            // ∂C/∂zₙ₋₁ = ∂aₙ₋₁/∂zₙ₋₁ * ∂C/∂aₙ₋₁
-                            c_wrt_z = network.layers[i].derivate_activation(z) * c_wrt_a;
-                        }
-
-                        // on the first batch, fill the vectors
-                        if j == 0 {
-                            // ∂C/∂bₙ = ∂Z/∂bₙ * ∂A/∂Z * ∂C/∂A
-                            // ∂Z/∂bₙ = 1
-                            // ∂C/∂bₙ = 1 * c_wrt_z = c_wrt_z
-                            bias_updates.push(c_wrt_z.clone());
-                            // ∂C/∂wₙ = ∂zₙ/∂wₙ * c_wrt_z
-                            // Z(w,X,b) = w.X + b
-                            // ∂Z/∂w = Xᵀ
-                            weight_updates.push(transpose(a_prev).dot(&c_wrt_z));
-                        } else {
-                            let curr = last_pred - (1usize + i);
-                            // on batches after the first, accumulate updates
-                            bias_updates[curr] = &bias_updates[curr] + &c_wrt_z;
-                            weight_updates[curr] =
-                                &weight_updates[curr] + &transpose(a_prev).dot(&c_wrt_z);
-                        }
-                    }
+            Optimizers::SGD => param.zip_mut_with(grad, |w, g| *w -= lr * g),
+            Optimizers::Momentum { momentum, nesterov } => {
+                m.zip_mut_with(grad, |v, g| *v = momentum * *v - lr * g);
+                if *nesterov {
+                    // look ahead along the updated velocity before applying the gradient step
+                    let step: Array2<f32> = m.mapv(|v| momentum * v) - grad * lr;
+                    *param += &step;
+                } else {
+                    *param += &*m;
+                }
+            }
+            Optimizers::AdaGrad { epsilon } => {
+                v.zip_mut_with(grad, |s, g| *s += g * g);
+                let step: Array2<f32> = grad / &v.mapv(|s| s.sqrt() + epsilon);
+                *param -= &(step * lr);
+            }
+            Optimizers::RMSProp { rho, epsilon } => {
+                v.zip_mut_with(grad, |s, g| *s = rho * *s + (1. - rho) * g * g);
+                let step: Array2<f32> = grad / &v.mapv(|s| s.sqrt() + epsilon);
+                *param -= &(step * lr);
+            }
+            Optimizers::Adam {
+                beta1,
+                beta2,
+                epsilon,
+            }
+            | Optimizers::AdamW {
+                beta1,
+                beta2,
+                epsilon,
+                ..
+            } => {
+                m.zip_mut_with(grad, |m, g| *m = beta1 * *m + (1. - beta1) * g);
+                v.zip_mut_with(grad, |v, g| *v = beta2 * *v + (1. - beta2) * g * g);
+                // bias correction so the zero-initialized moments don't shrink the first steps
+                let m_hat: Array2<f32> = m.mapv(|m| m / (1. - beta1.powi(step)));
+                let v_hat: Array2<f32> = v.mapv(|v| v / (1. - beta2.powi(step)));
+                let mut step: Array2<f32> = m_hat / v_hat.mapv(|v| v.sqrt() + epsilon);
+                if let (Optimizers::AdamW { weight_decay, .. }, Some(mask)) = (self, decay) {
+                    step += &(&*param * mask * *weight_decay);
                 }
-                vec![weight_updates, bias_updates]
+                *param -= &(step * lr);
             }
         }
     }
 }
+
+impl Optimizer for OptimizerState {
+    fn step(&mut self, weights: &mut [Array2<f32>], biases: &mut [Array2<f32>], grads: &Gradients) {
+        if weights.len() != self.decay.len() {
+            panic!(
+                "the optimizer was built for a model with {} layers but got {}",
+                self.decay.len(),
+                weights.len()
+            );
+        }
+        self.step += 1;
+        for i in 0..weights.len() {
+            self.optimizer.update(
+                self.step,
+                &mut self.first_moment.weights[i],
+                &mut self.second_moment.weights[i],
+                &mut weights[i],
+                &grads.weights[i],
+                self.lr,
+                Some(&self.decay[i]),
+            );
+            self.optimizer.update(
+                self.step,
+                &mut self.first_moment.biases[i],
+                &mut self.second_moment.biases[i],
+                &mut biases[i],
+                &grads.biases[i],
+                self.lr,
+                None,
+            );
+        }
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f32) {
+        self.lr = lr;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activations::Activations,
+        cost::Cost,
+        layers::Layers,
+        netutil::{Net, Sequential},
+        regularizers::Regularizer,
+    };
+    use ndarray::array;
+
+    // w = [1, -2] after two steps of lr 0.1 with the gradients [0.5, -1] then [1, 0.5]
+    // only the first entry is marked for weight decay
+    fn two_steps(optimizer: Optimizers) -> Vec<Array2<f32>> {
+        let mut w: Array2<f32> = array![[1., -2.]];
+        let mut m: Array2<f32> = Array2::zeros((1, 2));
+        let mut v: Array2<f32> = Array2::zeros((1, 2));
+        let decay: Array2<f32> = array![[1., 0.]];
+        let grads: [Array2<f32>; 2] = [array![[0.5, -1.]], array![[1., 0.5]]];
+        let mut steps: Vec<Array2<f32>> = Vec::new();
+        for (step, g) in grads.iter().enumerate() {
+            optimizer.update(
+                step as i32 + 1,
+                &mut m,
+                &mut v,
+                &mut w,
+                g,
+                0.1,
+                Some(&decay),
+            );
+            steps.push(w.clone());
+        }
+        steps
+    }
+
+    fn assert_steps(optimizer: Optimizers, expected: [[f32; 2]; 2]) {
+        for (step, (w, e)) in two_steps(optimizer).iter().zip(expected.iter()).enumerate() {
+            for (a, b) in w.iter().zip(e.iter()) {
+                assert!((a - b).abs() < 1e-5, "step {}: {} != {:?}", step + 1, w, e);
+            }
+        }
+    }
+
+    #[test]
+    fn momentum_accumulates_velocity() {
+        // v = [-0.05, 0.1] then 0.9v - 0.1g = [-0.145, 0.04]
+        assert_steps(
+            Optimizers::Momentum {
+                momentum: 0.9,
+                nesterov: false,
+            },
+            [[0.95, -1.9], [0.805, -1.86]],
+        );
+    }
+
+    #[test]
+    fn nesterov_looks_ahead_along_the_velocity() {
+        // w += 0.9v - 0.1g with the updated v: [-0.095, 0.19] then [-0.2305, -0.014]
+        assert_steps(
+            Optimizers::Momentum {
+                momentum: 0.9,
+                nesterov: true,
+            },
+            [[0.905, -1.81], [0.6745, -1.824]],
+        );
+    }
+
+    #[test]
+    fn adagrad_divides_by_the_summed_squares() {
+        // s = [0.25, 1] gives steps of ±1, then s = [1.25, 1.25] gives [1, 0.5] / √1.25
+        assert_steps(
+            Optimizers::AdaGrad { epsilon: 1e-7 },
+            [[0.9, -1.9], [0.810557, -1.944721]],
+        );
+    }
+
+    #[test]
+    fn rmsprop_divides_by_the_decaying_squares() {
+        // s = [0.025, 0.1] gives steps of ±√10, then s = [0.1225, 0.115]
+        assert_steps(
+            Optimizers::RMSProp {
+                rho: 0.9,
+                epsilon: 1e-7,
+            },
+            [[0.683772, -1.683772], [0.398058, -1.831214]],
+        );
+    }
+
+    #[test]
+    fn adam_corrects_the_moment_bias() {
+        // m̂ = g and v̂ = g² on the first step so it moves every entry by exactly lr
+        // then m = [0.145, -0.04], v = [0.00124975, 0.001249] over 1 - 0.9² and 1 - 0.999²
+        assert_steps(
+            Optimizers::Adam {
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-7,
+            },
+            [[0.9, -1.9], [0.803482, -1.873366]],
+        );
+    }
+
+    #[test]
+    fn adamw_decays_only_the_marked_entries() {
+        // adam's steps plus 0.1 * 0.01w on the first entry, the second one matches adam exactly
+        assert_steps(
+            Optimizers::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-7,
+                weight_decay: 0.01,
+            },
+            [[0.899, -1.9], [0.801583, -1.873366]],
+        );
+    }
+
+    #[test]
+    fn adamw_leaves_biases_and_normalization_alone() {
+        let mut model: Sequential = Sequential::new(3, Cost::MSE);
+        model.add(Layers::Dense {
+            units: 4,
+            activation: Activations::Tanh,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        });
+        model.add(Layers::BatchNorm {
+            momentum: 0.9,
+            epsilon: 1e-5,
+        });
+        model.add(Layers::LayerNorm { epsilon: 1e-5 });
+        for b in model.biases.iter_mut() {
+            b.fill(0.5);
+        }
+        let mut optimizer: OptimizerState = Optimizers::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-7,
+            weight_decay: 0.1,
+        }
+        .build(0.5, &model);
+        // the buffers already match every parameter before the first step
+        for (m, w) in optimizer
+            .first_moment
+            .weights
+            .iter()
+            .zip(model.weights.iter())
+        {
+            assert_eq!(m.dim(), w.dim());
+        }
+        let (weights, biases) = (model.weights.clone(), model.biases.clone());
+        // with zero gradients adam's own step is 0, so anything that moves was decayed
+        let zeros: Gradients = Gradients::zeros_like(&weights, &biases);
+        optimizer.step(&mut model.weights, &mut model.biases, &zeros);
+        let shrunk: Array2<f32> = &weights[0] * 0.95;
+        assert!((&model.weights[0] - &shrunk)
+            .iter()
+            .all(|d: &f32| d.abs() < 1e-6));
+        assert_eq!(model.weights[1..], weights[1..]);
+        assert_eq!(model.biases, biases);
+    }
+}
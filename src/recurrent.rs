@@ -0,0 +1,411 @@
+#![allow(dead_code)]
+use crate::{
+    activations::Activations,
+    autograd::{Tape, Var},
+};
+use ndarray::{s, stack, Array2, ArrayView2, Axis};
+
+// the step function a recurrent layer applies at every timestep
+// sequences come in as (batch × time*features) rows and weights are stored as [W_x; W_h] with the
+// gates side by side, so one step is x_t.W_x + h_{t-1}.W_h + b
+pub enum Cell<'a> {
+    // h = act(x.W_x + h.W_h + b)
+    Simple(&'a Activations),
+    // gates in the order input, forget, candidate, output
+    LSTM,
+    // gates in the order update, reset, candidate, with the reset gate applied after h.W_h
+    GRU,
+}
+
+// how the hidden state gradient is carried back through time
+pub struct Bptt {
+    // cut the gradient flowing between timesteps every this many steps, counting back from the end
+    pub truncate: Option<usize>,
+    // rescale the layer's weight and bias gradients so their combined L2 norm is at most this
+    pub clip_norm: Option<f32>,
+}
+
+impl<'a> Cell<'a> {
+    pub fn gates(&self) -> usize {
+        match self {
+            Cell::Simple(_) => 1,
+            Cell::LSTM => 4,
+            Cell::GRU => 3,
+        }
+    }
+
+    // how many (batch × units) values every timestep keeps for the backward pass
+    fn cached(&self) -> usize {
+        match self {
+            // z, h
+            Cell::Simple(_) => 2,
+            // i, f, g, o, c, h
+            Cell::LSTM => 6,
+            // z, r, n, (h.W_h)_n, h
+            Cell::GRU => 5,
+        }
+    }
+
+    // runs the whole sequence and returns (output, cache) where output is every hidden state side by
+    // side when return_sequences is set and only the last one otherwise
+    pub fn forward(
+        &self,
+        input: &ArrayView2<f32>,
+        weights: &Array2<f32>,
+        bias: &Array2<f32>,
+        steps: usize,
+        units: usize,
+        return_sequences: bool,
+    ) -> (Array2<f32>, Array2<f32>) {
+        let batch: usize = input.nrows();
+        let features: usize = input.ncols() / steps;
+        let w_x: ArrayView2<f32> = weights.slice(s![..features, ..]);
+        let w_h: ArrayView2<f32> = weights.slice(s![features.., ..]);
+        let width: usize = self.cached() * units;
+        let mut cache: Array2<f32> = Array2::zeros((batch, steps * width));
+        let mut h: Array2<f32> = Array2::zeros((batch, units));
+        let mut c: Array2<f32> = Array2::zeros((batch, units));
+
+        for t in 0..steps {
+            let x_t: ArrayView2<f32> = input.slice(s![.., t * features..(t + 1) * features]);
+            let a_x: Array2<f32> = x_t.dot(&w_x) + bias;
+            let a_h: Array2<f32> = h.dot(&w_h);
+            let gate =
+                |a: &Array2<f32>, k: usize| a.slice(s![.., k * units..(k + 1) * units]).to_owned();
+            let parts: Vec<Array2<f32>> = match self {
+                Cell::Simple(activation) => {
+                    let z: Array2<f32> = a_x + a_h;
+                    h = activation.activate(&z);
+                    vec![z, h.clone()]
+                }
+                Cell::LSTM => {
+                    let a: Array2<f32> = a_x + a_h;
+                    let i: Array2<f32> = sigmoid(&gate(&a, 0));
+                    let f: Array2<f32> = sigmoid(&gate(&a, 1));
+                    let g: Array2<f32> = gate(&a, 2).mapv(f32::tanh);
+                    let o: Array2<f32> = sigmoid(&gate(&a, 3));
+                    c = &f * &c + &i * &g;
+                    h = &o * &c.mapv(f32::tanh);
+                    vec![i, f, g, o, c.clone(), h.clone()]
+                }
+                Cell::GRU => {
+                    let z: Array2<f32> = sigmoid(&(gate(&a_x, 0) + gate(&a_h, 0)));
+                    let r: Array2<f32> = sigmoid(&(gate(&a_x, 1) + gate(&a_h, 1)));
+                    let h_n: Array2<f32> = gate(&a_h, 2);
+                    let n: Array2<f32> = (gate(&a_x, 2) + &r * &h_n).mapv(f32::tanh);
+                    // h = (1-z)n + z h_{t-1}
+                    h = &n + &(&z * &(&h - &n));
+                    vec![z, r, n, h_n, h.clone()]
+                }
+            };
+            for (k, part) in parts.iter().enumerate() {
+                let start: usize = t * width + k * units;
+                cache.slice_mut(s![.., start..start + units]).assign(part);
+            }
+        }
+
+        let output: Array2<f32> = if return_sequences {
+            let hidden: usize = (self.cached() - 1) * units;
+            let states: Vec<ArrayView2<f32>> = (0..steps)
+                .map(|t| cache.slice(s![.., t * width + hidden..(t + 1) * width]))
+                .collect();
+            stack(Axis(1), &states).unwrap()
+        } else {
+            h
+        };
+        (output, cache)
+    }
+
+    // forward recorded on tape for a (batch × time*features) input, unrolled one timestep at a time
+    // the tape differentiates the whole unrolled sequence, so Bptt's truncation and clipping don't apply
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        tape: &mut Tape<'a>,
+        input: Var,
+        weights: Var,
+        bias: Var,
+        steps: usize,
+        units: usize,
+        return_sequences: bool,
+    ) -> Var {
+        let shape: Vec<usize> = tape.shape(input);
+        let (batch, features): (usize, usize) = (shape[0], shape[1] / steps);
+        let w_x: Var = tape.slice(weights, 0, 0, features);
+        let w_h: Var = tape.slice(weights, 0, features, features + units);
+        let mut h: Var = tape.leaf(Array2::<f32>::zeros((batch, units)).into_dyn());
+        let mut c: Var = tape.leaf(Array2::<f32>::zeros((batch, units)).into_dyn());
+        let mut states: Vec<Var> = Vec::with_capacity(steps);
+
+        for t in 0..steps {
+            let x_t: Var = tape.slice(input, 1, t * features, (t + 1) * features);
+            let a_x: Var = tape.matmul(x_t, w_x);
+            let a_x: Var = tape.add(a_x, bias);
+            let a_h: Var = tape.matmul(h, w_h);
+            let gate = |tape: &mut Tape<'a>, a: Var, k: usize| -> Var {
+                tape.slice(a, 1, k * units, (k + 1) * units)
+            };
+            h = match self {
+                Cell::Simple(activation) => {
+                    let z: Var = tape.add(a_x, a_h);
+                    activation.record(tape, z)
+                }
+                Cell::LSTM => {
+                    let a: Var = tape.add(a_x, a_h);
+                    let i: Var = gate(tape, a, 0);
+                    let i: Var = tape.sigmoid(i);
+                    let f: Var = gate(tape, a, 1);
+                    let f: Var = tape.sigmoid(f);
+                    let g: Var = gate(tape, a, 2);
+                    let g: Var = tape.tanh(g);
+                    let o: Var = gate(tape, a, 3);
+                    let o: Var = tape.sigmoid(o);
+                    let kept: Var = tape.mul(f, c);
+                    let written: Var = tape.mul(i, g);
+                    c = tape.add(kept, written);
+                    let tanh_c: Var = tape.tanh(c);
+                    tape.mul(o, tanh_c)
+                }
+                Cell::GRU => {
+                    let (z_x, z_h) = (gate(tape, a_x, 0), gate(tape, a_h, 0));
+                    let z: Var = tape.add(z_x, z_h);
+                    let z: Var = tape.sigmoid(z);
+                    let (r_x, r_h) = (gate(tape, a_x, 1), gate(tape, a_h, 1));
+                    let r: Var = tape.add(r_x, r_h);
+                    let r: Var = tape.sigmoid(r);
+                    let h_n: Var = gate(tape, a_h, 2);
+                    let reset: Var = tape.mul(r, h_n);
+                    let n_x: Var = gate(tape, a_x, 2);
+                    let n: Var = tape.add(n_x, reset);
+                    let n: Var = tape.tanh(n);
+                    // h = n + z(h_{t-1} - n)
+                    let diff: Var = tape.sub(h, n);
+                    let carried: Var = tape.mul(z, diff);
+                    tape.add(n, carried)
+                }
+            };
+            states.push(h);
+        }
+
+        if return_sequences {
+            tape.concat(&states, 1)
+        } else {
+            h
+        }
+    }
+
+    // backpropagation through time, returns (∂C/∂input, ∂C/∂weights, ∂C/∂biases)
+    #[allow(clippy::too_many_arguments)]
+    pub fn backward(
+        &self,
+        input: &ArrayView2<f32>,
+        weights: &Array2<f32>,
+        cache: &Array2<f32>,
+        c_wrt_out: &ArrayView2<f32>,
+        steps: usize,
+        units: usize,
+        return_sequences: bool,
+        bptt: &Bptt,
+    ) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+        let batch: usize = input.nrows();
+        let features: usize = input.ncols() / steps;
+        let w_x: ArrayView2<f32> = weights.slice(s![..features, ..]);
+        let w_h: ArrayView2<f32> = weights.slice(s![features.., ..]);
+        let width: usize = self.cached() * units;
+        let part = |t: usize, k: usize| {
+            let start: usize = t * width + k * units;
+            cache.slice(s![.., start..start + units]).to_owned()
+        };
+        let hidden: usize = self.cached() - 1;
+        let zeros: Array2<f32> = Array2::zeros((batch, units));
+
+        let mut c_wrt_input: Array2<f32> = Array2::zeros(input.dim());
+        let mut c_wrt_w_x: Array2<f32> = Array2::zeros(w_x.dim());
+        let mut c_wrt_w_h: Array2<f32> = Array2::zeros(w_h.dim());
+        let mut c_wrt_b: Array2<f32> = Array2::zeros((1, weights.ncols()));
+        // gradients flowing into h_{t} and c_{t} from step t+1
+        let mut dh_next: Array2<f32> = zeros.clone();
+        let mut dc_next: Array2<f32> = zeros.clone();
+
+        for t in (0..steps).rev() {
+            if let Some(k) = bptt.truncate {
+                if k > 0 && t + 1 < steps && (steps - 1 - t).is_multiple_of(k) {
+                    dh_next.fill(0.);
+                    dc_next.fill(0.);
+                }
+            }
+            let mut dh: Array2<f32> = dh_next.clone();
+            if return_sequences {
+                dh += &c_wrt_out.slice(s![.., t * units..(t + 1) * units]);
+            } else if t == steps - 1 {
+                dh += c_wrt_out;
+            }
+            let h_prev: Array2<f32> = if t > 0 {
+                part(t - 1, hidden)
+            } else {
+                zeros.clone()
+            };
+
+            // ∂C/∂(x.W_x + b) and ∂C/∂(h.W_h) for every gate, which only differ for the GRU candidate
+            let (da_x, da_h): (Array2<f32>, Array2<f32>) = match self {
+                Cell::Simple(activation) => {
+                    let da: Array2<f32> = activation.backward(&part(t, 0), &dh);
+                    (da.clone(), da)
+                }
+                Cell::LSTM => {
+                    let (i, f, g, o, c) =
+                        (part(t, 0), part(t, 1), part(t, 2), part(t, 3), part(t, 4));
+                    let c_prev: Array2<f32> = if t > 0 { part(t - 1, 4) } else { zeros.clone() };
+                    let tanh_c: Array2<f32> = c.mapv(f32::tanh);
+                    let dc: Array2<f32> = &dc_next + &(&dh * &o * tanh_c.mapv(|x| 1. - x * x));
+                    dc_next = &dc * &f;
+                    let da: Array2<f32> = stack(
+                        Axis(1),
+                        &[
+                            (&dc * &g * sigmoid_slope(&i)).view(),
+                            (&dc * &c_prev * sigmoid_slope(&f)).view(),
+                            (&dc * &i * g.mapv(|x| 1. - x * x)).view(),
+                            (&dh * &tanh_c * sigmoid_slope(&o)).view(),
+                        ],
+                    )
+                    .unwrap();
+                    (da.clone(), da)
+                }
+                Cell::GRU => {
+                    let (z, r, n, h_n) = (part(t, 0), part(t, 1), part(t, 2), part(t, 3));
+                    let dn: Array2<f32> = &dh * &z.mapv(|x| 1. - x) * n.mapv(|x| 1. - x * x);
+                    let dz: Array2<f32> = &dh * &(&h_prev - &n) * sigmoid_slope(&z);
+                    let dr: Array2<f32> = &dn * &h_n * sigmoid_slope(&r);
+                    let da_x: Array2<f32> =
+                        stack(Axis(1), &[dz.view(), dr.view(), dn.view()]).unwrap();
+                    let da_h: Array2<f32> =
+                        stack(Axis(1), &[dz.view(), dr.view(), (&dn * &r).view()]).unwrap();
+                    (da_x, da_h)
+                }
+            };
+
+            let x_t: ArrayView2<f32> = input.slice(s![.., t * features..(t + 1) * features]);
+            c_wrt_w_x += &x_t.t().dot(&da_x);
+            c_wrt_w_h += &h_prev.t().dot(&da_h);
+            c_wrt_b += &da_x.sum_axis(Axis(0)).insert_axis(Axis(0));
+            c_wrt_input
+                .slice_mut(s![.., t * features..(t + 1) * features])
+                .assign(&da_x.dot(&w_x.t()));
+            dh_next = da_h.dot(&w_h.t());
+            if let Cell::GRU = self {
+                // h_{t-1} also reaches h_t directly through z h_{t-1}
+                dh_next += &(&dh * &part(t, 0));
+            }
+        }
+
+        let mut c_wrt_w: Array2<f32> =
+            stack(Axis(0), &[c_wrt_w_x.view(), c_wrt_w_h.view()]).unwrap();
+        if let Some(max_norm) = bptt.clip_norm {
+            let norm: f32 = (c_wrt_w.mapv(|x| x * x).sum() + c_wrt_b.mapv(|x| x * x).sum()).sqrt();
+            if norm > max_norm {
+                c_wrt_w *= max_norm / norm;
+                c_wrt_b *= max_norm / norm;
+            }
+        }
+        (c_wrt_input, c_wrt_w, c_wrt_b)
+    }
+}
+
+fn sigmoid(a: &Array2<f32>) -> Array2<f32> {
+    a.mapv(|x: f32| 1. / (1. + (-x).exp()))
+}
+
+// σ' written in terms of σ's output
+fn sigmoid_slope(s: &Array2<f32>) -> Array2<f32> {
+    s.mapv(|x: f32| x * (1. - x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEPS: usize = 4;
+    const UNITS: usize = 2;
+
+    // a tanh cell over 4 steps of 3 features, only the last hidden state is scored
+    fn run(bptt: &Bptt) -> (Array2<f32>, Array2<f32>, Array2<f32>) {
+        let activation: Activations = Activations::Tanh;
+        let cell: Cell = Cell::Simple(&activation);
+        let input: Array2<f32> =
+            Array2::from_shape_fn((2, STEPS * 3), |(i, j)| ((i * 12 + j) as f32 * 0.61).sin());
+        let weights: Array2<f32> = Array2::from_shape_fn((3 + UNITS, UNITS), |(i, j)| {
+            ((i * 2 + j) as f32 * 0.9).cos()
+        });
+        let bias: Array2<f32> = Array2::from_shape_fn((1, UNITS), |(_, j)| 0.1 * j as f32);
+        let (_, cache) = cell.forward(&input.view(), &weights, &bias, STEPS, UNITS, false);
+        let c_wrt_out: Array2<f32> = Array2::ones((2, UNITS));
+        cell.backward(
+            &input.view(),
+            &weights,
+            &cache,
+            &c_wrt_out.view(),
+            STEPS,
+            UNITS,
+            false,
+            bptt,
+        )
+    }
+
+    // ∂C/∂x_t for every sample
+    fn step_gradient(c_wrt_input: &Array2<f32>, t: usize) -> Array2<f32> {
+        c_wrt_input.slice(s![.., t * 3..(t + 1) * 3]).to_owned()
+    }
+
+    #[test]
+    fn truncation_cuts_the_gradient_past_k_steps() {
+        let full = run(&Bptt {
+            truncate: None,
+            clip_norm: None,
+        });
+        let truncated = run(&Bptt {
+            truncate: Some(2),
+            clip_norm: None,
+        });
+        // the last 2 steps are inside the window and see the same gradient either way
+        for t in 2..STEPS {
+            assert_eq!(step_gradient(&truncated.0, t), step_gradient(&full.0, t));
+        }
+        // everything before them only reached the output through the cut
+        for t in 0..2 {
+            assert!(step_gradient(&full.0, t)
+                .iter()
+                .any(|g: &f32| g.abs() > 1e-4));
+            assert!(step_gradient(&truncated.0, t)
+                .iter()
+                .all(|g: &f32| *g == 0.));
+        }
+    }
+
+    #[test]
+    fn clipping_caps_the_weight_and_bias_norm() {
+        let norm = |(_, w, b): &(Array2<f32>, Array2<f32>, Array2<f32>)| {
+            (w.mapv(|x| x * x).sum() + b.mapv(|x| x * x).sum()).sqrt()
+        };
+        let full = run(&Bptt {
+            truncate: None,
+            clip_norm: None,
+        });
+        let max_norm: f32 = norm(&full) / 2.;
+        let clipped = run(&Bptt {
+            truncate: None,
+            clip_norm: Some(max_norm),
+        });
+        assert!((norm(&clipped) - max_norm).abs() < 1e-5);
+        // rescaled rather than cut off, and the input gradient isn't touched
+        let scaled: Array2<f32> = &full.1 * 0.5;
+        assert!((&clipped.1 - &scaled).iter().all(|d: &f32| d.abs() < 1e-6));
+        assert_eq!(clipped.0, full.0);
+        // a cap above the norm leaves the gradients alone
+        let loose = run(&Bptt {
+            truncate: None,
+            clip_norm: Some(max_norm * 4.),
+        });
+        assert_eq!(loose.1, full.1);
+        assert_eq!(loose.2, full.2);
+    }
+}
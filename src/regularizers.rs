@@ -0,0 +1,92 @@
+#![allow(dead_code)]
+use crate::autograd::{Tape, Var};
+use ndarray::Array2;
+
+// penalty on a layer's weights or biases, added to the loss and to the parameter's gradient in backprop
+pub enum Regularizer {
+    None,
+    // λ Σ|w|
+    L1(f32),
+    // λ Σw²
+    L2(f32),
+    // λ₁ Σ|w| + λ₂ Σw²
+    ElasticNet { l1: f32, l2: f32 },
+}
+
+impl Regularizer {
+    pub fn penalty(&self, param: &Array2<f32>) -> f32 {
+        let (l1, l2) = self.coefficients();
+        if l1 == 0. && l2 == 0. {
+            return 0.;
+        }
+        param.iter().map(|w: &f32| l1 * w.abs() + l2 * w * w).sum()
+    }
+
+    // ∂penalty/∂w = λ₁ sign(w) + 2λ₂ w, with sign(0) = 0
+    pub fn gradient(&self, param: &Array2<f32>) -> Array2<f32> {
+        let (l1, l2) = self.coefficients();
+        param.mapv(|w: f32| {
+            let sign: f32 = if w == 0. { 0. } else { w.signum() };
+            l1 * sign + 2. * l2 * w
+        })
+    }
+
+    // penalty recorded on tape as λ₁ Σ|w| + λ₂ Σw², None when there's nothing to add
+    pub fn record(&self, tape: &mut Tape, param: Var) -> Option<Var> {
+        let (l1, l2) = self.coefficients();
+        if l1 == 0. && l2 == 0. {
+            return None;
+        }
+        let magnitude: Var = tape.abs(param);
+        let l1_term: Var = tape.sum(magnitude);
+        let l1_term: Var = tape.scale(l1_term, l1);
+        let square: Var = tape.mul(param, param);
+        let l2_term: Var = tape.sum(square);
+        let l2_term: Var = tape.scale(l2_term, l2);
+        Some(tape.add(l1_term, l2_term))
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, Regularizer::None)
+    }
+
+    // (λ₁, λ₂) so every variant goes through the same elastic net formula
+    fn coefficients(&self) -> (f32, f32) {
+        match self {
+            Regularizer::None => (0., 0.),
+            Regularizer::L1(l1) => (*l1, 0.),
+            Regularizer::L2(l2) => (0., *l2),
+            Regularizer::ElasticNet { l1, l2 } => (*l1, *l2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn elastic_net_is_the_sum_of_both_penalties() {
+        let w: Array2<f32> = array![[1., -2.], [0., 0.5]];
+        // 0.1 * 3.5 + 0.2 * 5.25
+        let net: Regularizer = Regularizer::ElasticNet { l1: 0.1, l2: 0.2 };
+        assert!((net.penalty(&w) - 1.4).abs() < 1e-6);
+        let sum: f32 = Regularizer::L1(0.1).penalty(&w) + Regularizer::L2(0.2).penalty(&w);
+        assert!((net.penalty(&w) - sum).abs() < 1e-6);
+        assert_eq!(Regularizer::None.penalty(&w), 0.);
+    }
+
+    #[test]
+    fn gradients_use_the_sign_and_leave_zeros_alone_under_l1() {
+        let w: Array2<f32> = array![[1., -2.], [0., 0.5]];
+        // 0.1 sign(w) + 0.4w
+        let net: Regularizer = Regularizer::ElasticNet { l1: 0.1, l2: 0.2 };
+        let expected: Array2<f32> = array![[0.5, -0.9], [0., 0.3]];
+        assert!((net.gradient(&w) - expected)
+            .iter()
+            .all(|d: &f32| d.abs() < 1e-6));
+        assert_eq!(Regularizer::L1(0.1).gradient(&w)[[1, 0]], 0.);
+        assert_eq!(Regularizer::None.gradient(&w), Array2::zeros((2, 2)));
+    }
+}
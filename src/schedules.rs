@@ -0,0 +1,453 @@
+#![allow(dead_code, unused_variables)]
+use crate::callbacks::{Callback, EpochLogs, History, Mode, Monitor, TrainingContext};
+use std::f32::consts::PI;
+
+// enum storing each learning rate schedule
+// t counts steps or epochs depending on the Frequency the scheduler runs at
+pub enum LearningRateSchedule {
+    // lr * factor^⌊t/step_size⌋
+    StepDecay {
+        factor: f32,
+        step_size: usize,
+    },
+    // lr * decay_rate^(t/decay_steps)
+    ExponentialDecay {
+        decay_rate: f32,
+        decay_steps: usize,
+    },
+    // SGDR: anneals from lr to min_lr along a half cosine, then restarts with a period period_mult times longer
+    CosineWarmRestarts {
+        min_lr: f32,
+        period: usize,
+        period_mult: f32,
+    },
+    // ramps linearly from lr/warmup up to lr over warmup steps, then hands over to `then` starting from t = 0
+    LinearWarmup {
+        warmup: usize,
+        then: Box<LearningRateSchedule>,
+    },
+    // multiplies the rate by factor whenever the monitored quantity stalls for patience epochs
+    // reacts to epoch metrics rather than t, so the scheduler tracks it between epochs
+    ReduceOnPlateau {
+        monitor: Monitor,
+        mode: Mode,
+        factor: f32,
+        patience: usize,
+        min_delta: f32,
+        min_lr: f32,
+    },
+}
+
+impl LearningRateSchedule {
+    // Err with the reason when the parameters would divide by zero or never finish finding a cycle
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            LearningRateSchedule::StepDecay { factor, step_size } => {
+                if *step_size == 0 {
+                    return Err("step decay needs a step_size above 0".to_string());
+                }
+                if *factor <= 0. {
+                    return Err(format!(
+                        "step decay factor must be above 0 but got {}",
+                        factor
+                    ));
+                }
+                Ok(())
+            }
+            LearningRateSchedule::ExponentialDecay {
+                decay_rate,
+                decay_steps,
+            } => {
+                if *decay_steps == 0 {
+                    return Err("exponential decay needs decay_steps above 0".to_string());
+                }
+                if *decay_rate <= 0. {
+                    return Err(format!(
+                        "exponential decay rate must be above 0 but got {}",
+                        decay_rate
+                    ));
+                }
+                Ok(())
+            }
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr,
+                period,
+                period_mult,
+            } => {
+                if *period == 0 {
+                    return Err("cosine warm restarts need a period above 0".to_string());
+                }
+                // shrinking periods add up to a finite number of steps, past which there'd be no cycle left
+                if *period_mult < 1. {
+                    return Err(format!(
+                        "cosine warm restarts need a period_mult of at least 1 but got {}",
+                        period_mult
+                    ));
+                }
+                if *min_lr < 0. {
+                    return Err(format!("min_lr can't be negative but got {}", min_lr));
+                }
+                Ok(())
+            }
+            LearningRateSchedule::LinearWarmup { then, .. } => then.validate(),
+            LearningRateSchedule::ReduceOnPlateau { factor, min_lr, .. } => {
+                if !(*factor > 0. && *factor < 1.) {
+                    return Err(format!(
+                        "reduce on plateau factor must be in (0, 1) but got {}",
+                        factor
+                    ));
+                }
+                if *min_lr < 0. {
+                    return Err(format!("min_lr can't be negative but got {}", min_lr));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // expects a schedule that passed validate
+    pub fn rate(&self, lr: f32, t: usize) -> f32 {
+        match self {
+            LearningRateSchedule::StepDecay { factor, step_size } => {
+                lr * factor.powi((t / step_size) as i32)
+            }
+            LearningRateSchedule::ExponentialDecay {
+                decay_rate,
+                decay_steps,
+            } => lr * decay_rate.powf(t as f32 / *decay_steps as f32),
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr,
+                period,
+                period_mult,
+            } => {
+                // walk forward through the restarts to find where t sits in the current cycle
+                let mut t_cur: f32 = t as f32;
+                let mut t_i: f32 = *period as f32;
+                while t_cur >= t_i {
+                    t_cur -= t_i;
+                    t_i *= period_mult;
+                }
+                min_lr + 0.5 * (lr - min_lr) * (1. + (PI * t_cur / t_i).cos())
+            }
+            LearningRateSchedule::LinearWarmup { warmup, then } => {
+                if t < *warmup {
+                    lr * (t + 1) as f32 / *warmup as f32
+                } else {
+                    then.rate(lr, t - warmup)
+                }
+            }
+            LearningRateSchedule::ReduceOnPlateau { .. } => lr,
+        }
+    }
+
+    // finds a ReduceOnPlateau, including one nested behind a warmup
+    fn plateau(&self) -> Option<&LearningRateSchedule> {
+        match self {
+            LearningRateSchedule::ReduceOnPlateau { .. } => Some(self),
+            LearningRateSchedule::LinearWarmup { then, .. } => then.plateau(),
+            _ => None,
+        }
+    }
+}
+
+// whether t advances once per batch or once per epoch
+pub enum Frequency {
+    Step,
+    Epoch,
+}
+
+// callback that drives any Optimizer's learning rate from a schedule
+// the optimizer's learning rate the first time training starts is the initial rate every later train call
+// schedules from too, and it's put back on the optimizer whenever training ends
+pub struct LearningRateScheduler {
+    pub schedule: LearningRateSchedule,
+    pub frequency: Frequency,
+    initial_lr: Option<f32>,
+    step: usize,
+    epoch: usize,
+    // running state for ReduceOnPlateau
+    scale: f32,
+    best: Option<f32>,
+    wait: usize,
+}
+
+impl LearningRateScheduler {
+    // panics when the schedule doesn't pass validate
+    pub fn new(schedule: LearningRateSchedule, frequency: Frequency) -> Self {
+        if let Err(e) = schedule.validate() {
+            panic!("invalid learning rate schedule: {}", e);
+        }
+        LearningRateScheduler {
+            schedule,
+            frequency,
+            initial_lr: None,
+            step: 0,
+            epoch: 0,
+            scale: 1.,
+            best: None,
+            wait: 0,
+        }
+    }
+
+    fn apply(&self, ctx: &mut TrainingContext) {
+        let t: usize = match self.frequency {
+            Frequency::Step => self.step,
+            Frequency::Epoch => self.epoch,
+        };
+        let base: f32 = self.initial_lr.unwrap_or(ctx.optimizer.learning_rate()) * self.scale;
+        ctx.optimizer.set_learning_rate(self.schedule.rate(base, t));
+    }
+}
+
+impl Callback for LearningRateScheduler {
+    fn on_epoch_begin(&mut self, epoch: usize, ctx: &mut TrainingContext) {
+        // reset so the same callback can be reused across several train calls, the schedule starts over
+        // from the rate captured on the first one rather than wherever the last run left the optimizer
+        if epoch == 0 {
+            if self.initial_lr.is_none() {
+                self.initial_lr = Some(ctx.optimizer.learning_rate());
+            }
+            self.step = 0;
+            self.scale = 1.;
+            self.best = None;
+            self.wait = 0;
+        }
+        self.epoch = epoch;
+        self.apply(ctx);
+    }
+
+    fn on_batch_end(&mut self, batch: usize, loss: f32, ctx: &mut TrainingContext) {
+        self.step += 1;
+        if let Frequency::Step = self.frequency {
+            self.apply(ctx);
+        }
+    }
+
+    fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+        if let Some(LearningRateSchedule::ReduceOnPlateau {
+            monitor,
+            mode,
+            factor,
+            patience,
+            min_delta,
+            min_lr,
+        }) = self.schedule.plateau()
+        {
+            let value: f32 = match monitor.value(logs) {
+                Some(value) => value,
+                None => return,
+            };
+            if mode.improved(value, self.best, *min_delta) {
+                self.best = Some(value);
+                self.wait = 0;
+                return;
+            }
+            self.wait += 1;
+            if self.wait >= *patience {
+                self.wait = 0;
+                let initial: f32 = self.initial_lr.unwrap_or(ctx.optimizer.learning_rate());
+                // an initial rate of 0 stays 0 whatever the scale, so there's no floor to keep
+                let floor: f32 = if initial > 0. { min_lr / initial } else { 0. };
+                let scale: f32 = (self.scale * factor).max(floor);
+                if scale < self.scale {
+                    self.scale = scale;
+                    println!("reducing learning rate to {}", initial * self.scale);
+                    self.apply(ctx);
+                }
+            }
+        }
+    }
+
+    fn on_train_end(&mut self, history: &History, ctx: &mut TrainingContext) {
+        if let Some(initial) = self.initial_lr {
+            ctx.optimizer.set_learning_rate(initial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cost::Cost, netutil::Sequential, optimizers::Optimizers};
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn step_decay_drops_every_step_size() {
+        let schedule: LearningRateSchedule = LearningRateSchedule::StepDecay {
+            factor: 0.5,
+            step_size: 3,
+        };
+        assert!(close(schedule.rate(1., 0), 1.));
+        assert!(close(schedule.rate(1., 2), 1.));
+        assert!(close(schedule.rate(1., 3), 0.5));
+        assert!(close(schedule.rate(1., 7), 0.25));
+    }
+
+    #[test]
+    fn exponential_decay_is_continuous() {
+        let schedule: LearningRateSchedule = LearningRateSchedule::ExponentialDecay {
+            decay_rate: 0.25,
+            decay_steps: 2,
+        };
+        assert!(close(schedule.rate(1., 0), 1.));
+        assert!(close(schedule.rate(1., 1), 0.5));
+        assert!(close(schedule.rate(1., 4), 0.0625));
+    }
+
+    #[test]
+    fn cosine_warm_restarts_restart_with_longer_periods() {
+        let schedule: LearningRateSchedule = LearningRateSchedule::CosineWarmRestarts {
+            min_lr: 0.,
+            period: 4,
+            period_mult: 2.,
+        };
+        assert!(close(schedule.rate(1., 0), 1.));
+        assert!(close(schedule.rate(1., 2), 0.5));
+        // the second cycle starts at t = 4 and lasts 8 steps
+        assert!(close(schedule.rate(1., 4), 1.));
+        assert!(close(schedule.rate(1., 8), 0.5));
+        assert!(close(schedule.rate(1., 12), 1.));
+    }
+
+    #[test]
+    fn linear_warmup_hands_over_from_zero() {
+        let schedule: LearningRateSchedule = LearningRateSchedule::LinearWarmup {
+            warmup: 4,
+            then: Box::new(LearningRateSchedule::StepDecay {
+                factor: 0.1,
+                step_size: 2,
+            }),
+        };
+        assert!(close(schedule.rate(1., 0), 0.25));
+        assert!(close(schedule.rate(1., 3), 1.));
+        assert!(close(schedule.rate(1., 4), 1.));
+        assert!(close(schedule.rate(1., 6), 0.1));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        let invalid: Vec<LearningRateSchedule> = vec![
+            LearningRateSchedule::StepDecay {
+                factor: 0.5,
+                step_size: 0,
+            },
+            LearningRateSchedule::ExponentialDecay {
+                decay_rate: 0.5,
+                decay_steps: 0,
+            },
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr: 0.,
+                period: 0,
+                period_mult: 1.,
+            },
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr: 0.,
+                period: 10,
+                period_mult: 0.,
+            },
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr: 0.,
+                period: 10,
+                period_mult: 0.5,
+            },
+            LearningRateSchedule::LinearWarmup {
+                warmup: 2,
+                then: Box::new(LearningRateSchedule::StepDecay {
+                    factor: 0.5,
+                    step_size: 0,
+                }),
+            },
+        ];
+        for schedule in invalid.iter() {
+            assert!(schedule.validate().is_err());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid learning rate schedule")]
+    fn scheduler_refuses_invalid_schedules() {
+        LearningRateScheduler::new(
+            LearningRateSchedule::CosineWarmRestarts {
+                min_lr: 0.,
+                period: 0,
+                period_mult: 2.,
+            },
+            Frequency::Step,
+        );
+    }
+
+    #[test]
+    fn scheduler_starts_over_on_every_train_call() {
+        let mut model: Sequential = Sequential::new(1, Cost::MSE);
+        let mut optimizer = Optimizers::SGD.build(1., &model);
+        let mut scheduler: LearningRateScheduler = LearningRateScheduler::new(
+            LearningRateSchedule::StepDecay {
+                factor: 0.5,
+                step_size: 2,
+            },
+            Frequency::Step,
+        );
+        for run in 0..3 {
+            let mut ctx: TrainingContext = TrainingContext {
+                model: &mut model,
+                optimizer: &mut optimizer,
+            };
+            scheduler.on_epoch_begin(0, &mut ctx);
+            assert!(close(ctx.optimizer.learning_rate(), 1.), "run {}", run);
+            for batch in 0..4 {
+                scheduler.on_batch_end(batch, 0., &mut ctx);
+            }
+            assert!(close(ctx.optimizer.learning_rate(), 0.25), "run {}", run);
+            // the third run never reaches on_train_end, like a train call that panicked, and the next
+            // one still starts from 1 rather than compounding the decay
+            if run < 2 {
+                scheduler.on_train_end(&History::new(), &mut ctx);
+                assert!(close(ctx.optimizer.learning_rate(), 1.), "run {}", run);
+            }
+        }
+        let mut ctx: TrainingContext = TrainingContext {
+            model: &mut model,
+            optimizer: &mut optimizer,
+        };
+        scheduler.on_epoch_begin(0, &mut ctx);
+        assert!(close(ctx.optimizer.learning_rate(), 1.));
+    }
+
+    #[test]
+    fn plateaus_at_a_zero_learning_rate_stay_at_zero() {
+        let mut model: Sequential = Sequential::new(1, Cost::MSE);
+        let mut optimizer = Optimizers::SGD.build(0., &model);
+        let mut scheduler: LearningRateScheduler = LearningRateScheduler::new(
+            LearningRateSchedule::ReduceOnPlateau {
+                monitor: Monitor::Loss,
+                mode: Mode::Min,
+                factor: 0.5,
+                patience: 1,
+                min_delta: 0.,
+                min_lr: 1e-3,
+            },
+            Frequency::Epoch,
+        );
+        let mut ctx: TrainingContext = TrainingContext {
+            model: &mut model,
+            optimizer: &mut optimizer,
+        };
+        for epoch in 0..4 {
+            scheduler.on_epoch_begin(epoch, &mut ctx);
+            let logs: EpochLogs = EpochLogs {
+                epoch,
+                loss: 1.,
+                accuracy: 0.,
+                validation: None,
+            };
+            scheduler.on_epoch_end(&logs, &mut ctx);
+            assert_eq!(ctx.optimizer.learning_rate(), 0.);
+        }
+        // the loss never improves after the first epoch so every later one halves the scale, with no
+        // min_lr / 0 floor in the way
+        assert_eq!(scheduler.scale, 0.125);
+    }
+}
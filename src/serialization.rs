@@ -0,0 +1,1259 @@
+#![allow(dead_code)]
+use crate::{
+    activations::{Activation, Activations},
+    cost::{Cost, Loss},
+    layers::Layers,
+    netutil::Sequential,
+    regularizers::Regularizer,
+};
+use ndarray::Array2;
+use std::{collections::HashMap, fmt, io};
+
+// file layout, everything little endian:
+// magic "FE0M" | format version u32 | payload length u64 | payload | checksum of the payload u64
+// payload: input shape, cost, layer count, every layer's config, then every weight, bias and state matrix
+pub const MAGIC: &[u8; 4] = b"FE0M";
+pub const FORMAT_VERSION: u32 = 1;
+// custom activations and costs are stored as this tag followed by their id and params
+const CUSTOM_TAG: u8 = 255;
+
+#[derive(Debug)]
+pub enum ModelError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    ChecksumMismatch {
+        stored: u64,
+        computed: u64,
+    },
+    Truncated,
+    UnknownTag {
+        kind: &'static str,
+        tag: u8,
+    },
+    ShapeMismatch {
+        layer: usize,
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    // the stored layer stack doesn't fit together
+    InvalidLayer {
+        layer: usize,
+        reason: String,
+    },
+    // the stored cost has parameters it can't work with
+    InvalidCost(String),
+    // a custom activation or cost whose id isn't in the registry the model was loaded with
+    Unregistered {
+        kind: &'static str,
+        id: String,
+    },
+    // a kind of model the file format has no encoding for
+    Unsupported(String),
+    // training data that doesn't line up with the model's inputs and outputs
+    InvalidData(String),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Io(e) => write!(f, "io error: {}", e),
+            ModelError::BadMagic => write!(f, "not a fe0_ml model file"),
+            ModelError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "model file is format version {} but this build reads version {}",
+                found, supported
+            ),
+            ModelError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "checksum mismatch (stored {:#018x}, computed {:#018x}), the file is corrupt",
+                stored, computed
+            ),
+            ModelError::Truncated => write!(f, "model file ended unexpectedly"),
+            ModelError::UnknownTag { kind, tag } => write!(f, "unknown {} id {}", kind, tag),
+            ModelError::ShapeMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {} expects parameters of shape {:?} but the file holds {:?}",
+                layer, expected, found
+            ),
+            ModelError::InvalidLayer { layer, reason } => {
+                write!(f, "layer {} is invalid: {}", layer, reason)
+            }
+            ModelError::InvalidCost(reason) => write!(f, "cost is invalid: {}", reason),
+            ModelError::Unregistered { kind, id } => write!(
+                f,
+                "custom {} {:?} isn't registered, add it to the Registry passed to load_with",
+                kind, id
+            ),
+            ModelError::Unsupported(reason) => write!(f, "can't save model: {}", reason),
+            ModelError::InvalidData(reason) => write!(f, "data doesn't fit the model: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ModelError {
+    fn from(e: io::Error) -> Self {
+        ModelError::Io(e)
+    }
+}
+
+// builds a custom activation or cost back up from the params it was saved with
+pub type ActivationConstructor = fn(&[f32]) -> Box<dyn Activation>;
+pub type LossConstructor = fn(&[f32]) -> Box<dyn Loss>;
+
+// rebuilds custom activations and costs from the id and params they were saved with
+pub struct Registry {
+    activations: HashMap<String, ActivationConstructor>,
+    losses: HashMap<String, LossConstructor>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            activations: HashMap::new(),
+            losses: HashMap::new(),
+        }
+    }
+
+    pub fn register_activation(&mut self, id: &str, constructor: ActivationConstructor) {
+        self.activations.insert(id.to_string(), constructor);
+    }
+
+    pub fn register_loss(&mut self, id: &str, constructor: LossConstructor) {
+        self.losses.insert(id.to_string(), constructor);
+    }
+}
+
+// 64 bit FNV-1a, plenty to catch truncated or bit-flipped files
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, v: &str) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    fn pair(&mut self, v: (usize, usize)) {
+        self.u64(v.0 as u64);
+        self.u64(v.1 as u64);
+    }
+
+    fn matrix(&mut self, m: &Array2<f32>) {
+        self.u64(m.nrows() as u64);
+        self.u64(m.ncols() as u64);
+        for v in m.iter() {
+            self.f32(*v);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ModelError> {
+        let end: usize = self.pos.checked_add(n).ok_or(ModelError::Truncated)?;
+        let bytes: &[u8] = self.buf.get(self.pos..end).ok_or(ModelError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ModelError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ModelError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, ModelError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, ModelError> {
+        let len: usize = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn pair(&mut self) -> Result<(usize, usize), ModelError> {
+        Ok((self.u64()? as usize, self.u64()? as usize))
+    }
+
+    fn matrix(&mut self) -> Result<Array2<f32>, ModelError> {
+        let rows: usize = self.u64()? as usize;
+        let cols: usize = self.u64()? as usize;
+        let len: usize = rows.checked_mul(cols).ok_or(ModelError::Truncated)?;
+        let data: Vec<f32> = self
+            .take(len.checked_mul(4).ok_or(ModelError::Truncated)?)?
+            .chunks_exact(4)
+            .map(|b: &[u8]| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(Array2::from_shape_vec((rows, cols), data).unwrap())
+    }
+}
+
+fn write_cost(w: &mut Writer, cost: &Cost) {
+    match cost {
+        Cost::MSE => w.u8(0),
+        Cost::CrossEntropy => w.u8(1),
+        Cost::BinaryCrossEntropy => w.u8(2),
+        Cost::MAE => w.u8(3),
+        Cost::Huber { delta } => {
+            w.u8(4);
+            w.f32(*delta);
+        }
+        Cost::LogCosh => w.u8(5),
+        Cost::Hinge => w.u8(6),
+        Cost::SquaredHinge => w.u8(7),
+        Cost::BinaryCrossEntropyWithLogits => w.u8(8),
+        Cost::SparseCategoricalCrossEntropy => w.u8(9),
+        Cost::KLDivergence => w.u8(10),
+        Cost::CosineSimilarity => w.u8(11),
+        Cost::Custom(f) => write_custom(w, &f.id(), &f.params()),
+    }
+}
+
+fn read_cost(r: &mut Reader, registry: &Registry) -> Result<Cost, ModelError> {
+    match r.u8()? {
+        0 => Ok(Cost::MSE),
+        1 => Ok(Cost::CrossEntropy),
+        2 => Ok(Cost::BinaryCrossEntropy),
+        3 => Ok(Cost::MAE),
+        4 => Ok(Cost::Huber { delta: r.f32()? }),
+        5 => Ok(Cost::LogCosh),
+        6 => Ok(Cost::Hinge),
+        7 => Ok(Cost::SquaredHinge),
+        8 => Ok(Cost::BinaryCrossEntropyWithLogits),
+        9 => Ok(Cost::SparseCategoricalCrossEntropy),
+        10 => Ok(Cost::KLDivergence),
+        11 => Ok(Cost::CosineSimilarity),
+        CUSTOM_TAG => {
+            let (id, params) = read_custom(r)?;
+            match registry.losses.get(&id) {
+                Some(constructor) => Ok(Cost::Custom(constructor(&params))),
+                None => Err(ModelError::Unregistered { kind: "cost", id }),
+            }
+        }
+        tag => Err(ModelError::UnknownTag { kind: "cost", tag }),
+    }
+}
+
+// with_input_shape panics on an invalid cost, a corrupt file should be an error instead
+fn read_valid_cost(r: &mut Reader, registry: &Registry) -> Result<Cost, ModelError> {
+    let cost: Cost = read_cost(r, registry)?;
+    cost.validate().map_err(ModelError::InvalidCost)?;
+    Ok(cost)
+}
+
+fn write_custom(w: &mut Writer, id: &str, params: &[f32]) {
+    w.u8(CUSTOM_TAG);
+    w.string(id);
+    w.u32(params.len() as u32);
+    for p in params {
+        w.f32(*p);
+    }
+}
+
+fn read_custom(r: &mut Reader) -> Result<(String, Vec<f32>), ModelError> {
+    let id: String = r.string()?;
+    let len: usize = r.u32()? as usize;
+    let params: Vec<f32> = (0..len)
+        .map(|_| r.f32())
+        .collect::<Result<Vec<f32>, ModelError>>()?;
+    Ok((id, params))
+}
+
+// parameterized activations write their parameters straight after the tag
+fn write_activation(w: &mut Writer, activation: &Activations) {
+    match activation {
+        Activations::Linear => w.u8(10),
+        Activations::Sigmoid => w.u8(0),
+        Activations::ReLU => w.u8(1),
+        Activations::LeakyReLU { a } => {
+            w.u8(2);
+            w.f32(*a);
+        }
+        Activations::Tanh => w.u8(3),
+        Activations::Softmax { temperature } => {
+            w.u8(4);
+            w.f32(*temperature);
+        }
+        Activations::LogSoftmax { temperature } => {
+            w.u8(11);
+            w.f32(*temperature);
+        }
+        Activations::SoftPlus => w.u8(5),
+        Activations::SoftSign => w.u8(6),
+        Activations::ELU { a } => {
+            w.u8(7);
+            w.f32(*a);
+        }
+        Activations::SELU => w.u8(8),
+        Activations::GELU => w.u8(9),
+        Activations::Swish { beta } => {
+            w.u8(12);
+            w.f32(*beta);
+        }
+        Activations::SiLU => w.u8(13),
+        Activations::Mish => w.u8(14),
+        Activations::HardSigmoid => w.u8(15),
+        Activations::HardSwish => w.u8(16),
+        Activations::HardTanh => w.u8(17),
+        Activations::ExactGELU => w.u8(18),
+        Activations::Custom(f) => write_custom(w, &f.id(), &f.params()),
+    }
+}
+
+fn read_activation(r: &mut Reader, registry: &Registry) -> Result<Activations, ModelError> {
+    match r.u8()? {
+        0 => Ok(Activations::Sigmoid),
+        1 => Ok(Activations::ReLU),
+        2 => Ok(Activations::LeakyReLU { a: r.f32()? }),
+        3 => Ok(Activations::Tanh),
+        4 => Ok(Activations::Softmax {
+            temperature: r.f32()?,
+        }),
+        5 => Ok(Activations::SoftPlus),
+        6 => Ok(Activations::SoftSign),
+        7 => Ok(Activations::ELU { a: r.f32()? }),
+        8 => Ok(Activations::SELU),
+        9 => Ok(Activations::GELU),
+        10 => Ok(Activations::Linear),
+        12 => Ok(Activations::Swish { beta: r.f32()? }),
+        13 => Ok(Activations::SiLU),
+        14 => Ok(Activations::Mish),
+        15 => Ok(Activations::HardSigmoid),
+        16 => Ok(Activations::HardSwish),
+        17 => Ok(Activations::HardTanh),
+        18 => Ok(Activations::ExactGELU),
+        CUSTOM_TAG => {
+            let (id, params) = read_custom(r)?;
+            match registry.activations.get(&id) {
+                Some(constructor) => Ok(Activations::Custom(constructor(&params))),
+                None => Err(ModelError::Unregistered {
+                    kind: "activation",
+                    id,
+                }),
+            }
+        }
+        11 => Ok(Activations::LogSoftmax {
+            temperature: r.f32()?,
+        }),
+        tag => Err(ModelError::UnknownTag {
+            kind: "activation",
+            tag,
+        }),
+    }
+}
+
+fn write_regularizer(w: &mut Writer, regularizer: &Regularizer) {
+    match regularizer {
+        Regularizer::None => w.u8(0),
+        Regularizer::L1(l1) => {
+            w.u8(1);
+            w.f32(*l1);
+        }
+        Regularizer::L2(l2) => {
+            w.u8(2);
+            w.f32(*l2);
+        }
+        Regularizer::ElasticNet { l1, l2 } => {
+            w.u8(3);
+            w.f32(*l1);
+            w.f32(*l2);
+        }
+    }
+}
+
+fn read_regularizer(r: &mut Reader) -> Result<Regularizer, ModelError> {
+    match r.u8()? {
+        0 => Ok(Regularizer::None),
+        1 => Ok(Regularizer::L1(r.f32()?)),
+        2 => Ok(Regularizer::L2(r.f32()?)),
+        3 => Ok(Regularizer::ElasticNet {
+            l1: r.f32()?,
+            l2: r.f32()?,
+        }),
+        tag => Err(ModelError::UnknownTag {
+            kind: "regularizer",
+            tag,
+        }),
+    }
+}
+
+// options are stored as a presence byte followed by the value when there is one
+fn write_recurrence(
+    w: &mut Writer,
+    return_sequences: bool,
+    truncate: Option<usize>,
+    clip_norm: Option<f32>,
+) {
+    w.u8(return_sequences as u8);
+    w.u8(truncate.is_some() as u8);
+    w.u64(truncate.unwrap_or(0) as u64);
+    w.u8(clip_norm.is_some() as u8);
+    w.f32(clip_norm.unwrap_or(0.));
+}
+
+fn read_recurrence(r: &mut Reader) -> Result<(bool, Option<usize>, Option<f32>), ModelError> {
+    let return_sequences: bool = r.u8()? != 0;
+    let has_truncate: bool = r.u8()? != 0;
+    let truncate: usize = r.u64()? as usize;
+    let has_clip_norm: bool = r.u8()? != 0;
+    let clip_norm: f32 = r.f32()?;
+    Ok((
+        return_sequences,
+        if has_truncate { Some(truncate) } else { None },
+        if has_clip_norm { Some(clip_norm) } else { None },
+    ))
+}
+
+fn write_layer(w: &mut Writer, layer: &Layers) {
+    match layer {
+        Layers::Dense {
+            units,
+            activation,
+            init_func,
+            kernel_regularizer,
+            bias_regularizer,
+        } => {
+            w.u8(0);
+            w.u64(*units as u64);
+            write_activation(w, activation);
+            w.string(init_func);
+            write_regularizer(w, kernel_regularizer);
+            write_regularizer(w, bias_regularizer);
+        }
+        Layers::Conv2D {
+            filters,
+            kernel_size,
+            stride,
+            padding,
+            activation,
+            init_func,
+            kernel_regularizer,
+            bias_regularizer,
+        } => {
+            w.u8(1);
+            w.u64(*filters as u64);
+            w.pair(*kernel_size);
+            w.u64(*stride as u64);
+            w.u64(*padding as u64);
+            write_activation(w, activation);
+            w.string(init_func);
+            write_regularizer(w, kernel_regularizer);
+            write_regularizer(w, bias_regularizer);
+        }
+        Layers::MaxPool2D { pool_size, stride } => {
+            w.u8(2);
+            w.pair(*pool_size);
+            w.u64(*stride as u64);
+        }
+        Layers::AvgPool2D { pool_size, stride } => {
+            w.u8(3);
+            w.pair(*pool_size);
+            w.u64(*stride as u64);
+        }
+        Layers::Flatten => w.u8(4),
+        Layers::Dropout { rate } => {
+            w.u8(5);
+            w.f32(*rate);
+        }
+        Layers::BatchNorm { momentum, epsilon } => {
+            w.u8(6);
+            w.f32(*momentum);
+            w.f32(*epsilon);
+        }
+        Layers::LayerNorm { epsilon } => {
+            w.u8(7);
+            w.f32(*epsilon);
+        }
+        Layers::PReLU { alpha } => {
+            w.u8(8);
+            w.f32(*alpha);
+        }
+        Layers::SimpleRNN {
+            units,
+            activation,
+            init_func,
+            return_sequences,
+            truncate,
+            clip_norm,
+        } => {
+            w.u8(9);
+            w.u64(*units as u64);
+            write_activation(w, activation);
+            w.string(init_func);
+            write_recurrence(w, *return_sequences, *truncate, *clip_norm);
+        }
+        Layers::LSTM {
+            units,
+            init_func,
+            return_sequences,
+            truncate,
+            clip_norm,
+        }
+        | Layers::GRU {
+            units,
+            init_func,
+            return_sequences,
+            truncate,
+            clip_norm,
+        } => {
+            w.u8(if let Layers::LSTM { .. } = layer {
+                10
+            } else {
+                11
+            });
+            w.u64(*units as u64);
+            w.string(init_func);
+            write_recurrence(w, *return_sequences, *truncate, *clip_norm);
+        }
+        Layers::Embedding {
+            vocab_size,
+            dim,
+            padding_idx,
+        } => {
+            w.u8(12);
+            w.u64(*vocab_size as u64);
+            w.u64(*dim as u64);
+            w.u8(padding_idx.is_some() as u8);
+            w.u64(padding_idx.unwrap_or(0) as u64);
+        }
+        Layers::MultiHeadAttention {
+            heads,
+            causal,
+            mask_padding,
+            init_func,
+        } => {
+            w.u8(13);
+            w.u64(*heads as u64);
+            w.u8(*causal as u8);
+            w.u8(*mask_padding as u8);
+            w.string(init_func);
+        }
+        Layers::PositionalEncoding {
+            learned,
+            mask_padding,
+        } => {
+            w.u8(14);
+            w.u8(*learned as u8);
+            w.u8(*mask_padding as u8);
+        }
+        Layers::TransformerEncoderBlock {
+            heads,
+            ff_units,
+            causal,
+            mask_padding,
+            epsilon,
+        } => {
+            w.u8(15);
+            w.u64(*heads as u64);
+            w.u64(*ff_units as u64);
+            w.u8(*causal as u8);
+            w.u8(*mask_padding as u8);
+            w.f32(*epsilon);
+        }
+        Layers::Residual { block } => {
+            w.u8(16);
+            write_stack(w, block);
+        }
+        Layers::Parallel { branches } => {
+            w.u8(17);
+            w.u64(branches.len() as u64);
+            for branch in branches {
+                write_stack(w, branch);
+            }
+        }
+    }
+}
+
+// the inner layers of a container, count first
+fn write_stack(w: &mut Writer, layers: &[Layers]) {
+    w.u64(layers.len() as u64);
+    for layer in layers {
+        write_layer(w, layer);
+    }
+}
+
+fn read_stack(r: &mut Reader, registry: &Registry) -> Result<Vec<Layers>, ModelError> {
+    let count: usize = r.u64()? as usize;
+    (0..count).map(|_| read_layer(r, registry)).collect()
+}
+
+fn read_layer(r: &mut Reader, registry: &Registry) -> Result<Layers, ModelError> {
+    match r.u8()? {
+        0 => Ok(Layers::Dense {
+            units: r.u64()? as usize,
+            activation: read_activation(r, registry)?,
+            init_func: r.string()?,
+            kernel_regularizer: read_regularizer(r)?,
+            bias_regularizer: read_regularizer(r)?,
+        }),
+        1 => Ok(Layers::Conv2D {
+            filters: r.u64()? as usize,
+            kernel_size: r.pair()?,
+            stride: r.u64()? as usize,
+            padding: r.u64()? as usize,
+            activation: read_activation(r, registry)?,
+            init_func: r.string()?,
+            kernel_regularizer: read_regularizer(r)?,
+            bias_regularizer: read_regularizer(r)?,
+        }),
+        2 => Ok(Layers::MaxPool2D {
+            pool_size: r.pair()?,
+            stride: r.u64()? as usize,
+        }),
+        3 => Ok(Layers::AvgPool2D {
+            pool_size: r.pair()?,
+            stride: r.u64()? as usize,
+        }),
+        4 => Ok(Layers::Flatten),
+        5 => Ok(Layers::Dropout { rate: r.f32()? }),
+        6 => Ok(Layers::BatchNorm {
+            momentum: r.f32()?,
+            epsilon: r.f32()?,
+        }),
+        7 => Ok(Layers::LayerNorm { epsilon: r.f32()? }),
+        8 => Ok(Layers::PReLU { alpha: r.f32()? }),
+        9 => {
+            let units: usize = r.u64()? as usize;
+            let activation: Activations = read_activation(r, registry)?;
+            let init_func: String = r.string()?;
+            let (return_sequences, truncate, clip_norm) = read_recurrence(r)?;
+            Ok(Layers::SimpleRNN {
+                units,
+                activation,
+                init_func,
+                return_sequences,
+                truncate,
+                clip_norm,
+            })
+        }
+        12 => {
+            let vocab_size: usize = r.u64()? as usize;
+            let dim: usize = r.u64()? as usize;
+            let has_padding: bool = r.u8()? != 0;
+            let padding_idx: usize = r.u64()? as usize;
+            Ok(Layers::Embedding {
+                vocab_size,
+                dim,
+                padding_idx: if has_padding { Some(padding_idx) } else { None },
+            })
+        }
+        13 => Ok(Layers::MultiHeadAttention {
+            heads: r.u64()? as usize,
+            causal: r.u8()? != 0,
+            mask_padding: r.u8()? != 0,
+            init_func: r.string()?,
+        }),
+        14 => Ok(Layers::PositionalEncoding {
+            learned: r.u8()? != 0,
+            mask_padding: r.u8()? != 0,
+        }),
+        15 => Ok(Layers::TransformerEncoderBlock {
+            heads: r.u64()? as usize,
+            ff_units: r.u64()? as usize,
+            causal: r.u8()? != 0,
+            mask_padding: r.u8()? != 0,
+            epsilon: r.f32()?,
+        }),
+        16 => Ok(Layers::Residual {
+            block: read_stack(r, registry)?,
+        }),
+        17 => {
+            let count: usize = r.u64()? as usize;
+            Ok(Layers::Parallel {
+                branches: (0..count)
+                    .map(|_| read_stack(r, registry))
+                    .collect::<Result<_, _>>()?,
+            })
+        }
+        tag @ (10 | 11) => {
+            let units: usize = r.u64()? as usize;
+            let init_func: String = r.string()?;
+            let (return_sequences, truncate, clip_norm) = read_recurrence(r)?;
+            Ok(if tag == 10 {
+                Layers::LSTM {
+                    units,
+                    init_func,
+                    return_sequences,
+                    truncate,
+                    clip_norm,
+                }
+            } else {
+                Layers::GRU {
+                    units,
+                    init_func,
+                    return_sequences,
+                    truncate,
+                    clip_norm,
+                }
+            })
+        }
+        tag => Err(ModelError::UnknownTag { kind: "layer", tag }),
+    }
+}
+
+fn check_shape(layer: usize, m: &Array2<f32>, expected: (usize, usize)) -> Result<(), ModelError> {
+    let found: (usize, usize) = m.dim();
+    if found != expected {
+        return Err(ModelError::ShapeMismatch {
+            layer,
+            expected,
+            found,
+        });
+    }
+    Ok(())
+}
+
+pub fn encode(model: &Sequential) -> Vec<u8> {
+    let mut payload: Writer = Writer { buf: Vec::new() };
+    payload.u32(model.input_shape.len() as u32);
+    for dim in model.input_shape.iter() {
+        payload.u64(*dim as u64);
+    }
+    write_cost(&mut payload, &model.cost);
+    payload.u32(model.layers.len() as u32);
+    for layer in model.layers.iter() {
+        write_layer(&mut payload, layer);
+    }
+    for i in 0..model.layers.len() {
+        payload.matrix(&model.weights[i]);
+        payload.matrix(&model.biases[i]);
+        payload.matrix(&model.state[i]);
+    }
+
+    let mut out: Writer = Writer { buf: Vec::new() };
+    out.buf.extend_from_slice(MAGIC);
+    out.u32(FORMAT_VERSION);
+    out.u64(payload.buf.len() as u64);
+    out.buf.extend_from_slice(&payload.buf);
+    out.u64(checksum(&payload.buf));
+    out.buf
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Sequential, ModelError> {
+    decode_with(bytes, &Registry::new())
+}
+
+// like decode, looking up any custom activations and costs in registry
+pub fn decode_with(bytes: &[u8], registry: &Registry) -> Result<Sequential, ModelError> {
+    let mut header: Reader = Reader { buf: bytes, pos: 0 };
+    if header.take(4).map_err(|_| ModelError::BadMagic)? != MAGIC {
+        return Err(ModelError::BadMagic);
+    }
+    let version: u32 = header.u32()?;
+    if version != FORMAT_VERSION {
+        return Err(ModelError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let len: usize = header.u64()? as usize;
+    let payload: &[u8] = header.take(len)?;
+    let stored: u64 = header.u64()?;
+    let computed: u64 = checksum(payload);
+    if stored != computed {
+        return Err(ModelError::ChecksumMismatch { stored, computed });
+    }
+
+    let mut r: Reader = Reader {
+        buf: payload,
+        pos: 0,
+    };
+    let rank: usize = r.u32()? as usize;
+    let input_shape: Vec<usize> = (0..rank)
+        .map(|_| r.u64().map(|d: u64| d as usize))
+        .collect::<Result<Vec<usize>, ModelError>>()?;
+    let mut model: Sequential =
+        Sequential::with_input_shape(&input_shape, read_valid_cost(&mut r, registry)?);
+    let num_layers: usize = r.u32()? as usize;
+    for i in 0..num_layers {
+        let layer: Layers = read_layer(&mut r, registry)?;
+        let shape: Vec<usize> = layer
+            .output_shape(model.output_shape())
+            .map_err(|reason: String| ModelError::InvalidLayer { layer: i, reason })?;
+        model.layers.push(layer);
+        model.shapes.push(shape);
+    }
+    for i in 0..num_layers {
+        let (weight_shape, bias_shape) =
+            model.layers[i].parameter_shapes(model.layer_input_shape(i));
+        let weights: Array2<f32> = r.matrix()?;
+        check_shape(i, &weights, weight_shape)?;
+        let biases: Array2<f32> = r.matrix()?;
+        check_shape(i, &biases, bias_shape)?;
+        let state: Array2<f32> = r.matrix()?;
+        check_shape(
+            i,
+            &state,
+            model.layers[i].state_shape(model.layer_input_shape(i)),
+        )?;
+        model.weights.push(weights);
+        model.biases.push(biases);
+        model.state.push(state);
+    }
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netutil::Net;
+    use ndarray::{array, Array, ArrayD, IxDyn};
+
+    fn dense(units: usize, activation: Activations) -> Layers {
+        Layers::Dense {
+            units,
+            activation,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        }
+    }
+
+    fn inputs(shape: &[usize]) -> ArrayD<f32> {
+        let n: usize = shape.iter().product();
+        Array::from_iter((0..n).map(|i| ((i * 11 + 3) as f32 * 0.53).sin()))
+            .into_shape(IxDyn(shape))
+            .unwrap()
+    }
+
+    // a loaded model has to predict exactly what the saved one did, and write back the same bytes
+    fn assert_round_trip(model: &Sequential, input: &ArrayD<f32>, registry: &Registry) {
+        let bytes: Vec<u8> = encode(model);
+        let loaded: Sequential = decode_with(&bytes, registry).unwrap();
+        assert_eq!(loaded.input_shape, model.input_shape);
+        assert_eq!(loaded.shapes, model.shapes);
+        assert_eq!(loaded.predict(input), model.predict(input));
+        assert_eq!(encode(&loaded), bytes);
+    }
+
+    // the payload of an encoded model edited and wrapped back up with a valid checksum
+    fn rewrap<F: Fn(&mut Vec<u8>)>(bytes: &[u8], edit: F) -> Vec<u8> {
+        let mut payload: Vec<u8> = bytes[16..bytes.len() - 8].to_vec();
+        edit(&mut payload);
+        let mut out: Writer = Writer { buf: Vec::new() };
+        out.buf.extend_from_slice(MAGIC);
+        out.u32(FORMAT_VERSION);
+        out.u64(payload.len() as u64);
+        out.buf.extend_from_slice(&payload);
+        out.u64(checksum(&payload));
+        out.buf
+    }
+
+    fn small_model() -> Sequential {
+        let mut model: Sequential = Sequential::new(2, Cost::MSE);
+        model.add(dense(3, Activations::Tanh));
+        model
+    }
+
+    #[test]
+    fn dense_models_round_trip() {
+        let mut model: Sequential = Sequential::new(3, Cost::CrossEntropy);
+        model.add(Layers::Dense {
+            units: 4,
+            activation: Activations::LeakyReLU { a: 0.2 },
+            init_func: String::from("he"),
+            kernel_regularizer: Regularizer::ElasticNet { l1: 0.01, l2: 0.02 },
+            bias_regularizer: Regularizer::L1(0.5),
+        });
+        model.add(dense(4, Activations::ELU { a: 0.5 }));
+        model.add(dense(3, Activations::Softmax { temperature: 0.5 }));
+        assert_round_trip(&model, &inputs(&[2, 3]), &Registry::new());
+    }
+
+    #[test]
+    fn image_layers_round_trip() {
+        let mut model: Sequential =
+            Sequential::with_input_shape(&[2, 6, 6], Cost::BinaryCrossEntropy);
+        model.add(Layers::Conv2D {
+            filters: 3,
+            kernel_size: (3, 3),
+            stride: 1,
+            padding: 1,
+            activation: Activations::ReLU,
+            init_func: String::from("he"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        });
+        model.add(Layers::MaxPool2D {
+            pool_size: (2, 2),
+            stride: 2,
+        });
+        model.add(Layers::AvgPool2D {
+            pool_size: (2, 2),
+            stride: 1,
+        });
+        model.add(Layers::Flatten);
+        model.add(Layers::Dropout { rate: 0.3 });
+        model.add(dense(2, Activations::Sigmoid));
+        assert_round_trip(&model, &inputs(&[2, 2, 6, 6]), &Registry::new());
+    }
+
+    #[test]
+    fn normalization_layers_round_trip_with_their_state() {
+        let mut model: Sequential = Sequential::new(4, Cost::MSE);
+        model.add(Layers::BatchNorm {
+            momentum: 0.8,
+            epsilon: 1e-3,
+        });
+        model.add(Layers::LayerNorm { epsilon: 1e-4 });
+        model.add(Layers::PReLU { alpha: 0.3 });
+        model.add(dense(2, Activations::GELU));
+        // running statistics other than the starting ones, so a lost state would change the prediction
+        model.state[0] = array![[0.5, -0.2, 0.1, 0.3], [2., 0.5, 1.5, 0.8]];
+        assert_round_trip(&model, &inputs(&[3, 4]), &Registry::new());
+    }
+
+    #[test]
+    fn recurrent_layers_round_trip() {
+        let mut model: Sequential = Sequential::with_input_shape(&[3, 2], Cost::LogCosh);
+        model.add(Layers::SimpleRNN {
+            units: 3,
+            activation: Activations::ELU { a: 0.9 },
+            init_func: String::from("xavier"),
+            return_sequences: true,
+            truncate: Some(2),
+            clip_norm: Some(1.5),
+        });
+        model.add(Layers::LSTM {
+            units: 2,
+            init_func: String::from("xavier"),
+            return_sequences: true,
+            truncate: None,
+            clip_norm: Some(0.5),
+        });
+        model.add(Layers::GRU {
+            units: 2,
+            init_func: String::from("xavier"),
+            return_sequences: false,
+            truncate: Some(1),
+            clip_norm: None,
+        });
+        assert_round_trip(&model, &inputs(&[2, 3, 2]), &Registry::new());
+    }
+
+    #[test]
+    fn sequence_layers_round_trip() {
+        let mut model: Sequential =
+            Sequential::with_input_shape(&[4], Cost::SparseCategoricalCrossEntropy);
+        model.add(Layers::Embedding {
+            vocab_size: 7,
+            dim: 4,
+            padding_idx: Some(0),
+        });
+        model.add(Layers::PositionalEncoding {
+            learned: true,
+            mask_padding: true,
+        });
+        model.add(Layers::MultiHeadAttention {
+            heads: 2,
+            causal: true,
+            mask_padding: true,
+            init_func: String::from("xavier"),
+        });
+        model.add(Layers::TransformerEncoderBlock {
+            heads: 2,
+            ff_units: 6,
+            causal: false,
+            mask_padding: true,
+            epsilon: 1e-5,
+        });
+        model.add(Layers::Flatten);
+        model.add(dense(3, Activations::Softmax { temperature: 1. }));
+        let tokens: ArrayD<f32> = array![[1., 5., 2., 0.], [6., 3., 0., 0.]].into_dyn();
+        assert_round_trip(&model, &tokens, &Registry::new());
+    }
+
+    #[test]
+    fn containers_round_trip() {
+        let mut model: Sequential = Sequential::new(4, Cost::KLDivergence);
+        model.add(Layers::Residual {
+            block: vec![
+                Layers::BatchNorm {
+                    momentum: 0.9,
+                    epsilon: 1e-3,
+                },
+                dense(4, Activations::Swish { beta: 1.2 }),
+            ],
+        });
+        model.add(Layers::Parallel {
+            branches: vec![
+                vec![dense(2, Activations::HardTanh)],
+                vec![
+                    Layers::LayerNorm { epsilon: 1e-3 },
+                    dense(3, Activations::Mish),
+                ],
+            ],
+        });
+        model.add(dense(3, Activations::Softmax { temperature: 2. }));
+        model.state[0] = array![[0.1, 0.2, -0.3, 0.4, 1.1, 0.9, 1.3, 0.7]];
+        assert_round_trip(&model, &inputs(&[3, 4]), &Registry::new());
+    }
+
+    #[test]
+    fn saved_files_load_back() {
+        let model: Sequential = small_model();
+        let path: std::path::PathBuf =
+            std::env::temp_dir().join(format!("fe0_ml_round_trip_{}.fe0", std::process::id()));
+        model.save(&path).unwrap();
+        let loaded: Result<Sequential, ModelError> = Sequential::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let x: ArrayD<f32> = inputs(&[2, 2]);
+        assert_eq!(loaded.unwrap().predict(&x), model.predict(&x));
+    }
+
+    // scales its input by a saved factor, and a loss weighing squared errors by one
+    struct Scaled(f32);
+
+    impl Activation for Scaled {
+        fn activate(&self, z: &Array2<f32>) -> Array2<f32> {
+            z * self.0
+        }
+
+        fn derivate(&self, z: &Array2<f32>) -> Array2<f32> {
+            Array2::from_elem(z.dim(), self.0)
+        }
+
+        fn name(&self) -> String {
+            String::from("scaled")
+        }
+
+        fn params(&self) -> Vec<f32> {
+            vec![self.0]
+        }
+    }
+
+    struct WeightedSquares(f32);
+
+    impl Loss for WeightedSquares {
+        fn calculate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> f32 {
+            self.0 * (predicted - expected).mapv(|e: f32| e * e).sum() / predicted.nrows() as f32
+        }
+
+        fn derivate(&self, predicted: &Array2<f32>, expected: &Array2<f32>) -> Array2<f32> {
+            (predicted - expected) * (2. * self.0 / predicted.nrows() as f32)
+        }
+
+        fn name(&self) -> String {
+            String::from("weighted squares")
+        }
+
+        fn params(&self) -> Vec<f32> {
+            vec![self.0]
+        }
+    }
+
+    fn custom_model() -> Sequential {
+        let mut model: Sequential =
+            Sequential::new(3, Cost::Custom(Box::new(WeightedSquares(0.25))));
+        model.add(dense(4, Activations::Custom(Box::new(Scaled(-1.5)))));
+        model
+    }
+
+    #[test]
+    fn custom_activations_and_costs_round_trip_through_the_registry() {
+        let mut registry: Registry = Registry::new();
+        registry.register_activation("scaled", |params: &[f32]| Box::new(Scaled(params[0])));
+        registry.register_loss("weighted squares", |params: &[f32]| {
+            Box::new(WeightedSquares(params[0]))
+        });
+        let model: Sequential = custom_model();
+        assert_round_trip(&model, &inputs(&[2, 3]), &registry);
+        let loaded: Sequential = decode_with(&encode(&model), &registry).unwrap();
+        let (p, y) = (array![[1., 2.]], array![[0., 0.]]);
+        assert_eq!(loaded.cost.calculate(&p, &y), model.cost.calculate(&p, &y));
+    }
+
+    #[test]
+    fn unregistered_customs_are_rejected_on_load() {
+        let bytes: Vec<u8> = encode(&custom_model());
+        // the cost is read before any layer
+        match decode(&bytes) {
+            Err(ModelError::Unregistered { kind: "cost", id }) => {
+                assert_eq!(id, "weighted squares")
+            }
+            other => panic!("expected an unregistered cost but got {:?}", other.err()),
+        }
+        let mut registry: Registry = Registry::new();
+        registry.register_loss("weighted squares", |params: &[f32]| {
+            Box::new(WeightedSquares(params[0]))
+        });
+        match decode_with(&bytes, &registry) {
+            Err(ModelError::Unregistered {
+                kind: "activation",
+                id,
+            }) => assert_eq!(id, "scaled"),
+            other => panic!(
+                "expected an unregistered activation but got {:?}",
+                other.err()
+            ),
+        }
+    }
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        let path: std::path::PathBuf = std::env::temp_dir().join("fe0_ml_missing/model.fe0");
+        assert!(matches!(Sequential::load(&path), Err(ModelError::Io(_))));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(decode(b"PK\x03\x04"), Err(ModelError::BadMagic)));
+        assert!(matches!(decode(b"FE"), Err(ModelError::BadMagic)));
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let mut bytes: Vec<u8> = encode(&small_model());
+        bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+        match decode(&bytes) {
+            Err(ModelError::UnsupportedVersion {
+                found: 2,
+                supported: 1,
+            }) => {}
+            other => panic!("expected an unsupported version but got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn corrupt_payloads_fail_the_checksum() {
+        let mut bytes: Vec<u8> = encode(&small_model());
+        bytes[40] ^= 0x10;
+        assert!(matches!(
+            decode(&bytes),
+            Err(ModelError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes: Vec<u8> = encode(&small_model());
+        for len in [6, 14, 30, bytes.len() - 3].iter() {
+            assert!(matches!(decode(&bytes[..*len]), Err(ModelError::Truncated)));
+        }
+        // a checksum that matches a payload cut short
+        let cut: Vec<u8> = rewrap(&bytes, |payload: &mut Vec<u8>| {
+            payload.truncate(payload.len() - 5)
+        });
+        assert!(matches!(decode(&cut), Err(ModelError::Truncated)));
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        // the payload starts with the rank as a u32 and the one input dimension as a u64, then the cost
+        let bytes: Vec<u8> = rewrap(&encode(&small_model()), |payload: &mut Vec<u8>| {
+            payload[12] = 200
+        });
+        match decode(&bytes) {
+            Err(ModelError::UnknownTag {
+                kind: "cost",
+                tag: 200,
+            }) => {}
+            other => panic!("expected an unknown cost but got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn parameters_of_the_wrong_shape_are_rejected() {
+        let mut model: Sequential = small_model();
+        model.weights[0] = Array2::zeros((3, 2));
+        match decode(&encode(&model)) {
+            Err(ModelError::ShapeMismatch {
+                layer: 0,
+                expected: (2, 3),
+                found: (3, 2),
+            }) => {}
+            other => panic!("expected a shape mismatch but got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn zero_stride_is_rejected_on_load() {
+        let mut model: Sequential = Sequential::with_input_shape(&[1, 4, 4], Cost::MSE);
+        model.add(Layers::AvgPool2D {
+            pool_size: (2, 2),
+            stride: 2,
+        });
+        // what a corrupt or hand-written file would hold, add would never let this through
+        model.layers[0] = Layers::AvgPool2D {
+            pool_size: (2, 2),
+            stride: 0,
+        };
+        match decode(&encode(&model)) {
+            Err(ModelError::InvalidLayer { layer: 0, .. }) => {}
+            other => panic!("expected an invalid layer but got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn invalid_cost_is_rejected_on_load() {
+        let mut model: Sequential = Sequential::new(2, Cost::MSE);
+        model.cost = Cost::Huber { delta: -1. };
+        match decode(&encode(&model)) {
+            Err(ModelError::InvalidCost(_)) => {}
+            other => panic!("expected an invalid cost but got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn padding_outside_the_vocabulary_is_rejected_on_load() {
+        let mut model: Sequential = Sequential::new(5, Cost::MSE);
+        model.add(Layers::Embedding {
+            vocab_size: 10,
+            dim: 4,
+            padding_idx: Some(0),
+        });
+        model.layers[0] = Layers::Embedding {
+            vocab_size: 10,
+            dim: 4,
+            padding_idx: Some(10),
+        };
+        match decode(&encode(&model)) {
+            Err(ModelError::InvalidLayer { layer: 0, .. }) => {}
+            other => panic!("expected an invalid layer but got {:?}", other.err()),
+        }
+    }
+}
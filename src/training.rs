@@ -0,0 +1,290 @@
+#![allow(dead_code, unused_variables)]
+use crate::{
+    callbacks::{Callback, EpochLogs, History, TrainingContext},
+    metrics::Evaluation,
+    optimizers::Optimizer,
+    serialization::ModelError,
+    typings::Validation,
+};
+use ndarray::Array2;
+use std::path::Path;
+
+// what callbacks can reach on the model being trained, implemented by Sequential and Model
+pub trait Trainable {
+    // one entry per layer, in the order the optimizer sees them
+    fn weights(&mut self) -> &mut Vec<Array2<f32>>;
+    fn biases(&mut self) -> &mut Vec<Array2<f32>>;
+    fn state(&mut self) -> &mut Vec<Array2<f32>>;
+    // (weights, biases) without borrowing them mutably, what optimizers size their buffers from
+    fn parameters(&self) -> (&[Array2<f32>], &[Array2<f32>]);
+    // every layer's Layers::decay_mask, in the same order as weights
+    fn decay_masks(&self) -> Vec<Array2<f32>>;
+    // set by callbacks to end training after the current epoch
+    fn set_stop_training(&mut self, stop: bool);
+    fn stop_training(&self) -> bool;
+    fn save(&self, path: &Path) -> Result<(), ModelError>;
+}
+
+// the parts of training that depend on the kind of model, fit runs everything else
+pub trait Fit: Trainable {
+    // a whole dataset, and a single stacked mini-batch of one
+    type Data;
+    type Batch;
+
+    // (train, held out) with the last fraction of data held out, like keras' validation_split
+    fn split(data: Self::Data, fraction: f32) -> (Self::Data, Self::Data);
+    fn batches(data: Self::Data, batch_size: usize) -> Vec<Self::Batch>;
+    // forward, state update, backprop and one optimizer step, returns the batch's (loss, accuracy)
+    fn train_batch(&mut self, batch: &Self::Batch, optimizer: &mut dyn Optimizer) -> (f32, f32);
+    fn validate(&self, data: &Self::Data) -> Evaluation;
+}
+
+// minibatch training shared by every model, running every hook in callbacks as it goes
+// returns the per-epoch logs
+pub fn fit<M: Fit>(
+    model: &mut M,
+    data: M::Data,
+    optimizer: &mut dyn Optimizer,
+    batch_size: usize,
+    epochs: usize,
+    validation: Validation<M::Data>,
+    callbacks: &mut [&mut dyn Callback],
+) -> History {
+    let (data, validation): (M::Data, Option<M::Data>) = match validation {
+        Validation::None => (data, None),
+        Validation::Data(held_out) => (data, Some(held_out)),
+        Validation::Split(fraction) => {
+            let (train, held_out): (M::Data, M::Data) = M::split(data, fraction);
+            (train, Some(held_out))
+        }
+    };
+    // every batch is stacked up front
+    let batches: Vec<M::Batch> = M::batches(data, batch_size);
+    let mut history: History = History::new();
+    model.set_stop_training(false);
+    for epoch in 0..epochs {
+        for callback in callbacks.iter_mut() {
+            callback.on_epoch_begin(epoch, &mut TrainingContext { model, optimizer });
+        }
+
+        let mut epoch_cost: f32 = 0f32;
+        let mut epoch_accuracy: f32 = 0f32;
+        for (i, batch) in batches.iter().enumerate() {
+            let (cost, accuracy): (f32, f32) = model.train_batch(batch, optimizer);
+            epoch_cost += cost;
+            epoch_accuracy += accuracy;
+            for callback in callbacks.iter_mut() {
+                callback.on_batch_end(i, cost, &mut TrainingContext { model, optimizer });
+            }
+        }
+
+        let logs: EpochLogs = EpochLogs {
+            epoch,
+            loss: epoch_cost / batches.len() as f32,
+            accuracy: epoch_accuracy / batches.len() as f32,
+            validation: validation
+                .as_ref()
+                .map(|data: &M::Data| model.validate(data)),
+        };
+        for callback in callbacks.iter_mut() {
+            callback.on_epoch_end(&logs, &mut TrainingContext { model, optimizer });
+        }
+        history.epochs.push(logs);
+        if model.stop_training() {
+            break;
+        }
+    }
+
+    for callback in callbacks.iter_mut() {
+        callback.on_train_end(&history, &mut TrainingContext { model, optimizer });
+    }
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activations::Activations,
+        callbacks::{EarlyStopping, Mode, Monitor},
+        cost::Cost,
+        layers::Layers,
+        model::{Model, Node},
+        netutil::{Net, Sequential},
+        optimizers::Optimizers,
+        regularizers::Regularizer,
+        typings::{MultiSample, Sample},
+    };
+    use ndarray::{Array, ArrayD, Axis};
+
+    fn dense(units: usize, activation: Activations) -> Layers {
+        Layers::Dense {
+            units,
+            activation,
+            init_func: String::from("xavier"),
+            kernel_regularizer: Regularizer::None,
+            bias_regularizer: Regularizer::None,
+        }
+    }
+
+    // (samples × 3) inputs and the sum of each row as the label
+    fn data(samples: usize) -> (ArrayD<f32>, ArrayD<f32>) {
+        let x: ArrayD<f32> =
+            Array::from_shape_fn((samples, 3), |(i, j)| ((i * 3 + j) as f32 * 0.37).sin())
+                .into_dyn();
+        let y: ArrayD<f32> = x.sum_axis(Axis(1)).insert_axis(Axis(1));
+        (x, y)
+    }
+
+    // counts every hook and stops training once stop_at epochs have ended
+    struct Recorder {
+        stop_at: usize,
+        epochs_begun: usize,
+        batches: usize,
+        epochs_ended: usize,
+        finished: bool,
+    }
+
+    impl Callback for Recorder {
+        fn on_epoch_begin(&mut self, epoch: usize, ctx: &mut TrainingContext) {
+            self.epochs_begun += 1;
+        }
+
+        fn on_batch_end(&mut self, batch: usize, loss: f32, ctx: &mut TrainingContext) {
+            self.batches += 1;
+        }
+
+        fn on_epoch_end(&mut self, logs: &EpochLogs, ctx: &mut TrainingContext) {
+            self.epochs_ended += 1;
+            if self.epochs_ended == self.stop_at {
+                ctx.model.set_stop_training(true);
+            }
+        }
+
+        fn on_train_end(&mut self, history: &History, ctx: &mut TrainingContext) {
+            self.finished = true;
+        }
+    }
+
+    fn recorder(stop_at: usize) -> Recorder {
+        Recorder {
+            stop_at,
+            epochs_begun: 0,
+            batches: 0,
+            epochs_ended: 0,
+            finished: false,
+        }
+    }
+
+    fn graph() -> Model {
+        let mut model: Model = Model::new();
+        let input: Node = model.input(&[3]);
+        let hidden: Node = model.layer(dense(4, Activations::Tanh), input);
+        let output: Node = model.layer(dense(1, Activations::Linear), hidden);
+        model.output(output, Cost::MSE);
+        model
+    }
+
+    #[test]
+    fn graph_models_run_callbacks_and_validation() {
+        let mut model: Model = graph();
+        let (x, y) = data(16);
+        let mut optimizer = Optimizers::SGD.build(0.05, &model);
+        let mut stopper: Recorder = recorder(3);
+        let history: History = model
+            .train(
+                MultiSample::new(vec![x], vec![y]),
+                &mut optimizer,
+                4,
+                10,
+                Validation::Split(0.25),
+                &mut [&mut stopper],
+            )
+            .unwrap();
+        // 12 training samples in batches of 4, stopped after the third epoch
+        assert_eq!(history.epochs.len(), 3);
+        assert_eq!(history.val_loss().len(), 3);
+        assert_eq!(stopper.epochs_begun, 3);
+        assert_eq!(stopper.batches, 9);
+        assert!(stopper.finished);
+
+        // a second run clears the flag the first one left set
+        let history: History = model
+            .train(
+                MultiSample::new(vec![data(8).0], vec![data(8).1]),
+                &mut optimizer,
+                4,
+                2,
+                Validation::None,
+                &mut [],
+            )
+            .unwrap();
+        assert_eq!(history.epochs.len(), 2);
+        assert!(history.val_loss().is_empty());
+    }
+
+    #[test]
+    fn early_stopping_restores_graph_model_weights() {
+        let mut model: Model = graph();
+        let (x, y) = data(16);
+        let held_out: MultiSample = MultiSample::new(vec![x.clone()], vec![y.clone()]);
+        // a learning rate this large makes the validation loss blow up after the first epoch
+        let mut optimizer = Optimizers::SGD.build(50., &model);
+        let mut early_stopping: EarlyStopping =
+            EarlyStopping::new(Monitor::ValLoss, Mode::Min, 1, 0., true);
+        let history: History = model
+            .train(
+                MultiSample::new(vec![x], vec![y]),
+                &mut optimizer,
+                16,
+                20,
+                Validation::Data(held_out.clone()),
+                &mut [&mut early_stopping],
+            )
+            .unwrap();
+        assert!(early_stopping.stopped_epoch.is_some());
+        assert!(history.epochs.len() < 20);
+        let best: f32 = early_stopping.best.unwrap();
+        assert!((model.validate(&held_out).loss - best).abs() <= 1e-4 * best.max(1.));
+    }
+
+    #[test]
+    fn graph_models_refuse_to_save() {
+        let model: Model = graph();
+        assert!(matches!(
+            Trainable::save(&model, Path::new("graph.fe0")),
+            Err(ModelError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn sequential_models_share_the_loop() {
+        let mut model: Sequential = Sequential::new(3, Cost::MSE);
+        model.add(dense(4, Activations::Tanh));
+        model.add(dense(1, Activations::Linear));
+        let (x, y) = data(16);
+        let dataset: Vec<Sample> = x
+            .outer_iter()
+            .zip(y.outer_iter())
+            .map(|(x, y)| {
+                Sample::new(
+                    x.insert_axis(Axis(0)).to_owned(),
+                    y.insert_axis(Axis(0)).to_owned(),
+                )
+            })
+            .collect();
+        let mut optimizer = Optimizers::SGD.build(0.05, &model);
+        let mut stopper: Recorder = recorder(2);
+        let history: History = model.train(
+            dataset,
+            &mut optimizer,
+            4,
+            10,
+            Validation::Split(0.25),
+            &mut [&mut stopper],
+        );
+        assert_eq!(history.epochs.len(), 2);
+        assert_eq!(history.val_accuracy().len(), 2);
+        assert_eq!(stopper.batches, 6);
+    }
+}
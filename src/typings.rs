@@ -1,6 +1,110 @@
-use ndarray::Array2;
+#![allow(dead_code)]
+use ndarray::{Array1, Array2, ArrayD, Axis};
 pub type Dataset = Vec<Sample>;
-pub type BatchedDataset = Vec<Dataset>;
-pub type ForwardBatch = Vec<Vec<Vec<Array2<f32>>>>;
+// each batch is a single Sample holding the stacked inputs and labels
+pub type BatchedDataset = Vec<Sample>;
+// every layer's z, activation and cache for a whole batch, z and a are (batch, ..sample shape) per layer
+pub struct ForwardBatch {
+    pub z: Vec<ArrayD<f32>>,
+    pub a: Vec<ArrayD<f32>>,
+    pub cache: Vec<Cache>,
+}
+
+// what a layer's forward pass keeps for backward and update_state besides its input
 #[derive(Clone)]
-pub struct Sample(pub Array2<f32>, pub Array2<f32>);
+pub enum Cache {
+    // the dropout mask, the batch statistics, every timestep's activations, 0×0 when there's nothing to keep
+    Matrix(Array2<f32>),
+    // a container's (input, z, cache) for every inner layer, in the order their parameters are packed
+    Inner(Vec<(Array2<f32>, Array2<f32>, Cache)>),
+}
+
+impl Cache {
+    pub fn empty() -> Self {
+        Cache::Matrix(Array2::zeros((0, 0)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Cache::Matrix(m) => m.is_empty(),
+            Cache::Inner(inner) => inner.is_empty(),
+        }
+    }
+
+    // the single matrix a non-container layer keeps
+    pub fn matrix(&self) -> &Array2<f32> {
+        match self {
+            Cache::Matrix(m) => m,
+            Cache::Inner(_) => panic!("expected a layer's cache but got a container's"),
+        }
+    }
+
+    // every inner layer's (input, z, cache) a container keeps
+    pub fn inner(&self) -> &[(Array2<f32>, Array2<f32>, Cache)] {
+        match self {
+            Cache::Inner(inner) => inner,
+            Cache::Matrix(_) => panic!("expected a container's cache but got a layer's"),
+        }
+    }
+}
+// (inputs, labels, weights), inputs and labels have the batch as their first axis followed by the shape
+// of a single sample, so a lone MNIST image is (1, 1, 28, 28) with a (1, 10) label
+// weights scale every sample's share of the loss during training and evaluation, one per batch entry
+#[derive(Clone)]
+pub struct Sample(pub ArrayD<f32>, pub ArrayD<f32>, pub Array1<f32>);
+
+impl Sample {
+    // every sample weighted 1
+    pub fn new(inputs: ArrayD<f32>, labels: ArrayD<f32>) -> Self {
+        let weights: Array1<f32> = Array1::ones(inputs.len_of(Axis(0)));
+        Sample(inputs, labels, weights)
+    }
+}
+
+// (inputs, labels, weights) for a graph Model, one tensor per model input and output with samples along
+// axis 0 and one weight per sample like Sample's
+#[derive(Clone)]
+pub struct MultiSample(pub Vec<ArrayD<f32>>, pub Vec<ArrayD<f32>>, pub Array1<f32>);
+
+impl MultiSample {
+    // every sample weighted 1
+    pub fn new(inputs: Vec<ArrayD<f32>>, labels: Vec<ArrayD<f32>>) -> Self {
+        let samples: usize = inputs
+            .iter()
+            .chain(labels.iter())
+            .next()
+            .map_or(0, |x: &ArrayD<f32>| x.len_of(Axis(0)));
+        MultiSample(inputs, labels, Array1::ones(samples))
+    }
+}
+
+// data used to score the model at the end of every training epoch
+pub enum Validation<D = Dataset> {
+    None,
+    Data(D),
+    // fraction of the training set held out before batching
+    Split(f32),
+}
+
+// one tensor per layer in forward order, laid out exactly like Sequential::weights and Sequential::biases
+#[derive(Clone)]
+pub struct Gradients {
+    pub weights: Vec<Array2<f32>>,
+    pub biases: Vec<Array2<f32>>,
+}
+
+impl Gradients {
+    pub fn new() -> Self {
+        Gradients {
+            weights: Vec::new(),
+            biases: Vec::new(),
+        }
+    }
+
+    pub fn zeros_like(weights: &[Array2<f32>], biases: &[Array2<f32>]) -> Self {
+        Gradients {
+            weights: weights.iter().map(|w| Array2::zeros(w.raw_dim())).collect(),
+            biases: biases.iter().map(|b| Array2::zeros(b.raw_dim())).collect(),
+        }
+    }
+}